@@ -0,0 +1,156 @@
+//! Web Mercator tile math in `f64`. The bounds `slippy_map_tiles` gives a
+//! tile and the tile it finds a point in are `f32`, a few decimeters off at
+//! the equator and more than a pixel apart between neighbouring tiles past
+//! zoom 17, so everything that places a coordinate in a tile or a pixel goes
+//! through here instead. Tile coordinates are fractional: the integer part
+//! is the tile, the rest the position within it, with `y` growing south.
+//!
+//! Shared by the generator and `stitch_pictures`, so that chips are placed
+//! with the same math as the tiles they are made of.
+
+use std::f64::consts::PI;
+
+use slippy_map_tiles::Tile;
+
+/// A point, degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatLon {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// The edges of a tile, degrees.
+#[derive(Clone, Copy, Debug)]
+pub struct TileBounds {
+    pub north: f64,
+    pub south: f64,
+    pub west: f64,
+    pub east: f64,
+}
+
+fn tiles_across(zoom: u8) -> f64 {
+    2f64.powi(zoom as i32)
+}
+
+/// The position of `lat`, `lon` among the tiles of `zoom`, as fractional
+/// `x`, `y`.
+pub fn tile_xy(lat: f64, lon: f64, zoom: u8) -> (f64, f64) {
+    let n = tiles_across(zoom);
+    let x = (lon + 180.0) / 360.0 * n;
+    let y = (1.0 - lat.to_radians().tan().asinh() / PI) / 2.0 * n;
+    (x, y)
+}
+
+/// The tile of `zoom` `lat`, `lon` is in, as `x`, `y`; points past the
+/// edges of the map are in the tiles along them.
+pub fn tile_at(lat: f64, lon: f64, zoom: u8) -> (u32, u32) {
+    let (x, y) = tile_xy(lat, lon, zoom);
+    let last = tiles_across(zoom) - 1.0;
+    (
+        x.floor().clamp(0.0, last) as u32,
+        y.floor().clamp(0.0, last) as u32,
+    )
+}
+
+/// The coordinate at fractional `x`, `y` of `zoom`.
+pub fn coordinate(x: f64, y: f64, zoom: u8) -> LatLon {
+    let n = tiles_across(zoom);
+    LatLon {
+        latitude: (PI * (1.0 - 2.0 * y / n)).sinh().atan().to_degrees(),
+        longitude: x / n * 360.0 - 180.0,
+    }
+}
+
+/// The coordinate `fx`, `fy` of the way across `tile`, from its north-west
+/// corner.
+pub fn within(tile: Tile, fx: f64, fy: f64) -> LatLon {
+    coordinate(tile.x() as f64 + fx, tile.y() as f64 + fy, tile.zoom())
+}
+
+pub fn bounds(tile: Tile) -> TileBounds {
+    let (nw, se) = (within(tile, 0.0, 0.0), within(tile, 1.0, 1.0));
+    TileBounds {
+        north: nw.latitude,
+        south: se.latitude,
+        west: nw.longitude,
+        east: se.longitude,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZOOM: u8 = 17;
+
+    #[test]
+    fn tile_xy_round_trips() {
+        for (lat, lon) in [(55.75, 37.62), (-33.86, 151.21), (0.0, 0.0), (84.9, -179.9)] {
+            let (x, y) = tile_xy(lat, lon, ZOOM);
+            let c = coordinate(x, y, ZOOM);
+            assert!(
+                (c.latitude - lat).abs() < 1e-9,
+                "{lat} came back as {}",
+                c.latitude
+            );
+            assert!(
+                (c.longitude - lon).abs() < 1e-9,
+                "{lon} came back as {}",
+                c.longitude
+            );
+        }
+    }
+
+    #[test]
+    fn tile_at_finds_the_tile_of_every_point_in_it() {
+        let tile = Tile::new(ZOOM, 79_233, 40_977).unwrap();
+        for (fx, fy) in [
+            (0.0, 0.0),
+            (0.5, 0.5),
+            (1e-6, 1.0 - 1e-6),
+            (1.0 - 1e-6, 1e-6),
+        ] {
+            let c = within(tile, fx, fy);
+            assert_eq!(tile_at(c.latitude, c.longitude, ZOOM), (tile.x(), tile.y()));
+        }
+        let c = within(tile, 1.0 + 1e-6, 1.0 + 1e-6);
+        assert_eq!(
+            tile_at(c.latitude, c.longitude, ZOOM),
+            (tile.x() + 1, tile.y() + 1)
+        );
+    }
+
+    #[test]
+    fn tile_at_keeps_points_off_the_map_on_its_edge() {
+        let last = (1 << ZOOM) - 1;
+        assert_eq!(tile_at(89.9, 180.0, ZOOM), (last, 0));
+        assert_eq!(tile_at(-89.9, -180.0, ZOOM), (0, last));
+    }
+
+    #[test]
+    fn neighbouring_tiles_share_their_edges() {
+        let tile = Tile::new(ZOOM, 79_233, 40_977).unwrap();
+        let b = bounds(tile);
+        let east = bounds(Tile::new(ZOOM, tile.x() + 1, tile.y()).unwrap());
+        let south = bounds(Tile::new(ZOOM, tile.x(), tile.y() + 1).unwrap());
+        assert_eq!(b.east, east.west);
+        assert_eq!(b.south, south.north);
+        assert!(b.north > b.south && b.east > b.west);
+    }
+
+    #[test]
+    fn bounds_match_tile_xy() {
+        let tile = Tile::new(ZOOM, 79_233, 40_977).unwrap();
+        let b = bounds(tile);
+        let (x0, y0) = tile_xy(b.north, b.west, ZOOM);
+        let (x1, y1) = tile_xy(b.south, b.east, ZOOM);
+        for (got, want) in [
+            (x0, 79_233.0),
+            (y0, 40_977.0),
+            (x1, 79_234.0),
+            (y1, 40_978.0),
+        ] {
+            assert!((got - want).abs() < 1e-6, "{got} is not {want}");
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Address points matched to the footprints they lie in. Many buildings
+//! carry no `addr:*` tags themselves and are addressed by a node inside
+//! them instead, usually the entrance or a point dropped by an import.
+
+use std::collections::{BTreeMap, HashMap};
+
+use geo::{BoundingRect, Contains, GeodesicArea, MultiPolygon, Point};
+use osmpbfreader::{Node, Tags};
+use serde::Serialize;
+
+use crate::{geometry::node_coord, zoom};
+
+/// `addr:*` tags without the prefix.
+pub fn tags(tags: &Tags) -> BTreeMap<&str, &str> {
+    tags.iter()
+        .filter_map(|(k, v)| Some((k.strip_prefix("addr:")?, v.as_str())))
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct AddressPoint<'a> {
+    pub node_id: i64,
+    pub address: BTreeMap<&'a str, &'a str>,
+}
+
+/// Footprints by the tiles of the tile zoom their bounding box touches.
+struct Grid {
+    cells: HashMap<(u32, u32), Vec<usize>>,
+}
+
+impl Grid {
+    fn new(footprints: &[MultiPolygon<f64>]) -> Self {
+        let mut cells: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, footprint) in footprints.iter().enumerate() {
+            let Some(rect) = footprint.bounding_rect() else {
+                continue;
+            };
+            let (x0, y0) = mercator::tile_at(rect.max().y, rect.min().x, zoom());
+            let (x1, y1) = mercator::tile_at(rect.min().y, rect.max().x, zoom());
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    cells.entry((x, y)).or_default().push(i);
+                }
+            }
+        }
+        Self { cells }
+    }
+
+    fn candidates(&self, p: Point<f64>) -> &[usize] {
+        let cell = mercator::tile_at(p.y(), p.x(), zoom());
+        self.cells.get(&cell).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Gives every address node that is not a building itself to the smallest
+/// footprint containing it, returning the points per footprint.
+pub fn assign<'a>(
+    footprints: &[MultiPolygon<f64>],
+    nodes: &'a HashMap<i64, Node>,
+) -> Vec<Vec<AddressPoint<'a>>> {
+    let grid = Grid::new(footprints);
+    let areas: Vec<_> = footprints
+        .iter()
+        .map(|f| f.geodesic_area_signed().abs())
+        .collect();
+    let mut assigned: Vec<Vec<AddressPoint>> = footprints.iter().map(|_| vec![]).collect();
+    let mut ids: Vec<_> = nodes.keys().collect();
+    ids.sort();
+    for id in ids {
+        let node = &nodes[id];
+        if node.tags.contains_key("building") {
+            continue;
+        }
+        let address = tags(&node.tags);
+        if address.is_empty() {
+            continue;
+        }
+        let c = node_coord(node);
+        let p = Point::new(c.longitude, c.latitude);
+        let best = grid
+            .candidates(p)
+            .iter()
+            .filter(|i| footprints[**i].contains(&p))
+            .min_by(|a, b| areas[**a].total_cmp(&areas[**b]));
+        if let Some(i) = best {
+            assigned[*i].push(AddressPoint {
+                node_id: node.id.0,
+                address,
+            });
+        }
+    }
+    assigned
+}
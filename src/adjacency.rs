@@ -0,0 +1,82 @@
+//! Which stitched chips border which, for tiled inference that blends
+//! predictions across chip edges. Chips touching along an edge, at a
+//! corner or overlapping are joined; the overlap tells apart the three
+//! and, for overlapping chips, by how much.
+
+use std::path::Path;
+
+use geo::{coord, Rect};
+use log::info;
+use serde::Serialize;
+
+use crate::chips::Grid;
+
+#[derive(Serialize)]
+struct Node<'a> {
+    key: &'a str,
+    /// `[x0, y0, x1, y1]` in the pixel grid of all tiles of the tile zoom.
+    px: [u32; 4],
+}
+
+#[derive(Serialize)]
+struct Edge {
+    a: usize,
+    b: usize,
+    /// Position of `b`'s top-left corner relative to `a`'s, pixels.
+    offset_px: [i64; 2],
+    /// Width and height of the shared area, pixels: zero in one direction
+    /// for chips sharing an edge, in both for chips meeting at a corner.
+    overlap_px: [u32; 2],
+}
+
+#[derive(Serialize)]
+struct Graph<'a> {
+    nodes: Vec<Node<'a>>,
+    edges: Vec<Edge>,
+}
+
+pub fn export_adjacency(chips: &Path, out: &Path) -> anyhow::Result<()> {
+    let grid = Grid::load(chips)?;
+    let px = |i: usize| grid.chips[i].px.map(|v| v as i64);
+    let mut edges = vec![];
+    for (a, chip) in grid.chips.iter().enumerate() {
+        let [x0, y0, x1, y1] = chip.px;
+        // Grown by a pixel so that chips just touching are found too.
+        let around = Rect::new(
+            coord! { x: x0 - 1.0, y: y0 - 1.0 },
+            coord! { x: x1 + 1.0, y: y1 + 1.0 },
+        );
+        for b in grid.overlapping(around).into_iter().filter(|b| *b > a) {
+            let ([ax0, ay0, ax1, ay1], [bx0, by0, bx1, by1]) = (px(a), px(b));
+            let (ox, oy) = (ax1.min(bx1) - ax0.max(bx0), ay1.min(by1) - ay0.max(by0));
+            if ox < 0 || oy < 0 {
+                continue;
+            }
+            edges.push(Edge {
+                a,
+                b,
+                offset_px: [bx0 - ax0, by0 - ay0],
+                overlap_px: [ox as u32, oy as u32],
+            });
+        }
+    }
+    let graph = Graph {
+        nodes: grid
+            .chips
+            .iter()
+            .map(|c| Node {
+                key: &c.key,
+                px: c.px.map(|v| v as u32),
+            })
+            .collect(),
+        edges,
+    };
+    std::fs::write(out, serde_json::to_string_pretty(&graph)?)?;
+    info!(
+        "Wrote {} chips and {} adjacencies to {}",
+        graph.nodes.len(),
+        graph.edges.len(),
+        out.display()
+    );
+    Ok(())
+}
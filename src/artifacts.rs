@@ -0,0 +1,190 @@
+//! Imagery artifact masks: per-chip maps of pixels that show mosaic seams,
+//! haze or clipped exposure, so training can skip those pixels instead of
+//! whole chips.
+
+use std::{io::Write, path::Path};
+
+use image::{GrayImage, Luma, RgbImage};
+use log::info;
+
+use crate::{formats::Formats, list_tiles, paths};
+
+pub const CLEAN: u8 = 0;
+pub const SEAM: u8 = 1;
+pub const HAZE: u8 = 2;
+pub const CLIPPED: u8 = 3;
+
+/// Channels at or beyond these are clipped.
+const CLIP_LOW: u8 = 3;
+const CLIP_HIGH: u8 = 252;
+/// Haze: bright, washed out and featureless within `HAZE_WINDOW` pixels.
+const HAZE_WINDOW: u32 = 15;
+const HAZE_MIN_LUMA: f64 = 150.0;
+const HAZE_MAX_STDDEV: f64 = 6.0;
+const HAZE_MAX_CHROMA: u8 = 40;
+/// Seams: a row or column step at least `SEAM_RATIO` times stronger than
+/// the steps on either side, averaged over runs of `SEAM_RUN` pixels.
+const SEAM_RUN: u32 = 64;
+const SEAM_RATIO: f64 = 3.0;
+const SEAM_MIN_STEP: f64 = 12.0;
+/// Width of the marked band on each side of a seam.
+const SEAM_BAND: u32 = 2;
+
+fn luma(img: &RgbImage) -> Vec<f64> {
+    img.pixels()
+        .map(|p| 0.299 * p.0[0] as f64 + 0.587 * p.0[1] as f64 + 0.114 * p.0[2] as f64)
+        .collect()
+}
+
+/// Summed area table with a zero row and column in front.
+fn integral(values: &[f64], w: u32, h: u32) -> Vec<f64> {
+    let (w, h) = (w as usize, h as usize);
+    let mut sums = vec![0.0; (w + 1) * (h + 1)];
+    for y in 0..h {
+        let mut row = 0.0;
+        for x in 0..w {
+            row += values[y * w + x];
+            sums[(y + 1) * (w + 1) + x + 1] = sums[y * (w + 1) + x + 1] + row;
+        }
+    }
+    sums
+}
+
+fn mark_clipped(img: &RgbImage, mask: &mut GrayImage) {
+    for (x, y, p) in img.enumerate_pixels() {
+        let dark = p.0.iter().all(|c| *c <= CLIP_LOW);
+        let blown = p.0.iter().all(|c| *c >= CLIP_HIGH);
+        if dark || blown {
+            mask.put_pixel(x, y, Luma([CLIPPED]));
+        }
+    }
+}
+
+fn mark_haze(img: &RgbImage, luma: &[f64], mask: &mut GrayImage) {
+    let (w, h) = img.dimensions();
+    let sums = integral(luma, w, h);
+    let squares: Vec<f64> = luma.iter().map(|v| v * v).collect();
+    let sums_sq = integral(&squares, w, h);
+    let r = HAZE_WINDOW / 2;
+    let stride = w as usize + 1;
+    for y in 0..h {
+        for x in 0..w {
+            let (x0, y0) = (x.saturating_sub(r) as usize, y.saturating_sub(r) as usize);
+            let (x1, y1) = ((x + r + 1).min(w) as usize, (y + r + 1).min(h) as usize);
+            let n = ((x1 - x0) * (y1 - y0)) as f64;
+            let area = |s: &[f64]| {
+                s[y1 * stride + x1] - s[y0 * stride + x1] - s[y1 * stride + x0]
+                    + s[y0 * stride + x0]
+            };
+            let mean = area(&sums) / n;
+            let var = (area(&sums_sq) / n - mean * mean).max(0.0);
+            let p = img.get_pixel(x, y).0;
+            let chroma = p.iter().max().unwrap() - p.iter().min().unwrap();
+            let hazy =
+                mean >= HAZE_MIN_LUMA && var.sqrt() <= HAZE_MAX_STDDEV && chroma <= HAZE_MAX_CHROMA;
+            if hazy && mask.get_pixel(x, y).0[0] == CLEAN {
+                mask.put_pixel(x, y, Luma([HAZE]));
+            }
+        }
+    }
+}
+
+/// Looks for horizontal seams, i.e. steps between rows `y - 1` and `y`.
+/// With `transpose` the image is read with x and y swapped, which finds
+/// vertical seams. Calls `mark(along, across)` for each seam pixel.
+fn find_seams(luma: &[f64], w: u32, h: u32, transpose: bool, mut mark: impl FnMut(u32, u32)) {
+    let (len, across) = if transpose { (h, w) } else { (w, h) };
+    let at = |a: u32, c: u32| {
+        if transpose {
+            luma[(a * w + c) as usize]
+        } else {
+            luma[(c * w + a) as usize]
+        }
+    };
+    // Absolute step between line `c - 1` and `c`, averaged over a run.
+    let step = |run: u32, c: u32| {
+        let start = run * SEAM_RUN;
+        let end = (start + SEAM_RUN).min(len);
+        (start..end)
+            .map(|a| (at(a, c) - at(a, c - 1)).abs())
+            .sum::<f64>()
+            / (end - start) as f64
+    };
+    let runs = len.div_ceil(SEAM_RUN);
+    let mut strong = vec![false; (runs * across) as usize];
+    for run in 0..runs {
+        for c in 2..across.saturating_sub(1) {
+            let s = step(run, c);
+            let neighbours = step(run, c - 1).max(step(run, c + 1));
+            strong[(run * across + c) as usize] =
+                s >= SEAM_MIN_STEP && s >= SEAM_RATIO * neighbours.max(1.0);
+        }
+    }
+    // Edges of buildings and roads rarely stay on one pixel line for long,
+    // so a seam has to continue into a neighbouring run.
+    let is_strong = |run: u32, c: u32| run < runs && strong[(run * across + c) as usize];
+    for run in 0..runs {
+        for c in 0..across {
+            let continued = (run > 0 && is_strong(run - 1, c)) || is_strong(run + 1, c);
+            if !is_strong(run, c) || !continued {
+                continue;
+            }
+            let start = run * SEAM_RUN;
+            for a in start..(start + SEAM_RUN).min(len) {
+                for b in c.saturating_sub(SEAM_BAND)..(c + SEAM_BAND).min(across) {
+                    mark(a, b);
+                }
+            }
+        }
+    }
+}
+
+fn mark_seams(luma: &[f64], mask: &mut GrayImage) {
+    let (w, h) = mask.dimensions();
+    find_seams(luma, w, h, false, |x, y| mask.put_pixel(x, y, Luma([SEAM])));
+    find_seams(luma, w, h, true, |y, x| mask.put_pixel(x, y, Luma([SEAM])));
+}
+
+/// Artifact mask of one image. Seams win over clipping, which wins over
+/// haze.
+pub fn artifact_mask(img: &RgbImage) -> GrayImage {
+    let mut mask = GrayImage::new(img.width(), img.height());
+    let luma = luma(img);
+    mark_clipped(img, &mut mask);
+    mark_haze(img, &luma, &mut mask);
+    mark_seams(&luma, &mut mask);
+    mask
+}
+
+/// Writes an artifact mask for every image in `chips` to `out`, with the
+/// same names, and `artifacts.csv` with the share of each artifact per
+/// chip next to the masks.
+pub fn export_artifact_masks(chips: &Path, out: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(out)?;
+    let ext = Formats::load()?.chips.ext();
+    let mut names = list_tiles(chips, ext);
+    names.sort_by_key(|t| (t.y(), t.x()));
+    info!("Checking {} chips for artifacts", names.len());
+
+    let mut csv = std::io::BufWriter::new(std::fs::File::create(out.join("artifacts.csv"))?);
+    writeln!(csv, "chip,seam,haze,clipped")?;
+    for chip in names {
+        let name = paths::stem(chip);
+        let img = image::open(paths::tile_file(chips, chip, ext))?.into_rgb8();
+        let mask = artifact_mask(&img);
+        let mut counts = [0u64; 4];
+        for p in mask.pixels() {
+            counts[p.0[0] as usize] += 1;
+        }
+        let total = (mask.width() * mask.height()) as f64;
+        writeln!(
+            csv,
+            "{name},{:.4},{:.4},{:.4}",
+            counts[SEAM as usize] as f64 / total,
+            counts[HAZE as usize] as f64 / total,
+            counts[CLIPPED as usize] as f64 / total
+        )?;
+        mask.save(paths::create_tile_file(out, chip, ".png")?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Roof and wall attributes of a building, mapped to small integer labels
+//! for auxiliary classification heads. Label 0 means the tag is missing and
+//! `vocabulary.len() + 1` means a value outside the vocabulary.
+
+use osmpbfreader::Tags;
+use serde::Serialize;
+
+pub const ROOF_SHAPES: &[&str] = &[
+    "flat",
+    "gabled",
+    "hipped",
+    "pyramidal",
+    "skillion",
+    "half-hipped",
+    "gambrel",
+    "mansard",
+    "dome",
+    "onion",
+    "round",
+    "saltbox",
+];
+
+pub const ROOF_MATERIALS: &[&str] = &[
+    "metal",
+    "roof_tiles",
+    "concrete",
+    "tar_paper",
+    "asphalt_shingle",
+    "slate",
+    "glass",
+    "wood",
+    "gravel",
+    "grass",
+    "plastic",
+    "eternit",
+];
+
+pub const BUILDING_MATERIALS: &[&str] = &[
+    "brick",
+    "concrete",
+    "wood",
+    "metal",
+    "glass",
+    "stone",
+    "plaster",
+    "timber_framing",
+    "cement_block",
+];
+
+pub fn label(vocabulary: &[&str], value: Option<&str>) -> u8 {
+    match value {
+        None => 0,
+        Some(value) => vocabulary
+            .iter()
+            .position(|v| *v == value)
+            .map(|i| i as u8 + 1)
+            .unwrap_or(vocabulary.len() as u8 + 1),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildingAttributes {
+    pub roof_shape: Option<String>,
+    pub roof_shape_label: u8,
+    pub roof_material: Option<String>,
+    pub roof_material_label: u8,
+    pub building_material: Option<String>,
+    pub building_material_label: u8,
+}
+
+impl BuildingAttributes {
+    pub fn from_tags(tags: &Tags) -> Self {
+        let get = |key: &str| tags.get(key).map(|v| v.to_string());
+        let roof_shape = get("roof:shape");
+        let roof_material = get("roof:material");
+        let building_material = get("building:material");
+        Self {
+            roof_shape_label: label(ROOF_SHAPES, roof_shape.as_deref()),
+            roof_material_label: label(ROOF_MATERIALS, roof_material.as_deref()),
+            building_material_label: label(BUILDING_MATERIALS, building_material.as_deref()),
+            roof_shape,
+            roof_material,
+            building_material,
+        }
+    }
+}
+
+/// The vocabularies, for decoding labels in downstream code.
+pub fn vocabularies() -> serde_json::Value {
+    serde_json::json!({
+        "roof_shape": ROOF_SHAPES,
+        "roof_material": ROOF_MATERIALS,
+        "building_material": BUILDING_MATERIALS,
+    })
+}
@@ -0,0 +1,274 @@
+//! Copy-paste augmentation: building patches cut out of rendered tiles,
+//! together with their labels, are blended into tiles without buildings to
+//! raise positive density in sparse areas.
+
+use std::path::PathBuf;
+
+use image::{GrayImage, Luma, RgbImage};
+use imageproc::region_labelling::{connected_components, Connectivity};
+use log::info;
+use serde::Serialize;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    classes, list_tiles, logging, paths,
+    rng::Rng,
+    workspace::{OUTLINES, TILES},
+};
+
+/// Buildings smaller than this many pixels are not worth pasting.
+const MIN_PATCH_PX: u32 = 20;
+/// Placement attempts per patch before giving up on overlap.
+const PLACEMENT_TRIES: usize = 10;
+
+#[derive(clap::Args, Clone, Debug, Serialize)]
+pub struct AugmentOptions {
+    /// Directory receiving `tiles/`, `outlines/` and `provenance.json`.
+    #[arg(long, default_value = "augmented")]
+    #[serde(skip)]
+    pub out: PathBuf,
+    /// Buildings pasted into every empty tile.
+    #[arg(long, default_value_t = 3)]
+    pub per_tile: usize,
+    /// Augment at most this many empty tiles.
+    #[arg(long)]
+    pub max_tiles: Option<usize>,
+    /// Width of the soft edge around a pasted patch, pixels.
+    #[arg(long, default_value_t = 3.0)]
+    pub feather: f64,
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+/// A building cut out of a donor tile, with a margin of surroundings for
+/// the soft edge.
+struct Patch {
+    donor: Tile,
+    /// Bounding box of the building in the donor, `[x, y, width, height]`.
+    bbox: [u32; 4],
+    image: RgbImage,
+    outline: RgbImage,
+    /// Building pixels of this instance only.
+    mask: GrayImage,
+}
+
+#[derive(Serialize)]
+struct Paste {
+    donor: String,
+    bbox: [u32; 4],
+    /// Where the top-left corner of the patch, margin included, went.
+    at: [u32; 2],
+    pixels: u64,
+}
+
+#[derive(Serialize)]
+struct AugmentedTile {
+    tile: String,
+    pastes: Vec<Paste>,
+}
+
+#[derive(Serialize)]
+struct Provenance<'a> {
+    options: &'a AugmentOptions,
+    tiles: Vec<AugmentedTile>,
+}
+
+fn building_mask(outline: &RgbImage) -> GrayImage {
+    GrayImage::from_fn(outline.width(), outline.height(), |x, y| {
+        Luma([classes::is_building_pixel(outline.get_pixel(x, y).0) as u8])
+    })
+}
+
+/// Every whole building of a tile, cropped with `margin` pixels around
+/// it. Buildings touching the tile border are cut off and skipped.
+fn extract_patches(tile: Tile, image: &RgbImage, outline: &RgbImage, margin: u32) -> Vec<Patch> {
+    let labels = connected_components(&building_mask(outline), Connectivity::Eight, Luma([0]));
+    let (w, h) = labels.dimensions();
+    // [min_x, min_y, max_x, max_y, pixels] per label
+    let mut boxes: Vec<[u32; 5]> = vec![];
+    for (x, y, l) in labels.enumerate_pixels() {
+        let l = l.0[0] as usize;
+        if l == 0 {
+            continue;
+        }
+        if boxes.len() < l {
+            boxes.resize(l, [u32::MAX, u32::MAX, 0, 0, 0]);
+        }
+        let b = &mut boxes[l - 1];
+        b[0] = b[0].min(x);
+        b[1] = b[1].min(y);
+        b[2] = b[2].max(x);
+        b[3] = b[3].max(y);
+        b[4] += 1;
+    }
+    let mut patches = vec![];
+    for (i, b) in boxes.iter().enumerate() {
+        let touches_edge = b[0] == 0 || b[1] == 0 || b[2] == w - 1 || b[3] == h - 1;
+        if b[4] < MIN_PATCH_PX || touches_edge {
+            continue;
+        }
+        let (x0, y0) = (b[0].saturating_sub(margin), b[1].saturating_sub(margin));
+        let (x1, y1) = ((b[2] + margin).min(w - 1), (b[3] + margin).min(h - 1));
+        let (pw, ph) = (x1 - x0 + 1, y1 - y0 + 1);
+        let label = i as u32 + 1;
+        patches.push(Patch {
+            donor: tile,
+            bbox: [b[0], b[1], b[2] - b[0] + 1, b[3] - b[1] + 1],
+            image: image::imageops::crop_imm(image, x0, y0, pw, ph).to_image(),
+            outline: image::imageops::crop_imm(outline, x0, y0, pw, ph).to_image(),
+            mask: GrayImage::from_fn(pw, ph, |x, y| {
+                Luma([if labels.get_pixel(x0 + x, y0 + y).0[0] == label {
+                    255
+                } else {
+                    0
+                }])
+            }),
+        });
+    }
+    patches
+}
+
+/// Blends `patch` into `image` with its top-left corner at `(ax, ay)`,
+/// fading out over `feather` pixels around the building, and copies the
+/// building's labels into `outline`. Returns the labelled pixel count.
+fn paste(
+    patch: &Patch,
+    image: &mut RgbImage,
+    outline: &mut RgbImage,
+    (ax, ay): (u32, u32),
+    feather: f64,
+) -> u64 {
+    let dist = imageproc::distance_transform::euclidean_squared_distance_transform(&patch.mask);
+    let mut pixels = 0;
+    for (x, y, d) in dist.enumerate_pixels() {
+        let d = d.0[0].sqrt();
+        let alpha = if d == 0.0 {
+            1.0
+        } else if feather > 0.0 {
+            (1.0 - d / feather).max(0.0)
+        } else {
+            0.0
+        };
+        if alpha <= 0.0 {
+            continue;
+        }
+        let src = patch.image.get_pixel(x, y);
+        let dst = image.get_pixel_mut(ax + x, ay + y);
+        for c in 0..3 {
+            dst.0[c] = (src.0[c] as f64 * alpha + dst.0[c] as f64 * (1.0 - alpha)).round() as u8;
+        }
+        if d == 0.0 {
+            *outline.get_pixel_mut(ax + x, ay + y) = *patch.outline.get_pixel(x, y);
+            pixels += 1;
+        }
+    }
+    pixels
+}
+
+fn load_pair(tile: Tile) -> anyhow::Result<(RgbImage, RgbImage)> {
+    let image = image::open(paths::tile_file(TILES, tile, ".jpg"))?.into_rgb8();
+    let outline_path = paths::tile_file(OUTLINES, tile, ".png");
+    let outline = if outline_path.is_file() {
+        image::open(outline_path)?.into_rgb8()
+    } else {
+        RgbImage::new(image.width(), image.height())
+    };
+    Ok((image, outline))
+}
+
+/// Pastes buildings from tiles that have some into tiles that have none,
+/// writing the results under `opts.out` and recording every paste in
+/// `provenance.json`. The source tiles are left untouched.
+pub fn augment(opts: &AugmentOptions) -> anyhow::Result<()> {
+    let mut tiles = list_tiles(TILES, ".jpg");
+    tiles.sort_by_key(|t| (t.y(), t.x()));
+
+    let mut patches = vec![];
+    let mut empty = vec![];
+    for &tile in &tiles {
+        let (image, outline) = load_pair(tile)?;
+        let found = extract_patches(tile, &image, &outline, opts.feather.ceil() as u32);
+        if outline.pixels().any(|p| classes::is_building_pixel(p.0)) {
+            patches.extend(found);
+        } else {
+            empty.push(tile);
+        }
+    }
+    logging::status!(
+        "{} empty tiles, {} building patches to paste from",
+        empty.len(),
+        patches.len()
+    );
+    if patches.is_empty() {
+        anyhow::bail!("No buildings to paste, render outlines first");
+    }
+    if let Some(max) = opts.max_tiles {
+        empty.truncate(max);
+    }
+
+    std::fs::create_dir_all(opts.out.join(TILES))?;
+    std::fs::create_dir_all(opts.out.join(OUTLINES))?;
+    let mut provenance = Provenance {
+        options: opts,
+        tiles: vec![],
+    };
+    for tile in empty {
+        let mut rng = Rng::new(opts.seed, ((tile.y() as i64) << 32) | tile.x() as i64);
+        let (mut image, mut outline) = load_pair(tile)?;
+        let mut pastes = vec![];
+        for _ in 0..opts.per_tile {
+            let patch = &patches[rng.below(patches.len())];
+            let (pw, ph) = patch.mask.dimensions();
+            if pw >= image.width() || ph >= image.height() {
+                continue;
+            }
+            // Keep pasted buildings apart from each other.
+            let at = (0..PLACEMENT_TRIES)
+                .map(|_| {
+                    (
+                        rng.below((image.width() - pw) as usize) as u32,
+                        rng.below((image.height() - ph) as usize) as u32,
+                    )
+                })
+                .find(|&(x, y)| {
+                    !(0..ph).any(|py| {
+                        (0..pw).any(|px| {
+                            classes::is_building_pixel(outline.get_pixel(x + px, y + py).0)
+                        })
+                    })
+                });
+            let Some(at) = at else {
+                info!("No free spot for a patch in {tile:?}");
+                continue;
+            };
+            let pixels = paste(patch, &mut image, &mut outline, at, opts.feather);
+            pastes.push(Paste {
+                donor: paths::stem(patch.donor),
+                bbox: patch.bbox,
+                at: [at.0, at.1],
+                pixels,
+            });
+        }
+        image.save(paths::create_tile_file(opts.out.join(TILES), tile, ".jpg")?)?;
+        outline.save(paths::create_tile_file(
+            opts.out.join(OUTLINES),
+            tile,
+            ".png",
+        )?)?;
+        provenance.tiles.push(AugmentedTile {
+            tile: paths::stem(tile),
+            pastes,
+        });
+    }
+
+    let pasted: usize = provenance.tiles.iter().map(|t| t.pastes.len()).sum();
+    logging::status!(
+        "Pasted {pasted} buildings into {} tiles",
+        provenance.tiles.len()
+    );
+    std::fs::write(
+        opts.out.join("provenance.json"),
+        serde_json::to_vec_pretty(&provenance)?,
+    )?;
+    Ok(())
+}
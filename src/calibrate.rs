@@ -0,0 +1,162 @@
+//! Estimates the systematic offset between imagery and OSM by sliding the
+//! rasterized building edges over the imagery gradients.
+
+use image::RgbImage;
+use imageproc::gradients::sobel_gradients;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    classes, list_tiles, logging, paths,
+    provider::{Calibration, Providers},
+    workspace::{OUTLINES, TILES},
+    ImageCache,
+};
+
+/// Edges of building areas in an outline: building pixels next to a pixel
+/// of another class.
+fn building_edges(outline: &RgbImage) -> Vec<(u32, u32)> {
+    let (w, h) = outline.dimensions();
+    let mut edges = vec![];
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let px = outline.get_pixel(x, y).0;
+            if !classes::is_building_pixel(px) {
+                continue;
+            }
+            let border = [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                .iter()
+                .any(|&(nx, ny)| outline.get_pixel(nx, ny).0 != px);
+            if border {
+                edges.push((x, y));
+            }
+        }
+    }
+    edges
+}
+
+/// How well the building edges of the outline of a tile line up with the
+/// gradients of its imagery, at every shift within `search_px` pixels.
+pub struct TileScores {
+    /// Mean gradient under the edges, shifted, row by row from the shift
+    /// `-search_px, -search_px`.
+    pub scores: Vec<f64>,
+    /// Mean gradient of the whole image, what edges anywhere would score.
+    pub background: f64,
+    /// Building edge pixels in the outline.
+    pub edges: usize,
+    pub meters_per_px: f64,
+}
+
+/// Scores `tile`, `None` if it has no building edges or its imagery and
+/// outline differ in size.
+pub fn tile_scores(tile: Tile, search_px: i32) -> anyhow::Result<Option<TileScores>> {
+    let outline = image::open(paths::tile_file(OUTLINES, tile, ".png"))?.into_rgb8();
+    let edges = building_edges(&outline);
+    if edges.is_empty() {
+        return Ok(None);
+    }
+    let image = image::open(paths::tile_file(TILES, tile, ".jpg"))?.into_luma8();
+    if image.dimensions() != outline.dimensions() {
+        return Ok(None);
+    }
+    let gradients = sobel_gradients(&image);
+    let (w, h) = (image.width() as i32, image.height() as i32);
+    let side = (2 * search_px + 1) as usize;
+    let mut scores = vec![0.0; side * side];
+    for dy in -search_px..=search_px {
+        for dx in -search_px..=search_px {
+            let mut sum = 0.0;
+            let mut n = 0;
+            for &(x, y) in &edges {
+                let (sx, sy) = (x as i32 + dx, y as i32 + dy);
+                if sx >= 0 && sy >= 0 && sx < w && sy < h {
+                    sum += gradients.get_pixel(sx as u32, sy as u32).0[0] as f64;
+                    n += 1;
+                }
+            }
+            if n > 0 {
+                let i = ((dy + search_px) as usize) * side + (dx + search_px) as usize;
+                scores[i] = sum / n as f64;
+            }
+        }
+    }
+    let background = gradients.pixels().map(|p| p.0[0] as f64).sum::<f64>() / (w * h).max(1) as f64;
+    Ok(Some(TileScores {
+        scores,
+        background,
+        edges: edges.len(),
+        meters_per_px: 1.0 / ImageCache::pixels_per_meter(tile, image.dimensions()),
+    }))
+}
+
+/// The shift with the best of `scores`, in pixels, and the gain of its
+/// score over that of no shift.
+pub fn best_shift(scores: &[f64], search_px: i32) -> ((i32, i32), f64) {
+    let side = (2 * search_px + 1) as usize;
+    let best = (0..scores.len())
+        .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+        .unwrap();
+    let center = scores[search_px as usize * side + search_px as usize];
+    let gain = if center > 0.0 {
+        scores[best] / center
+    } else {
+        1.0
+    };
+    (
+        (
+            (best % side) as i32 - search_px,
+            (best / side) as i32 - search_px,
+        ),
+        gain,
+    )
+}
+
+/// Measures the offset of `provider` on up to `sample` tiles with rendered
+/// outlines, trying every shift within `search_px` pixels, and adds it to
+/// the provider's offset in `providers.json`. Outlines drawn with the old
+/// offset only leave the residual to measure, so rerunning refines it.
+pub fn calibrate(provider: &str, sample: usize, search_px: i32) -> anyhow::Result<()> {
+    let mut tiles = list_tiles(OUTLINES, ".png");
+    tiles.retain(|t| paths::tile_file(TILES, *t, ".jpg").is_file());
+    tiles.sort_by_key(|t| (t.y(), t.x()));
+    // Spread the sample over the whole area instead of its first rows.
+    let stride = tiles.len().div_ceil(sample.max(1)).max(1);
+    let tiles: Vec<_> = tiles.into_iter().step_by(stride).collect();
+
+    let side = (2 * search_px + 1) as usize;
+    let mut scores = vec![0.0; side * side];
+    let mut used = 0;
+    let mut meters_per_px = 0.0;
+    for tile in &tiles {
+        let Some(tile_scores) = tile_scores(*tile, search_px)? else {
+            continue;
+        };
+        for (sum, score) in scores.iter_mut().zip(&tile_scores.scores) {
+            *sum += score;
+        }
+        meters_per_px += tile_scores.meters_per_px;
+        used += 1;
+    }
+    if used == 0 {
+        anyhow::bail!("No tiles with both imagery and building outlines to calibrate on");
+    }
+
+    let ((dx, dy), gain) = best_shift(&scores, search_px);
+    if dx.abs() == search_px || dy.abs() == search_px {
+        logging::status!("Best offset is at the edge of the search window, consider a larger one");
+    }
+    let m = meters_per_px / used as f64;
+    // +Y is down on screen, north is up.
+    let (east, north) = (dx as f64 * m, -dy as f64 * m);
+    logging::status!(
+        "{provider}: imagery is offset by {dx}, {dy} px ({east:.2} m east, {north:.2} m north) on {used} tiles, edge response x{gain:.3}"
+    );
+
+    let mut providers = Providers::load()?;
+    let entry = providers.0.entry(provider.to_string()).or_default();
+    entry.offset_m[0] += east;
+    entry.offset_m[1] += north;
+    entry.calibration = Some(Calibration { tiles: used, gain });
+    providers.save()?;
+    Ok(())
+}
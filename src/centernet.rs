@@ -0,0 +1,161 @@
+//! CenterNet-style detection targets for every stitched chip, written as
+//! `.npy` arrays at `1 / --stride` of the chip resolution:
+//! `<chip>.heatmap.npy`, `(H, W)` float32, a Gaussian around the center
+//! of every building's box that peaks at exactly 1 in its center cell;
+//! `<chip>.offset.npy`, `(2, H, W)`, the sub-cell position of the center;
+//! and `<chip>.size.npy`, `(2, H, W)`, box width and height in cells. The
+//! last two are only set in center cells.
+
+use std::path::{Path, PathBuf};
+
+use geo::BoundingRect;
+use log::info;
+
+use crate::{chips::Grid, classes::ClassOptions, logging, workspace::STITCHED, OsmData};
+
+/// Overlap a box shifted by the Gaussian radius keeps with the real one,
+/// as in CornerNet and CenterNet.
+const MIN_OVERLAP: f64 = 0.7;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct CenterNetOptions {
+    /// Directory with `tiles/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    #[arg(long, default_value = "centernet")]
+    pub out: PathBuf,
+    /// Output stride of the network, chip pixels per target cell.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=32))]
+    pub stride: u32,
+    #[command(flatten)]
+    pub classes: ClassOptions,
+}
+
+/// Largest radius a box of this size can be off by and still overlap its
+/// true position by `MIN_OVERLAP`, taking the worst of CornerNet's cases.
+fn gaussian_radius(height: f64, width: f64) -> f64 {
+    let b1 = height + width;
+    let c1 = width * height * (1.0 - MIN_OVERLAP) / (1.0 + MIN_OVERLAP);
+    let r1 = (b1 + (b1 * b1 - 4.0 * c1).sqrt()) / 2.0;
+    let b2 = 2.0 * (height + width);
+    let c2 = (1.0 - MIN_OVERLAP) * width * height;
+    let r2 = (b2 + (b2 * b2 - 16.0 * c2).sqrt()) / 2.0;
+    let a3 = 4.0 * MIN_OVERLAP;
+    let b3 = -2.0 * MIN_OVERLAP * (height + width);
+    let c3 = (MIN_OVERLAP - 1.0) * width * height;
+    let r3 = (b3 + (b3 * b3 - 4.0 * a3 * c3).sqrt()) / 2.0;
+    r1.min(r2).min(r3)
+}
+
+struct Targets {
+    width: usize,
+    height: usize,
+    heatmap: Vec<f32>,
+    offset: Vec<f32>,
+    size: Vec<f32>,
+}
+
+impl Targets {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            heatmap: vec![0.0; width * height],
+            offset: vec![0.0; 2 * width * height],
+            size: vec![0.0; 2 * width * height],
+        }
+    }
+
+    /// Adds a box centered at `(cx, cy)`, all in cells.
+    fn add(&mut self, cx: f64, cy: f64, w: f64, h: f64) {
+        let (x, y) = (cx.floor() as i64, cy.floor() as i64);
+        let plane = self.width * self.height;
+        let i = y as usize * self.width + x as usize;
+        self.offset[i] = (cx - x as f64) as f32;
+        self.offset[plane + i] = (cy - y as f64) as f32;
+        self.size[i] = w as f32;
+        self.size[plane + i] = h as f32;
+
+        let radius = gaussian_radius(h.ceil(), w.ceil()).max(0.0) as i64;
+        let sigma = (2 * radius + 1) as f64 / 6.0;
+        for gy in (y - radius).max(0)..=(y + radius).min(self.height as i64 - 1) {
+            for gx in (x - radius).max(0)..=(x + radius).min(self.width as i64 - 1) {
+                let d2 = ((gx - x).pow(2) + (gy - y).pow(2)) as f64;
+                let g = (-d2 / (2.0 * sigma * sigma)).exp() as f32;
+                let cell = &mut self.heatmap[gy as usize * self.width + gx as usize];
+                *cell = cell.max(g);
+            }
+        }
+    }
+}
+
+/// Writes a little-endian float32 array in NumPy's `.npy` format.
+fn write_npy(path: &Path, shape: &[usize], data: &[f32]) -> anyhow::Result<()> {
+    let shape: Vec<_> = shape.iter().map(|d| d.to_string()).collect();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}",
+        shape.join(", ")
+    );
+    // Magic, version and length take 10 bytes; the header is padded so the
+    // data starts 64-byte aligned.
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+    let mut out = Vec::with_capacity(10 + header.len() + 4 * data.len());
+    out.extend_from_slice(b"\x93NUMPY\x01\x00");
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    for v in data {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+pub fn export_centernet(osm: &OsmData, opts: &CenterNetOptions) -> anyhow::Result<()> {
+    let grid = Grid::load(&opts.chips)?;
+    let stride = opts.stride as f64;
+    let mut targets: Vec<_> = grid
+        .chips
+        .iter()
+        .map(|c| {
+            let [x0, y0, x1, y1] = c.px;
+            Targets::new(((x1 - x0) / stride) as usize, ((y1 - y0) / stride) as usize)
+        })
+        .collect();
+    info!("Writing CenterNet targets for {} chips", grid.chips.len());
+
+    let mut objects = 0;
+    for (_, ring) in grid.buildings(osm, &opts.classes)? {
+        let Some(bounds) = ring.bounding_rect() else {
+            continue;
+        };
+        let center = bounds.center();
+        for i in grid.overlapping(bounds) {
+            let [x0, y0, ..] = grid.chips[i].px;
+            let t = &mut targets[i];
+            let (cx, cy) = ((center.x - x0) / stride, (center.y - y0) / stride);
+            // Only buildings centered in the chip are its objects.
+            if cx < 0.0 || cy < 0.0 || cx >= t.width as f64 || cy >= t.height as f64 {
+                continue;
+            }
+            t.add(cx, cy, bounds.width() / stride, bounds.height() / stride);
+            objects += 1;
+        }
+    }
+
+    std::fs::create_dir_all(&opts.out)?;
+    for (chip, t) in grid.chips.iter().zip(&targets) {
+        let (w, h) = (t.width, t.height);
+        let path = |kind: &str| opts.out.join(format!("{}.{kind}.npy", chip.key));
+        write_npy(&path("heatmap"), &[h, w], &t.heatmap)?;
+        write_npy(&path("offset"), &[2, h, w], &t.offset)?;
+        write_npy(&path("size"), &[2, h, w], &t.size)?;
+    }
+    logging::status!(
+        "Wrote targets for {objects} buildings in {} chips",
+        grid.chips.len()
+    );
+    Ok(())
+}
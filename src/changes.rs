@@ -0,0 +1,167 @@
+//! Bi-temporal training data: aligned imagery from two dates together with
+//! a mask of the buildings that appeared or disappeared in between.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use image::{GrayImage, Luma, RgbImage};
+use serde::Serialize;
+
+use crate::{classes, list_tiles, logging, paths};
+
+pub const UNCHANGED: u8 = 0;
+pub const APPEARED: u8 = 1;
+pub const DEMOLISHED: u8 = 2;
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// One side of a pair: the snapshot date and the imagery taken around it.
+pub struct PairSource {
+    pub date: String,
+    pub tiles: PathBuf,
+}
+
+impl PairSource {
+    fn outlines(&self) -> PathBuf {
+        Path::new("snapshots").join(&self.date).join("outlines")
+    }
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    version: u32,
+    t1: &'a str,
+    t2: &'a str,
+    zoom: u8,
+    mask_values: MaskValues,
+    pairs: Vec<Pair>,
+}
+
+#[derive(Serialize)]
+struct MaskValues {
+    unchanged: u8,
+    appeared: u8,
+    demolished: u8,
+}
+
+#[derive(Serialize)]
+struct Pair {
+    x: u32,
+    y: u32,
+    image_t1: String,
+    image_t2: String,
+    change_mask: String,
+    appeared_px: u64,
+    demolished_px: u64,
+}
+
+/// Writes `image_t1/`, `image_t2/` and `change_mask/` under `out` for every
+/// tile that has imagery on both dates and an outline in either snapshot,
+/// and `manifest.json` listing them. The mask is single-channel, see
+/// `APPEARED` and `DEMOLISHED`.
+pub fn export_change_pairs(t1: &PairSource, t2: &PairSource, out: &Path) -> anyhow::Result<()> {
+    for side in [t1, t2] {
+        if !side.outlines().is_dir() {
+            anyhow::bail!(
+                "{} is missing, run `render-outlines --as-of {}` first",
+                side.outlines().display(),
+                side.date
+            );
+        }
+    }
+    for dir in ["image_t1", "image_t2", "change_mask"] {
+        std::fs::create_dir_all(out.join(dir))?;
+    }
+
+    // Snapshots only contain the tiles something was drawn into, so a tile
+    // without an outline on one date had no buildings then.
+    let mut tiles = list_tiles(t1.outlines(), ".png");
+    tiles.extend(list_tiles(t2.outlines(), ".png"));
+    tiles.sort_by_key(|t| (t.y(), t.x()));
+    tiles.dedup();
+    tiles.retain(|t| {
+        paths::tile_file(&t1.tiles, *t, ".jpg").is_file()
+            && paths::tile_file(&t2.tiles, *t, ".jpg").is_file()
+    });
+    logging::status!("{} tiles with imagery on both dates", tiles.len());
+
+    let mut pairs = vec![];
+    for tile in tiles {
+        let name = paths::stem(tile);
+        let (w, h) = image::image_dimensions(paths::tile_file(&t1.tiles, tile, ".jpg"))?;
+        let before = outline_or_blank(&paths::tile_file(t1.outlines(), tile, ".png"), w, h)?;
+        let after = outline_or_blank(&paths::tile_file(t2.outlines(), tile, ".png"), w, h)?;
+        if before.dimensions() != after.dimensions() {
+            anyhow::bail!("Outlines of {name} differ in size between the two dates");
+        }
+
+        let (mut appeared_px, mut demolished_px) = (0, 0);
+        let mask = GrayImage::from_fn(before.width(), before.height(), |x, y| {
+            let was = classes::is_building_pixel(before.get_pixel(x, y).0);
+            let is = classes::is_building_pixel(after.get_pixel(x, y).0);
+            match (was, is) {
+                (false, true) => {
+                    appeared_px += 1;
+                    Luma([APPEARED])
+                }
+                (true, false) => {
+                    demolished_px += 1;
+                    Luma([DEMOLISHED])
+                }
+                _ => Luma([UNCHANGED]),
+            }
+        });
+
+        let pair = Pair {
+            x: tile.x(),
+            y: tile.y(),
+            image_t1: format!("image_t1/{name}.jpg"),
+            image_t2: format!("image_t2/{name}.jpg"),
+            change_mask: format!("change_mask/{name}.png"),
+            appeared_px,
+            demolished_px,
+        };
+        std::fs::copy(
+            paths::tile_file(&t1.tiles, tile, ".jpg"),
+            paths::join_relative(out, &pair.image_t1),
+        )?;
+        std::fs::copy(
+            paths::tile_file(&t2.tiles, tile, ".jpg"),
+            paths::join_relative(out, &pair.image_t2),
+        )?;
+        mask.save(paths::join_relative(out, &pair.change_mask))?;
+        pairs.push(pair);
+    }
+
+    let changed = pairs
+        .iter()
+        .filter(|p| p.appeared_px + p.demolished_px > 0)
+        .count();
+    logging::status!("Exported {} pairs, {changed} with changes", pairs.len());
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        t1: &t1.date,
+        t2: &t2.date,
+        zoom: crate::zoom(),
+        mask_values: MaskValues {
+            unchanged: UNCHANGED,
+            appeared: APPEARED,
+            demolished: DEMOLISHED,
+        },
+        pairs,
+    };
+    let mut f = std::fs::File::create(out.join("manifest.json"))?;
+    serde_json::to_writer_pretty(&mut f, &manifest)?;
+    writeln!(f)?;
+    Ok(())
+}
+
+fn outline_or_blank(path: &Path, width: u32, height: u32) -> anyhow::Result<RgbImage> {
+    if path.is_file() {
+        Ok(image::open(path)?.into_rgb8())
+    } else {
+        Ok(RgbImage::new(width, height))
+    }
+}
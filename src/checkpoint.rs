@@ -0,0 +1,270 @@
+//! Resumable PBF parsing. The objects `OsmData` keeps are appended to
+//! `<pbf>.checkpoint` every `CHECKPOINT_BLOBS` blobs, together with the
+//! offset of the next blob, so a parse that dies halfway through a large
+//! extract picks up from there instead of from byte zero. The second pass
+//! for the coordinates of the nodes, see `nodes.rs`, is not checkpointed
+//! and runs again.
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use osmpbfreader::{blocks, primitive_block_from_blob, OsmObj};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    logging, nodes, read_only, rules,
+    timing::{self, Stage},
+    OsmData, ProgressFile,
+};
+
+/// Blobs decoded between checkpoints, in parallel.
+const CHECKPOINT_BLOBS: usize = 256;
+/// Bumped when what `OsmData::keeps` keeps changes, so that a checkpoint
+/// with other objects is parsed again.
+const KEEPS: u32 = 3;
+
+/// Identifies the extract a checkpoint was written for.
+#[derive(PartialEq, Serialize, Deserialize)]
+struct Header {
+    pbf_len: u64,
+    pbf_modified: u64,
+    keeps: u32,
+    /// `rules::fingerprint`, as the rules change what is kept.
+    rules: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Chunk {
+    /// Offset of the first blob not in this or an earlier chunk.
+    end_offset: u64,
+    /// Objects decoded, kept or not.
+    seen: u64,
+    objs: Vec<OsmObj>,
+}
+
+#[derive(Default)]
+struct Resumed {
+    objs: Vec<OsmObj>,
+    seen: u64,
+    offset: u64,
+    /// Length of the checkpoint up to its last complete chunk.
+    valid_len: u64,
+}
+
+fn checkpoint_path(pbf: &Path) -> PathBuf {
+    let mut name = pbf.as_os_str().to_owned();
+    name.push(".checkpoint");
+    PathBuf::from(name)
+}
+
+/// Frames are a little-endian `u64` length followed by postcard.
+fn write_frame(w: &mut impl Write, value: &impl Serialize) -> anyhow::Result<()> {
+    let data = postcard::to_stdvec(value)?;
+    w.write_all(&(data.len() as u64).to_le_bytes())?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+fn read_frame<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u64::from_le_bytes(data.get(..8)?.try_into().ok()?) as usize;
+    let frame = data.get(8..8 + len)?;
+    *data = &data[8 + len..];
+    Some(frame)
+}
+
+/// Reads the complete chunks of a checkpoint written for the same
+/// extract. A torn last chunk is ignored and will be parsed again.
+fn resume(path: &Path, header: &Header) -> Option<Resumed> {
+    let mut data = vec![];
+    File::open(path).ok()?.read_to_end(&mut data).ok()?;
+    let mut rest = data.as_slice();
+    let found: Header = postcard::from_bytes(read_frame(&mut rest)?).ok()?;
+    if found != *header {
+        return None;
+    }
+    let mut resumed = Resumed {
+        valid_len: (data.len() - rest.len()) as u64,
+        ..Default::default()
+    };
+    while let Some(frame) = read_frame(&mut rest) {
+        let Ok(chunk) = postcard::from_bytes::<Chunk>(frame) else {
+            break;
+        };
+        resumed.objs.extend(chunk.objs);
+        resumed.seen += chunk.seen;
+        resumed.offset = chunk.end_offset;
+        resumed.valid_len = (data.len() - rest.len()) as u64;
+    }
+    Some(resumed)
+}
+
+fn decode(
+    blobs: &[osmpbfreader::fileformat::Blob],
+    keep: impl Fn(&OsmObj) -> bool + Sync,
+) -> anyhow::Result<(u64, Vec<OsmObj>)> {
+    let decoded = blobs
+        .par_iter()
+        .map(|blob| {
+            let _span = timing::span(Stage::Parse);
+            let block = primitive_block_from_blob(blob)?;
+            let mut seen = 0;
+            let objs: Vec<_> = blocks::iter(&block)
+                .inspect(|_| seen += 1)
+                .filter(&keep)
+                .collect();
+            Ok((seen, objs))
+        })
+        .collect::<osmpbfreader::Result<Vec<_>>>()?;
+    let seen = decoded.iter().map(|(seen, _)| seen).sum();
+    Ok((
+        seen,
+        decoded.into_iter().flat_map(|(_, objs)| objs).collect(),
+    ))
+}
+
+fn progress_bar(len: u64) -> ProgressBar {
+    logging::progress_bar(
+        len,
+        ProgressStyle::with_template(
+            "[{eta_precise}] {bar:120} [{bytes}/{total_bytes} {percent}%] {msg}",
+        )
+        .unwrap(),
+    )
+}
+
+/// The next `CHECKPOINT_BLOBS` blobs, none at the end of the file.
+fn next_batch(
+    blobs: &mut impl Iterator<Item = osmpbfreader::Result<osmpbfreader::fileformat::Blob>>,
+) -> anyhow::Result<Vec<osmpbfreader::fileformat::Blob>> {
+    let _span = timing::span(Stage::Io);
+    Ok(blobs
+        .take(CHECKPOINT_BLOBS)
+        .collect::<osmpbfreader::Result<Vec<_>>>()?)
+}
+
+/// The second pass: reads the untagged nodes the ways among `objs`
+/// reference, into the `--node-store` if there is one.
+fn resolve_nodes(path: &Path, len: u64, objs: &[OsmObj]) -> anyhow::Result<nodes::Builder> {
+    let mut wanted: Vec<i64> = objs
+        .iter()
+        .filter_map(OsmObj::way)
+        .flat_map(|way| way.nodes.iter().map(|id| id.0))
+        .collect();
+    wanted.sort_unstable();
+    wanted.dedup();
+    let keep = |obj: &OsmObj| match obj {
+        OsmObj::Node(node) => node.tags.is_empty() && wanted.binary_search(&node.id.0).is_ok(),
+        _ => false,
+    };
+
+    let mut builder = nodes::Builder::new(nodes::store())?;
+    let progress = progress_bar(len);
+    let mut pbf = osmpbfreader::OsmPbfReader::new(ProgressFile {
+        inner: File::open(path)?,
+        progress: progress.clone(),
+    });
+    let mut blobs = pbf.blobs();
+    let mut found = 0;
+    loop {
+        let batch = next_batch(&mut blobs)?;
+        if batch.is_empty() {
+            break;
+        }
+        let (_, objs) = decode(&batch, keep)?;
+        for obj in objs {
+            if let OsmObj::Node(node) = obj {
+                builder.push(node)?;
+                found += 1;
+            }
+        }
+        progress.set_message(format!("{found} of {} way nodes", wanted.len()));
+    }
+    progress.finish();
+    Ok(builder)
+}
+
+/// Loads an extract, resuming from its checkpoint if there is one. The
+/// checkpoint is removed once the whole file has been read. With
+/// `--read-only` one is neither written nor removed, only resumed from.
+pub fn load(filename: &OsStr) -> anyhow::Result<OsmData> {
+    let path = Path::new(filename);
+    let meta = std::fs::metadata(path)?;
+    let header = Header {
+        pbf_len: meta.len(),
+        pbf_modified: meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+        keeps: KEEPS,
+        rules: rules::fingerprint(),
+    };
+    let checkpoint = checkpoint_path(path);
+    let (mut state, mut out) = match (resume(&checkpoint, &header), read_only()) {
+        (Some(state), read_only) => {
+            logging::status!(
+                "Resuming from byte {} with {} objects read",
+                state.offset,
+                state.seen
+            );
+            let out = match read_only {
+                true => None,
+                false => {
+                    let out = File::options().write(true).open(&checkpoint)?;
+                    out.set_len(state.valid_len)?;
+                    Some(out)
+                }
+            };
+            (state, out)
+        }
+        (None, true) => (Resumed::default(), None),
+        (None, false) => {
+            let mut out = File::create(&checkpoint)?;
+            write_frame(&mut out, &header)?;
+            (Resumed::default(), Some(out))
+        }
+    };
+    if let Some(out) = &mut out {
+        out.seek(SeekFrom::End(0))?;
+    }
+
+    let progress = progress_bar(header.pbf_len);
+    progress.set_position(state.offset);
+    let mut r = File::open(path)?;
+    r.seek(SeekFrom::Start(state.offset))?;
+    let mut pbf = osmpbfreader::OsmPbfReader::new(ProgressFile {
+        inner: r,
+        progress: progress.clone(),
+    });
+
+    let mut blobs = pbf.blobs();
+    loop {
+        let batch = next_batch(&mut blobs)?;
+        if batch.is_empty() {
+            break;
+        }
+        let (seen, objs) = decode(&batch, OsmData::keeps)?;
+        let chunk = Chunk {
+            end_offset: progress.position(),
+            seen,
+            objs,
+        };
+        if let Some(out) = &mut out {
+            let _span = timing::span(Stage::Io);
+            write_frame(out, &chunk)?;
+            out.sync_data()?;
+        }
+        state.seen += seen;
+        state.objs.extend(chunk.objs);
+        progress.set_message(format!("{} objects, {} kept", state.seen, state.objs.len()));
+    }
+    progress.finish();
+    let nodes = resolve_nodes(path, header.pbf_len, &state.objs)?;
+    if out.take().is_some() {
+        std::fs::remove_file(&checkpoint)?;
+    }
+    OsmData::from_objs(state.objs, nodes)
+}
@@ -0,0 +1,144 @@
+//! Checks for the usual misconfigurations. They fail with a hint the user
+//! can act on instead of an unwrap backtrace.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use reqwest::StatusCode;
+use slippy_map_tiles::BBox;
+
+/// Whether `--pbf` points at a readable file.
+pub fn pbf(path: &Path) -> anyhow::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => bail!(
+            "--pbf {} is a directory, not an OSM extract\n\
+             hint: pass the .osm.pbf file itself",
+            path.display()
+        ),
+        Ok(_) => Ok(()),
+        Err(why) => bail!(
+            "cannot open the OSM extract {}: {why}\n\
+             hint: download an extract, e.g. from https://download.geofabrik.de, \
+             and pass it with --pbf <FILE>",
+            path.display()
+        ),
+    }
+}
+
+/// Creates `dir` if needed and makes sure files can be written into it.
+pub fn writable_dir(dir: &Path) -> anyhow::Result<()> {
+    let probe = dir.join(".write-test");
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&probe, b""))
+        .and_then(|()| std::fs::remove_file(&probe));
+    result.map_err(|why| {
+        anyhow!(
+            "cannot write to the output directory {}: {why}\n\
+             hint: run from a directory you own, or fix its permissions",
+            dir.display()
+        )
+    })
+}
+
+/// With `--read-only`, that `out` lies outside the dataset directory, the
+/// working directory, so nothing is written into the store.
+pub fn outside_dataset(out: &Path) -> anyhow::Result<()> {
+    let dataset = std::env::current_dir()?.canonicalize()?;
+    // The output usually does not exist yet; its nearest existing
+    // ancestor tells where it would go.
+    let absolute = std::path::absolute(out)?;
+    let existing = absolute
+        .ancestors()
+        .find_map(|a| a.canonicalize().ok())
+        .unwrap_or_default();
+    if existing.starts_with(&dataset) {
+        bail!(
+            "{} is inside the dataset, which --read-only leaves untouched\n\
+             hint: pass an output outside {}, e.g. --out /tmp/{}",
+            out.display(),
+            dataset.display(),
+            out.file_name().unwrap_or_default().to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// Whether the imagery directory exists, which it does not when the tool
+/// is run from the wrong directory.
+pub fn tiles_dir(dir: &Path) -> anyhow::Result<()> {
+    // Under a nested `tile_paths` the directory itself need not exist.
+    if dir.is_dir() || !crate::paths::list_tiles(dir, ".jpg").is_empty() {
+        return Ok(());
+    }
+    bail!(
+        "there is no imagery directory {} here\n\
+         hint: run from the dataset directory, or create it with download-tiles",
+        dir.display()
+    )
+}
+
+/// Builds a bounding box, explaining the expected order when the corners
+/// are swapped.
+pub fn bbox(top: f32, left: f32, bottom: f32, right: f32) -> anyhow::Result<BBox> {
+    if top < bottom {
+        bail!(
+            "the top of the bounding box ({top}) is south of its bottom ({bottom})\n\
+             hint: the order is TOP,LEFT,BOTTOM,RIGHT, i.e. north latitude, west longitude, \
+             south latitude, east longitude"
+        );
+    }
+    if left > right {
+        bail!(
+            "the left of the bounding box ({left}) is east of its right ({right})\n\
+             hint: the order is TOP,LEFT,BOTTOM,RIGHT; boxes across the antimeridian \
+             are not supported"
+        );
+    }
+    BBox::new(top, left, bottom, right).ok_or_else(|| {
+        anyhow!(
+            "bounding box {top},{left},{bottom},{right} is outside the map\n\
+             hint: latitudes go from -85 to 85 and longitudes from -180 to 180 degrees"
+        )
+    })
+}
+
+/// Parses `--bbox TOP,LEFT,BOTTOM,RIGHT`.
+pub fn parse_bbox(s: &str) -> anyhow::Result<BBox> {
+    let corners: Vec<f32> = s
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|why| anyhow!("{s:?} is not a list of numbers: {why}"))?;
+    let [top, left, bottom, right] = corners[..] else {
+        bail!(
+            "{s:?} has {} numbers instead of 4\n\
+             hint: the order is TOP,LEFT,BOTTOM,RIGHT, e.g. 55.9,37.3,55.5,37.9",
+            corners.len()
+        );
+    };
+    bbox(top, left, bottom, right)
+}
+
+/// Explains a failed tile download.
+pub fn tile_server(url: &str, why: reqwest::Error) -> anyhow::Error {
+    if let Some(status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)) = why.status() {
+        anyhow!(
+            "the tile server answered {url} with {status}\n\
+             hint: check the API key and headers of the --provider in providers.toml"
+        )
+    } else if let Some(status) = why.status() {
+        anyhow!(
+            "the tile server answered {url} with {status}\n\
+             hint: it may be rate limiting; lower `rate_limit` or raise `retries` of the \
+             --provider in providers.toml, or wait a while and run again; \
+             downloaded tiles are kept"
+        )
+    } else if why.is_connect() || why.is_timeout() {
+        anyhow!(
+            "cannot reach the tile server for {url}: {why}\n\
+             hint: check the network connection; behind a proxy, set HTTPS_PROXY"
+        )
+    } else {
+        anyhow!("downloading {url} failed: {why}")
+    }
+}
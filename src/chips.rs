@@ -0,0 +1,160 @@
+//! Stitched chips, which are named after their top-left tile, and where
+//! they lie.
+
+use std::{collections::HashMap, path::Path};
+
+use geo::{LineString, Point, Rect};
+use serde::Serialize;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    classes::{ClassOptions, FeatureClass},
+    footprint_class,
+    formats::Formats,
+    list_tiles, paths, provider, register, ring_area, way_coords,
+    workspace::TILES,
+    zoom, GeoCoordinate, ImageCache, OsmData,
+};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Extent {
+    pub key: String,
+    pub zoom: u8,
+    /// Top-left tile of the chip.
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub north: f64,
+    pub south: f64,
+    pub east: f64,
+    pub west: f64,
+}
+
+/// Size of the downloaded tiles, from which the extent of a chip follows.
+pub fn tile_px() -> u32 {
+    list_tiles(TILES, ".jpg")
+        .first()
+        .and_then(|t| image::image_dimensions(paths::tile_file(TILES, *t, ".jpg")).ok())
+        .map(|(w, _)| w)
+        .unwrap_or(256)
+}
+
+pub fn extent(chip: Tile, width: u32, height: u32, tile_px: u32) -> Extent {
+    let nw = mercator::within(chip, 0.0, 0.0);
+    let se = mercator::within(chip, (width / tile_px) as f64, (height / tile_px) as f64);
+    Extent {
+        key: paths::stem(chip),
+        zoom: zoom(),
+        x: chip.x(),
+        y: chip.y(),
+        width,
+        height,
+        north: nw.latitude,
+        south: se.latitude,
+        east: se.longitude,
+        west: nw.longitude,
+    }
+}
+
+/// A chip placed in the pixel grid spanning all tiles of the tile zoom.
+pub struct Placed {
+    pub key: String,
+    /// `[x0, y0, x1, y1]`
+    pub px: [f64; 4],
+    /// The tile in the middle of the chip.
+    pub center: Tile,
+}
+
+/// The stitched chips in a directory, found by the tiles they are made of.
+pub struct Grid {
+    pub chips: Vec<Placed>,
+    pub tile_px: u32,
+    by_tile: HashMap<(u32, u32), Vec<usize>>,
+}
+
+impl Grid {
+    /// Reads the sizes of the chips in `dir/tiles`, row by row.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let tile_px = tile_px();
+        let mut grid = Self {
+            chips: vec![],
+            tile_px,
+            by_tile: HashMap::new(),
+        };
+        let ext = Formats::load()?.chips.ext();
+        let mut names = list_tiles(dir.join(TILES), ext);
+        names.sort_by_key(|t| (t.y(), t.x()));
+        for chip in names {
+            let key = paths::stem(chip);
+            let (w, h) = image::image_dimensions(paths::tile_file(dir.join(TILES), chip, ext))?;
+            let (tx, ty) = (w / tile_px, h / tile_px);
+            for y in chip.y()..chip.y() + ty {
+                for x in chip.x()..chip.x() + tx {
+                    grid.by_tile
+                        .entry((x, y))
+                        .or_default()
+                        .push(grid.chips.len());
+                }
+            }
+            let (x0, y0) = ((chip.x() * tile_px) as f64, (chip.y() * tile_px) as f64);
+            grid.chips.push(Placed {
+                key,
+                px: [x0, y0, x0 + w as f64, y0 + h as f64],
+                center: Tile::new(zoom(), chip.x() + tx / 2, chip.y() + ty / 2).unwrap(),
+            });
+        }
+        Ok(grid)
+    }
+
+    /// Chips overlapping `bounds`, given in grid pixels.
+    pub fn overlapping(&self, bounds: Rect<f64>) -> Vec<usize> {
+        let tile_of = |v: f64| (v / self.tile_px as f64).floor() as u32;
+        let mut found = vec![];
+        for y in tile_of(bounds.min().y)..=tile_of(bounds.max().y) {
+            for x in tile_of(bounds.min().x)..=tile_of(bounds.max().x) {
+                found.extend(self.by_tile.get(&(x, y)).into_iter().flatten());
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    /// Position of a coordinate in the grid, placed within its tile the
+    /// way the outlines are drawn.
+    pub fn px(&self, c: GeoCoordinate) -> Point<f64> {
+        let (x, y) = mercator::tile_at(c.latitude, c.longitude, zoom());
+        let tile = Tile::new(zoom(), x, y).unwrap();
+        let size = (self.tile_px, self.tile_px);
+        let p = ImageCache::geo_to_screen_f64(tile, size, c);
+        Point::new(
+            (x * self.tile_px) as f64 + p.x,
+            (y * self.tile_px) as f64 + p.y,
+        )
+    }
+
+    /// Building ways of a building class, by id, as rings in grid pixels
+    /// registered to the imagery like the outlines.
+    pub fn buildings<'a>(
+        &'a self,
+        osm: &'a OsmData,
+        classes: &'a ClassOptions,
+    ) -> anyhow::Result<impl Iterator<Item = (FeatureClass, LineString<f64>)> + 'a> {
+        let offset_m = provider::Providers::load()?.offset_m(&provider::source().name);
+        let mut ways: Vec<_> = osm.ways_buildings.values().collect();
+        ways.sort_by_key(|w| w.id);
+        Ok(ways.into_iter().filter_map(move |way| {
+            let coords = way_coords(way, &osm.nodes_all).filter(|c| c.len() >= 3)?;
+            let class = footprint_class(&way.tags, &ring_area(&coords), classes, self.tile_px)?;
+            if !crate::classes::is_building(class) {
+                return None;
+            }
+            let ring = register(&coords, offset_m)
+                .into_iter()
+                .map(|c| self.px(c))
+                .collect();
+            Some((class, ring))
+        }))
+    }
+}
@@ -0,0 +1,432 @@
+//! Mapping from OSM tags to the classes painted into the outlines:
+//! buildings and their subclasses, and features such as land use, trees,
+//! roads and water, each with the shape it is rasterized as.
+
+use std::sync::OnceLock;
+
+use osmpbfreader::Tags;
+
+use crate::rules;
+
+/// Outline color of every `FeatureClass`, indexed by its value.
+pub const COLOR_INDEX: &[[u8; 3]] = &[
+    [0, 0, 0],
+    [255, 0, 0],
+    [0, 255, 0],
+    [255, 255, 0],
+    [255, 128, 0],
+    [128, 64, 0],
+    [128, 0, 128],
+    [0, 255, 255],
+    [160, 82, 45],
+    [189, 183, 107],
+    [0, 0, 128],
+    [70, 130, 180],
+    [255, 0, 255],
+    [0, 100, 0],
+    [64, 64, 64],
+    [96, 96, 128],
+    [128, 96, 96],
+    [96, 128, 64],
+    [255, 255, 255],
+    [192, 192, 192],
+    [0, 128, 255],
+    [0, 0, 255],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureClass {
+    Nothing = 0,
+    BuildingBelowAreaThreshold = 1,
+    Normal = 2,
+    /// A building with one of `--exclude-tags`, or over `--max-area`.
+    BuildingHasExcludedTags = 3,
+    /// `building=construction`
+    UnderConstruction = 4,
+    /// `landuse=construction`, drawn underneath buildings.
+    ConstructionSite = 5,
+    /// Lifecycle-prefixed buildings such as `demolished:building=*`.
+    Demolished = 6,
+    /// `building=greenhouse`
+    Greenhouse = 7,
+    /// `building=barn`
+    Barn = 8,
+    /// `building=farm_auxiliary`
+    FarmAuxiliary = 9,
+    /// `power=plant` with `plant:source=solar`
+    SolarPlant = 10,
+    /// `power=generator` with `generator:source=solar`, i.e. panel rows.
+    SolarPanel = 11,
+    /// `power=substation`
+    Substation = 12,
+    /// `natural=tree` nodes and `natural=tree_row` ways.
+    Tree = 13,
+    /// Background `landuse=*` areas, drawn before everything else.
+    LanduseResidential = 14,
+    LanduseIndustrial = 15,
+    /// `landuse=commercial` and `landuse=retail`
+    LanduseCommercial = 16,
+    LanduseFarmland = 17,
+    /// Pixels the loss should not count, see `--ignore-small`.
+    Ignore = 18,
+    /// `highway=*` ways, buffered to the width of the road.
+    Road = 19,
+    /// `natural=water` and `waterway=riverbank` areas.
+    Water = 20,
+    /// Reserved for pixels without a label, which `stitch_pictures` paints
+    /// where a tile has no outline or lies outside the area. Nothing is
+    /// drawn in it, and rules cannot map tags to it.
+    NoLabel = 21,
+}
+
+/// Every class, in index order.
+pub const ALL_CLASSES: &[FeatureClass] = &[
+    FeatureClass::Nothing,
+    FeatureClass::BuildingBelowAreaThreshold,
+    FeatureClass::Normal,
+    FeatureClass::BuildingHasExcludedTags,
+    FeatureClass::UnderConstruction,
+    FeatureClass::ConstructionSite,
+    FeatureClass::Demolished,
+    FeatureClass::Greenhouse,
+    FeatureClass::Barn,
+    FeatureClass::FarmAuxiliary,
+    FeatureClass::SolarPlant,
+    FeatureClass::SolarPanel,
+    FeatureClass::Substation,
+    FeatureClass::Tree,
+    FeatureClass::LanduseResidential,
+    FeatureClass::LanduseIndustrial,
+    FeatureClass::LanduseCommercial,
+    FeatureClass::LanduseFarmland,
+    FeatureClass::Ignore,
+    FeatureClass::Road,
+    FeatureClass::Water,
+    FeatureClass::NoLabel,
+];
+
+const _: () = assert!(ALL_CLASSES.len() == COLOR_INDEX.len());
+
+static COLORS: OnceLock<Vec<[u8; 3]>> = OnceLock::new();
+
+/// The outline color of every class: `COLOR_INDEX`, as `--class-rules`
+/// recolor it.
+pub fn colors() -> &'static [[u8; 3]] {
+    COLORS.get().map_or(COLOR_INDEX, |c| c)
+}
+
+/// Sets the colors for the rest of the run, before anything is drawn.
+pub fn set_colors(colors: Vec<[u8; 3]>) {
+    COLORS.set(colors).expect("colors set twice");
+}
+
+/// How a feature is rasterized.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Area,
+    /// A polyline buffered to the given total width.
+    Line {
+        width_m: f64,
+    },
+    /// A single node drawn as a filled disk.
+    Disk {
+        radius_m: f64,
+    },
+}
+
+const LIFECYCLE_PREFIXES: &[&str] = &["demolished", "razed", "destroyed", "removed"];
+/// Width of a lane, meters, for roads tagged with `lanes` but no `width`.
+const LANE_WIDTH_M: f64 = 3.5;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ClassOptions {
+    /// Render buildings under construction, construction sites and
+    /// demolished buildings as their own classes.
+    #[arg(long)]
+    pub change_classes: bool,
+    /// Render greenhouses, barns and auxiliary farm buildings as their own
+    /// classes instead of as normal buildings.
+    #[arg(long)]
+    pub agricultural_classes: bool,
+    /// Render solar plants, solar panels and substations.
+    #[arg(long)]
+    pub power_classes: bool,
+    /// Render trees as disks and tree rows as buffered lines.
+    #[arg(long)]
+    pub tree_classes: bool,
+    /// Crown radius of a single tree, meters. Tree rows are drawn twice as
+    /// wide.
+    #[arg(long, default_value_t = 3.0)]
+    pub crown_radius: f64,
+    /// Render residential, industrial, commercial and farmland areas as
+    /// background classes underneath everything else.
+    #[arg(long)]
+    pub landuse_classes: bool,
+    /// Render `highway=*` ways as roads, buffered to their `width`, or to
+    /// `lanes` times 3.5 m, or else to a width typical of their kind.
+    #[arg(long)]
+    pub road_classes: bool,
+    /// Render `natural=water` and `waterway=riverbank` areas as water.
+    /// Only closed ways are drawn, not multipolygon relations.
+    #[arg(long)]
+    pub water_classes: bool,
+    /// Render buildings under this many square meters as small buildings.
+    #[arg(long, value_name = "M2", default_value_t = 100.0)]
+    pub min_area: f64,
+    /// Render buildings over this many square meters as excluded
+    /// buildings, e.g. the outlines of whole industrial sites.
+    #[arg(long, value_name = "M2")]
+    pub max_area: Option<f64>,
+    /// Render buildings with any of these tags as excluded buildings, e.g.
+    /// `building=shed,building=roof,building=construction`; `key=*`
+    /// matches any value.
+    #[arg(long, value_name = "KEY=VALUE,...", value_delimiter = ',', value_parser = parse_tag)]
+    pub exclude_tags: Vec<(String, String)>,
+    /// Leave out the excluded buildings instead of rendering them as a
+    /// class of their own.
+    #[arg(long)]
+    pub drop_excluded: bool,
+    /// Also render buildings covering fewer than this many pixels of the
+    /// tiles as small buildings, besides those under `--min-area`. A pixel
+    /// covers less ground away from the equator and at higher zooms.
+    #[arg(long, value_name = "PX")]
+    pub small_building_px: Option<f64>,
+    /// Leave out buildings covering fewer than this many pixels, which at
+    /// the zoom of the tiles are noise rather than something to learn.
+    /// With `--ignore-small` they are marked as ignored instead.
+    #[arg(long, value_name = "PX")]
+    pub min_footprint_px: Option<f64>,
+}
+
+fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+            Ok((key.to_owned(), value.to_owned()))
+        }
+        _ => Err(format!("{s:?} is not KEY=VALUE or KEY=*")),
+    }
+}
+
+impl ClassOptions {
+    /// Whether a building with `tags` has one of `--exclude-tags`.
+    pub fn has_excluded_tags(&self, tags: &Tags) -> bool {
+        self.exclude_tags
+            .iter()
+            .any(|(key, value)| match value.as_str() {
+                "*" => tags.contains_key(key.as_str()),
+                value => tags.contains(key, value),
+            })
+    }
+
+    /// `class` of an excluded building as `--drop-excluded` has it.
+    pub fn excluded(&self) -> Option<FeatureClass> {
+        (!self.drop_excluded).then_some(FeatureClass::BuildingHasExcludedTags)
+    }
+}
+
+fn landuse_class(tags: &Tags) -> Option<FeatureClass> {
+    match tags.get("landuse")?.as_str() {
+        "residential" => Some(FeatureClass::LanduseResidential),
+        "industrial" => Some(FeatureClass::LanduseIndustrial),
+        "commercial" | "retail" => Some(FeatureClass::LanduseCommercial),
+        "farmland" => Some(FeatureClass::LanduseFarmland),
+        _ => None,
+    }
+}
+
+fn is_water(tags: &Tags) -> bool {
+    tags.contains("natural", "water") || tags.contains("waterway", "riverbank")
+}
+
+/// A `highway=*` way drawn as a line: not an area such as a pedestrian
+/// square, and not a road that is only planned or gone.
+fn is_road(tags: &Tags) -> bool {
+    match tags.get("highway").map(|v| v.as_str()) {
+        None | Some("proposed" | "construction" | "abandoned" | "razed") => false,
+        Some(_) => !tags.contains("area", "yes"),
+    }
+}
+
+/// Total width of a road, meters: its `width`, else its `lanes` times
+/// `LANE_WIDTH_M`, else what is usual for its `highway` value.
+fn road_width(tags: &Tags) -> f64 {
+    let number = |key: &str| {
+        let value = tags.get(key)?;
+        // `width=7 m` and `width=7m` are common too.
+        let value = value.trim_end_matches('m').trim();
+        value.parse::<f64>().ok().filter(|v| *v > 0.0)
+    };
+    if let Some(width) = number("width") {
+        return width;
+    }
+    if let Some(lanes) = number("lanes") {
+        return lanes * LANE_WIDTH_M;
+    }
+    match tags.get("highway").map(|v| v.as_str()) {
+        Some("motorway" | "trunk") => 4.0 * LANE_WIDTH_M,
+        Some("primary" | "secondary") => 3.0 * LANE_WIDTH_M,
+        Some("tertiary" | "residential" | "unclassified" | "living_street") => 2.0 * LANE_WIDTH_M,
+        Some("footway" | "path" | "cycleway" | "bridleway" | "steps" | "pedestrian") => 2.0,
+        Some(v) if v.ends_with("_link") => LANE_WIDTH_M,
+        _ => 4.0,
+    }
+}
+
+/// Class of an object tagged `building=*`, before the area thresholds.
+/// `None` if `--class-rules` or `--drop-excluded` leave it out.
+pub fn building_class(tags: &Tags, opts: &ClassOptions) -> Option<FeatureClass> {
+    if opts.has_excluded_tags(tags) {
+        return opts.excluded();
+    }
+    if rules::active() {
+        return rules::class(tags);
+    }
+    Some(builtin_building_class(tags, opts))
+}
+
+fn builtin_building_class(tags: &Tags, opts: &ClassOptions) -> FeatureClass {
+    if opts.change_classes && tags.contains("building", "construction") {
+        return FeatureClass::UnderConstruction;
+    }
+    if opts.agricultural_classes {
+        match tags.get("building").map(|v| v.as_str()) {
+            Some("greenhouse") => return FeatureClass::Greenhouse,
+            Some("barn") => return FeatureClass::Barn,
+            Some("farm_auxiliary") => return FeatureClass::FarmAuxiliary,
+            _ => {}
+        }
+    }
+    FeatureClass::Normal
+}
+
+fn is_lifecycle_building(tags: &Tags) -> bool {
+    LIFECYCLE_PREFIXES
+        .iter()
+        .any(|p| tags.contains_key(format!("{p}:building").as_str()))
+}
+
+/// Class of an object that is not a building but is rendered anyway, or
+/// `None` if it is not rendered with these options.
+pub fn feature_class(tags: &Tags, opts: &ClassOptions) -> Option<FeatureClass> {
+    if rules::active() {
+        return rules::class(tags);
+    }
+    if opts.change_classes {
+        if tags.contains("landuse", "construction") {
+            return Some(FeatureClass::ConstructionSite);
+        }
+        if is_lifecycle_building(tags) {
+            return Some(FeatureClass::Demolished);
+        }
+    }
+    if opts.power_classes {
+        if tags.contains("power", "plant") && tags.contains("plant:source", "solar") {
+            return Some(FeatureClass::SolarPlant);
+        }
+        if tags.contains("power", "generator") && tags.contains("generator:source", "solar") {
+            return Some(FeatureClass::SolarPanel);
+        }
+        if tags.contains("power", "substation") {
+            return Some(FeatureClass::Substation);
+        }
+    }
+    if opts.tree_classes
+        && (tags.contains("natural", "tree") || tags.contains("natural", "tree_row"))
+    {
+        return Some(FeatureClass::Tree);
+    }
+    if opts.road_classes && is_road(tags) {
+        return Some(FeatureClass::Road);
+    }
+    if opts.water_classes && is_water(tags) {
+        return Some(FeatureClass::Water);
+    }
+    if opts.landuse_classes {
+        if let Some(class) = landuse_class(tags) {
+            return Some(class);
+        }
+    }
+    None
+}
+
+pub fn shape(tags: &Tags, opts: &ClassOptions) -> Shape {
+    if tags.contains("natural", "tree") {
+        Shape::Disk {
+            radius_m: opts.crown_radius,
+        }
+    } else if tags.contains("natural", "tree_row") {
+        Shape::Line {
+            width_m: opts.crown_radius * 2.0,
+        }
+    } else if is_road(tags) {
+        Shape::Line {
+            width_m: road_width(tags),
+        }
+    } else {
+        Shape::Area
+    }
+}
+
+/// Whether a non-building object could be rendered with some options, so
+/// the loader knows to keep it.
+pub fn is_feature(tags: &Tags) -> bool {
+    tags.contains("landuse", "construction")
+        || is_lifecycle_building(tags)
+        || tags.contains("power", "plant")
+        || tags.contains("power", "generator")
+        || tags.contains("power", "substation")
+        || tags.contains("natural", "tree")
+        || tags.contains("natural", "tree_row")
+        || landuse_class(tags).is_some()
+        || is_road(tags)
+        || is_water(tags)
+        || rules::class(tags).is_some()
+}
+
+/// Whether a node is only kept for drawing features of ways: the nodes
+/// of `highway=*` are crossings, stops and the like, not roads.
+pub fn is_way_only_feature(tags: &Tags) -> bool {
+    tags.contains_key("highway")
+}
+
+/// Classes of standing structures, whatever their subclass or size.
+const BUILDING_CLASSES: &[FeatureClass] = &[
+    FeatureClass::BuildingBelowAreaThreshold,
+    FeatureClass::Normal,
+    FeatureClass::BuildingHasExcludedTags,
+    FeatureClass::UnderConstruction,
+    FeatureClass::Greenhouse,
+    FeatureClass::Barn,
+    FeatureClass::FarmAuxiliary,
+];
+
+pub fn is_building(class: FeatureClass) -> bool {
+    BUILDING_CLASSES.contains(&class)
+}
+
+/// Whether an outline pixel belongs to a building of any class.
+pub fn is_building_pixel(px: [u8; 3]) -> bool {
+    BUILDING_CLASSES.iter().any(|c| colors()[*c as usize] == px)
+}
+
+/// Position of buildings in `draw_order`.
+pub const BUILDINGS_ORDER: u8 = 10;
+
+/// Classes are drawn in increasing order, so buildings end up on top of
+/// the areas they stand in and panels on top of their plant. Ties are
+/// broken by OSM id, which keeps the output deterministic.
+pub fn draw_order(class: FeatureClass) -> u8 {
+    match class {
+        FeatureClass::LanduseResidential
+        | FeatureClass::LanduseIndustrial
+        | FeatureClass::LanduseCommercial
+        | FeatureClass::LanduseFarmland => 0,
+        FeatureClass::Water | FeatureClass::ConstructionSite | FeatureClass::SolarPlant => 1,
+        FeatureClass::Road
+        | FeatureClass::SolarPanel
+        | FeatureClass::Substation
+        | FeatureClass::Tree => 2,
+        FeatureClass::Demolished => BUILDINGS_ORDER + 1,
+        _ => BUILDINGS_ORDER,
+    }
+}
@@ -0,0 +1,174 @@
+//! COCO instance segmentation annotations for the stitched chips:
+//! `annotations.json` with `images`, `annotations` and `categories`, next
+//! to `images/` with the chips. Every building is a polygon in chip
+//! pixels, drawn from the same rings as the outlines and cut at the chip
+//! edge.
+
+use std::path::PathBuf;
+
+use geo::{Area, BooleanOps, BoundingRect, Coord, Polygon, Rect};
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    chips::Grid,
+    classes::{ClassOptions, ALL_CLASSES},
+    formats::Formats,
+    logging,
+    oriented::category,
+    release::link_or_copy,
+    workspace::{STITCHED, TILES},
+    OsmData,
+};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct CocoOptions {
+    /// Directory with `tiles/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    #[arg(long, default_value = "coco")]
+    pub out: PathBuf,
+    #[command(flatten)]
+    pub classes: ClassOptions,
+}
+
+#[derive(Serialize)]
+struct Image {
+    id: usize,
+    file_name: String,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct Annotation {
+    id: usize,
+    image_id: usize,
+    category_id: usize,
+    /// Flat `[x1, y1, x2, y2, ...]` per part left by the chip edge.
+    segmentation: Vec<Vec<f64>>,
+    area: f64,
+    /// `[x, y, width, height]`
+    bbox: [f64; 4],
+    iscrowd: u8,
+}
+
+#[derive(Serialize)]
+struct Category {
+    id: usize,
+    name: &'static str,
+    supercategory: &'static str,
+}
+
+#[derive(Serialize)]
+struct Coco {
+    images: Vec<Image>,
+    annotations: Vec<Annotation>,
+    categories: Vec<Category>,
+}
+
+/// Rounded to a tenth of a pixel, which keeps the file small.
+fn round(v: f64) -> f64 {
+    (v * 10.0).round() / 10.0
+}
+
+pub fn export_coco(osm: &OsmData, opts: &CocoOptions) -> anyhow::Result<()> {
+    let grid = Grid::load(&opts.chips)?;
+    info!("Annotating buildings in {} chips", grid.chips.len());
+    let ext = Formats::load()?.chips.ext();
+
+    // Category ids are the class indices, as in `masks/classes.json`.
+    let categories = ALL_CLASSES
+        .iter()
+        .filter_map(|&class| {
+            Some(Category {
+                id: class as usize,
+                name: category(class)?,
+                supercategory: "building",
+            })
+        })
+        .collect();
+    let images: Vec<_> = grid
+        .chips
+        .iter()
+        .enumerate()
+        .map(|(i, chip)| {
+            let [x0, y0, x1, y1] = chip.px;
+            Image {
+                id: i + 1,
+                file_name: format!("{}{ext}", chip.key),
+                width: (x1 - x0) as u32,
+                height: (y1 - y0) as u32,
+            }
+        })
+        .collect();
+
+    let mut annotations = vec![];
+    for (class, ring) in grid.buildings(osm, &opts.classes)? {
+        if category(class).is_none() {
+            continue;
+        }
+        let footprint = Polygon::new(ring, vec![]);
+        let Some(bounds) = footprint.bounding_rect() else {
+            continue;
+        };
+        for i in grid.overlapping(bounds) {
+            let [x0, y0, x1, y1] = grid.chips[i].px;
+            let chip = Rect::new(Coord { x: x0, y: y0 }, Coord { x: x1, y: y1 }).to_polygon();
+            let inside = footprint.intersection(&chip);
+            let area = inside.unsigned_area();
+            let Some(bounds) = inside.bounding_rect().filter(|_| area > 0.0) else {
+                continue;
+            };
+            let segmentation = inside
+                .iter()
+                .map(|part| {
+                    part.exterior()
+                        .coords()
+                        .flat_map(|c| [round(c.x - x0), round(c.y - y0)])
+                        .collect()
+                })
+                .collect();
+            annotations.push(Annotation {
+                id: annotations.len() + 1,
+                image_id: i + 1,
+                category_id: class as usize,
+                segmentation,
+                area: round(area),
+                bbox: [
+                    round(bounds.min().x - x0),
+                    round(bounds.min().y - y0),
+                    round(bounds.width()),
+                    round(bounds.height()),
+                ],
+                iscrowd: 0,
+            });
+        }
+    }
+
+    let image_dir = opts.out.join("images");
+    std::fs::create_dir_all(&image_dir)?;
+    for image in &images {
+        let target = image_dir.join(&image.file_name);
+        // Relinked every time, the chip may have been stitched again.
+        if target.exists() {
+            std::fs::remove_file(&target)?;
+        }
+        link_or_copy(&opts.chips.join(TILES).join(&image.file_name), &target)?;
+    }
+    let coco = Coco {
+        images,
+        annotations,
+        categories,
+    };
+    std::fs::write(
+        opts.out.join("annotations.json"),
+        serde_json::to_vec(&coco)?,
+    )?;
+    logging::status!(
+        "Wrote {} annotations for {} chips",
+        coco.annotations.len(),
+        coco.images.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! One crop per building, for datasets classifying buildings by their
+//! attributes, e.g. roof shape or use: `building-crops` cuts a square of
+//! the imagery around every footprint with `--margin-m` to each side,
+//! writes it to `images/<id>.jpg` and the footprint as a mask to
+//! `masks/<id>.png`, both named after the OSM id of the way, and lists
+//! them with their tags in `buildings.jsonl`.
+//!
+//! Crops are cut from `tiles/` as `region` mosaics a window; buildings
+//! whose imagery is not downloaded are left out.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::PathBuf,
+};
+
+use log::info;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    checks,
+    classes::{self, ClassOptions},
+    footprint_class, geometry, interest_bbox, logging, provider,
+    region::{self, TileImages},
+    register, ring_area, way_coords, zoom, GeoCoordinate, OsmData,
+};
+
+#[derive(clap::Args)]
+pub struct CropOptions {
+    #[arg(long, default_value = "building-crops")]
+    pub out: PathBuf,
+    /// Imagery kept around the footprint on each side, meters.
+    #[arg(long, default_value_t = 4.0)]
+    pub margin_m: f64,
+    /// Side of the crops, pixels, whatever the size of the building.
+    #[arg(long, default_value_t = 224)]
+    pub size: u32,
+    #[command(flatten)]
+    pub classes: ClassOptions,
+}
+
+#[derive(Serialize)]
+struct CropRecord<'a> {
+    osm_id: i64,
+    image: String,
+    mask: String,
+    class: String,
+    area_m2: f64,
+    /// `[west, south, east, north]` of the crop.
+    bounds: [f32; 4],
+    tags: BTreeMap<&'a str, &'a str>,
+}
+
+struct Building<'a> {
+    way: &'a osmpbfreader::Way,
+    class: classes::FeatureClass,
+    /// Moved by the provider offset, like the outlines.
+    coords: Vec<GeoCoordinate>,
+    area_m2: f64,
+}
+
+/// The square around `coords` with `margin_m` to each side of their
+/// extent, as `TOP,LEFT,BOTTOM,RIGHT`.
+fn square_around(coords: &[GeoCoordinate], margin_m: f64) -> [f64; 4] {
+    let (mut south, mut west) = (f64::MAX, f64::MAX);
+    let (mut north, mut east) = (f64::MIN, f64::MIN);
+    for c in coords {
+        south = south.min(c.latitude);
+        north = north.max(c.latitude);
+        west = west.min(c.longitude);
+        east = east.max(c.longitude);
+    }
+    let (lat, lon) = ((south + north) / 2.0, (west + east) / 2.0);
+    let m_per_lon = 111_320.0 * lat.to_radians().cos();
+    let side_m = ((north - south) * 111_320.0).max((east - west) * m_per_lon) + 2.0 * margin_m;
+    let (half_lat, half_lon) = (side_m / 2.0 / 111_320.0, side_m / 2.0 / m_per_lon);
+    [
+        lat + half_lat,
+        lon - half_lon,
+        lat - half_lat,
+        lon + half_lon,
+    ]
+}
+
+/// Writes a crop and a mask for every building way of `osm` in the area
+/// of interest into `--out`.
+pub fn export_crops(osm: &OsmData, opts: &CropOptions) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        opts.margin_m >= 0.0 && opts.margin_m.is_finite(),
+        "--margin-m must be a number of meters, 0 or more"
+    );
+    anyhow::ensure!(opts.size > 0, "--size must be at least one pixel");
+    let offset_m = provider::Providers::load()?.offset_m(&provider::source().name);
+    let tile_px = crate::chips::tile_px();
+    let bbox = interest_bbox();
+
+    // Buildings by the tile they start in, so that the tiles of a group
+    // are decoded once.
+    let mut by_tile: HashMap<(u32, u32), Vec<Building>> = HashMap::new();
+    for way in osm.ways_buildings.values() {
+        let Some(coords) = way_coords(way, &osm.nodes_all).filter(|c| c.len() >= 3) else {
+            continue;
+        };
+        let first = coords[0];
+        let (lat, lon) = (first.latitude as f32, first.longitude as f32);
+        if !(bbox.bottom()..=bbox.top()).contains(&lat)
+            || !(bbox.left()..=bbox.right()).contains(&lon)
+        {
+            continue;
+        }
+        let area = ring_area(&coords);
+        let Some(class) = footprint_class(&way.tags, &area, &opts.classes, tile_px) else {
+            continue;
+        };
+        if !classes::is_building(class) {
+            continue;
+        }
+        by_tile
+            .entry(mercator::tile_at(first.latitude, first.longitude, zoom()))
+            .or_default()
+            .push(Building {
+                way,
+                class,
+                coords: register(&coords, offset_m),
+                area_m2: geometry::footprint_area(&area),
+            });
+    }
+    let total: usize = by_tile.values().map(Vec::len).sum();
+    info!("Cropping {total} buildings");
+
+    let (images, masks) = (opts.out.join("images"), opts.out.join("masks"));
+    std::fs::create_dir_all(&images)?;
+    std::fs::create_dir_all(&masks)?;
+    let groups: Vec<_> = by_tile.into_values().collect();
+    let records = groups
+        .par_iter()
+        .map(|group| {
+            let mut tiles = TileImages::new();
+            let mut records = vec![];
+            for b in group {
+                let [top, left, bottom, right] = square_around(&b.coords, opts.margin_m);
+                let crop = checks::bbox(top as f32, left as f32, bottom as f32, right as f32)?;
+                let size = (opts.size, opts.size);
+                let Some((image, mask)) =
+                    region::footprint_crop(&crop, size, &b.coords, &mut tiles)
+                else {
+                    continue;
+                };
+                let id = b.way.id.0;
+                let (image_name, mask_name) =
+                    (format!("images/{id}.jpg"), format!("masks/{id}.png"));
+                image.save(opts.out.join(&image_name))?;
+                mask.save(opts.out.join(&mask_name))?;
+                records.push(CropRecord {
+                    osm_id: id,
+                    image: image_name,
+                    mask: mask_name,
+                    class: format!("{:?}", b.class),
+                    area_m2: b.area_m2,
+                    bounds: [crop.left(), crop.bottom(), crop.right(), crop.top()],
+                    tags: b
+                        .way
+                        .tags
+                        .iter()
+                        .map(|(k, v)| (k.as_str(), v.as_str()))
+                        .collect(),
+                });
+            }
+            Ok(records)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut records: Vec<_> = records.into_iter().flatten().collect();
+    records.sort_by_key(|r| r.osm_id);
+
+    let path = opts.out.join("buildings.jsonl");
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    for record in &records {
+        serde_json::to_writer(&mut w, record)?;
+        writeln!(w)?;
+    }
+    w.flush()?;
+    logging::status!(
+        "Wrote {} building crops to {}, left out {} without imagery",
+        records.len(),
+        opts.out.display(),
+        total - records.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Content-addressed imagery. Ocean and placeholder tiles come back from
+//! the provider byte for byte the same thousands of times, so with a
+//! store every blob is kept once under `tile-blobs/` by its SHA-256 and
+//! `tiles/{y}-{x}.jpg` is a hard link to it. Everything reading `tiles/`
+//! works unchanged; the store is used whenever `tile-blobs/` exists, see
+//! `dedup-tiles`.
+
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{Cursor, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use log::info;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use slippy_map_tiles::Tile;
+
+use crate::{
+    list_tiles, logging, paths,
+    timing::{self, Stage},
+    workspace::TILES,
+};
+
+pub const BLOBS_DIR: &str = "tile-blobs";
+const INDEX_FILE: &str = "index.jsonl";
+
+#[derive(Clone, Debug, Serialize)]
+struct IndexEntry {
+    /// `{y}-{x}`, like the file names.
+    tile: String,
+    /// Hex SHA-256 of the JPEG.
+    hash: String,
+}
+
+/// The blobs and the tile to hash mapping, `index.jsonl`, an append-only
+/// journal where the last line for a tile wins.
+pub struct Store {
+    dir: PathBuf,
+    journal: Mutex<File>,
+}
+
+impl Store {
+    /// Opens the store next to `tiles/` if there is one.
+    pub fn detect() -> anyhow::Result<Option<Self>> {
+        if Path::new(BLOBS_DIR).is_dir() {
+            Self::open(Path::new(BLOBS_DIR)).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Opens or creates the store in `dir`.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(INDEX_FILE))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            journal: Mutex::new(journal),
+        })
+    }
+
+    /// Blobs are spread over 256 directories by their first byte.
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(format!("{hash}.jpg"))
+    }
+
+    /// Writes the blob for `data` unless it exists, links `path` to it and
+    /// returns its hash.
+    pub fn put(&self, tile: Tile, path: &Path, data: &[u8]) -> anyhow::Result<String> {
+        let hash = hex(&Sha256::digest(data));
+        let blob = self.blob_path(&hash);
+        if !blob.is_file() {
+            std::fs::create_dir_all(blob.parent().unwrap())?;
+            // Unique per tile, so that racing writers of the same blob
+            // never share a temporary file.
+            let tmp = blob.with_extension(format!("{}.tmp", paths::stem(tile)));
+            std::fs::write(&tmp, data)?;
+            std::fs::rename(&tmp, &blob)?;
+        }
+        if let Ok(meta) = path.metadata() {
+            let linked = blob.metadata()?;
+            if (meta.dev(), meta.ino()) == (linked.dev(), linked.ino()) {
+                return Ok(hash);
+            }
+            std::fs::remove_file(path)?;
+        }
+        std::fs::hard_link(&blob, path).with_context(|| {
+            format!(
+                "linking {} to {}\n\
+                 hint: `tiles/` and `{BLOBS_DIR}/` have to be on the same file system",
+                path.display(),
+                blob.display()
+            )
+        })?;
+        let entry = IndexEntry {
+            tile: paths::stem(tile),
+            hash,
+        };
+        // One write per line, so concurrent downloads never interleave.
+        let line = format!("{}\n", serde_json::to_string(&entry)?);
+        self.journal.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(entry.hash)
+    }
+
+    /// Removes blobs no tile links to any more, returning their count and
+    /// total size.
+    pub fn collect_garbage(&self) -> anyhow::Result<(usize, u64)> {
+        let mut removed = (0, 0);
+        for shard in std::fs::read_dir(&self.dir)? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for blob in std::fs::read_dir(&shard)? {
+                let blob = blob?.path();
+                let meta = blob.metadata()?;
+                if meta.nlink() == 1 {
+                    std::fs::remove_file(&blob)?;
+                    removed.0 += 1;
+                    removed.1 += meta.len();
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Saves a downloaded tile into `tiles/`, through the store if there is one.
+pub fn save_tile(
+    store: Option<&Store>,
+    tile: Tile,
+    img: &image::DynamicImage,
+) -> anyhow::Result<()> {
+    let path = paths::create_tile_file(TILES, tile, ".jpg")?;
+    let mut data = Cursor::new(Vec::new());
+    {
+        let _span = timing::span(Stage::Encode);
+        img.write_to(&mut data, image::ImageOutputFormat::Jpeg(75))?;
+    }
+    let _span = timing::span(Stage::Io);
+    match store {
+        Some(store) => {
+            store.put(tile, &path, data.get_ref())?;
+        }
+        None => std::fs::write(&path, data.into_inner())?,
+    }
+    Ok(())
+}
+
+/// Moves `tiles/` into the store and reports how much it saved.
+pub fn dedup_tiles() -> anyhow::Result<()> {
+    let store = Store::open(Path::new(BLOBS_DIR))?;
+    let tiles = list_tiles(TILES, ".jpg");
+    info!("Deduplicating {} tiles", tiles.len());
+    let blobs = tiles
+        .par_iter()
+        .map(|tile| {
+            let path = paths::tile_file(TILES, *tile, ".jpg");
+            let data = std::fs::read(&path)?;
+            let hash = store
+                .put(*tile, &path, &data)
+                .with_context(|| format!("deduplicating {}", path.display()))?;
+            Ok((hash, data.len() as u64))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (garbage, garbage_bytes) = store.collect_garbage()?;
+    let logical: u64 = blobs.iter().map(|(_, len)| len).sum();
+    let mut unique = HashSet::new();
+    let stored: u64 = blobs
+        .iter()
+        .filter(|(hash, _)| unique.insert(hash))
+        .map(|(_, len)| len)
+        .sum();
+    logging::status!(
+        "{} tiles, {} unique blobs, {:.1} MiB stored for {:.1} MiB of tiles",
+        tiles.len(),
+        unique.len(),
+        stored as f64 / (1 << 20) as f64,
+        logical as f64 / (1 << 20) as f64,
+    );
+    if garbage > 0 {
+        logging::status!(
+            "Removed {garbage} unreferenced blobs, {:.1} MiB",
+            garbage_bytes as f64 / (1 << 20) as f64
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,356 @@
+//! What changed between two versions of a dataset, to validate a new
+//! extract or a pipeline change before retraining on it. `diff datasets A
+//! B` compares the manifests of two `render-outlines` runs, tile by tile,
+//! and the outlines of the tiles both have pixel by pixel, as class
+//! indices so that a change of color scheme alone is no change.
+//!
+//! It writes `diff.json` with the added, removed and changed tiles and the
+//! pixels every class gained and lost, and `heatmap.png`, where every pixel
+//! is a tile, or a square of them over large areas: red the more of its
+//! labels changed, green if only B has it, blue if only A has it, and gray
+//! if it is the same in both.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use image::{GrayImage, Rgb, RgbImage};
+use log::info;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    classes::{ALL_CLASSES, COLOR_INDEX},
+    manifest::{self, TileEntry},
+    masks, paths,
+};
+
+/// Longest side of the heatmap, pixels.
+const HEATMAP_SIDE: u32 = 4096;
+const CLASSES: usize = COLOR_INDEX.len();
+
+#[derive(clap::Subcommand)]
+pub enum DiffCommand {
+    /// Compare two datasets rendered by `render-outlines`, the `--out-dir`
+    /// of each, and write what changed into `--out`.
+    Datasets(DatasetsOptions),
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct DatasetsOptions {
+    /// The earlier dataset.
+    pub a: PathBuf,
+    /// The later dataset.
+    pub b: PathBuf,
+    #[arg(long, default_value = "dataset-diff")]
+    pub out: PathBuf,
+}
+
+impl DiffCommand {
+    pub fn out(&self) -> &Path {
+        match self {
+            DiffCommand::Datasets(opts) => &opts.out,
+        }
+    }
+}
+
+/// Pixels per class of label that became another, `[from][to]`.
+type Transitions = Vec<[u64; CLASSES]>;
+
+#[derive(Serialize)]
+struct TileChange {
+    changed_px: u64,
+    /// Of the pixels of the tile.
+    share: f64,
+}
+
+#[derive(Serialize)]
+struct ClassChange {
+    class: String,
+    /// Pixels of tiles both have that became this class.
+    gained: u64,
+    /// Pixels of tiles both have that were this class and became another.
+    lost: u64,
+    /// Pixels of this class in the tiles only B has.
+    in_added: u64,
+    /// Pixels of this class in the tiles only A has.
+    in_removed: u64,
+    /// Where the lost pixels went, by class.
+    became: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    a: PathBuf,
+    b: PathBuf,
+    tiles_a: usize,
+    tiles_b: usize,
+    unchanged: usize,
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: BTreeMap<String, TileChange>,
+    /// Classes with any change.
+    classes: Vec<ClassChange>,
+}
+
+fn class_name(i: usize) -> String {
+    format!("{:?}", ALL_CLASSES[i])
+}
+
+/// The outline of a manifest entry as class indices. The outline is the
+/// first file recorded for a tile.
+fn load_labels(dir: &Path, entry: &TileEntry) -> anyhow::Result<GrayImage> {
+    let Some(file) = entry.files.first() else {
+        anyhow::bail!(
+            "{}: the manifest lists no files for tile {}",
+            dir.display(),
+            entry.tile
+        );
+    };
+    let path = paths::join_relative(dir, file);
+    let outline = image::open(&path)
+        .map_err(|why| anyhow::anyhow!("cannot read {}: {why}", path.display()))?;
+    Ok(masks::index_mask(&outline.to_rgb8()))
+}
+
+/// The outline files of the entries hold the same bytes.
+fn same_files(a: &Path, ea: &TileEntry, b: &Path, eb: &TileEntry) -> anyhow::Result<bool> {
+    let (Some(fa), Some(fb)) = (ea.files.first(), eb.files.first()) else {
+        return Ok(false);
+    };
+    let read = |dir, file| {
+        let path = paths::join_relative(dir, file);
+        std::fs::read(&path).map_err(|why| anyhow::anyhow!("cannot read {}: {why}", path.display()))
+    };
+    Ok(read(a, fa)? == read(b, fb)?)
+}
+
+/// Counts the pixels of every class of `labels` into `counts`.
+fn count_classes(labels: &GrayImage, counts: &mut [u64; CLASSES]) {
+    for p in labels.pixels() {
+        counts[(p.0[0] as usize).min(CLASSES - 1)] += 1;
+    }
+}
+
+/// What one tile both datasets have contributes to the totals.
+enum Compared {
+    Same,
+    Changed(TileChange, Transitions),
+}
+
+fn compare(a: &Path, ea: &TileEntry, b: &Path, eb: &TileEntry) -> anyhow::Result<Compared> {
+    if same_files(a, ea, b, eb)? {
+        return Ok(Compared::Same);
+    }
+    let (la, lb) = (load_labels(a, ea)?, load_labels(b, eb)?);
+    anyhow::ensure!(
+        la.dimensions() == lb.dimensions(),
+        "tile {} is {:?} in {} and {:?} in {}\n\
+         hint: compare datasets rendered at the same zoom and tile size",
+        ea.tile,
+        la.dimensions(),
+        a.display(),
+        lb.dimensions(),
+        b.display()
+    );
+    let mut transitions = vec![[0; CLASSES]; CLASSES];
+    let mut changed_px = 0;
+    for (pa, pb) in la.pixels().zip(lb.pixels()) {
+        let (from, to) = (pa.0[0] as usize, pb.0[0] as usize);
+        if from != to {
+            transitions[from.min(CLASSES - 1)][to.min(CLASSES - 1)] += 1;
+            changed_px += 1;
+        }
+    }
+    if changed_px == 0 {
+        return Ok(Compared::Same);
+    }
+    let share = changed_px as f64 / (la.width() * la.height()) as f64;
+    Ok(Compared::Changed(
+        TileChange { changed_px, share },
+        transitions,
+    ))
+}
+
+/// Pixels per class over the tiles `names` of the dataset in `dir`.
+fn class_pixels(
+    dir: &Path,
+    tiles: &BTreeMap<String, TileEntry>,
+    names: &[String],
+) -> anyhow::Result<[u64; CLASSES]> {
+    names
+        .par_iter()
+        .map(|name| {
+            let mut counts = [0; CLASSES];
+            count_classes(&load_labels(dir, &tiles[name])?, &mut counts);
+            Ok(counts)
+        })
+        .try_reduce(
+            || [0; CLASSES],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                Ok(a)
+            },
+        )
+}
+
+fn class_changes(
+    transitions: &Transitions,
+    in_added: &[u64; CLASSES],
+    in_removed: &[u64; CLASSES],
+) -> Vec<ClassChange> {
+    (0..CLASSES)
+        .map(|class| {
+            let became: BTreeMap<_, _> = (0..CLASSES)
+                .filter(|&to| transitions[class][to] > 0)
+                .map(|to| (class_name(to), transitions[class][to]))
+                .collect();
+            ClassChange {
+                class: class_name(class),
+                gained: (0..CLASSES).map(|from| transitions[from][class]).sum(),
+                lost: became.values().sum(),
+                in_added: in_added[class],
+                in_removed: in_removed[class],
+                became,
+            }
+        })
+        .filter(|c| c.gained + c.lost + c.in_added + c.in_removed > 0)
+        .collect()
+}
+
+/// Draws the change of every tile, averaged over the tiles of a heatmap
+/// pixel.
+fn heatmap(report: &Report, tiles: &[(Tile, f64, [f64; 2])]) -> Option<RgbImage> {
+    if tiles.is_empty() {
+        return None;
+    }
+    let (min_x, max_x) = tiles.iter().fold((u32::MAX, 0), |(lo, hi), (t, ..)| {
+        (lo.min(t.x()), hi.max(t.x()))
+    });
+    let (min_y, max_y) = tiles.iter().fold((u32::MAX, 0), |(lo, hi), (t, ..)| {
+        (lo.min(t.y()), hi.max(t.y()))
+    });
+    let side = (max_x - min_x + 1).max(max_y - min_y + 1);
+    let cell = side.div_ceil(HEATMAP_SIDE);
+    let (width, height) = ((max_x - min_x) / cell + 1, (max_y - min_y) / cell + 1);
+    // Per pixel: tiles, summed change share, tiles only in B, only in A.
+    let mut sums = vec![(0u32, 0.0, 0.0, 0.0); (width * height) as usize];
+    for (tile, share, [added, removed]) in tiles {
+        let (x, y) = ((tile.x() - min_x) / cell, (tile.y() - min_y) / cell);
+        let sum = &mut sums[(y * width + x) as usize];
+        sum.0 += 1;
+        sum.1 += share;
+        sum.2 += added;
+        sum.3 += removed;
+    }
+    info!(
+        "Heatmap of {} tiles, {cell}x{cell} tiles per pixel",
+        report.tiles_a.max(report.tiles_b)
+    );
+    Some(RgbImage::from_fn(width, height, |x, y| {
+        let (n, share, added, removed) = sums[(y * width + x) as usize];
+        if n == 0 {
+            return Rgb([0, 0, 0]);
+        }
+        let n = n as f64;
+        // A square root, so that a few changed pixels still show.
+        let channel = |v: f64| (255.0 * (v / n).sqrt()).round() as u8;
+        let (r, g, b) = (channel(share), channel(added), channel(removed));
+        match (r, g, b) {
+            (0, 0, 0) => Rgb([48, 48, 48]),
+            _ => Rgb([r, g, b]),
+        }
+    }))
+}
+
+/// Compares the datasets in `opts.a` and `opts.b` and writes the report
+/// and the heatmap into `opts.out`.
+pub fn diff_datasets(opts: &DatasetsOptions) -> anyhow::Result<()> {
+    let (a, b) = (manifest::read(&opts.a)?, manifest::read(&opts.b)?);
+    let added: Vec<String> = b.keys().filter(|t| !a.contains_key(*t)).cloned().collect();
+    let removed: Vec<String> = a.keys().filter(|t| !b.contains_key(*t)).cloned().collect();
+    let common: Vec<&String> = a.keys().filter(|t| b.contains_key(*t)).collect();
+    info!(
+        "Comparing {} tiles both have, {} added and {} removed",
+        common.len(),
+        added.len(),
+        removed.len()
+    );
+
+    let compared = common
+        .par_iter()
+        .map(|name| {
+            let result = compare(&opts.a, &a[*name], &opts.b, &b[*name])?;
+            Ok(((*name).clone(), result))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut transitions = vec![[0; CLASSES]; CLASSES];
+    let mut changed = BTreeMap::new();
+    let mut unchanged = 0;
+    for (name, result) in compared {
+        match result {
+            Compared::Same => unchanged += 1,
+            Compared::Changed(change, t) => {
+                for (row, t) in transitions.iter_mut().zip(t) {
+                    row.iter_mut().zip(t).for_each(|(a, b)| *a += b);
+                }
+                changed.insert(name, change);
+            }
+        }
+    }
+    let in_added = class_pixels(&opts.b, &b, &added)?;
+    let in_removed = class_pixels(&opts.a, &a, &removed)?;
+
+    let report = Report {
+        a: opts.a.clone(),
+        b: opts.b.clone(),
+        tiles_a: a.len(),
+        tiles_b: b.len(),
+        unchanged,
+        classes: class_changes(&transitions, &in_added, &in_removed),
+        added,
+        removed,
+        changed,
+    };
+
+    std::fs::create_dir_all(&opts.out)?;
+    std::fs::write(
+        opts.out.join("diff.json"),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    let parse = |name: &String| paths::parse(OsStr::new(name), "");
+    let tiles: Vec<_> = a
+        .keys()
+        .chain(report.added.iter())
+        .filter_map(|name| {
+            let tile = parse(name)?;
+            let share = report.changed.get(name).map_or(0.0, |c| c.share);
+            let (added, removed) = (!a.contains_key(name), !b.contains_key(name));
+            Some((tile, share, [added as u8 as f64, removed as u8 as f64]))
+        })
+        .collect();
+    if let Some(img) = heatmap(&report, &tiles) {
+        img.save(opts.out.join("heatmap.png"))?;
+    }
+
+    println!(
+        "{} -> {}: {} tiles added, {} removed, {} changed, {} unchanged, written to {}",
+        opts.a.display(),
+        opts.b.display(),
+        report.added.len(),
+        report.removed.len(),
+        report.changed.len(),
+        report.unchanged,
+        opts.out.display()
+    );
+    for class in &report.classes {
+        println!(
+            "  {:<28} +{} -{} px, {} px in added and {} px in removed tiles",
+            class.class, class.gained, class.lost, class.in_added, class.in_removed
+        );
+    }
+    Ok(())
+}
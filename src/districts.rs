@@ -0,0 +1,234 @@
+//! Dataset coverage per administrative area, so under-represented
+//! districts can be targeted in the next run.
+
+use std::{collections::HashMap, io::Write, path::Path};
+
+use anyhow::Context;
+
+use geo::{Centroid, Contains, GeodesicArea, MultiPolygon, Point, Polygon};
+use log::info;
+use osmpbfreader::OsmObj;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    classes::{colors, ClassOptions},
+    formats::Formats,
+    geometry::{line_string, relation_rings, rings_to_multipolygon, MemberReport},
+    list_tiles, logging, paths, way_coords,
+    workspace::{OUTLINES, STITCHED, TILES},
+    zoom, OsmData,
+};
+
+pub struct District {
+    pub id: i64,
+    pub name: String,
+    pub area: MultiPolygon<f64>,
+}
+
+/// Reads `boundary=administrative` relations of the given `admin_level`
+/// together with the ways and nodes they are made of.
+pub fn load_districts(
+    filename: &std::ffi::OsStr,
+    admin_level: &str,
+) -> anyhow::Result<Vec<District>> {
+    let path = Path::new(filename);
+    let r = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+    let objs = pbf
+        .get_objs_and_deps(|obj| {
+            obj.is_relation()
+                && obj.tags().contains("boundary", "administrative")
+                && obj.tags().contains("admin_level", admin_level)
+        })
+        .with_context(|| format!("reading the districts of {}", path.display()))?;
+
+    let mut nodes = HashMap::new();
+    let mut ways = HashMap::new();
+    let mut relations = HashMap::new();
+    for obj in objs.into_values() {
+        match obj {
+            OsmObj::Node(n) => {
+                nodes.insert(n.id.0, n);
+            }
+            OsmObj::Way(w) => {
+                ways.insert(w.id.0, w);
+            }
+            OsmObj::Relation(r) => {
+                relations.insert(r.id.0, r);
+            }
+        }
+    }
+
+    let mut districts = vec![];
+    let mut report = MemberReport::default();
+    let mut ids: Vec<_> = relations.keys().copied().collect();
+    ids.sort();
+    for rel in ids.iter().map(|id| &relations[id]) {
+        if !rel.tags.contains("admin_level", admin_level) {
+            continue; // pulled in as a dependency of another relation
+        }
+        let rings = relation_rings(rel, &ways, &relations);
+        report.add(&rings);
+        let area = rings_to_multipolygon(&rings, &nodes);
+        let name = rel
+            .tags
+            .get("name")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format!("relation {}", rel.id.0));
+        if area.0.is_empty() {
+            info!("District {name} has no closed outer ring, skipping");
+            continue;
+        }
+        districts.push(District {
+            id: rel.id.0,
+            name,
+            area,
+        });
+    }
+    report.log();
+    Ok(districts)
+}
+
+#[derive(Default)]
+struct DistrictStats {
+    tiles: usize,
+    outlines: usize,
+    chips: usize,
+    buildings: usize,
+    small_buildings: usize,
+    footprint_m2: f64,
+    /// Outline pixels per entry of `classes::colors`, with anything else
+    /// (error markers, stray colors) in the last slot.
+    class_pixels: Vec<u64>,
+}
+
+fn tile_center(t: Tile) -> Point<f64> {
+    let c = mercator::within(t, 0.5, 0.5);
+    Point::new(c.longitude, c.latitude)
+}
+
+fn find_district(districts: &[District], p: &Point<f64>) -> Option<usize> {
+    districts.iter().position(|d| d.area.contains(p))
+}
+
+/// Writes `districts.csv` with one row per district. Tiles and chips are
+/// assigned by their center, buildings by their centroid; buildings under
+/// `--min-area` are counted as small.
+pub fn district_stats(
+    osm: &OsmData,
+    districts: &[District],
+    classes: &ClassOptions,
+    out: &Path,
+) -> anyhow::Result<()> {
+    let mut stats: Vec<_> = districts
+        .iter()
+        .map(|_| DistrictStats {
+            class_pixels: vec![0; colors().len() + 1],
+            ..Default::default()
+        })
+        .collect();
+
+    for way in osm.ways_buildings.values() {
+        let Some(coords) = way_coords(way, &osm.nodes_all) else {
+            continue;
+        };
+        if coords.len() < 3 {
+            continue;
+        }
+        let poly = Polygon::new(line_string(&coords), vec![]);
+        let Some(center) = poly.centroid() else {
+            continue;
+        };
+        let Some(i) = find_district(districts, &center) else {
+            continue;
+        };
+        let area = poly.geodesic_area_signed().abs();
+        stats[i].buildings += 1;
+        stats[i].footprint_m2 += area;
+        if area < classes.min_area {
+            stats[i].small_buildings += 1;
+        }
+    }
+
+    for tile in list_tiles(TILES, ".jpg") {
+        if let Some(i) = find_district(districts, &tile_center(tile)) {
+            stats[i].tiles += 1;
+        }
+    }
+
+    for tile in list_tiles(OUTLINES, ".png") {
+        let Some(i) = find_district(districts, &tile_center(tile)) else {
+            continue;
+        };
+        stats[i].outlines += 1;
+        let img = image::open(paths::tile_file(OUTLINES, tile, ".png"))?.into_rgb8();
+        for px in img.pixels() {
+            let class = colors()
+                .iter()
+                .position(|c| *c == px.0)
+                .unwrap_or(colors().len());
+            stats[i].class_pixels[class] += 1;
+        }
+    }
+
+    // Stitched blocks are named after their top-left tile; their extent
+    // follows from the image size.
+    let tile_px = list_tiles(TILES, ".jpg")
+        .first()
+        .and_then(|t| image::image_dimensions(paths::tile_file(TILES, *t, ".jpg")).ok())
+        .map(|(w, _)| w)
+        .unwrap_or(256);
+    let ext = Formats::load()?.chips.ext();
+    let chips = Path::new(STITCHED).join(TILES);
+    for chip in list_tiles(&chips, ext) {
+        let Ok((w, h)) = image::image_dimensions(paths::tile_file(&chips, chip, ext)) else {
+            continue;
+        };
+        let center = Tile::new(
+            zoom(),
+            chip.x() + w / tile_px / 2,
+            chip.y() + h / tile_px / 2,
+        )
+        .with_context(|| format!("chip {chip:?} of {w}x{h} pixels reaches past the map"))?;
+        if let Some(i) = find_district(districts, &tile_center(center)) {
+            stats[i].chips += 1;
+        }
+    }
+
+    let mut csv = std::io::BufWriter::new(std::fs::File::create(out)?);
+    write!(
+        csv,
+        "osm_id,name,tiles,outlines,chips,buildings,small_buildings,footprint_m2"
+    )?;
+    for i in 0..colors().len() {
+        write!(csv, ",class_{i}_px")?;
+    }
+    writeln!(csv, ",other_px")?;
+    for (d, s) in districts.iter().zip(&stats) {
+        write!(
+            csv,
+            "{},\"{}\",{},{},{},{},{},{:.1}",
+            d.id,
+            d.name.replace('"', "\"\""),
+            s.tiles,
+            s.outlines,
+            s.chips,
+            s.buildings,
+            s.small_buildings,
+            s.footprint_m2
+        )?;
+        for px in &s.class_pixels {
+            write!(csv, ",{px}")?;
+        }
+        writeln!(csv)?;
+        logging::status!(
+            "{}: {} tiles, {} chips, {} buildings",
+            d.name,
+            s.tiles,
+            s.chips,
+            s.buildings
+        );
+    }
+    csv.flush()?;
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Buildings mapped twice, which would otherwise be counted as two
+//! instances: building ways whose footprints overlap by at least
+//! `--duplicate-iou` of their union, e.g. an import over hand-mapped
+//! outlines, and building nodes inside a building way, a point mapped
+//! before the outline was. They are collapsed when the extract is loaded,
+//! so that nothing downstream sees them, and `render-outlines` lists what
+//! was collapsed in `duplicates.json`.
+//!
+//! Of two duplicate ways the one kept is, in this order, the one with a
+//! `building=*` value other than `yes`, the one with more tags, and the
+//! older one, with the lower id. A way always wins over a node.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    sync::OnceLock,
+};
+
+use geo::{Area, BooleanOps, BoundingRect, Contains, Point, Polygon, Rect};
+use log::info;
+use osmpbfreader::Way;
+use serde::Serialize;
+
+use crate::{geometry, way_coords, zoom, OsmData};
+
+const DUPLICATES_FILE: &str = "duplicates.json";
+
+static IOU: OnceLock<Option<f64>> = OnceLock::new();
+
+/// Sets `--duplicate-iou`, `None` for `--keep-duplicates`, for the rest
+/// of the run.
+pub fn set_iou(iou: Option<f64>) {
+    IOU.set(iou).expect("duplicate IoU set twice");
+}
+
+#[derive(Debug, Serialize)]
+pub struct Duplicate {
+    /// The way kept in place of this one.
+    pub kept: i64,
+    pub iou: f64,
+}
+
+/// What loading collapsed, by the id of what was dropped.
+#[derive(Debug, Default, Serialize)]
+pub struct Collapsed {
+    pub ways: BTreeMap<i64, Duplicate>,
+    /// Building node to the building way it lies in.
+    pub nodes: BTreeMap<i64, i64>,
+}
+
+#[derive(Serialize)]
+struct DuplicatesRecord<'a> {
+    iou: Option<f64>,
+    #[serde(flatten)]
+    collapsed: &'a Collapsed,
+}
+
+/// The order ways are kept in, best first.
+fn precedence(way: &Way) -> (bool, std::cmp::Reverse<usize>, i64) {
+    let plain = way.tags.get("building").is_none_or(|v| v == "yes");
+    (plain, std::cmp::Reverse(way.tags.len()), way.id.0)
+}
+
+/// Kept footprints by the tiles of the tile zoom their bounding box
+/// touches, like the address grid.
+#[derive(Default)]
+struct Grid {
+    cells: HashMap<(u32, u32), Vec<usize>>,
+}
+
+impl Grid {
+    fn cells(rect: Rect<f64>) -> impl Iterator<Item = (u32, u32)> {
+        let (x0, y0) = mercator::tile_at(rect.max().y, rect.min().x, zoom());
+        let (x1, y1) = mercator::tile_at(rect.min().y, rect.max().x, zoom());
+        (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| (x, y)))
+    }
+
+    fn insert(&mut self, rect: Rect<f64>, i: usize) {
+        for cell in Self::cells(rect) {
+            self.cells.entry(cell).or_default().push(i);
+        }
+    }
+
+    fn candidates(&self, rect: Rect<f64>) -> HashSet<usize> {
+        Self::cells(rect)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+fn iou(a: &Polygon<f64>, b: &Polygon<f64>) -> f64 {
+    let intersection = a.intersection(b).unsigned_area();
+    let union = a.unsigned_area() + b.unsigned_area() - intersection;
+    match union > 0.0 {
+        true => intersection / union,
+        false => 0.0,
+    }
+}
+
+fn overlaps(a: Rect<f64>, b: Rect<f64>) -> bool {
+    a.min().x <= b.max().x
+        && b.min().x <= a.max().x
+        && a.min().y <= b.max().y
+        && b.min().y <= a.max().y
+}
+
+/// Drops the duplicate building ways and nodes of `osm`, unless
+/// `--keep-duplicates`, and returns what it dropped.
+pub fn collapse(osm: &mut OsmData) -> Collapsed {
+    let mut collapsed = Collapsed::default();
+    let Some(min_iou) = IOU.get().copied().flatten() else {
+        return collapsed;
+    };
+
+    let mut ways: Vec<_> = osm.ways_buildings.values().collect();
+    ways.sort_by_key(|w| precedence(w));
+    let mut grid = Grid::default();
+    let mut kept: Vec<(i64, Polygon<f64>, Rect<f64>)> = vec![];
+    for way in ways {
+        let Some(coords) = way_coords(way, &osm.nodes_all).filter(|c| c.len() >= 3) else {
+            continue;
+        };
+        let poly = Polygon::new(geometry::line_string(&coords), vec![]);
+        let Some(rect) = poly.bounding_rect() else {
+            continue;
+        };
+        let mut candidates: Vec<_> = grid.candidates(rect).into_iter().collect();
+        // The best match, and of equal ones the first kept.
+        candidates.sort_unstable();
+        let duplicate = candidates
+            .into_iter()
+            .filter(|i| overlaps(kept[*i].2, rect))
+            .map(|i| (i, iou(&kept[i].1, &poly)))
+            .filter(|(_, iou)| *iou >= min_iou)
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)));
+        match duplicate {
+            Some((i, iou)) => {
+                let dup = Duplicate {
+                    kept: kept[i].0,
+                    iou,
+                };
+                collapsed.ways.insert(way.id.0, dup);
+            }
+            None => {
+                grid.insert(rect, kept.len());
+                kept.push((way.id.0, poly, rect));
+            }
+        }
+    }
+
+    for node in osm.nodes_only_buildings.values() {
+        let c = geometry::node_coord(node);
+        let p = Point::new(c.longitude, c.latitude);
+        let inside = grid
+            .candidates(Rect::new(p.0, p.0))
+            .into_iter()
+            .filter(|i| kept[*i].1.contains(&p))
+            .min();
+        if let Some(i) = inside {
+            collapsed.nodes.insert(node.id.0, kept[i].0);
+        }
+    }
+
+    osm.ways_buildings
+        .retain(|id, _| !collapsed.ways.contains_key(id));
+    osm.nodes_only_buildings
+        .retain(|id, _| !collapsed.nodes.contains_key(id));
+    if !collapsed.ways.is_empty() || !collapsed.nodes.is_empty() {
+        info!(
+            "Collapsed {} duplicate building ways and {} building nodes inside a way",
+            collapsed.ways.len(),
+            collapsed.nodes.len()
+        );
+    }
+    collapsed
+}
+
+/// Writes what was collapsed into `dir`.
+pub fn save(collapsed: &Collapsed, dir: &Path) -> anyhow::Result<()> {
+    let record = DuplicatesRecord {
+        iou: IOU.get().copied().flatten(),
+        collapsed,
+    };
+    std::fs::write(
+        dir.join(DUPLICATES_FILE),
+        serde_json::to_vec_pretty(&record)?,
+    )?;
+    Ok(())
+}
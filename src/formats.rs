@@ -0,0 +1,133 @@
+//! Image formats of the generated artifacts, set in `formats.json`, e.g.
+//! `{"chips": "webp", "masks": "palette-png", "channels": {"coverage": "tiff16"}}`.
+//! Anything left out keeps the default: JPEG chips and RGB or grayscale
+//! PNG for everything else. The file also sets how tiles are named and
+//! where they go. `stitch_pictures` reads the same file for the chips and
+//! stitched masks it writes.
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageOutputFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    classes::colors,
+    paths::{self, TileNames},
+    store,
+};
+
+const FORMATS_PATH: &str = "formats.json";
+
+/// Stitched imagery chips.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChipFormat {
+    #[default]
+    Jpeg,
+    Webp,
+}
+
+/// Label images, per tile and stitched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaskFormat {
+    #[default]
+    Png,
+    /// Indexed PNG whose palette is `classes::colors`, so a pixel's index is
+    /// its class, and a fraction of the size of RGB.
+    PalettePng,
+}
+
+/// Single-channel rasters such as `roofs` or `coverage`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelFormat {
+    #[default]
+    Png,
+    /// 16-bit grayscale TIFF, 0..=255 stretched to the full range, for
+    /// tools that expect confidences at that depth.
+    Tiff16,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Formats {
+    pub chips: ChipFormat,
+    pub masks: MaskFormat,
+    /// By channel name, channels not listed are PNG.
+    pub channels: BTreeMap<String, ChannelFormat>,
+    /// Names of tile and chip files, after `rename-tiles` when changed
+    /// on existing output.
+    pub tile_names: TileNames,
+    /// Where tile and chip files go, see `paths`. A directory per kind by
+    /// default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_paths: Option<String>,
+}
+
+impl ChipFormat {
+    pub fn ext(self) -> &'static str {
+        match self {
+            Self::Jpeg => ".jpg",
+            Self::Webp => ".webp",
+        }
+    }
+}
+
+impl ChannelFormat {
+    pub fn ext(self) -> &'static str {
+        match self {
+            Self::Png => ".png",
+            Self::Tiff16 => ".tif",
+        }
+    }
+
+    pub fn encode(self, img: &GrayImage) -> image::ImageResult<Vec<u8>> {
+        match self {
+            Self::Png => store::encode_png(img),
+            Self::Tiff16 => {
+                let wide: ImageBuffer<image::Luma<u16>, Vec<u16>> =
+                    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                        image::Luma([img.get_pixel(x, y).0[0] as u16 * 257])
+                    });
+                let mut data = Cursor::new(vec![]);
+                DynamicImage::ImageLuma16(wide).write_to(&mut data, ImageOutputFormat::Tiff)?;
+                Ok(data.into_inner())
+            }
+        }
+    }
+}
+
+impl MaskFormat {
+    pub fn encode(self, img: &RgbImage) -> image::ImageResult<Vec<u8>> {
+        match self {
+            Self::Png => store::encode_png(img),
+            Self::PalettePng => match store::encode_palette_png(img, colors()) {
+                Some(data) => Ok(data),
+                // More colors than a palette holds, which only blending
+                // post-processing could produce.
+                None => store::encode_png(img),
+            },
+        }
+    }
+}
+
+impl Formats {
+    pub fn load() -> anyhow::Result<Self> {
+        match std::fs::read(FORMATS_PATH) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn tile_paths(&self) -> &str {
+        self.tile_paths
+            .as_deref()
+            .unwrap_or(paths::DEFAULT_TILE_PATHS)
+    }
+
+    pub fn channel(&self, name: &str) -> ChannelFormat {
+        self.channels.get(name).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,155 @@
+//! Building footprints as GeoJSON, to look at the geometry in QGIS or
+//! similar before rasterizing it: one feature per building with its OSM
+//! id, area and a few tags as properties.
+
+use std::{collections::HashSet, io::Write, path::PathBuf};
+
+use geo::{GeodesicArea, LineString, MultiPolygon, Polygon};
+use log::info;
+use osmpbfreader::Tags;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    geometry::{line_string, load_building_relations, relation_rings, rings_to_multipolygon},
+    logging, way_coords, OsmData,
+};
+
+#[derive(clap::Args)]
+pub struct GeoJsonOptions {
+    #[arg(long, default_value = "buildings.geojson")]
+    pub out: PathBuf,
+    /// Write newline-delimited GeoJSON, one feature per line, instead of
+    /// a FeatureCollection. Large areas load faster this way.
+    #[arg(long)]
+    pub lines: bool,
+    /// Tags copied into the properties when present.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "building,building:levels,height,name,addr:street,addr:housenumber"
+    )]
+    pub tags: Vec<String>,
+    /// Also write building relations as multipolygons, with their inner
+    /// rings as holes, in place of their member ways. Reads the extract a
+    /// second time for the member ways.
+    #[arg(long)]
+    pub relations: bool,
+}
+
+fn ring(line: &LineString<f64>) -> Value {
+    line.coords().map(|c| json!([c.x, c.y])).collect()
+}
+
+fn polygon(poly: &Polygon<f64>) -> Value {
+    std::iter::once(poly.exterior())
+        .chain(poly.interiors())
+        .map(ring)
+        .collect()
+}
+
+fn feature(
+    osm_type: &str,
+    osm_id: i64,
+    geometry: Value,
+    area_m2: f64,
+    tags: &Tags,
+    opts: &GeoJsonOptions,
+) -> Value {
+    let mut properties = Map::new();
+    properties.insert("osm_type".into(), osm_type.into());
+    properties.insert("osm_id".into(), osm_id.into());
+    properties.insert("area_m2".into(), area_m2.into());
+    for key in &opts.tags {
+        if let Some(value) = tags.get(key.as_str()) {
+            properties.insert(key.clone(), value.as_str().into());
+        }
+    }
+    json!({
+        "type": "Feature",
+        "id": format!("{osm_type}/{osm_id}"),
+        "geometry": geometry,
+        "properties": properties,
+    })
+}
+
+/// Writes the buildings of `osm`, and with `--relations` the building
+/// relations of `pbf`, to `--out`.
+pub fn export_geojson(
+    osm: &OsmData,
+    pbf: &std::ffi::OsStr,
+    opts: &GeoJsonOptions,
+) -> anyhow::Result<()> {
+    let mut relation_features = vec![];
+    let mut written = HashSet::new();
+    if opts.relations {
+        let loaded = load_building_relations(pbf)?;
+        let mut skipped = 0;
+        for rel in &loaded.relations {
+            let rings = relation_rings(rel, &loaded.ways, &loaded.all_relations);
+            let area = rings_to_multipolygon(&rings, &loaded.nodes);
+            if area.0.is_empty() {
+                skipped += 1;
+                continue;
+            }
+            let geometry = json!({
+                "type": "MultiPolygon",
+                "coordinates": area.iter().map(polygon).collect::<Vec<_>>(),
+            });
+            let area_m2 = area.geodesic_area_signed().abs();
+            relation_features.push(feature(
+                "relation", rel.id.0, geometry, area_m2, &rel.tags, opts,
+            ));
+            written.insert(rel.id.0);
+        }
+        info!("Skipped {skipped} building relations without a closed outer ring");
+    }
+
+    let mut ways = osm.standalone_buildings(&written);
+    ways.sort_by_key(|w| w.id);
+    let mut features = vec![];
+    for way in ways {
+        let Some(coords) = way_coords(way, &osm.nodes_all) else {
+            continue;
+        };
+        if coords.len() < 3 {
+            continue;
+        }
+        // GeoJSON rings are closed.
+        let mut exterior = line_string(&coords);
+        exterior.close();
+        let poly = Polygon::new(exterior, vec![]);
+        let area_m2 = MultiPolygon::new(vec![poly.clone()])
+            .geodesic_area_signed()
+            .abs();
+        let geometry = json!({ "type": "Polygon", "coordinates": polygon(&poly) });
+        features.push(feature("way", way.id.0, geometry, area_m2, &way.tags, opts));
+    }
+    // Relations after the ways, like in the metadata table.
+    features.extend(relation_features);
+
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&opts.out)?);
+    if opts.lines {
+        for feature in &features {
+            serde_json::to_writer(&mut w, feature)?;
+            writeln!(w)?;
+        }
+    } else {
+        write!(w, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+        for (i, feature) in features.iter().enumerate() {
+            if i > 0 {
+                writeln!(w, ",")?;
+            } else {
+                writeln!(w)?;
+            }
+            serde_json::to_writer(&mut w, feature)?;
+        }
+        writeln!(w, "\n]}}")?;
+    }
+    w.flush()?;
+    logging::status!(
+        "Wrote {} buildings to {}",
+        features.len(),
+        opts.out.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,549 @@
+//! Turning OSM relations into polygons: reading building relations with
+//! their members, stitching member ways into closed rings and pairing
+//! outer rings with the holes they contain.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use geo::{Contains, Coord, GeodesicArea, LineString, MultiPolygon, Polygon};
+use log::{debug, info};
+use osmpbfreader::{Node, OsmId, OsmObj, Relation, Way};
+
+use crate::GeoCoordinate;
+
+pub fn node_coord(n: &Node) -> GeoCoordinate {
+    GeoCoordinate {
+        longitude: (n.decimicro_lon as f64) / 10_000_000.0,
+        latitude: (n.decimicro_lat as f64) / 10_000_000.0,
+    }
+}
+
+pub fn line_string(coords: &[GeoCoordinate]) -> LineString<f64> {
+    LineString::new(coords.iter().map(|v| Coord::from(*v)).collect())
+}
+
+/// Joins way node lists end to end into the longest chains possible.
+/// Segments may be in any order; with `keep_direction` a segment is only
+/// appended where its first node meets the end of the chain, otherwise it
+/// may also be reversed to fit.
+pub fn join_segments(mut segments: Vec<Vec<i64>>, keep_direction: bool) -> Vec<Vec<i64>> {
+    segments.retain(|s| s.len() >= 2);
+    let mut chains = vec![];
+    while let Some(mut chain) = segments.pop() {
+        loop {
+            if chain.len() > 1 && chain.first() == chain.last() {
+                break;
+            }
+            let end = *chain.last().unwrap();
+            let next = segments.iter().position(|s| {
+                s.first() == Some(&end) || (!keep_direction && s.last() == Some(&end))
+            });
+            if let Some(next) = next {
+                let mut seg = segments.swap_remove(next);
+                if seg.first() != Some(&end) {
+                    seg.reverse();
+                }
+                chain.extend_from_slice(&seg[1..]);
+                continue;
+            }
+            // Nothing continues the chain, try growing it at the start.
+            let start = *chain.first().unwrap();
+            let prev = segments.iter().position(|s| {
+                s.last() == Some(&start) || (!keep_direction && s.first() == Some(&start))
+            });
+            let Some(prev) = prev else {
+                break;
+            };
+            let mut seg = segments.swap_remove(prev);
+            if seg.last() != Some(&start) {
+                seg.reverse();
+            }
+            seg.extend_from_slice(&chain[1..]);
+            chain = seg;
+        }
+        chains.push(chain);
+    }
+    chains
+}
+
+/// Joins way node lists end to end until they close. Segments may be in
+/// any order and direction. Returns the closed rings and the number of
+/// chains that could not be closed into a ring.
+pub fn assemble_rings(segments: Vec<Vec<i64>>) -> (Vec<Vec<i64>>, usize) {
+    let mut rings = vec![];
+    let mut unclosed = 0;
+    for chain in join_segments(segments, false) {
+        if chain.first() == chain.last() && chain.len() >= 4 {
+            rings.push(chain);
+        } else {
+            unclosed += 1;
+        }
+    }
+    (rings, unclosed)
+}
+
+/// Levels of relations within relations that are followed. Deeper ones,
+/// which are mistakes or the grouping relations of whole regions, are
+/// left out.
+pub const MAX_RELATION_DEPTH: usize = 3;
+
+/// Outer and inner rings of a multipolygon-style relation, as node ids.
+/// Members with an empty role are treated as outer, which is what most
+/// editors produce, and so is the `outline` of a `type=building` relation.
+pub struct RelationRings {
+    pub outer: Vec<Vec<i64>>,
+    pub inner: Vec<Vec<i64>>,
+    /// Segments that did not close into a ring.
+    pub unclosed: usize,
+    /// Member ways that are not in the provided way map.
+    pub missing_ways: usize,
+    /// Member relations whose rings were used.
+    pub nested: usize,
+    /// Members left out, by reason.
+    pub skipped: BTreeMap<String, usize>,
+}
+
+/// Whether a member with `role` is part of the area: outer, inner, or
+/// neither. `None` for roles such as `part`, `label` or `subarea` that name
+/// something other than the boundary.
+pub fn area_role(role: &str) -> Option<bool> {
+    match role {
+        "" | "outer" | "outline" => Some(false),
+        "inner" => Some(true),
+        _ => None,
+    }
+}
+
+/// Adds the member ways of `rel` to `segments`, `[outer, inner]`, and the
+/// rings of its member relations, swapped for a relation that is itself an
+/// inner member so that an island in a hole stays an island. `path` holds
+/// the relations being resolved, outermost first.
+fn collect_members(
+    rel: &Relation,
+    ways: &HashMap<i64, Way>,
+    relations: &HashMap<i64, Relation>,
+    path: &mut Vec<i64>,
+    inverted: bool,
+    rings: &mut RelationRings,
+    segments: &mut [Vec<Vec<i64>>; 2],
+) {
+    let top = path[0];
+    let skip = |rings: &mut RelationRings, reason: String| {
+        debug!(
+            "relation {top}: leaving out a member of {}, {reason}",
+            rel.id.0
+        );
+        *rings.skipped.entry(reason).or_default() += 1;
+    };
+    for r in rel.refs.iter() {
+        let Some(inner) = area_role(&r.role) else {
+            skip(rings, format!("role {:?}", r.role));
+            continue;
+        };
+        let inner = inner != inverted;
+        match r.member {
+            OsmId::Way(id) => {
+                let Some(way) = ways.get(&id.0) else {
+                    rings.missing_ways += 1;
+                    continue;
+                };
+                segments[inner as usize].push(way.nodes.iter().map(|n| n.0).collect());
+            }
+            OsmId::Relation(id) => {
+                if path.contains(&id.0) {
+                    skip(rings, "relation cycle".to_owned());
+                } else if path.len() > MAX_RELATION_DEPTH {
+                    skip(
+                        rings,
+                        format!("relation nested deeper than {MAX_RELATION_DEPTH}"),
+                    );
+                } else if let Some(member) = relations.get(&id.0) {
+                    rings.nested += 1;
+                    path.push(id.0);
+                    collect_members(member, ways, relations, path, inner, rings, segments);
+                    path.pop();
+                } else {
+                    skip(rings, "relation not loaded".to_owned());
+                }
+            }
+            OsmId::Node(_) => skip(rings, "node member".to_owned()),
+        }
+    }
+}
+
+/// Resolves member relations from `relations` down to `MAX_RELATION_DEPTH`
+/// and leaves out members that are not part of the area, each with a reason
+/// in `skipped`.
+pub fn relation_rings(
+    rel: &Relation,
+    ways: &HashMap<i64, Way>,
+    relations: &HashMap<i64, Relation>,
+) -> RelationRings {
+    let mut rings = RelationRings {
+        outer: vec![],
+        inner: vec![],
+        unclosed: 0,
+        missing_ways: 0,
+        nested: 0,
+        skipped: BTreeMap::new(),
+    };
+    let mut segments = [vec![], vec![]];
+    collect_members(
+        rel,
+        ways,
+        relations,
+        &mut vec![rel.id.0],
+        false,
+        &mut rings,
+        &mut segments,
+    );
+    let [outer, inner] = segments;
+    let (outer, outer_unclosed) = assemble_rings(outer);
+    let (inner, inner_unclosed) = assemble_rings(inner);
+    rings.outer = outer;
+    rings.inner = inner;
+    rings.unclosed = outer_unclosed + inner_unclosed;
+    rings
+}
+
+/// Relation members left out over a run, summed for the report logged at
+/// the end.
+#[derive(Debug, Default)]
+pub struct MemberReport {
+    pub relations: usize,
+    pub nested: usize,
+    pub missing_ways: usize,
+    pub skipped: BTreeMap<String, usize>,
+}
+
+impl MemberReport {
+    pub fn add(&mut self, rings: &RelationRings) {
+        self.relations += 1;
+        self.nested += rings.nested;
+        self.missing_ways += rings.missing_ways;
+        for (reason, n) in &rings.skipped {
+            *self.skipped.entry(reason.clone()).or_default() += n;
+        }
+    }
+
+    pub fn log(&self) {
+        info!(
+            "Read {} relations, resolved {} nested ones, {} member ways missing",
+            self.relations, self.nested, self.missing_ways
+        );
+        for (reason, n) in &self.skipped {
+            info!("Left out {n} relation members, {reason}");
+        }
+    }
+}
+
+/// Building relations with the ways and nodes they are made of, which the
+/// main load drops because member ways are rarely tagged themselves.
+pub struct BuildingRelations {
+    pub nodes: HashMap<i64, Node>,
+    pub ways: HashMap<i64, Way>,
+    pub relations: Vec<Relation>,
+    /// Every relation read, members of the buildings included.
+    pub all_relations: HashMap<i64, Relation>,
+}
+
+pub fn load_building_relations(filename: &std::ffi::OsStr) -> anyhow::Result<BuildingRelations> {
+    let r = std::fs::File::open(Path::new(filename))?;
+    let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+    let objs =
+        pbf.get_objs_and_deps(|obj| obj.is_relation() && obj.tags().contains_key("building"))?;
+    let mut loaded = BuildingRelations {
+        nodes: HashMap::new(),
+        ways: HashMap::new(),
+        relations: vec![],
+        all_relations: HashMap::new(),
+    };
+    for obj in objs.into_values() {
+        match obj {
+            OsmObj::Node(n) => {
+                loaded.nodes.insert(n.id.0, n);
+            }
+            OsmObj::Way(w) => {
+                loaded.ways.insert(w.id.0, w);
+            }
+            // Relations pulled in as members of another one have no
+            // `building` tag and are not buildings themselves.
+            OsmObj::Relation(r) => {
+                if r.tags.contains_key("building") {
+                    loaded.relations.push(r.clone());
+                }
+                loaded.all_relations.insert(r.id.0, r);
+            }
+        }
+    }
+    loaded.relations.sort_by_key(|r| r.id);
+    Ok(loaded)
+}
+
+/// Builds polygons from rings, giving each inner ring to the first outer
+/// ring that contains it. Rings with unknown nodes are dropped.
+pub fn rings_to_multipolygon(
+    rings: &RelationRings,
+    nodes: &HashMap<i64, Node>,
+) -> MultiPolygon<f64> {
+    let to_coords = |ring: &Vec<i64>| -> Option<Vec<GeoCoordinate>> {
+        ring.iter()
+            .map(|id| nodes.get(id).map(node_coord))
+            .collect()
+    };
+    let mut polygons: Vec<_> = rings
+        .outer
+        .iter()
+        .filter_map(to_coords)
+        .map(|c| Polygon::new(line_string(&c), vec![]))
+        .collect();
+    for hole in rings.inner.iter().filter_map(to_coords) {
+        let hole = line_string(&hole);
+        if let Some(poly) = polygons
+            .iter_mut()
+            .find(|p| Polygon::new(p.exterior().clone(), vec![]).contains(&hole))
+        {
+            poly.interiors_push(hole);
+        }
+    }
+    MultiPolygon::new(polygons)
+}
+
+/// Fills the holes under `min_m2`, such as ventilation shafts, which are
+/// speckle rather than courtyards. Returns how many were filled. Holes
+/// wind either way, hence the signed area: the unsigned one of a clockwise
+/// ring is the rest of the globe.
+pub fn fill_small_holes(area: &mut MultiPolygon<f64>, min_m2: f64) -> usize {
+    let mut filled = 0;
+    for poly in area.0.iter_mut() {
+        let holes = poly.interiors().len();
+        if holes == 0 {
+            continue;
+        }
+        let kept: Vec<_> = poly
+            .interiors()
+            .iter()
+            .filter(|hole| {
+                Polygon::new((*hole).clone(), vec![])
+                    .geodesic_area_signed()
+                    .abs()
+                    >= min_m2
+            })
+            .cloned()
+            .collect();
+        filled += holes - kept.len();
+        *poly = Polygon::new(poly.exterior().clone(), kept);
+    }
+    filled
+}
+
+/// Area in m^2 of the outer rings less their holes. Every ring is measured
+/// on its own, as the rings of a relation wind either way.
+pub fn footprint_area(area: &MultiPolygon<f64>) -> f64 {
+    let ring = |r: &LineString<f64>| Polygon::new(r.clone(), vec![]).geodesic_area_signed().abs();
+    area.0
+        .iter()
+        .map(|p| ring(p.exterior()) - p.interiors().iter().map(ring).sum::<f64>())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmpbfreader::{NodeId, Ref, RelationId, Tags, WayId};
+
+    fn node(id: i64, lat: f64, lon: f64) -> (i64, Node) {
+        let node = Node {
+            id: NodeId(id),
+            tags: Tags::new(),
+            decimicro_lat: (lat * 1e7) as i32,
+            decimicro_lon: (lon * 1e7) as i32,
+        };
+        (id, node)
+    }
+
+    fn way(id: i64, nodes: &[i64]) -> (i64, Way) {
+        let way = Way {
+            id: WayId(id),
+            tags: Tags::new(),
+            nodes: nodes.iter().map(|&n| NodeId(n)).collect(),
+        };
+        (id, way)
+    }
+
+    fn relation(id: i64, members: &[(OsmId, &str)]) -> Relation {
+        Relation {
+            id: RelationId(id),
+            tags: Tags::new(),
+            refs: members
+                .iter()
+                .map(|(member, role)| Ref {
+                    member: *member,
+                    role: (*role).into(),
+                })
+                .collect(),
+        }
+    }
+
+    fn rings(outer: Vec<Vec<i64>>, inner: Vec<Vec<i64>>) -> RelationRings {
+        RelationRings {
+            outer,
+            inner,
+            unclosed: 0,
+            missing_ways: 0,
+            nested: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// Corners of squares, counterclockwise from the bottom left: 1-4 a
+    /// large one, 5-8 one inside it, 9-12 one beside it and 13-16 one
+    /// inside that.
+    fn squares() -> HashMap<i64, Node> {
+        let square = |first: i64, bottom: f64, left: f64, side: f64| {
+            [
+                node(first, bottom, left),
+                node(first + 1, bottom, left + side),
+                node(first + 2, bottom + side, left + side),
+                node(first + 3, bottom + side, left),
+            ]
+        };
+        [
+            square(1, 55.0, 37.0, 0.01),
+            square(5, 55.004, 37.004, 0.001),
+            square(9, 55.0, 37.02, 0.01),
+            square(13, 55.004, 37.024, 0.001),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    #[test]
+    fn join_segments_reverses_segments_to_fit() {
+        let chains = join_segments(vec![vec![1, 2], vec![3, 2], vec![3, 4]], false);
+        assert_eq!(chains.len(), 1);
+        let chain = &chains[0];
+        assert!(
+            chain == &[1, 2, 3, 4] || chain == &[4, 3, 2, 1],
+            "{chain:?}"
+        );
+    }
+
+    #[test]
+    fn join_segments_keeping_direction_leaves_reversed_segments() {
+        let mut chains = join_segments(vec![vec![1, 2], vec![3, 2], vec![2, 5]], true);
+        chains.sort();
+        assert_eq!(chains, vec![vec![1, 2, 5], vec![3, 2]]);
+    }
+
+    #[test]
+    fn join_segments_grows_chains_at_the_start() {
+        let chains = join_segments(vec![vec![2, 3], vec![1, 2]], true);
+        assert_eq!(chains, vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn assemble_rings_counts_unclosed_chains() {
+        let (rings, unclosed) = assemble_rings(vec![
+            vec![1, 2, 3],
+            vec![1, 4, 3],
+            vec![5, 6],
+            vec![6, 7],
+            vec![8, 9, 8],
+        ]);
+        assert_eq!(rings.len(), 1);
+        assert_eq!(rings[0].len(), 5);
+        assert_eq!(rings[0].first(), rings[0].last());
+        // 5-6-7 is open, and 8-9-8 closes without enclosing anything.
+        assert_eq!(unclosed, 2);
+    }
+
+    #[test]
+    fn inner_member_relations_are_inverted() {
+        let ways: HashMap<_, _> = [
+            way(1, &[1, 2, 3, 4, 1]),
+            way(2, &[5, 6, 7, 8, 5]),
+            way(3, &[13, 14, 15, 16, 13]),
+        ]
+        .into_iter()
+        .collect();
+        // The courtyard of 1 is a relation whose own inner ring, 3, is an
+        // island in it.
+        let courtyard = relation(
+            20,
+            &[
+                (OsmId::Way(WayId(2)), "outer"),
+                (OsmId::Way(WayId(3)), "inner"),
+            ],
+        );
+        let relations: HashMap<_, _> = [(20, courtyard)].into_iter().collect();
+        let building = relation(
+            10,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Relation(RelationId(20)), "inner"),
+                (OsmId::Way(WayId(99)), "outer"),
+                (OsmId::Node(NodeId(1)), "label"),
+            ],
+        );
+        let rings = relation_rings(&building, &ways, &relations);
+        assert_eq!(rings.outer.len(), 2);
+        assert!(rings.outer.iter().any(|r| r[0] == 13));
+        assert_eq!(rings.inner.len(), 1);
+        assert_eq!(rings.inner[0][0], 5);
+        assert_eq!(rings.nested, 1);
+        assert_eq!(rings.missing_ways, 1);
+        assert_eq!(rings.skipped.values().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn holes_go_to_the_first_containing_outer() {
+        let nodes = squares();
+        let area = rings_to_multipolygon(
+            &rings(
+                vec![
+                    vec![1, 2, 3, 4, 1],
+                    vec![9, 10, 11, 12, 9],
+                    vec![1, 2, 3, 4, 1],
+                ],
+                vec![vec![5, 6, 7, 8, 5], vec![13, 14, 15, 16, 13]],
+            ),
+            &nodes,
+        );
+        let holes: Vec<_> = area.0.iter().map(|p| p.interiors().len()).collect();
+        assert_eq!(holes, vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn rings_with_unknown_nodes_and_stray_holes_are_dropped() {
+        let nodes = squares();
+        let area = rings_to_multipolygon(
+            &rings(
+                vec![vec![1, 2, 3, 4, 1], vec![9, 10, 99, 12, 9]],
+                vec![vec![13, 14, 15, 16, 13]],
+            ),
+            &nodes,
+        );
+        assert_eq!(area.0.len(), 1);
+        assert!(area.0[0].interiors().is_empty());
+    }
+
+    #[test]
+    fn fill_small_holes_fills_either_winding() {
+        let nodes = squares();
+        let mut area = rings_to_multipolygon(
+            &rings(
+                vec![vec![1, 2, 3, 4, 1], vec![9, 10, 11, 12, 9]],
+                vec![vec![5, 6, 7, 8, 5], vec![13, 16, 15, 14, 13]],
+            ),
+            &nodes,
+        );
+        // The holes are about 7000 m² each.
+        assert_eq!(fill_small_holes(&mut area, 1000.0), 0);
+        assert_eq!(fill_small_holes(&mut area, 10_000.0), 2);
+        assert!(area.0.iter().all(|p| p.interiors().is_empty()));
+    }
+}
@@ -0,0 +1,136 @@
+//! Low-zoom raster of building density over the area of interest, to help
+//! pick sampling strategies before committing to a full run.
+
+use std::{collections::HashMap, io::Write, path::Path};
+
+use geo::{Centroid, Coord, GeodesicArea, LineString, Point, Polygon};
+use image::{GrayImage, Luma};
+use log::info;
+use osmpbfreader::Way;
+use slippy_map_tiles::{BBox, Tile};
+
+use crate::{way_coords, OsmData};
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    buildings: u64,
+    /// Sum of footprint areas, m^2.
+    building_area: f64,
+}
+
+fn tile_area_m2(tile: Tile) -> f64 {
+    let mercator::TileBounds {
+        north: top,
+        south: bottom,
+        west: left,
+        east: right,
+    } = mercator::bounds(tile);
+    let ring = vec![
+        Coord { x: left, y: top },
+        Coord { x: right, y: top },
+        Coord {
+            x: right,
+            y: bottom,
+        },
+        Coord { x: left, y: bottom },
+        Coord { x: left, y: top },
+    ];
+    Polygon::new(LineString::new(ring), vec![])
+        .geodesic_area_signed()
+        .abs()
+}
+
+/// Footprint of a building way and its centroid.
+fn footprint(way: &Way, osm: &OsmData) -> Option<(Polygon, Point)> {
+    let coords = way_coords(way, &osm.nodes_all)?;
+    if coords.len() < 3 {
+        return None;
+    }
+    let poly = Polygon::new(
+        LineString::new(coords.iter().map(|v| (*v).into()).collect()),
+        vec![],
+    );
+    let center = poly.centroid()?;
+    Some((poly, center))
+}
+
+/// Tile at `zoom`, as `(x, y)`, containing the centroid of every building
+/// way, keyed by way id.
+pub fn building_cells(osm: &OsmData, zoom: u8) -> HashMap<i64, (u32, u32)> {
+    osm.ways_buildings
+        .values()
+        .filter_map(|way| {
+            let (_, center) = footprint(way, osm)?;
+            let cell = mercator::tile_at(center.y(), center.x(), zoom);
+            Some((way.id.0, cell))
+        })
+        .collect()
+}
+
+/// Writes `density.png` (buildings per km^2, scaled to the densest cell),
+/// `coverage.png` (fraction of the cell covered by footprints) and
+/// `heatmap.csv` with the raw numbers. Every pixel is one tile at `zoom`,
+/// and every building is counted in the tile containing its centroid.
+pub fn export_heatmap(osm: &OsmData, bbox: &BBox, zoom: u8, out_dir: &Path) -> anyhow::Result<()> {
+    let (x0, y0) = mercator::tile_at(bbox.top() as f64, bbox.left() as f64, zoom);
+    let (x1, y1) = mercator::tile_at(bbox.bottom() as f64, bbox.right() as f64, zoom);
+    let (width, height) = (x1 - x0 + 1, y1 - y0 + 1);
+    info!("Heatmap is {width}x{height} tiles at zoom {zoom}");
+
+    let mut cells = vec![Cell::default(); (width * height) as usize];
+    for way in osm.ways_buildings.values() {
+        let Some((poly, center)) = footprint(way, osm) else {
+            continue;
+        };
+        let (x, y) = mercator::tile_at(center.y(), center.x(), zoom);
+        if !(x0..=x1).contains(&x) || !(y0..=y1).contains(&y) {
+            continue;
+        }
+        let cell = &mut cells[((y - y0) * width + (x - x0)) as usize];
+        cell.buildings += 1;
+        cell.building_area += poly.geodesic_area_signed().abs();
+    }
+
+    std::fs::create_dir_all(out_dir)?;
+    let mut csv = std::io::BufWriter::new(std::fs::File::create(out_dir.join("heatmap.csv"))?);
+    writeln!(csv, "z,x,y,buildings,buildings_per_km2,building_fraction")?;
+
+    let mut density = vec![0.0; cells.len()];
+    let mut coverage = GrayImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let i = (y * width + x) as usize;
+            let tile = Tile::new(zoom, x0 + x, y0 + y).unwrap();
+            let area = tile_area_m2(tile);
+            let cell = cells[i];
+            density[i] = cell.buildings as f64 / (area / 1_000_000.0);
+            let fraction = (cell.building_area / area).min(1.0);
+            coverage.put_pixel(x, y, Luma([(fraction * 255.0).round() as u8]));
+            writeln!(
+                csv,
+                "{zoom},{},{},{},{:.3},{:.6}",
+                tile.x(),
+                tile.y(),
+                cell.buildings,
+                density[i],
+                fraction
+            )?;
+        }
+    }
+    csv.flush()?;
+
+    let max_density = density.iter().cloned().fold(0.0, f64::max);
+    let density_img = GrayImage::from_fn(width, height, |x, y| {
+        let d = density[(y * width + x) as usize];
+        if max_density > 0.0 {
+            Luma([(d / max_density * 255.0).round() as u8])
+        } else {
+            Luma([0])
+        }
+    });
+    density_img.save(out_dir.join("density.png"))?;
+    coverage.save(out_dir.join("coverage.png"))?;
+    info!("Densest cell: {max_density:.1} buildings/km^2");
+
+    Ok(())
+}
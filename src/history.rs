@@ -0,0 +1,105 @@
+//! Reconstructs the map as of a past date from a full-history extract, so
+//! that labels for two dates can be rendered over the same imagery.
+
+use std::collections::HashMap;
+
+use osmpbfreader::{groups, OsmId, OsmObj};
+
+use crate::{logging, nodes, OsmData, ProgressFile};
+
+/// Metadata of one version of an object.
+#[derive(Clone, Copy)]
+struct Version {
+    version: i32,
+    /// Seconds since the Unix epoch.
+    timestamp: i64,
+    visible: bool,
+}
+
+/// Parses `YYYY-MM-DD` into seconds since the Unix epoch at midnight UTC.
+pub fn parse_date(date: &str) -> anyhow::Result<i64> {
+    let mut parts = date.splitn(3, '-').map(str::parse::<i64>);
+    let (Some(Ok(y)), Some(Ok(m)), Some(Ok(d))) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("Bad date {date:?}, expected YYYY-MM-DD");
+    };
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        anyhow::bail!("Bad date {date:?}, expected YYYY-MM-DD");
+    }
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Ok((era * 146097 + doe - 719468) * 86400)
+}
+
+/// Loads the state of a full-history extract at `at` (seconds since the
+/// epoch): for every object the newest version no later than `at`, with
+/// objects whose newest such version is a deletion left out.
+pub fn load_snapshot(filename: &std::ffi::OsStr, at: i64) -> anyhow::Result<OsmData> {
+    let r = std::fs::File::open(filename)?;
+    let len = r.metadata()?.len();
+    let mut pbf = osmpbfreader::OsmPbfReader::new(ProgressFile::new(r, len));
+
+    let mut latest: HashMap<OsmId, (Version, OsmObj)> = HashMap::new();
+    let mut versions = 0usize;
+    for block in pbf.primitive_blocks() {
+        let block = block?;
+        let granularity = block.get_date_granularity() as i64;
+        for group in block.get_primitivegroup() {
+            let mut objs: Vec<(Version, OsmObj)> = vec![];
+            for (n, info) in groups::simple_nodes(group, &block).zip(group.get_nodes()) {
+                objs.push((version(info.get_info(), granularity), n.into()));
+            }
+            let dense = group.get_dense().get_denseinfo();
+            let mut timestamp = 0;
+            for (i, n) in groups::dense_nodes(group, &block).enumerate() {
+                timestamp += dense.get_timestamp().get(i).copied().unwrap_or(0);
+                let v = Version {
+                    version: dense.get_version().get(i).copied().unwrap_or(0),
+                    timestamp: timestamp * granularity / 1000,
+                    visible: dense.get_visible().get(i).copied().unwrap_or(true),
+                };
+                objs.push((v, n.into()));
+            }
+            for (w, info) in groups::ways(group, &block).zip(group.get_ways()) {
+                objs.push((version(info.get_info(), granularity), w.into()));
+            }
+            for (r, info) in groups::relations(group, &block).zip(group.get_relations()) {
+                objs.push((version(info.get_info(), granularity), r.into()));
+            }
+            versions += objs.len();
+            for (v, obj) in objs {
+                if v.timestamp > at {
+                    continue;
+                }
+                match latest.get(&obj.id()) {
+                    Some((old, _)) if old.version > v.version => {}
+                    _ => {
+                        latest.insert(obj.id(), (v, obj));
+                    }
+                }
+            }
+        }
+    }
+
+    let alive: Vec<_> = latest
+        .into_values()
+        .filter(|(v, _)| v.visible)
+        .map(|(_, obj)| obj)
+        .collect();
+    logging::status!(
+        "{versions} object versions, {} alive at snapshot",
+        alive.len()
+    );
+    OsmData::from_objs(alive, nodes::Builder::default())
+}
+
+fn version(info: &osmpbfreader::osmformat::Info, granularity: i64) -> Version {
+    Version {
+        version: info.get_version(),
+        timestamp: info.get_timestamp() * granularity / 1000,
+        visible: !info.has_visible() || info.get_visible(),
+    }
+}
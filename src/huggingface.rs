@@ -0,0 +1,203 @@
+//! Stitched chips laid out for the Hugging Face Hub: one `imagefolder`
+//! directory per split with a `metadata.parquet` index, and a `README.md`
+//! whose front matter declares the splits and features, so that uploading
+//! the directory publishes a dataset `load_dataset` reads as it is.
+
+use std::{collections::BTreeMap, fmt::Write as _, path::PathBuf, sync::Arc};
+
+use log::info;
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, DoubleType, Int32Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+
+use crate::{
+    chips::{self, Extent},
+    classes::colors,
+    formats::Formats,
+    list_tiles, paths,
+    release::link_or_copy,
+    split::{SplitRatios, SPLITS},
+    workspace::{OUTLINES, STITCHED, TILES},
+};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct HfOptions {
+    /// Directory with `tiles/` and `outlines/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    #[arg(long, default_value = "hf-dataset")]
+    pub out: PathBuf,
+    #[command(flatten)]
+    pub ratios: SplitRatios,
+}
+
+const SCHEMA: &str = "
+message chip {
+    REQUIRED BYTE_ARRAY file_name (UTF8);
+    REQUIRED BYTE_ARRAY mask_file_name (UTF8);
+    REQUIRED BYTE_ARRAY key (UTF8);
+    REQUIRED INT32 x;
+    REQUIRED INT32 y;
+    REQUIRED DOUBLE north;
+    REQUIRED DOUBLE south;
+    REQUIRED DOUBLE east;
+    REQUIRED DOUBLE west;
+}";
+
+/// Writes the index of one split. Paths are relative to the index, which
+/// is how `imagefolder` resolves `file_name` and `*_file_name` columns.
+fn write_index(path: &std::path::Path, chips: &[Extent], ext: &str) -> anyhow::Result<()> {
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let props = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(std::fs::File::create(path)?, schema, props)?;
+    let mut group = writer.next_row_group()?;
+    let strings: [Vec<ByteArray>; 3] = [
+        chips
+            .iter()
+            .map(|c| format!("{}{ext}", c.key).into_bytes().into())
+            .collect(),
+        chips
+            .iter()
+            .map(|c| format!("masks/{}.png", c.key).into_bytes().into())
+            .collect(),
+        chips.iter().map(|c| c.key.as_str().into()).collect(),
+    ];
+    let ints: [Vec<i32>; 2] = [
+        chips.iter().map(|c| c.x as i32).collect(),
+        chips.iter().map(|c| c.y as i32).collect(),
+    ];
+    let doubles: [Vec<f64>; 4] = [
+        chips.iter().map(|c| c.north).collect(),
+        chips.iter().map(|c| c.south).collect(),
+        chips.iter().map(|c| c.east).collect(),
+        chips.iter().map(|c| c.west).collect(),
+    ];
+    // Columns come in schema order.
+    for values in &strings {
+        let mut column = group.next_column()?.unwrap();
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(values, None, None)?;
+        column.close()?;
+    }
+    for values in &ints {
+        let mut column = group.next_column()?.unwrap();
+        column
+            .typed::<Int32Type>()
+            .write_batch(values, None, None)?;
+        column.close()?;
+    }
+    for values in &doubles {
+        let mut column = group.next_column()?.unwrap();
+        column
+            .typed::<DoubleType>()
+            .write_batch(values, None, None)?;
+        column.close()?;
+    }
+    group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// The card with the front matter the Hub reads and sections to fill in.
+fn card(counts: &BTreeMap<&str, usize>, ext: &str) -> String {
+    let mut s = String::from(
+        "---\n\
+         task_categories:\n- image-segmentation\n\
+         tags:\n- aerial-imagery\n- buildings\n- openstreetmap\n\
+         license: other\n\
+         configs:\n- config_name: default\n  data_files:\n",
+    );
+    for split in SPLITS.iter().filter(|s| counts.contains_key(**s)) {
+        writeln!(
+            s,
+            "  - split: {split}\n    path:\n    - data/{split}/*{ext}\n    - data/{split}/metadata.parquet"
+        )
+        .unwrap();
+    }
+    s.push_str(
+        "dataset_info:\n  features:\n  \
+         - name: image\n    dtype: image\n  \
+         - name: mask\n    dtype: image\n  \
+         - name: key\n    dtype: string\n  \
+         - name: x\n    dtype: int32\n  \
+         - name: y\n    dtype: int32\n",
+    );
+    for bound in ["north", "south", "east", "west"] {
+        writeln!(s, "  - name: {bound}\n    dtype: float64").unwrap();
+    }
+    s.push_str("  splits:\n");
+    for split in SPLITS {
+        if let Some(n) = counts.get(split) {
+            writeln!(s, "  - name: {split}\n    num_examples: {n}").unwrap();
+        }
+    }
+    s.push_str(
+        "---\n\n# Building segmentation from aerial imagery\n\n\
+         Aerial imagery chips with building outlines rasterized from \
+         OpenStreetMap.\n\n\
+         ## Fields\n\n\
+         - `image`: RGB chip.\n\
+         - `mask`: RGB label image, one color per class.\n\
+         - `key`: `{y}-{x}` of the top-left tile of the chip.\n\
+         - `north`, `south`, `east`, `west`: extent in degrees.\n\n\
+         ## Classes\n\n\
+         | index | color |\n|---|---|\n",
+    );
+    for (i, [r, g, b]) in colors().iter().enumerate() {
+        writeln!(s, "| {i} | `#{r:02x}{g:02x}{b:02x}` |").unwrap();
+    }
+    s.push_str(
+        "\n## Splits\n\n\
+         Assigned by area, so that neighbouring chips share a split.\n\n\
+         ## License\n\n\
+         Labels are derived from OpenStreetMap data, (c) OpenStreetMap \
+         contributors, ODbL. Fill in the terms of the imagery.\n",
+    );
+    s
+}
+
+pub fn export_hf_dataset(opts: &HfOptions) -> anyhow::Result<()> {
+    opts.ratios.check()?;
+    let tile_px = chips::tile_px();
+    let ext = Formats::load()?.chips.ext();
+    let mut names = list_tiles(opts.chips.join(TILES), ext);
+    names.sort_by_key(|t| (t.y(), t.x()));
+
+    // The directory is generated as a whole; a split that got smaller must
+    // not keep the chips it lost.
+    let data = opts.out.join("data");
+    if data.exists() {
+        std::fs::remove_dir_all(&data)?;
+    }
+    std::fs::create_dir_all(&opts.out)?;
+    let mut splits: BTreeMap<&str, Vec<Extent>> = BTreeMap::new();
+    for chip in names {
+        let image = paths::tile_file(opts.chips.join(TILES), chip, ext);
+        let mask = paths::tile_file(opts.chips.join(OUTLINES), chip, ".png");
+        if !mask.is_file() {
+            continue;
+        }
+        let split = opts.ratios.split_of(chip);
+        let dir = data.join(split);
+        std::fs::create_dir_all(dir.join("masks"))?;
+        // The imagefolder layout is fixed, whatever `tile_paths` says.
+        let name = paths::stem(chip);
+        link_or_copy(&image, &dir.join(format!("{name}{ext}")))?;
+        link_or_copy(&mask, &dir.join("masks").join(format!("{name}.png")))?;
+        let (w, h) = image::image_dimensions(&image)?;
+        splits
+            .entry(split)
+            .or_default()
+            .push(chips::extent(chip, w, h, tile_px));
+    }
+    for (split, chips) in &splits {
+        write_index(&data.join(split).join("metadata.parquet"), chips, ext)?;
+    }
+    let counts: BTreeMap<_, _> = splits.iter().map(|(s, c)| (*s, c.len())).collect();
+    std::fs::write(opts.out.join("README.md"), card(&counts, ext))?;
+    info!("Wrote {counts:?} chips to {}", opts.out.display());
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! Inverted index from tiles to the OSM objects drawn into them, written
+//! next to `outlines/` so that tools can look up what is in a tile without
+//! touching the extract.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::Path,
+};
+
+use osmpbfreader::OsmId;
+use serde::{Deserialize, Serialize};
+use slippy_map_tiles::Tile;
+
+use crate::logging;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Objects per tile. Tiles are keyed `{y}-{x}` like their images and
+/// objects are written `n123`, `w123` or `r123`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TileIndex {
+    pub tiles: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TileIndex {
+    /// Loads the index under `dir`, so that a rerun over existing outlines
+    /// extends it instead of starting over.
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(INDEX_FILE);
+        match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                logging::status!("Ignoring unreadable index {}: {e}", path.display());
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        let path = dir.join(INDEX_FILE);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, id: OsmId, tiles: HashSet<Tile>) {
+        for tile in tiles {
+            self.tiles
+                .entry(tile_key(tile))
+                .or_default()
+                .insert(object_key(id));
+        }
+    }
+}
+
+fn tile_key(tile: Tile) -> String {
+    format!("{}-{}", tile.y(), tile.x())
+}
+
+pub fn object_key(id: OsmId) -> String {
+    match id {
+        OsmId::Node(id) => format!("n{}", id.0),
+        OsmId::Way(id) => format!("w{}", id.0),
+        OsmId::Relation(id) => format!("r{}", id.0),
+    }
+}
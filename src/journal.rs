@@ -0,0 +1,138 @@
+//! Progress of `render-outlines` without work units, which draws the whole
+//! area in one pass and saves every `DRAW_CHUNK` buildings. After each save
+//! the objects drawn so far are appended to `render-journal.jsonl` with the
+//! tiles they drew into, so that a rerun after a crash skips them and only
+//! draws, and saves, what is left. The journal is removed when a run
+//! finishes; delete it to start over with other options.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use osmpbfreader::OsmId;
+use serde::{Deserialize, Serialize};
+use slippy_map_tiles::Tile;
+
+use crate::{index::object_key, logging, zoom};
+
+const JOURNAL_FILE: &str = "render-journal.jsonl";
+
+/// The passes an object is drawn in, journaled apart as a building is
+/// drawn in both with `--ignore-small`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    Draw,
+    Ignore,
+}
+
+/// Tiles drawn into, as `[x, y]` at the tile zoom.
+type Tiles = Vec<(u32, u32)>;
+
+/// One line of the journal: what was drawn between two saves.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    drawn: BTreeMap<String, Tiles>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    ignored: BTreeMap<String, Tiles>,
+}
+
+impl Entry {
+    fn pass(&self, pass: Pass) -> &BTreeMap<String, Tiles> {
+        match pass {
+            Pass::Draw => &self.drawn,
+            Pass::Ignore => &self.ignored,
+        }
+    }
+
+    fn pass_mut(&mut self, pass: Pass) -> &mut BTreeMap<String, Tiles> {
+        match pass {
+            Pass::Draw => &mut self.drawn,
+            Pass::Ignore => &mut self.ignored,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    /// Everything saved by earlier runs and this one.
+    saved: Entry,
+    /// Drawn since the last save, not in the outlines on disk yet.
+    pending: Entry,
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal in `out_dir`, ignoring a torn last line from a
+    /// crash.
+    pub fn open(out_dir: &Path) -> anyhow::Result<Self> {
+        let path = out_dir.join(JOURNAL_FILE);
+        let mut saved = Entry::default();
+        if let Ok(f) = File::open(&path) {
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                match serde_json::from_str::<Entry>(&line) {
+                    Ok(entry) => {
+                        saved.drawn.extend(entry.drawn);
+                        saved.ignored.extend(entry.ignored);
+                    }
+                    Err(e) => logging::status!("Ignoring journal line {line:?}: {e}"),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            saved,
+            pending: Entry::default(),
+            file,
+        })
+    }
+
+    /// Whether an earlier run left objects drawn.
+    pub fn resuming(&self) -> bool {
+        !self.saved.drawn.is_empty() || !self.saved.ignored.is_empty()
+    }
+
+    /// The tiles `id` drew into in `pass`, if an earlier save has it.
+    pub fn saved(&self, pass: Pass, id: OsmId) -> Option<HashSet<Tile>> {
+        let tiles = self.saved.pass(pass).get(&object_key(id))?;
+        Some(
+            tiles
+                .iter()
+                .filter_map(|&(x, y)| Tile::new(zoom(), x, y))
+                .collect(),
+        )
+    }
+
+    /// Notes that `id` drew into `tiles`, to be journaled with the next
+    /// save.
+    pub fn drawn(&mut self, pass: Pass, id: OsmId, tiles: &HashSet<Tile>) {
+        let mut tiles: Tiles = tiles.iter().map(|t| (t.x(), t.y())).collect();
+        tiles.sort_unstable();
+        self.pending.pass_mut(pass).insert(object_key(id), tiles);
+    }
+
+    /// Journals what was drawn since the last call, once it is saved.
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        let entry = std::mem::take(&mut self.pending);
+        if entry.drawn.is_empty() && entry.ignored.is_empty() {
+            return Ok(());
+        }
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)?;
+        self.file.sync_data()?;
+        self.saved.drawn.extend(entry.drawn);
+        self.saved.ignored.extend(entry.ignored);
+        Ok(())
+    }
+
+    /// Removes the journal of a finished run.
+    pub fn finish(self) -> anyhow::Result<()> {
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Coastlines and administrative boundaries, rasterized as thin lines into
+//! an auxiliary channel for models that take them as conditioning input.
+
+use std::collections::HashMap;
+
+use log::info;
+use osmpbfreader::{OsmId, OsmObj};
+
+use crate::{geometry, GeoCoordinate};
+
+pub const CHANNEL: &str = "lines";
+pub const COASTLINE: u8 = 1;
+pub const ADMIN_BOUNDARY: u8 = 2;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct LineOptions {
+    /// Draw `natural=coastline` ways into the `lines/` channel.
+    #[arg(long)]
+    pub coastline_lines: bool,
+    /// Draw administrative boundaries of these `admin_level`s into the
+    /// `lines/` channel, e.g. `--admin-lines 4,8`.
+    #[arg(long, value_delimiter = ',')]
+    pub admin_lines: Vec<String>,
+    /// Width of the drawn lines, meters.
+    #[arg(long, default_value_t = 2.0)]
+    pub line_width: f64,
+}
+
+impl LineOptions {
+    pub fn enabled(&self) -> bool {
+        self.coastline_lines || !self.admin_lines.is_empty()
+    }
+}
+
+pub struct LineFeature {
+    pub value: u8,
+    pub coords: Vec<GeoCoordinate>,
+    /// Draw only on the left side of the line. OSM coastlines have land on
+    /// the left, so this keeps the line on the shore instead of straddling
+    /// it.
+    pub left_only: bool,
+}
+
+/// Reads the requested line features in a pass of their own. Coastline
+/// ways are joined head to tail into long chains first, which keeps their
+/// direction intact and avoids gaps where the extract split them.
+pub fn load_lines(filename: &std::ffi::OsStr, opts: &LineOptions) -> Vec<LineFeature> {
+    if !opts.enabled() {
+        return vec![];
+    }
+    let r = std::fs::File::open(std::path::Path::new(filename)).unwrap();
+    let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+    let objs = pbf
+        .get_objs_and_deps(|obj| match obj {
+            OsmObj::Way(w) => opts.coastline_lines && w.tags.contains("natural", "coastline"),
+            OsmObj::Relation(r) => {
+                r.tags.contains("boundary", "administrative")
+                    && opts
+                        .admin_lines
+                        .iter()
+                        .any(|l| r.tags.contains("admin_level", l))
+            }
+            OsmObj::Node(_) => false,
+        })
+        .unwrap();
+
+    let mut nodes = HashMap::new();
+    let mut ways = HashMap::new();
+    let mut relations = vec![];
+    for obj in objs.into_values() {
+        match obj {
+            OsmObj::Node(n) => {
+                nodes.insert(n.id.0, n);
+            }
+            OsmObj::Way(w) => {
+                ways.insert(w.id.0, w);
+            }
+            OsmObj::Relation(r) => relations.push(r),
+        }
+    }
+    let to_coords = |ids: &[i64]| -> Option<Vec<GeoCoordinate>> {
+        ids.iter()
+            .map(|id| nodes.get(id).map(geometry::node_coord))
+            .collect()
+    };
+
+    let mut features = vec![];
+    let coastline: Vec<Vec<i64>> = ways
+        .values()
+        .filter(|w| w.tags.contains("natural", "coastline"))
+        .map(|w| w.nodes.iter().map(|n| n.0).collect())
+        .collect();
+    let coastline = geometry::join_segments(coastline, true);
+    info!("Coastline: {} chains", coastline.len());
+    for chain in coastline {
+        if let Some(coords) = to_coords(&chain) {
+            features.push(LineFeature {
+                value: COASTLINE,
+                coords,
+                left_only: true,
+            });
+        }
+    }
+
+    let mut boundary_ways: Vec<_> = relations
+        .iter()
+        .filter(|r| r.tags.contains("boundary", "administrative"))
+        .flat_map(|r| r.refs.iter())
+        .filter_map(|r| match r.member {
+            OsmId::Way(id) => Some(id.0),
+            _ => None,
+        })
+        .collect();
+    // Neighbouring districts share their border ways.
+    boundary_ways.sort();
+    boundary_ways.dedup();
+    info!("Administrative boundaries: {} ways", boundary_ways.len());
+    for id in boundary_ways {
+        let Some(way) = ways.get(&id) else {
+            continue;
+        };
+        let ids: Vec<_> = way.nodes.iter().map(|n| n.0).collect();
+        if let Some(coords) = to_coords(&ids) {
+            features.push(LineFeature {
+                value: ADMIN_BOUNDARY,
+                coords,
+                left_only: false,
+            });
+        }
+    }
+    features
+}
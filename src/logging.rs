@@ -0,0 +1,135 @@
+//! Command line control over logging, so nobody has to know about
+//! `RUST_LOG`. Level overrides take env_logger module prefixes; the
+//! downloader logs as `download`, the rasterizer as `render`, everything
+//! else under `map_segmentation_gendata::<module>` or its crate name.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::LevelFilter;
+use serde::Serialize;
+
+pub const DOWNLOAD: &str = "download";
+pub const RENDER: &str = "render";
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct LogOptions {
+    /// Log more: `-v` for progress, `-vv` for debugging, `-vvv` for
+    /// everything.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Do not log anything, not even errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Also append the log to this file, as JSON lines with `ts`, `level`,
+    /// `target` and `msg`.
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+    /// Level of one module, such as `download=debug,render=info`.
+    #[arg(long, global = true, value_name = "MODULE=LEVEL", value_delimiter = ',', value_parser = parse_override)]
+    pub log_level: Vec<(String, LevelFilter)>,
+}
+
+fn parse_override(s: &str) -> Result<(String, LevelFilter), String> {
+    let (module, level) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected MODULE=LEVEL, got {s:?}"))?;
+    let level = level
+        .parse()
+        .map_err(|_| format!("unknown log level {level:?}"))?;
+    Ok((module.to_string(), level))
+}
+
+/// A line of `--log-file`.
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    ts: String,
+    level: &'a str,
+    target: &'a str,
+    msg: String,
+}
+
+/// Whether `--quiet` was given, which also silences the progress and
+/// statistics printed rather than logged.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `println!` unless `--quiet`, for progress and what a command did, as
+/// opposed to what a command is run to print, such as `stats`.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::logging::quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use status;
+
+/// A progress bar of `len` steps drawn in `style`, hidden under `--quiet`.
+pub fn progress_bar(len: u64, style: ProgressStyle) -> ProgressBar {
+    let target = match quiet() {
+        true => ProgressDrawTarget::hidden(),
+        false => ProgressDrawTarget::stderr(),
+    };
+    ProgressBar::with_draw_target(Some(len), target).with_style(style)
+}
+
+/// Installs the logger. `RUST_LOG` still works, the flags take precedence
+/// over it.
+pub fn init(opts: &LogOptions) -> anyhow::Result<()> {
+    QUIET.store(opts.quiet, Ordering::Relaxed);
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Error);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let level = match (opts.quiet, opts.verbose) {
+        (true, _) => Some(LevelFilter::Off),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Info),
+        (false, 2) => Some(LevelFilter::Debug),
+        (false, _) => Some(LevelFilter::Trace),
+    };
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    for (module, level) in &opts.log_level {
+        builder.filter_module(module, *level);
+    }
+    if let Some(path) = &opts.log_file {
+        // Every record goes to stderr as text and to the file as JSON.
+        let file = Mutex::new(File::options().create(true).append(true).open(path)?);
+        builder.format(move |buf, record| {
+            let ts = buf.timestamp().to_string();
+            let line = JsonLine {
+                ts: ts.clone(),
+                level: record.level().as_str(),
+                target: record.target(),
+                msg: record.args().to_string(),
+            };
+            let mut file = file.lock().unwrap();
+            serde_json::to_writer(&mut *file, &line)?;
+            writeln!(file)?;
+            writeln!(
+                buf,
+                "[{ts} {:<5} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        });
+    }
+    builder.try_init()?;
+    Ok(())
+}
@@ -1,18 +1,619 @@
 use std::{
-    collections::{HashMap, HashSet},
-    ffi::OsString,
-    io::{BufReader, BufWriter, Cursor},
-    str::FromStr,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::Cursor,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        mpsc::sync_channel,
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    thread::ThreadId,
 };
 
-use geo::{Coord, GeodesicArea, LineString, Polygon};
-use image::{DynamicImage, ImageBuffer};
+use clap::{CommandFactory, Parser, Subcommand};
+use classes::{colors, ClassOptions, FeatureClass, Shape};
+use geo::{Coord, Intersects, LineString, MultiPolygon, Polygon, Rect};
+use image::{GrayImage, ImageBuffer};
 use imageproc::point::Point;
-use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
+use indicatif::{ProgressIterator, ProgressStyle};
+use journal::Pass;
 use log::{debug, info, warn};
 use osmpbfreader::{Node, Relation, Way};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use slippy_map_tiles::{lat_lon_to_tile, zorder_to_xy, BBox, LatLon, Tile};
+use slippy_map_tiles::{BBox, Tile};
+use timing::Stage;
+
+mod addresses;
+mod adjacency;
+mod artifacts;
+mod attributes;
+mod augment;
+mod calibrate;
+mod centernet;
+mod changes;
+mod checkpoint;
+mod checks;
+mod chips;
+mod classes;
+mod coco;
+mod crops;
+mod dedup;
+mod diff;
+mod districts;
+mod duplicates;
+mod formats;
+mod geojson;
+mod geometry;
+mod heatmap;
+mod history;
+mod huggingface;
+mod index;
+mod journal;
+mod lines;
+mod logging;
+mod manifest;
+mod maproulette;
+mod masks;
+mod memory;
+mod metadata;
+mod metrics;
+mod nodes;
+mod noise;
+mod onetile;
+mod oriented;
+mod outcome;
+mod parts;
+mod paths;
+mod postprocess;
+mod prefetch;
+mod provider;
+mod rawtiles;
+mod recipe;
+mod region;
+mod release;
+mod repro;
+mod rings;
+mod rng;
+mod rules;
+mod sampler;
+mod selftest;
+mod service;
+mod split;
+mod store;
+mod subset;
+mod tags;
+mod tilecache;
+mod timing;
+mod units;
+mod webdataset;
+mod worklist;
+mod workspace;
+
+#[global_allocator]
+static ALLOC: memory::CountingAlloc = memory::CountingAlloc;
+
+/// Building segmentation datasets from OpenStreetMap and satellite imagery.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// OSM extract to read buildings from.
+    #[arg(long)]
+    pbf: Option<PathBuf>,
+    /// Keep the coordinates of the nodes of the extract in this file
+    /// instead of in memory, for extracts of a country or more. The file
+    /// is written anew on every load, and needs an extract sorted by id,
+    /// as `osmium sort` leaves it.
+    #[arg(long, global = true, value_name = "FILE")]
+    node_store: Option<PathBuf>,
+    /// Overlap, as intersection over union, from which two building ways
+    /// are the same building mapped twice and only one is kept: the one
+    /// with a `building=*` value other than `yes`, else the one with more
+    /// tags, else the older one. Building nodes inside a building way are
+    /// dropped as well.
+    #[arg(long, global = true, value_name = "IOU", default_value_t = 0.9)]
+    duplicate_iou: f64,
+    /// Keep buildings mapped twice, as two buildings.
+    #[arg(long, global = true)]
+    keep_duplicates: bool,
+    /// Draw the buildings modeled in parts, with a `type=building`
+    /// relation, as their outline or as their parts. Parts carry the tags
+    /// of their outline under their own; `building:part` ways outside such
+    /// a relation are left out either way.
+    #[arg(long, global = true, value_enum, default_value_t = parts::Parts::Outline)]
+    building_parts: parts::Parts,
+    /// Zoom of the imagery tiles and of everything rendered onto them; 17
+    /// is where one pixel is about a meter.
+    #[arg(long, default_value_t = DEFAULT_ZOOM, value_parser = clap::value_parser!(u8).range(1..=MAX_ZOOM as i64))]
+    zoom: u8,
+    /// Area of interest as `TOP,LEFT,BOTTOM,RIGHT` in degrees, all of
+    /// Moscow by default.
+    #[arg(long, value_name = "TOP,LEFT,BOTTOM,RIGHT", value_parser = checks::parse_bbox)]
+    bbox: Option<BBox>,
+    /// Work only on the tiles listed in this file, as `z/x/y` per line or
+    /// CSV columns `z,x,y`, instead of all of the area of interest, which
+    /// defaults to their bounding box. Blank lines and `#` comments are
+    /// skipped, and a tile of a lower zoom than `--zoom` stands for all of
+    /// its tiles at `--zoom`.
+    #[arg(long, global = true, value_name = "FILE")]
+    tile_list: Option<PathBuf>,
+    /// Name of the area of interest, for `{aoi}` in the `tile_paths` of
+    /// `formats.json`.
+    #[arg(long, global = true, default_value = "moscow")]
+    aoi: String,
+    /// Build the dataset in DIR, created if need be, instead of the
+    /// working directory; relative paths, `--pbf` among them, are taken
+    /// from there. Imagery goes to `tiles/`, masks to `outlines/`, chips
+    /// to `stitched/` and the list of image and mask pairs to
+    /// `dataset.json`.
+    #[arg(long, global = true, value_name = "DIR")]
+    workspace: Option<PathBuf>,
+    /// Treat the dataset in the working directory, and the `--pbf`, as
+    /// read-only, e.g. a mounted snapshot: only exporters run, their
+    /// outputs have to go elsewhere, and no PBF checkpoint is written.
+    #[arg(long, global = true)]
+    read_only: bool,
+    /// Imagery provider to download tiles from, see `providers.toml`.
+    /// Outlines are drawn with its offset from `providers.json`.
+    #[arg(long, global = true, default_value = provider::DEFAULT_PROVIDER)]
+    provider: String,
+    #[command(flatten)]
+    log: logging::LogOptions,
+    /// Also write the time per stage as folded stacks, for flamegraph
+    /// tools like `inferno-flamegraph FILE > flame.svg`.
+    #[arg(long, global = true, value_name = "FILE")]
+    timings: Option<PathBuf>,
+    #[command(flatten)]
+    metrics: metrics::MetricsOptions,
+    /// Map tags to classes, and classes to colors, by the rules in this
+    /// TOML file instead of the built-in ones.
+    ///
+    /// Each `[[rule]]` has a `when`
+    /// expression of `key=value`, `key!=value`, `key=*` or `key` terms
+    /// joined by `AND`, `OR`, `NOT` and parentheses, the `class` it draws,
+    /// by name or index, and optionally its `color` as `[r, g, b]`. The
+    /// first matching rule wins; objects no rule matches are left out.
+    #[arg(long, global = true, value_name = "FILE")]
+    class_rules: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Count building nodes, ways and relations in the extract, and
+    /// building ways per class.
+    Stats {
+        #[command(flatten)]
+        classes: ClassOptions,
+    },
+    /// Count the `building=*` values of the building ways in the area of
+    /// interest, the class each becomes and the tags that come with it,
+    /// to design the class mapping before rendering.
+    Tags {
+        #[command(flatten)]
+        opts: tags::TagOptions,
+    },
+    /// Download imagery for the area of interest into `tiles/`, through
+    /// the blob store if `dedup-tiles` made one.
+    DownloadTiles {
+        /// Ask the server whether tiles fetched more than this many days
+        /// ago changed, with the ETag and Last-Modified it sent, and
+        /// download those that did again. Tiles fetched before the server
+        /// sent either count as fetched when their file was last written.
+        #[arg(long, value_name = "DAYS")]
+        max_age: Option<f64>,
+    },
+    /// Write a building density heatmap of the area of interest.
+    Heatmap {
+        /// Zoom of the heatmap; every pixel is one tile at this zoom.
+        #[arg(long, default_value_t = 13)]
+        zoom: u8,
+        #[arg(long, default_value = "heatmap")]
+        out: PathBuf,
+    },
+    /// Rasterize building footprints into `outlines/`.
+    RenderOutlines {
+        #[command(flatten)]
+        args: RenderArgs,
+    },
+    /// Render the labels of one tile as `render-outlines` does, into a
+    /// directory of their own, with its imagery downloaded if need be.
+    /// Takes seconds once the extract is checkpointed, to try rendering
+    /// arguments on.
+    RenderTile {
+        #[command(flatten)]
+        opts: onetile::TileOptions,
+        #[command(flatten)]
+        args: RenderArgs,
+    },
+    /// Render several variants of the dataset, as `render-outlines` with
+    /// different arguments and zooms, from one parse of the extract.
+    ///
+    /// A recipe is a TOML file with an `out_dir` and `[[variant]]` tables
+    /// of a `name`, `args` and optionally a `zoom`; a `sweep` table of
+    /// lists makes one variant per combination, filled in for `{param}` in
+    /// the name, zoom and arguments. Each variant renders into
+    /// `out_dir/<name>/`.
+    Recipe {
+        #[command(flatten)]
+        opts: recipe::RecipeOptions,
+    },
+    /// Stitch the tiles and outlines into chips in `stitched/`, by running
+    /// `stitch_pictures` from `run/`. Further arguments go to it as they
+    /// are, e.g. `stitch -- --edge pad`.
+    Stitch {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<std::ffi::OsString>,
+    },
+    /// Export (image_t1, image_t2, change_mask) triplets from two snapshots
+    /// rendered with `render-outlines --as-of`.
+    ChangePairs {
+        /// Date of the earlier snapshot, `YYYY-MM-DD`.
+        #[arg(long)]
+        t1: String,
+        /// Date of the later snapshot, `YYYY-MM-DD`.
+        #[arg(long)]
+        t2: String,
+        /// Imagery taken around `t1`.
+        #[arg(long, default_value = workspace::TILES)]
+        t1_tiles: PathBuf,
+        /// Imagery taken around `t2`, named like `tiles/`.
+        #[arg(long)]
+        t2_tiles: PathBuf,
+        #[arg(long, default_value = "change-pairs")]
+        out: PathBuf,
+    },
+    /// Paste buildings from rendered tiles into tiles that have none.
+    Augment {
+        #[command(flatten)]
+        opts: augment::AugmentOptions,
+    },
+    /// Write masks of seams, haze and clipped pixels for stitched chips.
+    ArtifactMasks {
+        #[arg(long, default_value = "stitched/tiles")]
+        chips: PathBuf,
+        #[arg(long, default_value = "stitched/artifacts")]
+        out: PathBuf,
+    },
+    /// Write which stitched chips border or overlap which, and by how much,
+    /// as JSON.
+    ChipGraph {
+        #[arg(long, default_value = workspace::STITCHED)]
+        chips: PathBuf,
+        #[arg(long, default_value = "stitched/adjacency.json")]
+        out: PathBuf,
+    },
+    /// Measure the offset between imagery and rendered outlines and store
+    /// it in `providers.json`, where `render-outlines` picks it up, for
+    /// the `--provider`.
+    Calibrate {
+        /// Number of tiles to measure on.
+        #[arg(long, default_value_t = 200)]
+        sample: usize,
+        /// Largest offset tried, pixels.
+        #[arg(long, default_value_t = 8)]
+        search_px: i32,
+    },
+    /// Render an arbitrary window at a chosen ground resolution: the
+    /// imagery mosaicked from `tiles/` and a mask of the objects in it.
+    Region {
+        #[command(flatten)]
+        opts: region::RegionOptions,
+    },
+    /// Write a square of imagery around every building and its footprint
+    /// as a mask, named after its OSM id and listed with its tags, for
+    /// building classification datasets.
+    BuildingCrops {
+        #[command(flatten)]
+        opts: crops::CropOptions,
+    },
+    /// Write random training windows as the seeded sampler draws them,
+    /// with `--min-share` for the classes a window must show.
+    SampleWindows {
+        #[command(flatten)]
+        opts: sampler::SampleOptions,
+    },
+    /// Serve windows over HTTP to training jobs: `/window` renders a
+    /// bounding box, `/chip` draws from the seeded sampler and `/stats`
+    /// tells what is served.
+    Serve {
+        #[command(flatten)]
+        opts: service::ServeOptions,
+    },
+    /// Write a per-building metadata table as JSON lines.
+    Metadata {
+        #[arg(long, default_value = "buildings.jsonl")]
+        out: PathBuf,
+        /// Also write building relations with their member roles. Reads
+        /// the extract a second time for the member ways.
+        #[arg(long)]
+        relations: bool,
+        /// Match nodes with `addr:*` tags to the footprint they lie in and
+        /// list them with the building.
+        #[arg(long)]
+        address_points: bool,
+        /// Fill the holes of building relations under this many m^2
+        /// instead of counting them out of the footprint.
+        #[arg(long, value_name = "M2", requires = "relations")]
+        min_hole_area: Option<f64>,
+    },
+    /// Write the building footprints as GeoJSON, with their OSM id, area
+    /// and some tags, to inspect the geometry before rasterizing it.
+    ExportGeojson {
+        #[command(flatten)]
+        opts: geojson::GeoJsonOptions,
+    },
+    /// Write what looks wrong in the source data, self-intersecting
+    /// footprints and tiles whose buildings do not match the imagery, as
+    /// MapRoulette challenges for mappers to fix.
+    ExportMaproulette {
+        #[command(flatten)]
+        opts: maproulette::MapRouletteOptions,
+    },
+    /// Write the minimum rotated rectangle of every building in the
+    /// stitched chips as DOTA-style oriented bounding boxes.
+    OrientedBoxes {
+        #[command(flatten)]
+        opts: oriented::OrientedOptions,
+    },
+    /// Write COCO instance segmentation annotations, building polygons in
+    /// pixels of the stitched chips.
+    Coco {
+        #[command(flatten)]
+        opts: coco::CocoOptions,
+    },
+    /// Link the stitched chips that touch objects with the given tags into
+    /// a directory of their own, e.g. only the chips of industrial areas.
+    Subset {
+        #[command(flatten)]
+        opts: subset::SubsetOptions,
+    },
+    /// Write CenterNet-style center heatmaps and offset and size targets
+    /// for the buildings in the stitched chips.
+    CenternetTargets {
+        #[command(flatten)]
+        opts: centernet::CenterNetOptions,
+    },
+    /// Write dataset coverage per administrative area to a CSV file.
+    Districts {
+        /// `admin_level` of the boundaries to aggregate by.
+        #[arg(long, default_value = "8")]
+        admin_level: String,
+        #[arg(long, default_value = "districts.csv")]
+        out: PathBuf,
+        /// `--min-area` counts small buildings as rendering does.
+        #[command(flatten)]
+        classes: ClassOptions,
+    },
+    /// Copy a tile store into another format, e.g. `tiles/` into a zstd
+    /// store that `stitch_pictures --tiles` reads without JPEG decoding.
+    ConvertTiles {
+        #[command(flatten)]
+        opts: rawtiles::ConvertOptions,
+    },
+    /// Keep every distinct tile image once in `tile-blobs/` and hard link
+    /// `tiles/` to it. Later downloads go through the store as well.
+    DedupTiles,
+    /// Rename tile and chip files to the `tile_names` of `formats.json`,
+    /// after changing it. Stitched blocks are rebuilt on the next run, the
+    /// manifest knowing them by their old names.
+    RenameTiles {
+        #[arg(default_values = ["tiles", "outlines", "roofs", "lines", "coverage", "stitched/tiles", "stitched/outlines"])]
+        dirs: Vec<PathBuf>,
+    },
+    /// Pack the stitched chips into WebDataset tar shards, with the
+    /// `.dvc` files that `dvc add` would write for them.
+    Webdataset {
+        #[command(flatten)]
+        opts: webdataset::WebDatasetOptions,
+    },
+    /// Split the stitched chips into train, validation and test by area,
+    /// listing them per split and optionally laying them out in a folder
+    /// per split.
+    Split {
+        #[command(flatten)]
+        opts: split::SplitOptions,
+    },
+    /// Lay the stitched chips out as a Hugging Face dataset with splits, a
+    /// parquet index per split and a dataset card.
+    HfDataset {
+        #[command(flatten)]
+        opts: huggingface::HfOptions,
+    },
+    /// Compare two versions of a dataset, e.g. `diff datasets A B`.
+    Diff {
+        #[command(subcommand)]
+        what: diff::DiffCommand,
+    },
+    /// Write `dataset.json`, every tile and chip of the workspace with
+    /// its mask, bounds and what it was made from.
+    Dataset,
+    /// Freeze the stitched chips into an immutable, checksummed release
+    /// in `releases/<VERSION>/`.
+    Release { version: String },
+    /// Write the chips added, changed and removed between two releases to
+    /// `releases/<TO>/delta-from-<FROM>.json`.
+    ReleaseDelta { from: String, to: String },
+    /// Regenerate a sample of the chips of a release with the current
+    /// code and configuration, and check them against its hashes. Takes
+    /// the arguments of `render-outlines` the release was rendered with.
+    VerifyRepro {
+        #[command(flatten)]
+        opts: repro::VerifyOptions,
+        #[command(flatten)]
+        args: RenderArgs,
+    },
+    /// Download one known tile from the `--provider`, check that it
+    /// decodes at the expected size and render a known polygon, as a quick
+    /// check before a long run on a new machine.
+    Selftest,
+    /// Print a shell completion script, e.g. `completions bash >
+    /// /etc/bash_completion.d/map-segmentation-gendata`.
+    Completions { shell: clap_complete::Shell },
+    /// Print the man page, or write one page per subcommand into `--out`.
+    Manpage {
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+/// Arguments of `render-outlines`, also those of every recipe variant.
+#[derive(clap::Args)]
+struct RenderArgs {
+    /// Directory to write `outlines/` and the other label directories
+    /// and reports into. The imagery is read from `tiles/` either way.
+    #[arg(long, default_value = ".")]
+    out_dir: PathBuf,
+    /// Also write roof shape labels into `roofs/` as single-channel
+    /// images, see `metadata` for the label vocabulary.
+    #[arg(long)]
+    roof_channel: bool,
+    /// With `index`, also write the outlines as single-channel class
+    /// indices into `masks/`, with the classes in `masks/classes.json`.
+    #[arg(long, value_enum, default_value_t)]
+    mask_format: masks::MaskMode,
+    /// Also write the share of every pixel covered by buildings into
+    /// `coverage/`, 0 to 255, measured with this many samples per pixel
+    /// side. A softer target than the outlines along building edges.
+    #[arg(long, value_name = "FACTOR", value_parser = clap::value_parser!(u32).range(2..=16))]
+    coverage: Option<u32>,
+    /// Fill building and area outlines by supersampling, this many
+    /// samples per pixel side: a pixel takes the class of a polygon
+    /// covering at least half of it. Edges follow the footprint instead
+    /// of jagging along the scanline fill; classes are never blended,
+    /// see `--coverage` for a soft target.
+    #[arg(long, value_name = "FACTOR", value_parser = clap::value_parser!(u32).range(2..=16))]
+    antialias: Option<u32>,
+    /// Instead of drawing buildings under 100 m^2 as their own class,
+    /// mark them and this many pixels around them as `Ignore`.
+    #[arg(long, value_name = "PX")]
+    ignore_small: Option<u32>,
+    /// Also draw building relations, with their inner rings as holes,
+    /// in place of their member ways. Reads the extract a second time
+    /// for the member ways, which are rarely tagged themselves.
+    #[arg(long)]
+    relations: bool,
+    #[command(flatten)]
+    classes: ClassOptions,
+    #[command(flatten)]
+    lines: lines::LineOptions,
+    #[command(flatten)]
+    noise: noise::NoiseOptions,
+    #[command(flatten)]
+    postprocess: postprocess::PostprocessOptions,
+    #[command(flatten)]
+    rings: rings::RingOptions,
+    /// Treat `--pbf` as a full-history extract and render the buildings
+    /// as they were at the start of this day (UTC, `YYYY-MM-DD`). The
+    /// labels go to `snapshots/<date>/` of `--out-dir` so that several
+    /// dates can be rendered over the same tiles.
+    #[arg(long)]
+    as_of: Option<String>,
+    #[command(flatten)]
+    thresholds: outcome::Thresholds,
+    #[command(flatten)]
+    memory: memory::MemoryOptions,
+    #[command(flatten)]
+    units: units::UnitOptions,
+}
+
+impl RenderArgs {
+    /// Fails on arguments that do not go together, before anything is
+    /// loaded.
+    fn check(&self) -> anyhow::Result<()> {
+        if let Some(unit_zoom) = self.units.unit_zoom {
+            anyhow::ensure!(
+                unit_zoom < zoom(),
+                "--unit-zoom {unit_zoom} makes units no larger than a tile of --zoom {}\n\
+                 hint: pick a unit zoom below the tile zoom",
+                zoom()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Command {
+    /// What the command writes, `None` if it writes into the dataset
+    /// itself and cannot run with `--read-only`.
+    fn outputs(&self) -> Option<Vec<&Path>> {
+        Some(match self {
+            Command::Stats { .. }
+            | Command::Selftest
+            | Command::Serve { .. }
+            | Command::Completions { .. }
+            | Command::Manpage { out: None } => vec![],
+            Command::Heatmap { out, .. }
+            | Command::ChangePairs { out, .. }
+            | Command::ArtifactMasks { out, .. }
+            | Command::ChipGraph { out, .. }
+            | Command::Metadata { out, .. }
+            | Command::Districts { out, .. }
+            | Command::Manpage { out: Some(out) } => vec![out],
+            Command::Tags { opts } => opts.out.as_deref().into_iter().collect(),
+            Command::Augment { opts } => vec![&opts.out],
+            Command::OrientedBoxes { opts } => vec![&opts.out],
+            Command::Coco { opts } => vec![&opts.out],
+            Command::Subset { opts } => vec![&opts.out],
+            Command::Region { opts } => vec![&opts.out],
+            Command::BuildingCrops { opts } => vec![&opts.out],
+            Command::SampleWindows { opts } => vec![&opts.out],
+            Command::ExportGeojson { opts } => vec![&opts.out],
+            Command::ExportMaproulette { opts } => vec![&opts.out],
+            Command::CenternetTargets { opts } => vec![&opts.out],
+            Command::ConvertTiles { opts } => vec![&opts.out],
+            Command::Webdataset { opts } => vec![&opts.out],
+            Command::HfDataset { opts } => vec![&opts.out],
+            // Moving takes the chips out of the dataset.
+            Command::Split { opts } => match opts.folders {
+                Some(split::Folders::Move) => return None,
+                _ => vec![&opts.out],
+            },
+            Command::Diff { what } => vec![what.out()],
+            Command::VerifyRepro { opts, .. } => vec![&opts.scratch],
+            Command::DownloadTiles { .. }
+            | Command::RenderOutlines { .. }
+            | Command::RenderTile { .. }
+            | Command::Recipe { .. }
+            | Command::Stitch { .. }
+            | Command::Calibrate { .. }
+            | Command::DedupTiles
+            | Command::RenameTiles { .. }
+            | Command::Dataset
+            | Command::Release { .. }
+            | Command::ReleaseDelta { .. } => return None,
+        })
+    }
+
+    fn reads_pbf(&self) -> bool {
+        matches!(
+            self,
+            Command::Stats { .. }
+                | Command::Tags { .. }
+                | Command::Heatmap { .. }
+                | Command::RenderOutlines { .. }
+                | Command::RenderTile { .. }
+                | Command::Recipe {
+                    opts: recipe::RecipeOptions { list: false, .. }
+                }
+                | Command::Metadata { .. }
+                | Command::Districts { .. }
+                | Command::OrientedBoxes { .. }
+                | Command::Coco { .. }
+                | Command::CenternetTargets { .. }
+                | Command::Subset { .. }
+                | Command::Region { .. }
+                | Command::BuildingCrops { .. }
+                | Command::SampleWindows { .. }
+                | Command::Serve { .. }
+                | Command::ExportGeojson { .. }
+                | Command::ExportMaproulette { .. }
+                | Command::VerifyRepro { .. }
+        )
+    }
+}
 
 struct ProgressFile<R: std::io::Read> {
     inner: R,
@@ -23,7 +624,8 @@ impl<R: std::io::Read> ProgressFile<R> {
     pub fn new(inner: R, len: u64) -> Self {
         Self {
             inner,
-            progress: ProgressBar::new(len).with_style(
+            progress: logging::progress_bar(
+                len,
                 ProgressStyle::with_template(
                     "[{eta_precise}] {bar:120} [{bytes}/{total_bytes} {percent}%]",
                 )
@@ -41,66 +643,325 @@ impl<R: std::io::Read> std::io::Read for ProgressFile<R> {
     }
 }
 
-fn fetch_buildings(filename: &std::ffi::OsStr) {
-    let r = std::fs::File::open(&std::path::Path::new(filename)).unwrap();
-    let len = r.metadata().unwrap().len();
-    let r = ProgressFile::new(r, len);
-    let mut pbf = osmpbfreader::OsmPbfReader::new(r);
+/// Everything from the PBF that the rest of the tool needs: the nodes of the
+/// kept ways (ways only reference nodes by id) and the tagged ones, the
+/// objects tagged `building` and the other nodes and ways some class in
+/// `classes` can render.
+struct OsmData {
+    nodes_all: nodes::Nodes,
+    nodes_only_buildings: HashMap<i64, Node>,
+    nodes_features: HashMap<i64, Node>,
+    ways_buildings: HashMap<i64, Way>,
+    ways_features: HashMap<i64, Way>,
+    relations_buildings: HashMap<i64, Relation>,
+    /// Ways that are part of the area of a building relation, to the
+    /// relation. Many are tagged `building` themselves and would otherwise
+    /// be drawn twice, once on their own and once with the relation.
+    relation_member_ways: HashMap<i64, i64>,
+    /// Buildings mapped twice, left out of the ones above.
+    duplicates: duplicates::Collapsed,
+}
 
-    let mut nodes_all = HashMap::new();
-    let mut nodes_only_buildings = HashMap::new();
-    let mut ways_buildings = HashMap::new();
-    let mut relations_buildings = HashMap::new();
+fn load_osm(filename: &std::ffi::OsStr) -> anyhow::Result<OsmData> {
+    checkpoint::load(filename)
+}
 
-    for obj in pbf.par_iter().map(Result::unwrap) {
-        let is_building = obj.tags().contains_key("building");
+impl OsmData {
+    /// Whether `from_objs` would keep this object. Untagged nodes are
+    /// only kept if a kept way references them, see `nodes.rs`.
+    fn keeps(obj: &osmpbfreader::OsmObj) -> bool {
         match obj {
-            osmpbfreader::OsmObj::Node(node) => {
-                if is_building {
-                    nodes_only_buildings.insert(node.id.0, node.clone());
-                }
-                nodes_all.insert(node.id.0, node);
-            }
+            osmpbfreader::OsmObj::Node(node) => !node.tags.is_empty(),
             osmpbfreader::OsmObj::Way(way) => {
-                if is_building {
-                    ways_buildings.insert(way.id.0, way);
-                }
+                way.tags.contains_key("building")
+                    || parts::is_part(&way.tags)
+                    || classes::is_feature(&way.tags)
             }
             osmpbfreader::OsmObj::Relation(rel) => {
-                if is_building {
-                    relations_buildings.insert(rel.id.0, rel);
+                rel.tags.contains_key("building") || parts::is_building_relation(&rel.tags)
+            }
+        }
+    }
+
+    /// Sorts objects into buildings and drawable features, adding their
+    /// nodes to those already in `nodes_all`.
+    fn from_objs(
+        objs: impl IntoIterator<Item = osmpbfreader::OsmObj>,
+        mut nodes_all: nodes::Builder,
+    ) -> anyhow::Result<Self> {
+        let _span = timing::span(Stage::Parse);
+        let mut nodes_only_buildings = HashMap::new();
+        let mut nodes_features = HashMap::new();
+        let mut ways_buildings = HashMap::new();
+        let mut ways_features = HashMap::new();
+        let mut relations_buildings = HashMap::new();
+        let mut ways_parts = HashMap::new();
+        let mut building_relations = vec![];
+
+        for obj in objs {
+            let is_building = obj.tags().contains_key("building");
+            match obj {
+                osmpbfreader::OsmObj::Node(node) => {
+                    if is_building {
+                        nodes_only_buildings.insert(node.id.0, node.clone());
+                    } else if classes::is_feature(&node.tags)
+                        && !classes::is_way_only_feature(&node.tags)
+                    {
+                        nodes_features.insert(node.id.0, node.clone());
+                    }
+                    nodes_all.push(node)?;
+                }
+                osmpbfreader::OsmObj::Way(way) => {
+                    if is_building {
+                        ways_buildings.insert(way.id.0, way);
+                    } else if parts::is_part(&way.tags) {
+                        ways_parts.insert(way.id.0, way);
+                    } else if classes::is_feature(&way.tags) {
+                        ways_features.insert(way.id.0, way);
+                    }
+                }
+                osmpbfreader::OsmObj::Relation(rel) => {
+                    if parts::is_building_relation(&rel.tags) {
+                        building_relations.push(rel.clone());
+                    }
+                    if is_building {
+                        relations_buildings.insert(rel.id.0, rel);
+                    }
+                }
+            }
+        }
+        building_relations.sort_by_key(|r| r.id);
+        parts::resolve(&mut ways_buildings, ways_parts, &building_relations);
+
+        let mut relation_member_ways = HashMap::new();
+        for rel in relations_buildings.values() {
+            for r in &rel.refs {
+                if let (osmpbfreader::OsmId::Way(id), Some(_)) =
+                    (r.member, geometry::area_role(&r.role))
+                {
+                    relation_member_ways.insert(id.0, rel.id.0);
                 }
             }
         }
+
+        let mut osm = OsmData {
+            nodes_all: nodes_all.finish()?,
+            nodes_only_buildings,
+            nodes_features,
+            ways_buildings,
+            ways_features,
+            relations_buildings,
+            relation_member_ways,
+            duplicates: Default::default(),
+        };
+        osm.duplicates = duplicates::collapse(&mut osm);
+        Ok(osm)
+    }
+
+    /// Building ways that are not part of one of `relations`, those being
+    /// the relations drawn or written as a whole.
+    fn standalone_buildings(&self, relations: &HashSet<i64>) -> Vec<&Way> {
+        self.ways_buildings
+            .values()
+            .filter(|way| {
+                self.relation_member_ways
+                    .get(&way.id.0)
+                    .is_none_or(|rel| !relations.contains(rel))
+            })
+            .collect()
+    }
+}
+
+fn fetch_buildings(filename: &std::ffi::OsStr, classes: &ClassOptions) -> anyhow::Result<()> {
+    let osm = load_osm(filename)?;
+
+    println!("Nodes of kept objects: {}", osm.nodes_all.len());
+    println!("Building nodes: {}", osm.nodes_only_buildings.len());
+    println!("Building ways: {}", osm.ways_buildings.len());
+    println!("Building relations: {}", osm.relations_buildings.len());
+    println!(
+        "Duplicates left out: {} building ways, {} building nodes",
+        osm.duplicates.ways.len(),
+        osm.duplicates.nodes.len()
+    );
+    let members = osm
+        .relation_member_ways
+        .keys()
+        .filter(|id| osm.ways_buildings.contains_key(id))
+        .count();
+    println!("Building ways that are relation members: {members}");
+
+    let mut per_class: BTreeMap<u8, (FeatureClass, usize)> = BTreeMap::new();
+    for way in osm.ways_buildings.values() {
+        let Some(class) = classes::building_class(&way.tags, classes) else {
+            continue;
+        };
+        per_class.entry(class as u8).or_insert((class, 0)).1 += 1;
+    }
+    for feature in collect_features(&osm, classes) {
+        per_class
+            .entry(feature.class as u8)
+            .or_insert((feature.class, 0))
+            .1 += 1;
     }
+    for (class, count) in per_class.values() {
+        println!("  {class:?}: {count}");
+    }
+    Ok(())
+}
+
+const DEFAULT_ZOOM: u8 = 17; // zoom where 1px=1m;
+/// Deepest zoom `--zoom` takes; tile names are padded for up to this.
+const MAX_ZOOM: u8 = 19;
 
-    println!("All nodes: {}", nodes_all.len());
-    println!("Building nodes: {}", nodes_only_buildings.len());
-    println!("Building ways: {}", ways_buildings.len());
-    println!("Building relations: {}", relations_buildings.len());
+static TILE_ZOOM: AtomicU8 = AtomicU8::new(DEFAULT_ZOOM);
+static INTEREST_BBOX: OnceLock<BBox> = OnceLock::new();
+static READ_ONLY: OnceLock<bool> = OnceLock::new();
+
+/// Zoom of the tiles, `--zoom`.
+fn zoom() -> u8 {
+    TILE_ZOOM.load(Ordering::Relaxed)
+}
+
+/// Sets the zoom of the tiles, once at the start, and again between the
+/// variants of a recipe, when nothing else runs.
+fn set_zoom(zoom: u8) {
+    TILE_ZOOM.store(zoom, Ordering::Relaxed);
+}
+
+/// Whether the dataset and the extract are only read, `--read-only`.
+fn read_only() -> bool {
+    READ_ONLY.get().copied().unwrap_or_default()
+}
+
+/// Area of interest, `--bbox` or all of Moscow with a generous margin,
+/// which is logged the first time it is used.
+fn interest_bbox() -> BBox {
+    INTEREST_BBOX
+        .get_or_init(|| {
+            let buf = 0.5;
+            logging::status!(
+                "No --bbox or --tile-list, the area of interest is all of Moscow \
+                 and {buf} degrees around it"
+            );
+            checks::bbox(55.93 + buf, 37.3 - buf, 55.56 - buf, 37.9 + buf).unwrap()
+        })
+        .clone()
 }
 
-const ZOOM: u8 = 17; // zoom where 1px=1m;
+fn download_image(tile: Tile) -> anyhow::Result<image::DynamicImage> {
+    let (image, _) = fetch_image(tile, None)?;
+    Ok(image.expect("a tile without validators is always sent"))
+}
 
-fn translate(value: f64, left_min: f64, left_max: f64, right_min: f64, right_max: f64) -> f64 {
-    log::trace!("translate({value}, {left_min}, {left_max}, {right_min}, {right_max}");
-    let left_span = left_max - left_min;
-    let right_span = right_max - right_min;
+/// Downloads `tile`, or with the `validators` of the copy on disk only if
+/// it changed; the image is `None` if it did not.
+fn fetch_image(
+    tile: Tile,
+    validators: Option<&provider::Validators>,
+) -> anyhow::Result<(Option<image::DynamicImage>, provider::Validators)> {
+    let _span = timing::span(Stage::Network);
+    let source = provider::source();
+    let url = source.url(tile, true);
+    let fetched = source.fetch(tile, validators).map_err(|why| {
+        metrics::DOWNLOAD_FAILURES.inc();
+        checks::tile_server(&url, why)
+    })?;
+    let Some(data) = fetched.data else {
+        return Ok((None, fetched.validators));
+    };
+    metrics::TILES_DOWNLOADED.inc();
+    let image = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .decode()
+        .map_err(|why| {
+            anyhow::anyhow!(
+                "the tile server sent something that is not an image for {url}: {why}\n\
+                 hint: the server may be down or blocking requests, try again later"
+            )
+        })?;
+    Ok((Some(image), fetched.validators))
+}
 
-    let value_scaled = (value - left_min) / left_span;
+/// Downloads the imagery of `tile` from the `--provider` into `tiles/`.
+fn download_tile(blobs: Option<&dedup::Store>, tile: Tile) -> anyhow::Result<image::DynamicImage> {
+    let (tileimg, validators) = fetch_image(tile, None)?;
+    let tileimg = tileimg.expect("a tile without validators is always sent");
+    dedup::save_tile(blobs, tile, &tileimg)?;
+    tilecache::record(tile, validators)?;
+    Ok(tileimg)
+}
 
-    let out = right_min + (value_scaled * right_span);
+/// Asks the server whether `tile` changed since the copy on disk was
+/// fetched, and downloads it again if it did. Returns whether it did.
+fn refresh_tile(
+    blobs: Option<&dedup::Store>,
+    tile: Tile,
+    validators: &provider::Validators,
+) -> anyhow::Result<bool> {
+    let (tileimg, validators) = fetch_image(tile, Some(validators))?;
+    let changed = tileimg.is_some();
+    if let Some(tileimg) = tileimg {
+        // Not overwritten in place, which would change a blob shared by
+        // other tiles through its hard links.
+        std::fs::remove_file(paths::tile_file(workspace::TILES, tile, ".jpg"))?;
+        dedup::save_tile(blobs, tile, &tileimg)?;
+    }
+    tilecache::record(tile, validators)?;
+    Ok(changed)
+}
 
-    log::trace!("translate({value}, {left_min}, {left_max}, {right_min}, {right_max}) -> {out}");
-    out
+/// Clips a ring in screen coordinates to the screen and a pixel around
+/// it, one side after another. Parts outside run along the border, out of
+/// sight, so that a ring too large for the tile stays one ring and its
+/// holes still fill even-odd.
+fn clip_to_screen(ring: &[Point<f64>], screen_size: (u32, u32)) -> Vec<Point<f64>> {
+    let (w, h) = (screen_size.0 as f64 + 1.0, screen_size.1 as f64 + 1.0);
+    // Inside where `sign * coordinate + limit` is not negative.
+    let sides = [
+        (false, 1.0, 1.0),
+        (true, 1.0, 1.0),
+        (false, -1.0, w),
+        (true, -1.0, h),
+    ];
+    let mut ring = ring.to_vec();
+    for (vertical, sign, limit) in sides {
+        let inside = |p: Point<f64>| sign * if vertical { p.y } else { p.x } + limit;
+        let mut clipped = Vec::with_capacity(ring.len() + 2);
+        for (i, &p) in ring.iter().enumerate() {
+            let q = ring[(i + 1) % ring.len()];
+            let (dp, dq) = (inside(p), inside(q));
+            if dp >= 0.0 {
+                clipped.push(p);
+            }
+            if (dp >= 0.0) != (dq >= 0.0) {
+                let t = dp / (dp - dq);
+                clipped.push(Point::new(p.x + (q.x - p.x) * t, p.y + (q.y - p.y) * t));
+            }
+        }
+        ring = clipped;
+        if ring.is_empty() {
+            break;
+        }
+    }
+    ring
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 struct GeoCoordinate {
     pub longitude: f64,
     pub latitude: f64,
 }
 
+impl From<mercator::LatLon> for GeoCoordinate {
+    fn from(value: mercator::LatLon) -> Self {
+        Self {
+            longitude: value.longitude,
+            latitude: value.latitude,
+        }
+    }
+}
+
 impl From<Point<f64>> for GeoCoordinate {
     fn from(value: Point<f64>) -> Self {
         Self {
@@ -128,89 +989,272 @@ impl From<GeoCoordinate> for Coord<f64> {
     }
 }
 
-const COLOR_INDEX: &[[u8; 3]] = &[[0, 0, 0], [255, 0, 0], [0, 255, 0]];
+/// The outline of a tile and its channels, locked together.
+struct TileImages {
+    outline: ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    channels: BTreeMap<String, GrayImage>,
+}
+
+impl TileImages {
+    fn new(outline: ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> Self {
+        Self {
+            outline,
+            channels: BTreeMap::new(),
+        }
+    }
 
-#[derive(Clone, Copy, Debug)]
-enum BuildingColor {
-    Nothing = 0,
-    BuildingBelowAreaThreshold = 1,
-    Normal = 2,
-    BuildingHasExcludedTags = 3,
+    /// The named channel, blank until first drawn into.
+    fn channel(&mut self, name: &str) -> &mut GrayImage {
+        let (w, h) = self.outline.dimensions();
+        if !self.channels.contains_key(name) {
+            self.channels.insert(name.to_owned(), GrayImage::new(w, h));
+        }
+        self.channels.get_mut(name).unwrap()
+    }
 }
 
+/// Outlines and channels of the tiles around, shared between threads. The
+/// map is locked only to look a tile up or add one, drawing locks the
+/// tile, so threads drawing into different tiles do not wait on each
+/// other.
 #[derive(Default)]
 struct ImageCache {
-    tiles: HashMap<Tile, ()>,
-    outlines: HashMap<Tile, ImageBuffer<image::Rgb<u8>, Vec<u8>>>,
-    dirty: HashSet<Tile>,
-    client: reqwest::blocking::Client,
+    /// Tiles with imagery in `tiles/`.
+    tiles: RwLock<HashSet<Tile>>,
+    images: RwLock<HashMap<Tile, Arc<Mutex<TileImages>>>>,
+    /// Auxiliary single-channel rasters, saved to a directory of the same
+    /// name alongside `outlines/`.
+    channels: BTreeSet<String>,
+    /// Whether to also save the outlines as class indices into `masks/`.
+    index_masks: bool,
+    /// Samples per pixel side polygons are filled with, `--antialias`.
+    antialias: Option<u32>,
+    dirty: Mutex<HashSet<Tile>>,
+    /// Tiles drawn into since the last `take_touched`, by thread, as an
+    /// object is drawn on one thread.
+    touched: Mutex<HashMap<ThreadId, HashSet<Tile>>>,
+    /// Held while a tile that is not in memory is read or downloaded, so
+    /// that it is fetched once.
+    fetching: Mutex<()>,
+    /// Directory holding `outlines/` and the channel directories. Imagery
+    /// is always read from and downloaded into `tiles/`.
+    out_dir: PathBuf,
+    /// Registration correction of the imagery provider, meters east and
+    /// north, see `calibrate`.
+    offset_m: [f64; 2],
+    manifest: Option<Mutex<manifest::OutputManifest>>,
+    blobs: Option<dedup::Store>,
+    /// How outlines and channels are encoded, from `formats.json`.
+    formats: formats::Formats,
+    /// Tiles whose outline and channels `evict` dropped from memory. They
+    /// are read back from `out_dir` when drawn into again.
+    evicted: Mutex<HashSet<Tile>>,
+    /// Work unit being rendered; drawing outside of it is clipped away.
+    unit: RwLock<Option<Tile>>,
 }
 
+// Drawing from several threads, and serving tiles while rendering, rely
+// on this.
+const _: () = {
+    const fn shared<T: Send + Sync>() {}
+    shared::<ImageCache>()
+};
+
 impl ImageCache {
-    pub fn prepare_tile(&mut self, tile: Tile) -> anyhow::Result<()> {
-        // let interest_center = (54.6961, 20.5120);
-        // let interest_zoom = ZOOM - 4; // 4096 area
-
-        // let interest_megatile =
-        //     slippy_map_tiles::lat_lon_to_tile(interest_center.0, interest_center.1, interest_zoom);
-        // let interest_megatile =
-        //     Tile::new(interest_zoom, interest_megatile.0, interest_megatile.1).unwrap();
-
-        // entire moscow
-        let buf = 0.5;
-        let interest_bbox =
-            slippy_map_tiles::BBox::new(55.93 + buf, 37.3 - buf, 55.56 - buf, 37.9 + buf).unwrap();
-
-        // inside TTK
-        // let interest_bbox = slippy_map_tiles::BBox::new(55.79, 37.53, 55.70, 37.7).unwrap();
-
-        let do_download = {
-            interest_bbox.overlaps_bbox(&tile.bbox())
-            // let mut t = tile;
-            // while t.zoom() > interest_megatile.zoom() {
-            //     t = t.parent().unwrap();
-            // }
-            // t == interest_megatile
-        };
+    /// The images of `tile`, read back, made blank or downloaded along
+    /// with the imagery if not in memory.
+    pub fn prepare_tile(&self, tile: Tile) -> anyhow::Result<Arc<Mutex<TileImages>>> {
+        if let Some(images) = self.images.read().unwrap().get(&tile) {
+            return Ok(images.clone());
+        }
 
-        if self.tiles.get(&tile).is_none() && !do_download {
+        let on_disk = self.tiles.read().unwrap().contains(&tile);
+        if !on_disk && !Self::in_interest(tile) {
             anyhow::bail!("Missing tile, and not downloading it");
         }
 
-        if let (Some(_a), Some(_b)) = (self.tiles.get_mut(&tile), self.outlines.get_mut(&tile)) {
-            return Ok(());
+        let _fetching = self.fetching.lock().unwrap();
+        if let Some(images) = self.images.read().unwrap().get(&tile) {
+            // Fetched by another thread in the meantime.
+            return Ok(images.clone());
         }
 
-        info!("Preparing tile {tile:?}");
+        if self.evicted.lock().unwrap().remove(&tile) {
+            return self.reload(tile);
+        }
 
-        assert_eq!(tile.zoom(), ZOOM);
+        if on_disk {
+            // Imagery is already on disk, only the outline is new.
+            let _span = timing::span(Stage::Io);
+            let (w, h) = image::image_dimensions(paths::tile_file(workspace::TILES, tile, ".jpg"))?;
+            return Ok(self.insert(tile, TileImages::new(ImageBuffer::new(w, h))));
+        }
 
-        let path = format!("https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{}/{}/{}", tile.zoom(), tile.y(), tile.x());
-        //let path = format!("https://core-sat.maps.yandex.net/tiles?l=sat&v=3.1124.0&x={}&y={}&z={}&scale=1&lang=ru_RU&client_id=yandex-web-maps", tile.x(), tile.y(), tile.zoom());
+        info!(target: logging::DOWNLOAD, "Preparing tile {tile:?}");
 
-        let tiledata = self
-            .client
-            .get(path)
-            .send()
-            .unwrap()
-            .bytes()
-            .unwrap()
-            .to_vec();
-        let tileimg = image::io::Reader::new(Cursor::new(tiledata))
-            .with_guessed_format()
-            .unwrap()
-            .decode()
-            .unwrap();
+        assert_eq!(tile.zoom(), zoom());
+
+        let tileimg = download_tile(self.blobs.as_ref(), tile)?;
         let outline_img: ImageBuffer<image::Rgb<u8>, Vec<_>> =
             ImageBuffer::new(tileimg.width(), tileimg.height());
+        self.tiles.write().unwrap().insert(tile);
+        Ok(self.insert(tile, TileImages::new(outline_img)))
+    }
 
-        tileimg
-            .save(format!("tiles/{}-{}.jpg", tile.y(), tile.x()))
-            .unwrap();
-        self.tiles.insert(tile, ());
-        self.outlines.insert(tile, outline_img);
+    fn insert(&self, tile: Tile, images: TileImages) -> Arc<Mutex<TileImages>> {
+        let images = Arc::new(Mutex::new(images));
+        self.images.write().unwrap().insert(tile, images.clone());
+        images
+    }
 
-        Ok(())
+    pub fn add_channel(&mut self, name: &str) {
+        self.channels.insert(name.to_string());
+    }
+
+    /// Reads an evicted tile back from `out_dir`.
+    fn reload(&self, tile: Tile) -> anyhow::Result<Arc<Mutex<TileImages>>> {
+        let _span = timing::span(Stage::Io);
+        let outline = image::open(paths::tile_file(
+            self.out_dir.join(workspace::OUTLINES),
+            tile,
+            ".png",
+        ))?;
+        let mut images = TileImages::new(outline.into_rgb8());
+        for channel in &self.channels {
+            let ext = self.formats.channel(channel).ext();
+            if let Ok(img) = image::open(paths::tile_file(self.out_dir.join(channel), tile, ext)) {
+                images.channels.insert(channel.clone(), img.into_luma8());
+            }
+        }
+        Ok(self.insert(tile, images))
+    }
+
+    /// Saves everything and drops all outlines and channels from memory.
+    /// Drawing into a tile while it is evicted loses the drawing, so this
+    /// is for when no other thread draws.
+    pub fn evict(&self) {
+        self.save();
+        let images = std::mem::take(&mut *self.images.write().unwrap());
+        self.evicted.lock().unwrap().extend(images.into_keys());
+    }
+
+    /// Marks `tile` as drawn into.
+    fn mark(&self, tile: Tile) {
+        self.mark_dirty(tile);
+        self.touched
+            .lock()
+            .unwrap()
+            .entry(std::thread::current().id())
+            .or_default()
+            .insert(tile);
+    }
+
+    /// Marks `tile` for saving, without counting it as touched by the
+    /// object the thread draws.
+    fn mark_dirty(&self, tile: Tile) {
+        let mut dirty = self.dirty.lock().unwrap();
+        dirty.insert(tile);
+        metrics::TILES_DIRTY.set(dirty.len() as i64);
+    }
+
+    /// Whether `tile` is in the area of interest, and so downloaded when
+    /// it is not on disk.
+    fn in_interest(tile: Tile) -> bool {
+        interest_bbox().overlaps_bbox(&tile.bbox())
+    }
+
+    /// Drops the tiles outside of the current work unit, and those outside
+    /// the area of interest that are not on disk: objects crossing the edge
+    /// of `--bbox` reach into them, but there is nothing to draw them on.
+    fn restrict(&self, mut tiles: HashSet<Tile>) -> HashSet<Tile> {
+        if let Some(unit) = *self.unit.read().unwrap() {
+            tiles.retain(|t| units::contains(unit, *t));
+        }
+        tiles.retain(|t| worklist::contains(*t));
+        let on_disk = self.tiles.read().unwrap();
+        tiles.retain(|t| on_disk.contains(t) || Self::in_interest(*t));
+        drop(on_disk);
+        tiles
+    }
+
+    /// Tiles containing at least one of the points.
+    fn vertex_tiles(points: &[GeoCoordinate]) -> HashSet<Tile> {
+        points
+            .iter()
+            .map(|v| {
+                let c = mercator::tile_at(v.latitude, v.longitude, zoom());
+                Tile::new(zoom(), c.0, c.1).unwrap()
+            })
+            .collect()
+    }
+
+    /// Tiles `poly` covers part of. A large building also covers tiles
+    /// it has no vertex in, so the tiles of its bounding box are checked
+    /// against the ring itself.
+    fn polygon_tiles(poly: &[GeoCoordinate]) -> HashSet<Tile> {
+        let vertices = Self::vertex_tiles(poly);
+        let (Some(x0), Some(x1)) = (
+            vertices.iter().map(|t| t.x()).min(),
+            vertices.iter().map(|t| t.x()).max(),
+        ) else {
+            return vertices;
+        };
+        let y0 = vertices.iter().map(|t| t.y()).min().unwrap();
+        let y1 = vertices.iter().map(|t| t.y()).max().unwrap();
+        if (x1 - x0 + 1) * (y1 - y0 + 1) == vertices.len() as u32 {
+            return vertices;
+        }
+        let ring = Polygon::new(
+            poly.iter()
+                .map(|c| Coord {
+                    x: c.longitude,
+                    y: c.latitude,
+                })
+                .collect(),
+            vec![],
+        );
+        let mut tiles = vertices;
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let tile = Tile::new(zoom(), x, y).unwrap();
+                let b = mercator::bounds(tile);
+                let bounds = Rect::new(
+                    Coord {
+                        x: b.west,
+                        y: b.south,
+                    },
+                    Coord {
+                        x: b.east,
+                        y: b.north,
+                    },
+                );
+                if !tiles.contains(&tile) && ring.intersects(&bounds) {
+                    tiles.insert(tile);
+                }
+            }
+        }
+        tiles
+    }
+
+    fn tile_relative_polygon(
+        tile: Tile,
+        screen_size: (u32, u32),
+        poly: &[GeoCoordinate],
+    ) -> Vec<Point<i32>> {
+        let screen: Vec<_> = poly
+            .iter()
+            .map(|c| Self::geo_to_screen_f64(tile, screen_size, *c))
+            .collect();
+        let mut tile_relative_poly: Vec<_> = clip_to_screen(&screen, screen_size)
+            .into_iter()
+            .map(|p| Point::new(p.x as i32, p.y as i32))
+            .collect();
+        while tile_relative_poly.len() > 1
+            && tile_relative_poly.last() == tile_relative_poly.first()
+        {
+            tile_relative_poly.pop();
+        }
+        tile_relative_poly
     }
 
     pub fn geo_to_screen_coordinate(
@@ -218,323 +1262,2185 @@ impl ImageCache {
         screen_size: (u32, u32),
         coord: GeoCoordinate,
     ) -> Point<i32> {
-        let top_lat = tile.top() as f64;
-        let bot_lat = tile.bottom() as f64;
-        let left_lon = tile.left() as f64;
-        let right_lon = tile.right() as f64;
-
-        let x = translate(
-            coord.longitude,
-            left_lon,
-            right_lon,
-            0.0,
-            screen_size.0 as f64,
-        ) as i32;
-        let y = translate(coord.latitude, top_lat, bot_lat, 0.0, screen_size.1 as f64) as i32;
+        let p = Self::geo_to_screen_f64(tile, screen_size, coord);
+        Point::new(p.x as i32, p.y as i32)
+    }
+
+    /// Pixels per meter on the ground at the tile's center latitude.
+    fn pixels_per_meter(tile: Tile, screen_size: (u32, u32)) -> f64 {
+        let b = mercator::bounds(tile);
+        let lat = (b.north + b.south) / 2.0;
+        let tile_width_m = 40_075_016.686 * lat.to_radians().cos() / 2f64.powi(tile.zoom() as i32);
+        screen_size.0 as f64 / tile_width_m
+    }
+
+    /// Tiles within `radius_m` of any of the points.
+    fn buffered_tiles(points: &[GeoCoordinate], radius_m: f64) -> HashSet<Tile> {
+        let dlat = radius_m / 111_320.0;
+        let mut buffered = vec![];
+        for p in points {
+            let dlon = dlat / p.latitude.to_radians().cos();
+            for (dy, dx) in [
+                (0.0, 0.0),
+                (dlat, dlon),
+                (dlat, -dlon),
+                (-dlat, dlon),
+                (-dlat, -dlon),
+            ] {
+                buffered.push(GeoCoordinate {
+                    latitude: p.latitude + dy,
+                    longitude: p.longitude + dx,
+                });
+            }
+        }
+        Self::vertex_tiles(&buffered)
+    }
+
+    fn geo_to_screen_f64(tile: Tile, screen_size: (u32, u32), coord: GeoCoordinate) -> Point<f64> {
+        // In Mercator, as the imagery is, not linearly between the edges of
+        // the tile, which would be off by a pixel or so at the tile zoom.
+        let (x, y) = mercator::tile_xy(coord.latitude, coord.longitude, tile.zoom());
         // NOTE: latitude is vertical coordinate, +Y is down
         // longitude is horizontal coordinate, and +X is right
+        Point::new(
+            (x - tile.x() as f64) * screen_size.0 as f64,
+            (y - tile.y() as f64) * screen_size.1 as f64,
+        )
+    }
+
+    pub fn draw_polygon(&self, poly: &[GeoCoordinate], how: FeatureClass) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let poly = &self.registered(poly);
+        info!(target: logging::RENDER, "Drawing polygon {poly:?}");
+        if let Some(factor) = self.antialias {
+            return self.draw_supersampled_polygon(poly, &[], factor, how);
+        }
 
-        // println!("{lon_px} {lat_px}");
-        Point::new(x, y)
+        for tile in self.restrict(Self::polygon_tiles(poly)) {
+            debug!(target: logging::RENDER, "Polygon is included in: {tile:?}");
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            Self::fill_polygon(tile, &mut images.outline, poly, how);
+        }
+
+        Ok(())
+    }
+
+    /// Draws the part of `poly` in `tile` into its outline `img`.
+    fn fill_polygon(
+        tile: Tile,
+        img: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+        poly: &[GeoCoordinate],
+        how: FeatureClass,
+    ) {
+        let screen_size = (img.width(), img.height());
+        let tile_relative_poly = Self::tile_relative_polygon(tile, screen_size, poly);
+        // A footprint under a pixel collapses to a point, which imageproc
+        // does not take for a polygon.
+        if tile_relative_poly.len() < 3 {
+            return;
+        }
+        imageproc::drawing::draw_polygon_mut(
+            img,
+            &tile_relative_poly,
+            image::Rgb(colors()[how as usize]),
+        );
     }
 
-    pub fn draw_polygon(
-        &mut self,
+    /// Like `draw_polygon`, leaving the pixels in `holes` as they were.
+    pub fn draw_polygon_with_holes(
+        &self,
         poly: &[GeoCoordinate],
-        how: BuildingColor,
+        holes: &[Vec<GeoCoordinate>],
+        how: FeatureClass,
     ) -> anyhow::Result<()> {
-        info!("Drawing polygon {poly:?}");
+        let _span = timing::span(Stage::Rasterize);
+        let poly = &self.registered(poly);
+        let holes: Vec<_> = holes.iter().map(|h| self.registered(h)).collect();
+        if let Some(factor) = self.antialias {
+            return self.draw_supersampled_polygon(poly, &holes, factor, how);
+        }
+        for tile in self.restrict(Self::polygon_tiles(poly)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            let img = &mut images.outline;
+            let screen_size = (img.width(), img.height());
+            let (outer, holes) = Self::tile_relative_area(tile, screen_size, poly, &holes);
+            fill_with_holes(img, &outer, &holes, image::Rgb(colors()[how as usize]));
+        }
+        Ok(())
+    }
 
-        let tiles: HashSet<_> = poly
-            .iter()
-            .map(|v| {
-                let c =
-                    slippy_map_tiles::lat_lon_to_tile(v.latitude as f32, v.longitude as f32, ZOOM);
-                Tile::new(ZOOM, c.0, c.1).unwrap()
-            })
-            .collect();
+    /// Draws a polygon grown by `buffer_px` pixels on every side.
+    pub fn draw_buffered_polygon(
+        &self,
+        poly: &[GeoCoordinate],
+        buffer_px: u32,
+        how: FeatureClass,
+    ) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let poly = &self.registered(poly);
+        let color = image::Rgb(colors()[how as usize]);
+        // A pixel is about a meter at zoom 17, twice that leaves room for
+        // higher resolution tiles.
+        let mut tiles = Self::buffered_tiles(poly, 2.0 * buffer_px as f64);
+        tiles.extend(Self::polygon_tiles(poly));
+        for tile in self.restrict(tiles) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            let img = &mut images.outline;
+            let screen_size = (img.width(), img.height());
+            let tile_relative_poly = Self::tile_relative_polygon(tile, screen_size, poly);
+            if tile_relative_poly.len() >= 3 {
+                imageproc::drawing::draw_polygon_mut(img, &tile_relative_poly, color);
+            }
+            if buffer_px > 0 {
+                let ring: Vec<_> = poly
+                    .iter()
+                    .map(|c| Self::geo_to_screen_f64(tile, screen_size, *c))
+                    .collect();
+                stroke_polyline(img, &ring, buffer_px as f64, 0.0, color);
+            }
+        }
+        Ok(())
+    }
 
-        for tile in tiles {
-            debug!("Polygon is included in: {tile:?}");
-            self.dirty.insert(tile);
-            self.prepare_tile(tile)?;
-            let img = self.outlines.get_mut(&tile).unwrap();
+    /// Draws a filled disk of `radius_m` meters around `center`.
+    pub fn draw_disk(
+        &self,
+        center: GeoCoordinate,
+        radius_m: f64,
+        how: FeatureClass,
+    ) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let center = self.registered(&[center])[0];
+        for tile in self.restrict(Self::buffered_tiles(&[center], radius_m)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            let img = &mut images.outline;
             let screen_size = (img.width(), img.height());
+            let radius = (radius_m * Self::pixels_per_meter(tile, screen_size)).round() as i32;
+            let c = Self::geo_to_screen_coordinate(tile, screen_size, center);
+            imageproc::drawing::draw_filled_circle_mut(
+                img,
+                (c.x, c.y),
+                radius.max(1),
+                image::Rgb(colors()[how as usize]),
+            );
+        }
+        Ok(())
+    }
 
-            let mut tile_relative_poly: Vec<_> = poly
+    /// Draws a polyline buffered to `width_m` meters, with round joins.
+    pub fn draw_line(
+        &self,
+        line: &[GeoCoordinate],
+        width_m: f64,
+        how: FeatureClass,
+    ) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let line = &self.registered(line);
+        let color = image::Rgb(colors()[how as usize]);
+        for tile in self.restrict(Self::buffered_tiles(line, width_m / 2.0)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            let img = &mut images.outline;
+            let screen_size = (img.width(), img.height());
+            let half = (width_m / 2.0 * Self::pixels_per_meter(tile, screen_size)).max(0.5);
+            let points: Vec<_> = line
                 .iter()
-                .map(|c| Self::geo_to_screen_coordinate(tile, screen_size, *c))
+                .map(|c| Self::geo_to_screen_f64(tile, screen_size, *c))
                 .collect();
-            while tile_relative_poly.last().unwrap() == tile_relative_poly.first().unwrap() {
-                tile_relative_poly.pop().unwrap();
-            }
-            imageproc::drawing::draw_polygon_mut(
-                img,
-                &tile_relative_poly,
-                image::Rgb(COLOR_INDEX[how as usize]),
-            );
+            stroke_polyline(img, &points, half, 0.0, color);
         }
+        Ok(())
+    }
 
+    /// Like `draw_line`, but into the named channel with a raw value. With
+    /// `left_only` the stroke lies entirely to the left of the direction
+    /// of travel instead of being centered on the line.
+    pub fn draw_channel_line(
+        &self,
+        channel: &str,
+        line: &[GeoCoordinate],
+        width_m: f64,
+        value: u8,
+        left_only: bool,
+    ) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let line = &self.registered(line);
+        for tile in self.restrict(Self::buffered_tiles(line, width_m)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            let img = images.channel(channel);
+            let screen_size = (img.width(), img.height());
+            let half = (width_m / 2.0 * Self::pixels_per_meter(tile, screen_size)).max(0.5);
+            let points: Vec<_> = line
+                .iter()
+                .map(|c| Self::geo_to_screen_f64(tile, screen_size, *c))
+                .collect();
+            let shift = if left_only { half } else { 0.0 };
+            stroke_polyline(img, &points, half, shift, image::Luma([value]));
+        }
         Ok(())
     }
 
-    pub fn save(&mut self) {
-        warn!("Saving image cache...");
-        // for (tile, img) in self.tiles.iter().filter(|v| self.dirty.contains(v.0)) {
-        //     img.save(format!("tiles/{}-{}.jpg", tile.y(), tile.x()))
-        //         .unwrap();
-        // }
-        for (tile, img) in self.outlines.iter().filter(|v| self.dirty.contains(v.0)) {
-            img.save(format!("outlines/{}-{}.png", tile.y(), tile.x()))
-                .unwrap();
+    /// Draws the part of `poly` in `tile` into its channel `img`.
+    fn fill_channel_polygon(tile: Tile, img: &mut GrayImage, poly: &[GeoCoordinate], value: u8) {
+        let screen_size = (img.width(), img.height());
+        let tile_relative_poly = Self::tile_relative_polygon(tile, screen_size, poly);
+        if tile_relative_poly.len() < 3 {
+            return;
         }
-        self.dirty.clear();
+        imageproc::drawing::draw_polygon_mut(img, &tile_relative_poly, image::Luma([value]));
     }
 
-    pub fn load() -> Self {
-        warn!("Loading image cache...");
-        let mut cache = Self::default();
-
-        for name in std::fs::read_dir("tiles").unwrap() {
-            let name = name.unwrap();
-            let name = name.file_name();
-            let name = name.to_string_lossy();
-            // let img = image::io::Reader::open(format!("tiles/{name}"))
-            //     .unwrap()
-            //     .decode()
-            //     .unwrap();
-            let mut parts = name.strip_suffix(".jpg").unwrap().split("-");
-            let y = parts.next().unwrap().parse().unwrap();
-            let x = parts.next().unwrap().parse().unwrap();
-            let tile = Tile::new(ZOOM, x, y).unwrap();
-            cache.tiles.insert(tile, ());
-        }
-        for name in std::fs::read_dir("outlines").unwrap().collect::<Vec<_>>().into_iter().progress_with_style(
-                ProgressStyle::with_template(
-                    "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
-                )
-                .unwrap(),
-            ) {
-            let name = name.unwrap();
-            let name = name.file_name();
-            let name = name.to_string_lossy();
-            let img = image::io::Reader::open(format!("outlines/{name}"))
-                .unwrap()
-                .decode()
-                .unwrap();
-            let mut parts = name.strip_suffix(".png").unwrap().split("-");
-            let y = parts.next().unwrap().parse().unwrap();
-            let x = parts.next().unwrap().parse().unwrap();
-            let tile = Tile::new(ZOOM, x, y).unwrap();
-            cache.outlines.insert(tile, img.into_rgb8());
+    /// Like `draw_polygon_with_holes`, into the named channel.
+    pub fn draw_channel_polygon_with_holes(
+        &self,
+        channel: &str,
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+        value: u8,
+    ) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let poly = &self.registered(poly);
+        let holes: Vec<_> = holes.iter().map(|h| self.registered(h)).collect();
+        for tile in self.restrict(Self::polygon_tiles(poly)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            let img = images.channel(channel);
+            let screen_size = (img.width(), img.height());
+            let (outer, holes) = Self::tile_relative_area(tile, screen_size, poly, &holes);
+            fill_with_holes(img, &outer, &holes, image::Luma([value]));
         }
+        Ok(())
+    }
 
-        info!(
-            "Loaded {} tiles and {} outlines",
-            cache.tiles.len(),
-            cache.outlines.len()
-        );
+    /// Adds the share of every pixel that `poly` less its `holes` covers,
+    /// 0 to 255, into the named channel, measured on a grid of `factor` x
+    /// `factor` samples per pixel. Shares of neighbours add up, so a
+    /// shared wall is not left half empty.
+    pub fn draw_coverage_polygon(
+        &self,
+        channel: &str,
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+        factor: u32,
+    ) -> anyhow::Result<()> {
+        let _span = timing::span(Stage::Rasterize);
+        let poly = &self.registered(poly);
+        let holes: Vec<_> = holes.iter().map(|h| self.registered(h)).collect();
+        for tile in self.restrict(Self::polygon_tiles(poly)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            Self::add_coverage(tile, images.channel(channel), poly, &holes, factor);
+        }
 
-        cache
+        Ok(())
     }
-}
 
-fn fetch_outline_way(
-    cache: &mut ImageCache,
-    way: &Way,
-    nodes: &HashMap<i64, Node>,
-) -> anyhow::Result<()> {
-    if way.nodes.len() < 3 {
-        info!("This way has less than 3 nodes, ignoring");
-        return Ok(());
-    }
-    let nodes: Vec<_> = way.nodes.iter().map(|v| nodes.get(&v.0)).collect();
-    if !nodes.iter().all(|v| v.is_some()) {
-        warn!("This way does not have all nodes available");
-        return Ok(());
+    /// Adds the coverage of the part of `poly` in `tile` into its channel
+    /// `img`.
+    fn add_coverage(
+        tile: Tile,
+        img: &mut GrayImage,
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+        factor: u32,
+    ) {
+        let screen_size = (img.width(), img.height());
+        let Some(((x0, y0), shares)) = Self::coverage(tile, screen_size, poly, holes, factor)
+        else {
+            return;
+        };
+        for (x, y, share) in shares.enumerate_pixels() {
+            if share.0[0] > 0 {
+                let px = img.get_pixel_mut(x0 + x, y0 + y);
+                px.0[0] = px.0[0].saturating_add(share.0[0]);
+            }
+        }
     }
-    let coords: Vec<_> = nodes
-        .iter()
-        .map(|v| v.unwrap())
-        .map(|n| GeoCoordinate {
-            longitude: (n.decimicro_lon as f64) / 10_000_000.0,
-            latitude: (n.decimicro_lat as f64) / 10_000_000.0,
-        })
-        .collect();
 
-    let geo_poly = Polygon::new(
-        LineString::new(coords.iter().map(|v| (*v).into()).collect()),
-        vec![],
-    );
-    let area = geo_poly.geodesic_area_signed().abs();
-    info!("Area: {area} m^2");
-    if area < 100.0 {
-        cache.draw_polygon(&coords, BuildingColor::BuildingBelowAreaThreshold)?;
-    } else {
-        cache.draw_polygon(&coords, BuildingColor::Normal)?;
+    /// Like `draw_polygon_with_holes`, filling the pixels that the area
+    /// covers at least half of, measured on a grid of `factor` x `factor`
+    /// samples per pixel, for `--antialias`. The edge then follows the
+    /// outline instead of the truncated vertices of the scanline fill.
+    fn draw_supersampled_polygon(
+        &self,
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+        factor: u32,
+        how: FeatureClass,
+    ) -> anyhow::Result<()> {
+        for tile in self.restrict(Self::polygon_tiles(poly)) {
+            self.mark(tile);
+            let images = self.prepare_tile(tile)?;
+            let mut images = images.lock().unwrap();
+            Self::fill_supersampled(tile, &mut images.outline, poly, holes, factor, how);
+        }
+        Ok(())
     }
-    Ok(())
-}
 
-fn build_outlines(filename: &std::ffi::OsStr) {
-    println!("Loading...");
-    // let r = std::fs::File::open(&std::path::Path::new(filename)).unwrap();
-    // let len = r.metadata().unwrap().len();
-    // let r = ProgressFile::new(r, len);
-    // let mut pbf = osmpbfreader::OsmPbfReader::new(r);
-
-    // let mut nodes_all = HashMap::new();
-    // let mut nodes_only_buildings = HashMap::new();
-    // let mut ways_all = HashMap::new();
-    // let mut ways_buildings = HashMap::new();
-    // let mut relations_buildings = HashMap::new();
-
-    // for obj in pbf.par_iter().map(Result::unwrap) {
-    //     let is_building = obj.tags().contains_key("building");
-    //     match obj {
-    //         osmpbfreader::OsmObj::Node(node) => {
-    //             if is_building {
-    //                 nodes_only_buildings.insert(node.id.0, node.clone());
-    //             }
-    //             nodes_all.insert(node.id.0, node);
-    //         }
-    //         osmpbfreader::OsmObj::Way(way) => {
-    //             if is_building {
-    //                 ways_buildings.insert(way.id.0, way.clone());
-    //             }
-    //             ways_all.insert(way.id.0, way);
-    //         }
-    //         osmpbfreader::OsmObj::Relation(rel) => {
-    //             if is_building {
-    //                 relations_buildings.insert(rel.id.0, rel);
-    //             }
-    //         }
-    //     }
-    // }
-    println!("Loading imgs...");
-    // let mut cache = ImageCache::load();
-    println!("Done!");
-
-    let client = reqwest::blocking::Client::new();
-
-    let mut tiles = HashSet::new();
-    for name in std::fs::read_dir("tiles").unwrap().collect::<Vec<_>>().into_iter().progress_with_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
-        )
-        .unwrap(),
+    /// Draws the part of `poly` less its `holes` in `tile` into its
+    /// outline `img`, as `draw_supersampled_polygon` does.
+    fn fill_supersampled(
+        tile: Tile,
+        img: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+        factor: u32,
+        how: FeatureClass,
     ) {
-    let name = name.unwrap();
-    let name = name.file_name();
-    let name = name.to_string_lossy();
-    // let img = image::io::Reader::open(format!("outlines/{name}"))
-    //     .unwrap()
-    //     .decode()
-    //     .unwrap();
-    let mut parts = name.strip_suffix(".jpg").unwrap().split("-");
-    let y = parts.next().unwrap().parse().unwrap();
-    let x = parts.next().unwrap().parse().unwrap();
-    let tile = Tile::new(ZOOM, x, y).unwrap();
-    tiles.insert(tile);
-}
-
-    let download_tile = |tile: Tile| {
-        if tiles.contains(&tile) {
+        let color = image::Rgb(colors()[how as usize]);
+        let screen_size = (img.width(), img.height());
+        let Some(((x0, y0), shares)) = Self::coverage(tile, screen_size, poly, holes, factor)
+        else {
             return;
+        };
+        for (x, y, share) in shares.enumerate_pixels() {
+            if share.0[0] >= 128 {
+                img.put_pixel(x0 + x, y0 + y, color);
+            }
         }
-        let path = format!("https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{}/{}/{}", tile.zoom(), tile.y(), tile.x());
-        //let path = format!("https://core-sat.maps.yandex.net/tiles?l=sat&v=3.1124.0&x={}&y={}&z={}&scale=1&lang=ru_RU&client_id=yandex-web-maps", tile.x(), tile.y(), tile.zoom());
-
-        let tiledata = client.get(path).send().unwrap().bytes().unwrap().to_vec();
-        let tileimg = image::io::Reader::new(Cursor::new(tiledata))
-            .with_guessed_format()
-            .unwrap()
-            .decode()
-            .unwrap();
+    }
 
-        tileimg
-            .save(format!("tiles/{}-{}.jpg", tile.y(), tile.x()))
-            .unwrap();
-    };
+    /// Draws `footprints` into the tiles they cover: the tiles in
+    /// parallel, each by one thread, and the footprints of a tile in
+    /// order, so that they overlap as if drawn one after another. Returns
+    /// the tiles every footprint was drawn into, and why one of them
+    /// could not be if one could not.
+    pub fn draw_footprints(
+        &self,
+        footprints: &[Footprint],
+    ) -> Vec<(HashSet<Tile>, anyhow::Result<()>)> {
+        let registered: Vec<_> = footprints
+            .iter()
+            .map(|f| self.registered(&f.coords))
+            .collect();
+        let tiles: Vec<_> = registered
+            .iter()
+            .map(|coords| self.restrict(Self::polygon_tiles(coords)))
+            .collect();
+        let mut buckets: HashMap<Tile, Vec<usize>> = HashMap::new();
+        for (i, covered) in tiles.iter().enumerate() {
+            for tile in covered {
+                buckets.entry(*tile).or_default().push(i);
+            }
+        }
+        let failed: HashMap<Tile, String> = buckets
+            .into_par_iter()
+            .filter_map(|(tile, bucket)| {
+                let _span = timing::span(Stage::Rasterize);
+                self.mark_dirty(tile);
+                let images = match self.prepare_tile(tile) {
+                    Ok(images) => images,
+                    Err(why) => return Some((tile, format!("{why:#}"))),
+                };
+                let mut images = images.lock().unwrap();
+                for i in bucket {
+                    let (footprint, coords) = (&footprints[i], &registered[i]);
+                    match self.antialias {
+                        Some(factor) => Self::fill_supersampled(
+                            tile,
+                            &mut images.outline,
+                            coords,
+                            &[],
+                            factor,
+                            footprint.class,
+                        ),
+                        None => {
+                            Self::fill_polygon(tile, &mut images.outline, coords, footprint.class)
+                        }
+                    }
+                    if let Some(label) = footprint.roof {
+                        Self::fill_channel_polygon(tile, images.channel("roofs"), coords, label);
+                    }
+                    if let Some(factor) = footprint.coverage {
+                        Self::add_coverage(
+                            tile,
+                            images.channel(COVERAGE_CHANNEL),
+                            coords,
+                            &[],
+                            factor,
+                        );
+                    }
+                }
+                None
+            })
+            .collect();
+        tiles
+            .into_iter()
+            .map(|mut covered| {
+                let why = covered.iter().find_map(|t| failed.get(t)).cloned();
+                covered.retain(|t| !failed.contains_key(t));
+                match why {
+                    Some(why) => (covered, Err(anyhow::anyhow!(why))),
+                    None => (covered, Ok(())),
+                }
+            })
+            .collect()
+    }
 
-    let buf = 0.5;
-    let interest_bbox =
-        slippy_map_tiles::BBox::new(55.93 + buf, 37.3 - buf, 55.56 - buf, 37.9 + buf).unwrap();
+    /// The share of every pixel that `poly` less its `holes` covers, 0 to
+    /// 255, measured on a grid of `factor` x `factor` samples per pixel.
+    /// Only the pixels of the polygon's bounding box are measured; their
+    /// top-left one comes first. `None` if the box is off the tile.
+    fn coverage(
+        tile: Tile,
+        screen_size: (u32, u32),
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+        factor: u32,
+    ) -> Option<((u32, u32), GrayImage)> {
+        let f = factor as f64;
+        let points: Vec<_> = poly
+            .iter()
+            .map(|c| {
+                let p = Self::geo_to_screen_f64(tile, screen_size, *c);
+                (p.x * f, p.y * f)
+            })
+            .collect();
+        let (mut lo, mut hi) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for &(x, y) in &points {
+            lo = (lo.0.min(x), lo.1.min(y));
+            hi = (hi.0.max(x), hi.1.max(y));
+        }
+        let first = |v: f64| (v / f).floor().max(0.0) as u32;
+        let last = |v: f64, size: u32| ((v / f).floor() + 1.0).min(size as f64) as u32;
+        let (x0, x1) = (first(lo.0), last(hi.0, screen_size.0));
+        let (y0, y1) = (first(lo.1), last(hi.1, screen_size.1));
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+        let mut samples = GrayImage::new((x1 - x0) * factor, (y1 - y0) * factor);
+        let local = |ring: &[GeoCoordinate]| {
+            let mut local: Vec<_> = ring
+                .iter()
+                .map(|c| {
+                    let p = Self::geo_to_screen_f64(tile, screen_size, *c);
+                    Point::new(
+                        (p.x * f - (x0 * factor) as f64) as i32,
+                        (p.y * f - (y0 * factor) as f64) as i32,
+                    )
+                })
+                .collect();
+            while local.len() > 1 && local.last() == local.first() {
+                local.pop();
+            }
+            local
+        };
+        let holes: Vec<_> = holes
+            .iter()
+            .map(|h| local(h))
+            .filter(|h| h.len() >= 3)
+            .collect();
+        fill_with_holes(&mut samples, &local(poly), &holes, image::Luma([1]));
+        let shares = GrayImage::from_fn(x1 - x0, y1 - y0, |x, y| {
+            let mut covered = 0u32;
+            for sy in 0..factor {
+                for sx in 0..factor {
+                    covered += samples.get_pixel(x * factor + sx, y * factor + sy).0[0] as u32;
+                }
+            }
+            let share = (covered * 255 + factor * factor / 2) / (factor * factor);
+            image::Luma([share as u8])
+        });
+        Some(((x0, y0), shares))
+    }
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(256)
-        .build_global()
-        .unwrap();
-    let iter = {
-        let top_left_tile = lat_lon_to_tile(interest_bbox.top(), interest_bbox.left(), ZOOM);
-        let bottom_right_tile =
-            lat_lon_to_tile(interest_bbox.bottom(), interest_bbox.right(), ZOOM);
+    /// `tile_relative_polygon` of an outer ring and its holes, leaving out
+    /// holes under a pixel.
+    fn tile_relative_area(
+        tile: Tile,
+        screen_size: (u32, u32),
+        poly: &[GeoCoordinate],
+        holes: &[Vec<GeoCoordinate>],
+    ) -> (Vec<Point<i32>>, Vec<Vec<Point<i32>>>) {
+        let outer = Self::tile_relative_polygon(tile, screen_size, poly);
+        let holes = holes
+            .iter()
+            .map(|h| Self::tile_relative_polygon(tile, screen_size, h))
+            .filter(|h| h.len() >= 3)
+            .collect();
+        (outer, holes)
+    }
+
+    /// Moves coordinates by the provider offset so they line up with the
+    /// imagery.
+    fn registered(&self, coords: &[GeoCoordinate]) -> Vec<GeoCoordinate> {
+        register(coords, self.offset_m)
+    }
 
-        (top_left_tile.0..=bottom_right_tile.0)
+    /// Applies `f` to every outline and marks them all for saving. Evicted
+    /// outlines are read back, saved and evicted again one at a time.
+    pub fn for_each_outline(
+        &self,
+        mut f: impl FnMut(Tile, &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>),
+    ) {
+        self.for_each_loaded_outline(&mut f);
+        let evicted = std::mem::take(&mut *self.evicted.lock().unwrap());
+        for tile in evicted {
+            let images = match self.reload(tile) {
+                Ok(images) => images,
+                Err(why) => {
+                    warn!("error reloading outline {tile:?}: {why}");
+                    continue;
+                }
+            };
+            f(tile, &mut images.lock().unwrap().outline);
+            self.dirty.lock().unwrap().insert(tile);
+            self.evict();
+        }
+    }
+
+    /// Like `for_each_outline`, but only for the outlines in memory.
+    pub fn for_each_loaded_outline(
+        &self,
+        mut f: impl FnMut(Tile, &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>),
+    ) {
+        let loaded: Vec<_> = self
+            .images
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(tile, images)| (*tile, images.clone()))
+            .collect();
+        for (tile, images) in loaded {
+            f(tile, &mut images.lock().unwrap().outline);
+            self.dirty.lock().unwrap().insert(tile);
+        }
+    }
+
+    /// Forgets a pruned unit, so that its tiles count as never downloaded.
+    pub fn forget(&self, unit: Tile) {
+        let mut tiles = self.tiles.write().unwrap();
+        let mut evicted = self.evicted.lock().unwrap();
+        for tile in units::tiles(unit) {
+            tiles.remove(&tile);
+            evicted.remove(&tile);
+        }
+    }
+
+    /// Tiles this thread drew into since it last asked.
+    pub fn take_touched(&self) -> HashSet<Tile> {
+        let id = std::thread::current().id();
+        self.touched.lock().unwrap().remove(&id).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        warn!("Saving image cache...");
+        // for (tile, img) in self.tiles.iter().filter(|v| self.dirty.contains(v.0)) {
+        //     img.save(format!("tiles/{}-{}.jpg", tile.y(), tile.x()))
+        //         .unwrap();
+        // }
+        for name in &self.channels {
+            std::fs::create_dir_all(self.out_dir.join(name)).unwrap();
+        }
+        let saved_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let started = std::time::Instant::now();
+        let dirty: Vec<_> = self.dirty.lock().unwrap().drain().collect();
+        let count = dirty.len();
+        metrics::TILES_DIRTY.set(0);
+        let dirty: Vec<_> = {
+            let images = self.images.read().unwrap();
+            dirty
+                .into_iter()
+                .filter_map(|tile| Some((tile, images.get(&tile)?.clone())))
+                .collect()
+        };
+        let encoded: Vec<_> = dirty
             .into_par_iter()
-            .flat_map(move |x| {
-                (top_left_tile.1..=bottom_right_tile.1)
-                    .into_par_iter()
-                    .map(move |y| (x, y))
+            .map(|(tile, images)| {
+                let mut images = images.lock().unwrap();
+                let name = paths::stem(tile);
+                let mut files = vec![];
+                let data = {
+                    let _span = timing::span(Stage::Encode);
+                    self.formats.masks.encode(&images.outline).unwrap()
+                };
+                files.push((
+                    paths::relative_tile_file(workspace::OUTLINES, tile, ".png"),
+                    data,
+                ));
+                if self.index_masks {
+                    let data = {
+                        let _span = timing::span(Stage::Encode);
+                        store::encode_png(&masks::index_mask(&images.outline)).unwrap()
+                    };
+                    files.push((paths::relative_tile_file(masks::DIR, tile, ".png"), data));
+                }
+                let building_px = images
+                    .outline
+                    .pixels()
+                    .filter(|p| classes::is_building_pixel(p.0))
+                    .count() as u64;
+                for channel in &self.channels {
+                    let format = self.formats.channel(channel);
+                    let data = {
+                        let _span = timing::span(Stage::Encode);
+                        format.encode(images.channel(channel)).unwrap()
+                    };
+                    files.push((paths::relative_tile_file(channel, tile, format.ext()), data));
+                }
+                (name, files, building_px)
+            })
+            .collect();
+        let writer = store::BatchWriter::new(&self.out_dir);
+        let mut entries = vec![];
+        for (name, files, building_px) in encoded {
+            let mut names = vec![];
+            for (file, data) in files {
+                writer.submit(&file, data);
+                names.push(file);
+            }
+            entries.push((name, names, building_px));
+        }
+        writer.finish().unwrap();
+        let _span = timing::span(Stage::Io);
+        for (name, files, building_px) in entries {
+            // Only recorded once the files are complete.
+            if let Some(manifest) = &self.manifest {
+                manifest
+                    .lock()
+                    .unwrap()
+                    .record(manifest::TileEntry {
+                        tile: name,
+                        files,
+                        building_px,
+                        saved_at,
+                    })
+                    .unwrap();
+            }
+        }
+        if let Some(manifest) = &self.manifest {
+            manifest.lock().unwrap().flush().unwrap();
+        }
+        metrics::TILES_SAVED.add(count as u64);
+        info!("Saved {count} tiles in {:.2?}", started.elapsed());
+    }
+
+    /// Loads the cache with outlines and channels kept under `out_dir`,
+    /// which is created if missing. With `lazy` the outlines stay on disk
+    /// until they are drawn into.
+    pub fn load(out_dir: &Path, lazy: bool) -> Self {
+        warn!("Loading image cache...");
+        let _span = timing::span(Stage::Io);
+        let offset_m = provider::Providers::load()
+            .unwrap()
+            .offset_m(&provider::source().name);
+        if offset_m != [0.0, 0.0] {
+            info!("Drawing with an offset of {offset_m:?} m");
+        }
+        let outlines = out_dir.join(workspace::OUTLINES);
+        std::fs::create_dir_all(&outlines).unwrap();
+        let mut cache = Self {
+            out_dir: out_dir.to_owned(),
+            offset_m,
+            manifest: Some(Mutex::new(manifest::OutputManifest::open(out_dir).unwrap())),
+            blobs: dedup::Store::detect().unwrap(),
+            formats: formats::Formats::load().unwrap(),
+            ..Self::default()
+        };
+
+        cache.tiles = RwLock::new(list_tiles(workspace::TILES, ".jpg").into_iter().collect());
+        let mut names = list_tiles(&outlines, ".png");
+        if lazy {
+            // Read back when needed, like evicted outlines.
+            cache.evicted.get_mut().unwrap().extend(names.drain(..));
+        }
+        let progress = logging::progress_bar(
+            names.len() as u64,
+            ProgressStyle::with_template(
+                "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
+            )
+            .unwrap(),
+        );
+        for tile in names.into_iter().progress_with(progress) {
+            let img = image::io::Reader::open(paths::tile_file(&outlines, tile, ".png"))
+                .unwrap()
+                .decode()
+                .unwrap();
+            cache.insert(tile, TileImages::new(img.into_rgb8()));
+        }
+
+        info!(
+            "Loaded {} tiles and {} outlines",
+            cache.tiles.read().unwrap().len(),
+            cache.images.read().unwrap().len()
+        );
+
+        cache
+    }
+}
+
+/// Moves coordinates by `offset_m`, meters east and north.
+fn register(coords: &[GeoCoordinate], offset_m: [f64; 2]) -> Vec<GeoCoordinate> {
+    let [east, north] = offset_m;
+    coords
+        .iter()
+        .map(|c| GeoCoordinate {
+            latitude: c.latitude + north / 111_320.0,
+            longitude: c.longitude + east / (111_320.0 * c.latitude.to_radians().cos()),
+        })
+        .collect()
+}
+
+/// Fills `poly` but not its `holes`, leaving what is under a hole as it
+/// was: drawn into a mask first, as painting the holes over would wipe
+/// out what was drawn before.
+fn fill_with_holes<C>(
+    canvas: &mut C,
+    poly: &[Point<i32>],
+    holes: &[Vec<Point<i32>>],
+    color: C::Pixel,
+) where
+    C: imageproc::drawing::Canvas,
+{
+    if poly.len() < 3 {
+        return;
+    }
+    if holes.is_empty() {
+        imageproc::drawing::draw_polygon_mut(canvas, poly, color);
+        return;
+    }
+    let (w, h) = canvas.dimensions();
+    let mut mask = GrayImage::new(w, h);
+    imageproc::drawing::draw_polygon_mut(&mut mask, poly, image::Luma([1]));
+    for hole in holes {
+        imageproc::drawing::draw_polygon_mut(&mut mask, hole, image::Luma([0]));
+    }
+    for (x, y, px) in mask.enumerate_pixels() {
+        if px.0[0] == 1 {
+            canvas.draw_pixel(x, y, color);
+        }
+    }
+}
+
+/// Strokes a polyline given in pixel coordinates with round joins. The
+/// stroke is `2 * half` pixels wide and moved `shift` pixels to the left of
+/// the direction of travel (screen coordinates, +Y down).
+fn stroke_polyline<C>(canvas: &mut C, points: &[Point<f64>], half: f64, shift: f64, color: C::Pixel)
+where
+    C: imageproc::drawing::Canvas,
+{
+    let mut joins = vec![];
+    for seg in points.windows(2) {
+        let (a, b) = (seg[0], seg[1]);
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let len = (dx * dx + dy * dy).sqrt();
+        if len < f64::EPSILON {
+            continue;
+        }
+        // Unit normal pointing left of travel.
+        let (lx, ly) = (dy / len, -dx / len);
+        let (cx, cy) = (lx * shift, ly * shift);
+        let (nx, ny) = (lx * half, ly * half);
+        let quad = [
+            Point::new((a.x + cx + nx) as i32, (a.y + cy + ny) as i32),
+            Point::new((b.x + cx + nx) as i32, (b.y + cy + ny) as i32),
+            Point::new((b.x + cx - nx) as i32, (b.y + cy - ny) as i32),
+            Point::new((a.x + cx - nx) as i32, (a.y + cy - ny) as i32),
+        ];
+        if quad[0] != quad[3] {
+            imageproc::drawing::draw_polygon_mut(canvas, &quad, color);
+        }
+        joins.push((a.x + cx, a.y + cy));
+        joins.push((b.x + cx, b.y + cy));
+    }
+    if half >= 1.0 {
+        for (x, y) in joins {
+            imageproc::drawing::draw_filled_circle_mut(
+                canvas,
+                (x as i32, y as i32),
+                half as i32,
+                color,
+            );
+        }
+    }
+}
+
+/// Coordinates of a way's nodes, or `None` if some node is not in `nodes`.
+fn way_coords(way: &Way, nodes: &nodes::Nodes) -> Option<Vec<GeoCoordinate>> {
+    let _span = timing::span(Stage::Resolve);
+    way.nodes.iter().map(|v| nodes.coord(v.0)).collect()
+}
+
+/// Tiles stored in `dir` as `{y}-{x}{ext}`. Other files are ignored.
+fn list_tiles(dir: impl AsRef<Path>, ext: &str) -> Vec<Tile> {
+    paths::list_tiles(dir, ext)
+}
+
+/// Channel of `--coverage`.
+const COVERAGE_CHANNEL: &str = "coverage";
+
+struct RenderOptions {
+    /// Where `outlines/` and the channel directories are written.
+    out_dir: PathBuf,
+    roof_channel: bool,
+    mask_format: masks::MaskMode,
+    coverage: Option<u32>,
+    antialias: Option<u32>,
+    ignore_small: Option<u32>,
+    classes: ClassOptions,
+    lines: lines::LineOptions,
+    noise: noise::NoiseOptions,
+    postprocess: postprocess::PostprocessOptions,
+    rings: rings::RingOptions,
+    memory: memory::MemoryOptions,
+    units: units::UnitOptions,
+    /// Width of the imagery tiles, for sizes in pixels.
+    tile_px: u32,
+}
+
+/// A building way resolved and classified, ready to draw.
+struct Footprint {
+    coords: Vec<GeoCoordinate>,
+    class: FeatureClass,
+    /// Label in the `roofs` channel, with `--roof-channel`.
+    roof: Option<u8>,
+    /// Samples per pixel side of `--coverage`, for buildings.
+    coverage: Option<u32>,
+}
+
+/// Resolves one building for drawing. The flag is `false` if the way had
+/// to be skipped because its geometry is unusable; there is nothing to
+/// draw then, nor for a building left out on purpose.
+fn way_footprint(
+    way: &Way,
+    nodes: &nodes::Nodes,
+    opts: &RenderOptions,
+    state: &mut RenderState,
+) -> (bool, Option<Footprint>) {
+    if way.nodes.len() < 3 {
+        info!("This way has less than 3 nodes, ignoring");
+        return (false, None);
+    }
+    let Some(coords) = way_coords(way, nodes) else {
+        warn!("This way does not have all nodes available");
+        return (false, None);
+    };
+    let Some(coords) = rings::close(&opts.rings, &mut state.ring_stats, way, coords) else {
+        warn!("Way {} is not closed, ignoring", way.id.0);
+        return (false, None);
+    };
+
+    let Some(class) = footprint_class(&way.tags, &ring_area(&coords), &opts.classes, opts.tile_px)
+    else {
+        // Left out on purpose, not for its geometry, so not skipped.
+        state.too_small.insert(way.id.into());
+        return (true, None);
+    };
+    if opts.ignore_small.is_some() && class == FeatureClass::BuildingBelowAreaThreshold {
+        // Already drawn by `fetch_ignore_way`.
+        return (true, None);
+    }
+    let (class, coords) = if opts.noise.enabled() {
+        match noise::perturb(
+            &opts.noise,
+            &mut state.noise_stats,
+            way.id.0,
+            class,
+            &coords,
+        ) {
+            Some(perturbed) => perturbed,
+            None => return (true, None),
+        }
+    } else {
+        (class, coords)
+    };
+    let roof = opts.roof_channel.then(|| {
+        let shape = way.tags.get("roof:shape").map(|v| v.as_str());
+        attributes::label(attributes::ROOF_SHAPES, shape)
+    });
+    let coverage = opts.coverage.filter(|_| classes::is_building(class));
+    (
+        true,
+        Some(Footprint {
+            coords,
+            class,
+            roof,
+            coverage,
+        }),
+    )
+}
+
+/// The area of a closed way, for `footprint_class`.
+fn ring_area(coords: &[GeoCoordinate]) -> MultiPolygon<f64> {
+    MultiPolygon::new(vec![Polygon::new(geometry::line_string(coords), vec![])])
+}
+
+/// Class of a building, telling small buildings apart by area in square
+/// meters and, with `--small-building-px`, in pixels of the tiles, and
+/// excluding those over `--max-area`. `None` for a footprint under
+/// `--min-footprint-px`, too small to show, or one `--drop-excluded`.
+fn footprint_class(
+    tags: &osmpbfreader::Tags,
+    footprint: &MultiPolygon<f64>,
+    opts: &ClassOptions,
+    tile_px: u32,
+) -> Option<FeatureClass> {
+    let area = geometry::footprint_area(footprint);
+    // The scale changes with latitude, and a footprint is small enough
+    // for it not to change across it.
+    let c = GeoCoordinate::from(footprint.0[0].exterior().0[0]);
+    let (x, y) = mercator::tile_at(c.latitude, c.longitude, zoom());
+    let tile = Tile::new(zoom(), x, y)?;
+    let area_px = area * ImageCache::pixels_per_meter(tile, (tile_px, tile_px)).powi(2);
+    info!("Area: {area} m^2, {area_px:.1} px");
+    if opts.min_footprint_px.is_some_and(|min| area_px < min) {
+        return None;
+    }
+    let class = classes::building_class(tags, opts)?;
+    if class != FeatureClass::BuildingHasExcludedTags && opts.max_area.is_some_and(|max| area > max)
+    {
+        return opts.excluded();
+    }
+    let small = area < opts.min_area || opts.small_building_px.is_some_and(|px| area_px < px);
+    if class == FeatureClass::Normal && small {
+        Some(FeatureClass::BuildingBelowAreaThreshold)
+    } else {
+        Some(class)
+    }
+}
+
+/// Marks a small building and `buffer_px` pixels around it as ignored.
+/// Runs before all other buildings so that they win where the buffer
+/// reaches into them.
+fn fetch_ignore_way(
+    cache: &ImageCache,
+    way: &Way,
+    nodes: &nodes::Nodes,
+    opts: &RenderOptions,
+    buffer_px: u32,
+) -> anyhow::Result<()> {
+    if way.nodes.len() < 3 {
+        return Ok(());
+    }
+    let Some(coords) = way_coords(way, nodes) else {
+        return Ok(());
+    };
+    // Counted when the way itself is drawn.
+    let Some(coords) = rings::close(&opts.rings, &mut Default::default(), way, coords) else {
+        return Ok(());
+    };
+    // Footprints too small to show are ignored along with the small ones.
+    if matches!(
+        footprint_class(&way.tags, &ring_area(&coords), &opts.classes, opts.tile_px),
+        None | Some(FeatureClass::BuildingBelowAreaThreshold)
+    ) {
+        cache.draw_buffered_polygon(&coords, buffer_px, FeatureClass::Ignore)?;
+    }
+    Ok(())
+}
+
+/// A building relation assembled into polygons, ready to draw.
+struct BuildingArea {
+    relation: Relation,
+    /// Empty if no outer ring closed.
+    area: MultiPolygon<f64>,
+}
+
+/// The building relations of `pbf` with their member ways resolved, for
+/// `render-outlines --relations`.
+fn load_building_areas(pbf: &std::ffi::OsStr) -> anyhow::Result<Vec<BuildingArea>> {
+    let loaded = geometry::load_building_relations(pbf)?;
+    let mut report = geometry::MemberReport::default();
+    let mut areas = vec![];
+    for relation in loaded.relations {
+        if parts::drawn_as_parts(&relation, &loaded.ways) {
+            continue;
+        }
+        let rings = geometry::relation_rings(&relation, &loaded.ways, &loaded.all_relations);
+        report.add(&rings);
+        let area = geometry::rings_to_multipolygon(&rings, &loaded.nodes);
+        areas.push(BuildingArea { relation, area });
+    }
+    report.log();
+    Ok(areas)
+}
+
+fn ring_coords(ring: &LineString<f64>) -> Vec<GeoCoordinate> {
+    ring.coords().map(|c| GeoCoordinate::from(*c)).collect()
+}
+
+/// Draws a building relation with its inner rings as holes. Returns
+/// `false` if no outer ring closed. `--noise` leaves relations as they
+/// are, it perturbs single rings.
+fn fetch_outline_relation(
+    cache: &ImageCache,
+    area: &BuildingArea,
+    opts: &RenderOptions,
+    state: &mut RenderState,
+) -> anyhow::Result<bool> {
+    if area.area.0.is_empty() {
+        warn!(
+            "Relation {} has no closed outer ring, ignoring",
+            area.relation.id.0
+        );
+        return Ok(false);
+    }
+    let tags = &area.relation.tags;
+    let Some(class) = footprint_class(tags, &area.area, &opts.classes, opts.tile_px) else {
+        state.too_small.insert(area.relation.id.into());
+        return Ok(true);
+    };
+    if opts.ignore_small.is_some() && class == FeatureClass::BuildingBelowAreaThreshold {
+        // Already drawn by `fetch_ignore_relation`.
+        return Ok(true);
+    }
+    let roof = opts.roof_channel.then(|| {
+        let shape = tags.get("roof:shape").map(|v| v.as_str());
+        attributes::label(attributes::ROOF_SHAPES, shape)
+    });
+    for poly in &area.area {
+        let outer = ring_coords(poly.exterior());
+        let holes: Vec<_> = poly.interiors().iter().map(ring_coords).collect();
+        cache.draw_polygon_with_holes(&outer, &holes, class)?;
+        if let Some(label) = roof {
+            cache.draw_channel_polygon_with_holes("roofs", &outer, &holes, label)?;
+        }
+        if let Some(factor) = opts.coverage.filter(|_| classes::is_building(class)) {
+            cache.draw_coverage_polygon(COVERAGE_CHANNEL, &outer, &holes, factor)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Like `fetch_ignore_way`, for a relation. Its holes are ignored too.
+fn fetch_ignore_relation(
+    cache: &ImageCache,
+    area: &BuildingArea,
+    opts: &RenderOptions,
+    buffer_px: u32,
+) -> anyhow::Result<()> {
+    if area.area.0.is_empty() {
+        return Ok(());
+    }
+    if matches!(
+        footprint_class(&area.relation.tags, &area.area, &opts.classes, opts.tile_px),
+        None | Some(FeatureClass::BuildingBelowAreaThreshold)
+    ) {
+        for poly in &area.area {
+            let outer = ring_coords(poly.exterior());
+            cache.draw_buffered_polygon(&outer, buffer_px, FeatureClass::Ignore)?;
+        }
+    }
+    Ok(())
+}
+
+/// A non-building object resolved to coordinates, ready to draw.
+struct Feature {
+    id: osmpbfreader::OsmId,
+    class: FeatureClass,
+    shape: Shape,
+    coords: Vec<GeoCoordinate>,
+}
+
+fn collect_features(osm: &OsmData, opts: &ClassOptions) -> Vec<Feature> {
+    let mut features = vec![];
+    for node in osm.nodes_features.values() {
+        if let Some(class) = classes::feature_class(&node.tags, opts) {
+            features.push(Feature {
+                id: node.id.into(),
+                class,
+                shape: classes::shape(&node.tags, opts),
+                coords: vec![geometry::node_coord(node)],
+            });
+        }
+    }
+    for way in osm.ways_features.values() {
+        let Some(class) = classes::feature_class(&way.tags, opts) else {
+            continue;
+        };
+        let Some(coords) = way_coords(way, &osm.nodes_all) else {
+            warn!("Way {} does not have all nodes available", way.id.0);
+            continue;
+        };
+        features.push(Feature {
+            id: way.id.into(),
+            class,
+            shape: classes::shape(&way.tags, opts),
+            coords,
+        });
+    }
+    features.sort_by_key(|f| (classes::draw_order(f.class), f.id.inner_id()));
+    features
+}
+
+/// Renders a non-building object such as a construction site or a tree.
+/// Returns `false` if it had too few nodes to draw.
+fn fetch_outline_feature(cache: &ImageCache, feature: &Feature) -> anyhow::Result<bool> {
+    match feature.shape {
+        Shape::Area if feature.coords.len() >= 3 => {
+            cache.draw_polygon(&feature.coords, feature.class)?
+        }
+        Shape::Area => return Ok(false),
+        Shape::Line { width_m } => cache.draw_line(&feature.coords, width_m, feature.class)?,
+        Shape::Disk { radius_m } => cache.draw_disk(feature.coords[0], radius_m, feature.class)?,
+    }
+    Ok(true)
+}
+
+/// Objects to draw in one pass, grouped by when they are drawn.
+#[derive(Default)]
+struct Batch<'a> {
+    lines: Vec<&'a lines::LineFeature>,
+    below: Vec<&'a Feature>,
+    ways: Vec<&'a Way>,
+    relations: Vec<&'a BuildingArea>,
+    above: Vec<&'a Feature>,
+}
+
+/// What a run accumulates across batches.
+struct RenderState {
+    index: index::TileIndex,
+    noise_stats: noise::NoiseStats,
+    ring_stats: rings::RingStats,
+    /// Buildings under `--min-footprint-px`, or left out by `--drop-excluded`.
+    too_small: HashSet<osmpbfreader::OsmId>,
+    postprocess_stats: postprocess::PostprocessStats,
+    outcome: outcome::RunOutcome,
+    /// Objects already in `outcome`.
+    counted: HashSet<osmpbfreader::OsmId>,
+    monitor: memory::Monitor,
+    /// What is saved, without work units, which are resumed whole.
+    journal: Option<journal::Journal>,
+}
+
+impl RenderState {
+    /// Counts an object once, however many work units it is drawn in.
+    fn count(&mut self, id: osmpbfreader::OsmId, result: anyhow::Result<bool>) {
+        if self.counted.insert(id) {
+            self.outcome.count(result);
+        } else if let Err(why) = result {
+            info!("error fetching outline: {why}")
+        }
+    }
+
+    /// Whether an interrupted run already drew and saved `id` in `pass`.
+    /// It is counted and indexed again, as that run ended before both
+    /// were saved.
+    fn resumed(&mut self, pass: Pass, id: osmpbfreader::OsmId) -> bool {
+        let Some(tiles) = self.journal.as_ref().and_then(|j| j.saved(pass, id)) else {
+            return false;
+        };
+        if pass == Pass::Draw {
+            self.count(id, Ok(true));
+        }
+        self.index.insert(id, tiles);
+        true
+    }
+
+    /// Indexes the tiles `id` drew into and journals them with the next
+    /// save if it was drawn.
+    fn drawn(&mut self, pass: Pass, id: osmpbfreader::OsmId, drawn: bool, tiles: HashSet<Tile>) {
+        if let Some(journal) = self.journal.as_mut().filter(|_| drawn) {
+            journal.drawn(pass, id, &tiles);
+        }
+        self.index.insert(id, tiles);
+    }
+
+    /// Saves the cache and journals what it holds.
+    fn save(&mut self, cache: &ImageCache) -> anyhow::Result<()> {
+        cache.save();
+        match &mut self.journal {
+            Some(journal) => journal.commit(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Buildings resolved and then drawn together, their tiles in parallel,
+/// between two saves.
+const DRAW_CHUNK: usize = 1000;
+
+/// Draws a batch: lines, features underneath buildings, buildings and
+/// the features on top of them.
+fn draw_batch(
+    cache: &ImageCache,
+    state: &mut RenderState,
+    batch: &Batch,
+    osm: &OsmData,
+    opts: &RenderOptions,
+) -> anyhow::Result<()> {
+    for line in &batch.lines {
+        if let Err(why) = cache.draw_channel_line(
+            lines::CHANNEL,
+            &line.coords,
+            opts.lines.line_width,
+            line.value,
+            line.left_only,
+        ) {
+            info!("error drawing line: {why}")
+        }
+    }
+    // Lines are not OSM objects of their own, keep them out of the index.
+    cache.take_touched();
+
+    for feature in &batch.below {
+        draw_feature(cache, state, feature);
+    }
+
+    if let Some(buffer_px) = opts.ignore_small {
+        for way in &batch.ways {
+            if state.resumed(Pass::Ignore, way.id.into()) {
+                continue;
+            }
+            let result = fetch_ignore_way(cache, way, &osm.nodes_all, opts, buffer_px);
+            if let Err(why) = &result {
+                info!("error fetching outline: {why}")
+            };
+            state.drawn(
+                Pass::Ignore,
+                way.id.into(),
+                result.is_ok(),
+                cache.take_touched(),
+            );
+        }
+        for area in &batch.relations {
+            let id = area.relation.id.into();
+            if state.resumed(Pass::Ignore, id) {
+                continue;
+            }
+            let result = fetch_ignore_relation(cache, area, opts, buffer_px);
+            if let Err(why) = &result {
+                info!("error fetching outline: {why}")
+            };
+            state.drawn(Pass::Ignore, id, result.is_ok(), cache.take_touched());
+        }
+    }
+    let progress = logging::progress_bar(
+        batch.ways.len() as u64,
+        ProgressStyle::with_template(
+            "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
+        )
+        .unwrap(),
+    );
+    for chunk in batch.ways.chunks(DRAW_CHUNK) {
+        let mut pending = vec![];
+        let mut footprints = vec![];
+        for way in chunk {
+            if state.resumed(Pass::Draw, way.id.into()) {
+                continue;
+            }
+            match way_footprint(way, &osm.nodes_all, opts, state) {
+                (usable, None) => {
+                    state.count(way.id.into(), Ok(usable));
+                    state.drawn(Pass::Draw, way.id.into(), usable, HashSet::new());
+                }
+                (_, Some(footprint)) => {
+                    pending.push(way.id.into());
+                    footprints.push(footprint);
+                }
+            }
+        }
+        let drawn = cache.draw_footprints(&footprints);
+        for (id, (tiles, result)) in pending.into_iter().zip(drawn) {
+            let drawn = result.is_ok();
+            state.count(id, result.map(|()| true));
+            state.drawn(Pass::Draw, id, drawn, tiles);
+        }
+        progress.inc(chunk.len() as u64);
+        state.save(cache)?;
+        if let Err(why) = state.monitor.check("buildings", cache) {
+            if let Err(why) = state.index.save(&opts.out_dir) {
+                warn!("error saving tile index: {why}")
+            }
+            return Err(why);
+        }
+    }
+    progress.finish();
+    for area in &batch.relations {
+        let id = area.relation.id.into();
+        if state.resumed(Pass::Draw, id) {
+            continue;
+        }
+        let result = fetch_outline_relation(cache, area, opts, state);
+        let drawn = matches!(result, Ok(true));
+        state.count(id, result);
+        state.drawn(Pass::Draw, id, drawn, cache.take_touched());
+    }
+    memory::report("buildings");
+
+    for feature in &batch.above {
+        draw_feature(cache, state, feature);
+    }
+    Ok(())
+}
+
+/// Draws a feature of a batch unless an interrupted run already did.
+fn draw_feature(cache: &ImageCache, state: &mut RenderState, feature: &Feature) {
+    if state.resumed(Pass::Draw, feature.id) {
+        return;
+    }
+    let result = fetch_outline_feature(cache, feature);
+    let drawn = matches!(result, Ok(true));
+    state.count(feature.id, result);
+    state.drawn(Pass::Draw, feature.id, drawn, cache.take_touched());
+}
+
+/// Post-processes the outlines in memory, or all of them with `all`.
+fn postprocess_outlines(
+    cache: &ImageCache,
+    state: &mut RenderState,
+    opts: &RenderOptions,
+    all: bool,
+) {
+    let stats = &mut state.postprocess_stats;
+    let f = |tile: Tile, img: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>| {
+        let pixels_per_meter = ImageCache::pixels_per_meter(tile, img.dimensions());
+        let _span = timing::span(Stage::Rasterize);
+        postprocess::process(img, pixels_per_meter, &opts.postprocess, stats)
+    };
+    if all {
+        cache.for_each_outline(f);
+    } else {
+        cache.for_each_loaded_outline(f);
+    }
+}
+
+/// Cells of the density pre-pass for `--densest-first` without work
+/// units, 16x16 tiles.
+const DENSITY_ZOOM: u8 = 13;
+
+/// Orders buildings by the number of buildings in their cell, so that the
+/// tiles of the densest cells are finished first. Cells stay together and
+/// ways without a centroid go last.
+fn sort_densest_first(ways: &mut [&Way], osm: &OsmData) {
+    let cells = heatmap::building_cells(osm, DENSITY_ZOOM);
+    let mut counts: HashMap<(u32, u32), usize> = HashMap::new();
+    for cell in cells.values() {
+        *counts.entry(*cell).or_default() += 1;
+    }
+    ways.sort_by_key(|w| {
+        let cell = cells.get(&w.id.0);
+        (Reverse(cell.map_or(0, |c| counts[c])), cell.copied(), w.id)
+    });
+}
+
+/// Runs `render-outlines` with `args` over loaded geometry into
+/// `out_dir`, returning the exit code of the outcome.
+fn render_command(
+    osm: &OsmData,
+    line_features: &[lines::LineFeature],
+    building_areas: &[BuildingArea],
+    args: RenderArgs,
+    out_dir: PathBuf,
+) -> anyhow::Result<u8> {
+    memory::report("load");
+    checks::writable_dir(&out_dir)?;
+    checks::tiles_dir(Path::new(workspace::TILES))?;
+    let outcome = render_outlines(
+        osm,
+        line_features,
+        building_areas,
+        &RenderOptions {
+            out_dir,
+            roof_channel: args.roof_channel,
+            mask_format: args.mask_format,
+            coverage: args.coverage,
+            antialias: args.antialias,
+            ignore_small: args.ignore_small,
+            classes: args.classes,
+            lines: args.lines,
+            noise: args.noise,
+            postprocess: args.postprocess,
+            rings: args.rings,
+            memory: args.memory,
+            units: args.units,
+            tile_px: chips::tile_px(),
+        },
+    )?;
+    Ok(outcome.code(&args.thresholds))
+}
+
+/// Renders `all` one work unit at a time, see `units`.
+fn render_units(
+    cache: &ImageCache,
+    state: &mut RenderState,
+    all: Batch,
+    zoom: u8,
+    osm: &OsmData,
+    opts: &RenderOptions,
+) -> anyhow::Result<()> {
+    let reach = |coords: &[GeoCoordinate]| ImageCache::buffered_tiles(coords, units::REACH_M);
+    // Rings also reach the tiles they cover without a vertex in them.
+    let ring_reach = |coords: &[GeoCoordinate]| {
+        let mut tiles = reach(coords);
+        tiles.extend(ImageCache::polygon_tiles(coords));
+        tiles
+    };
+    let mut plan = units::Plan::<Batch>::new(zoom);
+    for line in all.lines {
+        plan.insert(reach(&line.coords), |b| b.lines.push(line));
+    }
+    for feature in all.below {
+        plan.insert(reach(&feature.coords), |b| b.below.push(feature));
+    }
+    for way in all.ways {
+        match way_coords(way, &osm.nodes_all) {
+            Some(coords) => plan.insert(ring_reach(&coords), |b| b.ways.push(way)),
+            // Nothing to draw, only reported as skipped.
+            None => {
+                let (usable, _) = way_footprint(way, &osm.nodes_all, opts, state);
+                state.count(way.id.into(), Ok(usable));
+            }
+        }
+    }
+    for area in all.relations {
+        let tiles: HashSet<_> = area
+            .area
+            .iter()
+            .flat_map(|p| {
+                let coords: Vec<_> = p
+                    .exterior()
+                    .coords()
+                    .map(|c| GeoCoordinate::from(*c))
+                    .collect();
+                ring_reach(&coords)
             })
-            .map(move |(x, y)| Tile::new(ZOOM, x, y).unwrap())
+            .collect();
+        if tiles.is_empty() {
+            // Nothing to draw, only reported as skipped.
+            state.count(area.relation.id.into(), Ok(false));
+        } else {
+            plan.insert(tiles, |b| b.relations.push(area));
+        }
+    }
+    for feature in all.above {
+        plan.insert(reach(&feature.coords), |b| b.above.push(feature));
+    }
+
+    let mut done = units::Done::load(&opts.out_dir);
+    let mut order: Vec<_> = plan.units.into_values().collect();
+    if opts.units.densest_first {
+        // Stable, so units with as many buildings stay row by row.
+        order.sort_by_key(|(_, batch)| Reverse(batch.ways.len()));
+    }
+    let count = order.len();
+    let pending: Vec<_> = order
+        .iter()
+        .map(|(unit, _)| *unit)
+        .filter(|unit| !done.contains(*unit))
+        .filter(|unit| opts.units.only.as_ref().is_none_or(|o| o.contains(unit)))
+        .collect();
+    let on_disk = cache.tiles.read().unwrap().clone();
+    let interest_bbox = interest_bbox();
+    let fetch = |tile: Tile| {
+        if on_disk.contains(&tile) || !interest_bbox.overlaps_bbox(&tile.bbox()) {
+            return false;
+        }
+        match download_tile(cache.blobs.as_ref(), tile) {
+            Ok(_) => true,
+            // Tried again, and counted, when the unit is drawn.
+            Err(why) => {
+                warn!(target: logging::DOWNLOAD, "error prefetching {tile:?}: {why}");
+                false
+            }
+        }
+    };
+    std::thread::scope(|s| {
+        let prefetched = opts.units.prefetch.map(|ahead| {
+            // A unit waiting in `send` is ahead too.
+            let (tx, rx) = sync_channel(ahead as usize - 1);
+            let (pending, fetch) = (&pending, &fetch);
+            s.spawn(move || units::prefetch(pending, tx, fetch));
+            rx
+        });
+        for (i, (unit, batch)) in order.into_iter().enumerate() {
+            if done.contains(unit) || opts.units.only.as_ref().is_some_and(|o| !o.contains(&unit)) {
+                continue;
+            }
+            if let Some(rx) = &prefetched {
+                // Waiting here means downloads are what holds the run up.
+                let _span = timing::span(Stage::Network);
+                if let Ok((ready, fetched)) = rx.recv() {
+                    assert_eq!(ready, unit);
+                    cache.tiles.write().unwrap().extend(fetched);
+                }
+            }
+            logging::status!(
+                "Work unit {}/{count}: {unit:?}, {} buildings",
+                i + 1,
+                batch.ways.len()
+            );
+            *cache.unit.write().unwrap() = Some(unit);
+            draw_batch(cache, state, &batch, osm, opts)?;
+            if opts.postprocess.enabled() {
+                postprocess_outlines(cache, state, opts, false);
+            }
+            cache.evict();
+            if let Err(why) = state.index.save(&opts.out_dir) {
+                warn!("error saving tile index: {why}")
+            }
+            if let Some(cmd) = &opts.units.after_unit {
+                units::run_hook(cmd, unit, &opts.out_dir)?;
+                if opts.units.prune {
+                    let mut dirs = vec![(workspace::OUTLINES, ".png")];
+                    dirs.extend(
+                        cache
+                            .channels
+                            .iter()
+                            .map(|c| (c.as_str(), cache.formats.channel(c).ext())),
+                    );
+                    units::prune(unit, &opts.out_dir, &dirs);
+                    cache.forget(unit);
+                }
+            }
+            done.mark(unit)?;
+            memory::report("unit");
+        }
+        anyhow::Ok(())
+    })?;
+    *cache.unit.write().unwrap() = None;
+    Ok(())
+}
+
+fn render_outlines(
+    osm: &OsmData,
+    line_features: &[lines::LineFeature],
+    building_areas: &[BuildingArea],
+    opts: &RenderOptions,
+) -> anyhow::Result<outcome::RunOutcome> {
+    let journal = match opts.units.unit_zoom {
+        Some(_) => None,
+        None => Some(journal::Journal::open(&opts.out_dir)?),
+    };
+    let resuming = journal.as_ref().is_some_and(|j| j.resuming());
+    if resuming {
+        logging::status!("Resuming an interrupted run, see render-journal.jsonl");
+    }
+    logging::status!("Loading imgs...");
+    // Tiles drawn before are read back with their channels when drawn
+    // into again, so that a resumed run keeps the channels of what it
+    // skips.
+    let mut cache = ImageCache::load(&opts.out_dir, opts.units.unit_zoom.is_some() || resuming);
+    if opts.roof_channel {
+        cache.add_channel("roofs");
+    }
+    if opts.mask_format == masks::MaskMode::Index {
+        masks::write_class_map(&opts.out_dir)?;
+        cache.index_masks = true;
+    }
+    if opts.coverage.is_some() {
+        cache.add_channel(COVERAGE_CHANNEL);
+    }
+    cache.antialias = opts.antialias;
+    if opts.lines.enabled() {
+        cache.add_channel(lines::CHANNEL);
+    }
+    let mut state = RenderState {
+        index: index::TileIndex::load(&opts.out_dir),
+        noise_stats: noise::NoiseStats::default(),
+        ring_stats: rings::RingStats::default(),
+        too_small: HashSet::new(),
+        postprocess_stats: postprocess::PostprocessStats::default(),
+        outcome: outcome::RunOutcome::default(),
+        counted: HashSet::new(),
+        monitor: memory::Monitor::new(&opts.memory),
+        journal,
+    };
+    logging::status!("Done!");
+    memory::report("cache");
+
+    let features = collect_features(osm, &opts.classes);
+    let (below, above) = features
+        .iter()
+        .partition(|f| classes::draw_order(f.class) < classes::BUILDINGS_ORDER);
+    // The member ways of a relation that is drawn are left out, those of
+    // one without a closed outer ring are drawn on their own instead.
+    let drawn: HashSet<_> = building_areas
+        .iter()
+        .filter(|a| !a.area.0.is_empty())
+        .map(|a| a.relation.id.0)
+        .collect();
+    let mut ways = osm.standalone_buildings(&drawn);
+    ways.sort_by_key(|w| w.id);
+    if opts.units.densest_first && opts.units.unit_zoom.is_none() {
+        sort_densest_first(&mut ways, osm);
+    }
+    let all = Batch {
+        lines: line_features.iter().collect(),
+        below,
+        ways,
+        relations: building_areas.iter().collect(),
+        above,
+    };
+
+    match opts.units.unit_zoom {
+        Some(zoom) => render_units(&cache, &mut state, all, zoom, osm, opts)?,
+        None => {
+            draw_batch(&cache, &mut state, &all, osm, opts)?;
+            if opts.postprocess.enabled() {
+                postprocess_outlines(&cache, &mut state, opts, true);
+            }
+        }
+    }
+    if opts.postprocess.enabled() {
+        logging::status!("Post-processing: {:?}", state.postprocess_stats);
+        if let Err(why) =
+            postprocess::save(&opts.postprocess, &state.postprocess_stats, &opts.out_dir)
+        {
+            warn!("error saving post-processing record: {why}")
+        }
+    }
+    state.save(&cache)?;
+    if let Err(why) = state.index.save(&opts.out_dir) {
+        warn!("error saving tile index: {why}")
+    }
+    if let Some(journal) = state.journal.take() {
+        journal.finish()?;
+    }
+    if opts.noise.enabled() {
+        logging::status!("Label noise: {:?}", state.noise_stats);
+        if let Err(why) = noise::save(&opts.noise, &state.noise_stats, &opts.out_dir) {
+            warn!("error saving noise record: {why}")
+        }
+    }
+    state.ring_stats.report(&opts.rings);
+    if opts.classes.min_footprint_px.is_some() || opts.classes.drop_excluded {
+        logging::status!(
+            "Left out {} buildings, too small to show or excluded",
+            state.too_small.len()
+        );
+    }
+    if let Err(why) = rings::save(&opts.rings, &state.ring_stats, &opts.out_dir) {
+        warn!("error saving ring record: {why}")
+    }
+    if let Err(why) = duplicates::save(&osm.duplicates, &opts.out_dir) {
+        warn!("error saving duplicates record: {why}")
+    }
+    memory::report("save");
+    Ok(state.outcome)
+}
+
+/// Downloads the tiles of the area of interest, or of `--tile-list`,
+/// that are not on disk, and with `max_age` revalidates those older.
+fn download_tiles(max_age: Option<std::time::Duration>) -> anyhow::Result<()> {
+    let blobs = dedup::Store::detect()?;
+
+    let tiles: HashSet<_> = list_tiles(workspace::TILES, ".jpg").into_iter().collect();
+
+    let (refreshed, unchanged) = (AtomicU64::new(0), AtomicU64::new(0));
+    let download_tile = |tile: Tile| -> anyhow::Result<()> {
+        if !tiles.contains(&tile) {
+            download_tile(blobs.as_ref(), tile)?;
+            return Ok(());
+        }
+        let Some(max_age) = max_age else {
+            return Ok(());
+        };
+        if let Some(validators) = tilecache::stale(tile, max_age)? {
+            match refresh_tile(blobs.as_ref(), tile, &validators)? {
+                true => refreshed.fetch_add(1, Ordering::Relaxed),
+                false => unchanged.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+        Ok(())
     };
 
-    let top_left_tile = lat_lon_to_tile(interest_bbox.top(), interest_bbox.left(), ZOOM);
-    let bottom_right_tile = lat_lon_to_tile(interest_bbox.bottom(), interest_bbox.right(), ZOOM);
+    let interest_bbox = interest_bbox();
+    let top_left_tile = mercator::tile_at(
+        interest_bbox.top() as f64,
+        interest_bbox.left() as f64,
+        zoom(),
+    );
+    let bottom_right_tile = mercator::tile_at(
+        interest_bbox.bottom() as f64,
+        interest_bbox.right() as f64,
+        zoom(),
+    );
 
-    let count = (bottom_right_tile.0 - top_left_tile.0) * (bottom_right_tile.1 - top_left_tile.1);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(256)
+        .build_global()
+        .unwrap();
+    let (iter, count) = match worklist::tiles() {
+        Some(listed) => {
+            let mut listed: Vec<_> = listed.iter().copied().collect();
+            listed.sort_by_key(|t| (t.y(), t.x()));
+            let count = listed.len() as u64;
+            (rayon::iter::Either::Left(listed.into_par_iter()), count)
+        }
+        None => {
+            let (xs, ys) = (
+                top_left_tile.0..=bottom_right_tile.0,
+                top_left_tile.1..=bottom_right_tile.1,
+            );
+            let count = xs.clone().count() as u64 * ys.clone().count() as u64;
+            let iter = xs
+                .into_par_iter()
+                .flat_map(move |x| ys.clone().into_par_iter().map(move |y| (x, y)))
+                .map(move |(x, y)| Tile::new(zoom(), x, y).unwrap());
+            (rayon::iter::Either::Right(iter), count)
+        }
+    };
 
     let style = ProgressStyle::with_template(
         "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
     )
     .unwrap();
 
-    let pb = ProgressBar::new(count as u64).with_style(style);
+    let pb = logging::progress_bar(count, style);
+    metrics::DOWNLOADS_PENDING.set(count as i64);
 
-    iter.for_each(|v| {
-        download_tile(v);
+    // Stops at the first failure; tiles already on disk are not fetched
+    // again, so running again resumes.
+    let done = iter.try_for_each(|v| {
+        download_tile(v)?;
         pb.inc(1);
+        metrics::DOWNLOADS_PENDING.add(-1);
+        anyhow::Ok(())
     });
+    tilecache::flush();
+    done?;
+    if max_age.is_some() {
+        logging::status!(
+            "Revalidated old tiles: {} downloaded again, {} unchanged",
+            refreshed.into_inner(),
+            unchanged.into_inner()
+        );
+    }
+    Ok(())
+}
 
-    // let mut idx = 0;
-    // for way in ways_buildings.iter().progress_with_style(
-    //     ProgressStyle::with_template(
-    //         "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
-    //     )
-    //     .unwrap(),
-    // ) {
-    //     let mut interest_tags = String::new();
-    //     for tag in way.1.tags.iter() {
-    //         if tag.0.starts_with("building") {
-    //             let part = format!("{}={}; ", tag.0, tag.1);
-    //             interest_tags.extend(part.chars());
-    //         }
-    //     }
-    //     // info!("{way:?}");
-    //     // println!("{interest_tags}");
-    //     idx += 1;
-    //     if let Err(why) = fetch_outline_way(&mut cache, way.1, &nodes_all) {
-    //         info!("error fetching outline: {why}")
-    //     };
-    //     if idx % 100 == 0 {
-    //         cache.save();
-    //     }
-    // }
-
-    // cache.save();
-
-    // for rel in relations_buildings.iter().take(50) {
-    //     println!("------------");
-    //     fetch_outline(rel.1, &nodes_all, &ways_all);
-    // }
-}
-
-fn main() {
-    env_logger::init();
-    let file =
-        &OsString::from_str("/home/danya/Downloads/central-fed-district-latest.osm.pbf").unwrap();
-    //        &OsString::from_str("/home/danya/Downloads/kaliningrad-latest.osm.pbf").unwrap();
-    // fetch_buildings(&file);
-    build_outlines(&file);
+/// Runs `stitch_pictures`, installed next to this binary, from `run/`,
+/// and lists what it stitched in `dataset.json`.
+fn stitch(args: &[std::ffi::OsString], aoi: &str) -> anyhow::Result<ExitCode> {
+    let code = stitch_at(Path::new("."), args, aoi)?;
+    // Also after failed blocks, the others are in place.
+    if code != ExitCode::FAILURE {
+        workspace::write_dataset(aoi)?;
+    }
+    Ok(code)
+}
+
+/// Like `stitch`, from `root/run/`, stitching the outlines of `root`.
+fn stitch_at(root: &Path, args: &[std::ffi::OsString], aoi: &str) -> anyhow::Result<ExitCode> {
+    let exe = std::env::current_exe()?.with_file_name("stitch_pictures");
+    if !exe.is_file() {
+        anyhow::bail!(
+            "there is no stitch_pictures next to {}\n\
+             hint: build the whole workspace with `cargo build --workspace`",
+            exe.with_file_name("").display()
+        );
+    }
+    checks::tiles_dir(Path::new(workspace::TILES))?;
+    // It writes into these but does not make them.
+    let stitched = Path::new(workspace::STITCHED);
+    for dir in [
+        Path::new(workspace::RUN),
+        &stitched.join(workspace::TILES),
+        &stitched.join(workspace::OUTLINES),
+    ] {
+        std::fs::create_dir_all(root.join(dir))?;
+    }
+    let mut command = std::process::Command::new(&exe);
+    command
+        .current_dir(root.join(workspace::RUN))
+        .arg("--workspace")
+        .arg(workspace::get().dir(root))
+        .arg("--zoom")
+        .arg(zoom().to_string())
+        .arg("--aoi")
+        .arg(aoi);
+    if logging::quiet() && !args.iter().any(|a| a == "--quiet" || a == "-q") {
+        command.arg("--quiet");
+    }
+    // Blocks around a sparse list are mostly empty, only full ones are kept.
+    if worklist::tiles().is_some() && !args.iter().any(|a| a == "--strict-pairing") {
+        command.arg("--strict-pairing");
+    }
+    let status = command.args(args).status()?;
+    // Its exit codes, e.g. 3 for too many failed blocks, are passed on.
+    Ok(match status.code() {
+        Some(code) => ExitCode::from(code as u8),
+        None => ExitCode::FAILURE,
+    })
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    workspace::Workspace::enter(cli.workspace.as_deref())?;
+    logging::init(&cli.log)?;
+    let timings = cli.timings.clone();
+    let code = run(cli);
+    // Also after a failure, where the time went may be the reason.
+    timing::report(timings.as_deref())?;
+    code
+}
+
+fn run(cli: Cli) -> anyhow::Result<ExitCode> {
+    let formats = formats::Formats::load()?;
+    paths::set_tile_names(formats.tile_names);
+    // `render-tile` names the zoom with the tile.
+    set_zoom(match &cli.command {
+        Command::RenderTile { opts, .. } => opts.z,
+        _ => cli.zoom,
+    });
+    paths::set_tile_paths(formats.tile_paths(), &cli.aoi)?;
+    worklist::load(cli.tile_list.as_deref())?;
+    nodes::set_store(cli.node_store.clone());
+    anyhow::ensure!(
+        cli.duplicate_iou > 0.0 && cli.duplicate_iou <= 1.0,
+        "--duplicate-iou must be above 0 and at most 1"
+    );
+    duplicates::set_iou((!cli.keep_duplicates).then_some(cli.duplicate_iou));
+    parts::set_mode(cli.building_parts);
+    if let Some(bbox) = cli.bbox.or_else(worklist::bbox) {
+        INTEREST_BBOX.set(bbox).expect("bbox set twice");
+    }
+    provider::select(&cli.provider)?;
+    metrics::start(&cli.metrics)?;
+    rules::load(cli.class_rules.as_deref())?;
+    READ_ONLY.set(cli.read_only).expect("read-only set twice");
+    if cli.read_only {
+        let Some(outputs) = cli.command.outputs() else {
+            anyhow::bail!(
+                "this command writes into the dataset, which --read-only leaves untouched\n\
+                 hint: run it on a writable copy; exporters run with --read-only"
+            );
+        };
+        for out in outputs {
+            checks::outside_dataset(out)?;
+        }
+    }
+    let pbf = match (&cli.pbf, cli.command.reads_pbf()) {
+        (Some(path), true) => {
+            checks::pbf(path)?;
+            path.as_os_str()
+        }
+        (None, true) => anyhow::bail!(
+            "this command reads an OSM extract, but none was given\n\
+             hint: download one, e.g. from https://download.geofabrik.de, \
+             and pass it with --pbf <FILE>"
+        ),
+        (_, false) => std::ffi::OsStr::new(""),
+    };
+    match cli.command {
+        Command::Stats { classes } => fetch_buildings(pbf, &classes)?,
+        Command::Tags { opts } => tags::tag_frequencies(&load_osm(pbf)?, &opts)?,
+        Command::DownloadTiles { max_age } => {
+            checks::writable_dir(Path::new(workspace::TILES))?;
+            let max_age = match max_age {
+                Some(days) => {
+                    anyhow::ensure!(
+                        days >= 0.0 && days.is_finite(),
+                        "--max-age must be a number of days, 0 or more"
+                    );
+                    Some(std::time::Duration::from_secs_f64(days * 86_400.0))
+                }
+                None => None,
+            };
+            download_tiles(max_age)?
+        }
+        Command::Heatmap { zoom, out } => {
+            let osm = load_osm(pbf)?;
+            heatmap::export_heatmap(&osm, &interest_bbox(), zoom, &out)?;
+        }
+        Command::RenderOutlines { args } => {
+            args.check()?;
+            let line_features = lines::load_lines(pbf, &args.lines);
+            let building_areas = match args.relations {
+                true => load_building_areas(pbf)?,
+                false => vec![],
+            };
+            let (osm, out_dir) = match &args.as_of {
+                Some(date) => {
+                    let at = history::parse_date(date)?;
+                    let osm = history::load_snapshot(pbf, at)?;
+                    (osm, args.out_dir.join("snapshots").join(date))
+                }
+                None => (load_osm(pbf)?, args.out_dir.clone()),
+            };
+            let code = render_command(&osm, &line_features, &building_areas, args, out_dir)?;
+            return Ok(ExitCode::from(code));
+        }
+        Command::RenderTile { opts, args } => {
+            return Ok(ExitCode::from(onetile::render_tile(pbf, &opts, args)?))
+        }
+        Command::Recipe { opts } => return Ok(ExitCode::from(recipe::run(pbf, &opts)?)),
+        Command::Stitch { args } => return stitch(&args, &cli.aoi),
+        Command::Selftest => return selftest::selftest(),
+        Command::ChangePairs {
+            t1,
+            t2,
+            t1_tiles,
+            t2_tiles,
+            out,
+        } => {
+            changes::export_change_pairs(
+                &changes::PairSource {
+                    date: t1,
+                    tiles: t1_tiles,
+                },
+                &changes::PairSource {
+                    date: t2,
+                    tiles: t2_tiles,
+                },
+                &out,
+            )?;
+        }
+        Command::Augment { opts } => augment::augment(&opts)?,
+        Command::ArtifactMasks { chips, out } => artifacts::export_artifact_masks(&chips, &out)?,
+        Command::ChipGraph { chips, out } => adjacency::export_adjacency(&chips, &out)?,
+        Command::Calibrate { sample, search_px } => {
+            calibrate::calibrate(&cli.provider, sample, search_px)?
+        }
+        Command::Region { opts } => {
+            let building_areas = match opts.relations {
+                true => load_building_areas(pbf)?,
+                false => vec![],
+            };
+            let osm = load_osm(pbf)?;
+            let labels = region::Labels::new(&osm, &building_areas, &opts.classes, &opts.rings);
+            region::export_region(&labels, &opts)?;
+        }
+        Command::BuildingCrops { opts } => crops::export_crops(&load_osm(pbf)?, &opts)?,
+        Command::SampleWindows { opts } => {
+            let building_areas = match opts.relations {
+                true => load_building_areas(pbf)?,
+                false => vec![],
+            };
+            let osm = load_osm(pbf)?;
+            let labels = region::Labels::new(&osm, &building_areas, &opts.classes, &opts.rings);
+            sampler::export_windows(&labels, &opts)?;
+        }
+        Command::Serve { opts } => {
+            let building_areas = match opts.relations {
+                true => load_building_areas(pbf)?,
+                false => vec![],
+            };
+            let osm = load_osm(pbf)?;
+            let labels = region::Labels::new(&osm, &building_areas, &opts.classes, &opts.rings);
+            service::serve(&labels, &opts)?;
+        }
+        Command::Metadata {
+            out,
+            relations,
+            address_points,
+            min_hole_area,
+        } => {
+            let osm = load_osm(pbf)?;
+            metadata::export_metadata(&osm, pbf, relations, address_points, min_hole_area, &out)?;
+        }
+        Command::ExportGeojson { opts } => {
+            let osm = load_osm(pbf)?;
+            geojson::export_geojson(&osm, pbf, &opts)?;
+        }
+        Command::ExportMaproulette { opts } => {
+            let osm = load_osm(pbf)?;
+            maproulette::export_maproulette(&osm, &opts)?;
+        }
+        Command::OrientedBoxes { opts } => {
+            let osm = load_osm(pbf)?;
+            oriented::export_oriented_boxes(&osm, &opts)?;
+        }
+        Command::Coco { opts } => {
+            let osm = load_osm(pbf)?;
+            coco::export_coco(&osm, &opts)?;
+        }
+        Command::CenternetTargets { opts } => {
+            let osm = load_osm(pbf)?;
+            centernet::export_centernet(&osm, &opts)?;
+        }
+        Command::Subset { opts } => {
+            let osm = load_osm(pbf)?;
+            subset::export_subset(&osm, &opts)?;
+        }
+        Command::Districts {
+            admin_level,
+            out,
+            classes,
+        } => {
+            let districts = districts::load_districts(pbf, &admin_level)?;
+            info!("Loaded {} districts", districts.len());
+            let osm = load_osm(pbf)?;
+            districts::district_stats(&osm, &districts, &classes, &out)?;
+        }
+        Command::ConvertTiles { opts } => rawtiles::convert_tiles(&opts)?,
+        Command::DedupTiles => dedup::dedup_tiles()?,
+        Command::RenameTiles { dirs } => paths::rename_tiles(&dirs)?,
+        Command::Webdataset { opts } => webdataset::export_webdataset(&opts)?,
+        Command::HfDataset { opts } => huggingface::export_hf_dataset(&opts)?,
+        Command::Split { opts } => split::split_chips(&opts)?,
+        Command::Dataset => workspace::write_dataset(&cli.aoi)?,
+        Command::Release { version } => release::release(&version)?,
+        Command::ReleaseDelta { from, to } => release::release_delta(&from, &to)?,
+        Command::VerifyRepro { opts, args } => repro::verify(pbf, &opts, args, &cli.aoi)?,
+        Command::Diff { what } => match what {
+            diff::DiffCommand::Datasets(opts) => diff::diff_datasets(&opts)?,
+        },
+        Command::Completions { shell } => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+        }
+        Command::Manpage { out: Some(out) } => {
+            std::fs::create_dir_all(&out)?;
+            clap_mangen::generate_to(Cli::command(), &out)?;
+        }
+        Command::Manpage { out: None } => {
+            clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout())?;
+        }
+    }
+    Ok(ExitCode::from(outcome::SUCCESS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square `side_m` meters wide around a point of `tile` inside a pixel.
+    fn square(tile: Tile, side_m: f64) -> Vec<GeoCoordinate> {
+        let c = mercator::within(tile, 0.3, 0.3);
+        let dlat = side_m / 2.0 / 111_320.0;
+        let dlon = dlat / c.latitude.to_radians().cos();
+        let at = |dy: f64, dx: f64| GeoCoordinate {
+            latitude: c.latitude + dy,
+            longitude: c.longitude + dx,
+        };
+        vec![
+            at(dlat, -dlon),
+            at(dlat, dlon),
+            at(-dlat, dlon),
+            at(-dlat, -dlon),
+            at(dlat, -dlon),
+        ]
+    }
+
+    #[test]
+    fn sub_pixel_footprint_is_not_drawn() {
+        let tile = Tile::new(17, 79_230, 41_020).unwrap();
+        let poly = square(tile, 0.1);
+
+        let mut outline = image::RgbImage::new(256, 256);
+        ImageCache::fill_polygon(tile, &mut outline, &poly, FeatureClass::Normal);
+        assert!(outline.pixels().all(|px| px.0 == [0, 0, 0]));
+
+        let mut channel = GrayImage::new(256, 256);
+        ImageCache::fill_channel_polygon(tile, &mut channel, &poly, 1);
+        assert!(channel.pixels().all(|px| px.0 == [0]));
+
+        let (outer, holes) = ImageCache::tile_relative_area(tile, (256, 256), &poly, &[]);
+        fill_with_holes(&mut channel, &outer, &holes, image::Luma([1]));
+        assert!(channel.pixels().all(|px| px.0 == [0]));
+    }
+
+    #[test]
+    fn footprint_over_pixels_is_drawn() {
+        let tile = Tile::new(17, 79_230, 41_020).unwrap();
+        let mut outline = image::RgbImage::new(256, 256);
+        ImageCache::fill_polygon(
+            tile,
+            &mut outline,
+            &square(tile, 20.0),
+            FeatureClass::Normal,
+        );
+        assert!(outline.pixels().any(|px| px.0 != [0, 0, 0]));
+    }
+
+    /// Bounds of `ring`, rounded off to the pixel.
+    fn bounds(ring: &[Point<f64>]) -> (f64, f64, f64, f64) {
+        let (x0, y0, x1, y1) = ring.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(x0, y0, x1, y1), p| (x0.min(p.x), y0.min(p.y), x1.max(p.x), y1.max(p.y)),
+        );
+        (x0.round(), y0.round(), x1.round(), y1.round())
+    }
+
+    fn points(coords: &[(f64, f64)]) -> Vec<Point<f64>> {
+        coords.iter().map(|&(x, y)| Point::new(x, y)).collect()
+    }
+
+    #[test]
+    fn ring_inside_the_screen_is_not_clipped() {
+        let ring = points(&[(10.0, 10.0), (200.0, 10.0), (200.0, 50.0), (10.0, 50.0)]);
+        assert_eq!(clip_to_screen(&ring, (256, 256)), ring);
+    }
+
+    #[test]
+    fn ring_is_clipped_a_pixel_past_the_edges() {
+        let ring = points(&[(10.0, 10.0), (300.0, 10.0), (300.0, 20.0), (10.0, 20.0)]);
+        assert_eq!(
+            bounds(&clip_to_screen(&ring, (256, 256))),
+            (10.0, 10.0, 257.0, 20.0)
+        );
+
+        let around = points(&[
+            (-50.0, -50.0),
+            (400.0, -50.0),
+            (400.0, 300.0),
+            (-50.0, 300.0),
+        ]);
+        assert_eq!(
+            bounds(&clip_to_screen(&around, (256, 128))),
+            (-1.0, -1.0, 257.0, 129.0)
+        );
+    }
+
+    #[test]
+    fn ring_off_the_screen_is_clipped_away() {
+        let ring = points(&[(300.0, 10.0), (400.0, 10.0), (400.0, 50.0), (300.0, 50.0)]);
+        assert!(clip_to_screen(&ring, (256, 256)).is_empty());
+    }
+
+    fn tiles(origin: Tile, coords: &[(u32, u32)]) -> HashSet<Tile> {
+        coords
+            .iter()
+            .map(|&(dx, dy)| Tile::new(origin.zoom(), origin.x() + dx, origin.y() + dy).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn polygon_tiles_of_a_polygon_in_one_or_two_tiles() {
+        let tile = Tile::new(zoom(), 79_230, 41_020).unwrap();
+        assert_eq!(
+            ImageCache::polygon_tiles(&square(tile, 20.0)),
+            tiles(tile, &[(0, 0)])
+        );
+
+        let at = |dx: u32, fx: f64, fy: f64| {
+            let t = Tile::new(zoom(), tile.x() + dx, tile.y()).unwrap();
+            GeoCoordinate::from(mercator::within(t, fx, fy))
+        };
+        let across = [
+            at(0, 0.8, 0.4),
+            at(1, 0.2, 0.4),
+            at(1, 0.2, 0.6),
+            at(0, 0.8, 0.6),
+        ];
+        assert_eq!(
+            ImageCache::polygon_tiles(&across),
+            tiles(tile, &[(0, 0), (1, 0)])
+        );
+    }
+
+    #[test]
+    fn polygon_tiles_include_tiles_without_a_vertex() {
+        let tile = Tile::new(zoom(), 79_230, 41_020).unwrap();
+        let at = |dx: u32, dy: u32, fx: f64, fy: f64| {
+            let t = Tile::new(zoom(), tile.x() + dx, tile.y() + dy).unwrap();
+            GeoCoordinate::from(mercator::within(t, fx, fy))
+        };
+        // A triangle whose long side runs from the right of (2, 0) to the
+        // bottom of (0, 2), through (1, 1) and short of (2, 1) and (1, 2).
+        let triangle = [at(0, 0, 0.1, 0.1), at(2, 0, 0.7, 0.1), at(0, 2, 0.1, 0.7)];
+        assert_eq!(
+            ImageCache::polygon_tiles(&triangle),
+            tiles(tile, &[(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (0, 2)])
+        );
+    }
 }
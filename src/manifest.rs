@@ -0,0 +1,120 @@
+//! Record of every tile written by `render-outlines`, kept next to
+//! `outlines/` as an append-only journal that is flushed after each save,
+//! so that an interrupted run still knows what it produced.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging;
+
+const MANIFEST_FILE: &str = "manifest.jsonl";
+/// Compact once the journal has this many superseded lines.
+const COMPACT_AFTER: usize = 1000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TileEntry {
+    /// `{y}-{x}`, like the file names.
+    pub tile: String,
+    /// Files written for the tile, relative to the manifest.
+    pub files: Vec<String>,
+    /// Pixels of any building class in the outline.
+    pub building_px: u64,
+    /// Seconds since the Unix epoch.
+    pub saved_at: u64,
+}
+
+#[derive(Debug)]
+pub struct OutputManifest {
+    path: PathBuf,
+    pub tiles: BTreeMap<String, TileEntry>,
+    journal: File,
+    /// Lines in the journal, superseded ones included.
+    lines: usize,
+}
+
+impl OutputManifest {
+    /// Opens the manifest in `dir`, ignoring a torn last line from a crash.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let (tiles, lines) = match File::open(&path) {
+            Ok(f) => read_journal(f)?,
+            Err(_) => (BTreeMap::new(), 0),
+        };
+        let journal = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut manifest = Self {
+            path,
+            tiles,
+            journal,
+            lines,
+        };
+        manifest.compact()?;
+        Ok(manifest)
+    }
+
+    pub fn record(&mut self, entry: TileEntry) -> anyhow::Result<()> {
+        writeln!(self.journal, "{}", serde_json::to_string(&entry)?)?;
+        self.tiles.insert(entry.tile.clone(), entry);
+        self.lines += 1;
+        if self.lines > self.tiles.len() + COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Makes everything recorded so far durable.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.journal.flush()?;
+        self.journal.sync_data()?;
+        Ok(())
+    }
+
+    /// Rewrites the journal with one line per tile.
+    pub fn compact(&mut self) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut f = std::io::BufWriter::new(File::create(&tmp)?);
+        for entry in self.tiles.values() {
+            writeln!(f, "{}", serde_json::to_string(entry)?)?;
+        }
+        f.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.journal = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = self.tiles.len();
+        Ok(())
+    }
+}
+
+/// The latest entry of every tile in a journal, and the number of lines.
+fn read_journal(f: File) -> anyhow::Result<(BTreeMap<String, TileEntry>, usize)> {
+    let mut tiles = BTreeMap::new();
+    let mut lines = 0;
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        match serde_json::from_str::<TileEntry>(&line) {
+            Ok(entry) => {
+                tiles.insert(entry.tile.clone(), entry);
+                lines += 1;
+            }
+            Err(e) => logging::status!("Ignoring manifest line {line:?}: {e}"),
+        }
+    }
+    Ok((tiles, lines))
+}
+
+/// The tiles recorded in the manifest in `dir`, without writing to it.
+pub fn read(dir: &Path) -> anyhow::Result<BTreeMap<String, TileEntry>> {
+    let path = dir.join(MANIFEST_FILE);
+    let f = File::open(&path).map_err(|why| {
+        anyhow::anyhow!(
+            "cannot read {}: {why}\n\
+             hint: point at the --out-dir of a render-outlines run",
+            path.display()
+        )
+    })?;
+    Ok(read_journal(f)?.0)
+}
@@ -0,0 +1,228 @@
+//! Findings for mappers. `export-maproulette` writes what looks wrong in
+//! the source data as MapRoulette challenges, one GeoJSON file per kind of
+//! finding and one feature per task, so that fixing it in OSM improves the
+//! next version of the dataset:
+//!
+//! - `self-intersections.geojson`: building ways whose outline crosses
+//!   itself, which no fill draws as the mapper meant.
+//! - `offset-outliers.geojson`: tiles whose outlines line up best with
+//!   the imagery shifted by `--offset-px` or more, after the offset of the
+//!   provider, as `calibrate` measures it.
+//! - `imagery-mismatches.geojson`: tiles whose building edges hardly show
+//!   in the imagery, buildings built or demolished between the imagery and
+//!   the map, or misplaced.
+//!
+//! The tile findings need `outlines/` rendered over `tiles/`.
+
+use std::{io::Write, path::PathBuf};
+
+use geo::{line_intersection::line_intersection, Line, LineIntersection};
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use slippy_map_tiles::Tile;
+
+use crate::{
+    calibrate::{best_shift, tile_scores},
+    list_tiles, logging, paths, tilecache, way_coords,
+    workspace::{OUTLINES, TILES},
+    GeoCoordinate, OsmData,
+};
+
+/// Building edge pixels a tile needs for its edges to say anything.
+const MIN_EDGES: usize = 200;
+/// Gain over no shift a tile's best shift needs to count as an offset.
+const MIN_GAIN: f64 = 1.1;
+
+#[derive(clap::Args)]
+pub struct MapRouletteOptions {
+    /// Directory the challenges are written to.
+    #[arg(long, default_value = "maproulette")]
+    pub out: PathBuf,
+    /// Offset between outlines and imagery, in pixels, from which a tile
+    /// is an outlier.
+    #[arg(long, default_value_t = 3)]
+    pub offset_px: i32,
+    /// Largest offset tried, pixels.
+    #[arg(long, default_value_t = 8)]
+    pub search_px: i32,
+    /// Edge response of the buildings of a tile, over that of the whole
+    /// imagery, under which they count as not showing in it.
+    #[arg(long, default_value_t = 1.1)]
+    pub min_edge_contrast: f64,
+}
+
+/// Where the outline of a way first crosses itself, if it does.
+fn self_intersection(coords: &[GeoCoordinate]) -> Option<GeoCoordinate> {
+    let mut coords = coords.to_vec();
+    // A node repeated in place is no crossing.
+    coords.dedup();
+    if coords.len() < 3 {
+        return None;
+    }
+    if coords.first() != coords.last() {
+        coords.push(coords[0]);
+    }
+    let segments: Vec<Line<f64>> = coords.windows(2).map(|w| Line::new(w[0], w[1])).collect();
+    let n = segments.len();
+    for i in 0..n {
+        // Neighbors share an end, and the last segment the first one's.
+        for j in i + 2..n - usize::from(i == 0) {
+            match line_intersection(segments[i], segments[j]) {
+                Some(LineIntersection::SinglePoint { intersection, .. }) => {
+                    return Some(intersection.into())
+                }
+                Some(LineIntersection::Collinear { intersection }) => {
+                    return Some(intersection.start.into())
+                }
+                None => {}
+            }
+        }
+    }
+    None
+}
+
+fn task(id: String, geometry: Value, properties: Value) -> Value {
+    json!({
+        "type": "Feature",
+        "id": id,
+        "geometry": geometry,
+        "properties": properties,
+    })
+}
+
+fn tile_polygon(tile: Tile) -> Value {
+    let mercator::TileBounds {
+        north: top,
+        south: bottom,
+        west: left,
+        east: right,
+    } = mercator::bounds(tile);
+    json!({
+        "type": "Polygon",
+        "coordinates": [[[left, top], [right, top], [right, bottom], [left, bottom], [left, top]]],
+    })
+}
+
+fn self_intersections(osm: &OsmData) -> Vec<Value> {
+    let mut ways: Vec<_> = osm.ways_buildings.values().collect();
+    ways.sort_by_key(|w| w.id);
+    ways.par_iter()
+        .filter_map(|way| {
+            let coords = way_coords(way, &osm.nodes_all).filter(|c| c.len() >= 4)?;
+            let at = self_intersection(&coords)?;
+            let line: Vec<_> = coords.iter().map(|c| [c.longitude, c.latitude]).collect();
+            Some(task(
+                format!("way/{}", way.id.0),
+                json!({ "type": "LineString", "coordinates": line }),
+                json!({
+                    "@id": format!("way/{}", way.id.0),
+                    "finding": "self-intersection",
+                    "intersection": [at.longitude, at.latitude],
+                    "description": "The outline of this building crosses itself. \
+                        Fix the order of its nodes, or split it into several buildings.",
+                }),
+            ))
+        })
+        .collect()
+}
+
+/// The offset outliers and imagery mismatches among the rendered tiles.
+fn tile_findings(opts: &MapRouletteOptions) -> anyhow::Result<(Vec<Value>, Vec<Value>)> {
+    let mut tiles = list_tiles(OUTLINES, ".png");
+    tiles.retain(|t| paths::tile_file(TILES, *t, ".jpg").is_file());
+    tiles.sort_by_key(|t| (t.y(), t.x()));
+    let found = tiles
+        .par_iter()
+        .map(|tile| {
+            let Some(scores) = tile_scores(*tile, opts.search_px)? else {
+                return Ok((None, None));
+            };
+            if scores.edges < MIN_EDGES {
+                return Ok((None, None));
+            }
+            let name = paths::stem(*tile);
+            let ((dx, dy), gain) = best_shift(&scores.scores, opts.search_px);
+            let outlier =
+                (dx.abs().max(dy.abs()) >= opts.offset_px && gain >= MIN_GAIN).then(|| {
+                    let m = scores.meters_per_px;
+                    // +Y is down on screen, north is up.
+                    let (east, north) = (dx as f64 * m, -dy as f64 * m);
+                    task(
+                        format!("tile/{name}"),
+                        tile_polygon(*tile),
+                        json!({
+                            "finding": "offset-outlier",
+                            "tile": name,
+                            "offset_px": [dx, dy],
+                            "offset_m": [east, north],
+                            "description": format!(
+                                "The buildings here line up with the imagery best when moved \
+                                 {east:.1} m east and {north:.1} m north. Check whether they \
+                                 were traced from misaligned imagery."
+                            ),
+                        }),
+                    )
+                });
+            let center = scores.scores[scores.scores.len() / 2];
+            let contrast = match scores.background > 0.0 {
+                true => center / scores.background,
+                false => 1.0,
+            };
+            let mismatch = (contrast < opts.min_edge_contrast).then(|| {
+                // A read-only dataset may not let the journal open.
+                let imagery = tilecache::entry(*tile).ok().flatten();
+                task(
+                    format!("tile/{name}"),
+                    tile_polygon(*tile),
+                    json!({
+                        "finding": "imagery-mismatch",
+                        "tile": name,
+                        "edge_contrast": contrast,
+                        "imagery_last_modified": imagery.and_then(|e| e.validators.last_modified),
+                        "description": "The buildings mapped here hardly show in the imagery. \
+                            Check whether they were demolished, are still being built, or are \
+                            misplaced.",
+                    }),
+                )
+            });
+            Ok((outlier, mismatch))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (outliers, mismatches): (Vec<_>, Vec<_>) = found.into_iter().unzip();
+    Ok((
+        outliers.into_iter().flatten().collect(),
+        mismatches.into_iter().flatten().collect(),
+    ))
+}
+
+fn write_challenge(opts: &MapRouletteOptions, name: &str, tasks: Vec<Value>) -> anyhow::Result<()> {
+    let path = opts.out.join(name);
+    let count = tasks.len();
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    serde_json::to_writer(
+        &mut w,
+        &json!({ "type": "FeatureCollection", "features": tasks }),
+    )?;
+    w.flush()?;
+    logging::status!("Wrote {count} tasks to {}", path.display());
+    Ok(())
+}
+
+/// Writes the findings in `osm` and the rendered tiles to `--out`.
+pub fn export_maproulette(osm: &OsmData, opts: &MapRouletteOptions) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (1..=opts.search_px).contains(&opts.offset_px),
+        "--offset-px has to be at least 1 and at most --search-px {}",
+        opts.search_px
+    );
+    std::fs::create_dir_all(&opts.out)?;
+    write_challenge(opts, "self-intersections.geojson", self_intersections(osm))?;
+    if !std::path::Path::new(OUTLINES).is_dir() {
+        logging::status!("No outlines/ rendered, leaving out the tile findings");
+        return Ok(());
+    }
+    let (outliers, mismatches) = tile_findings(opts)?;
+    write_challenge(opts, "offset-outliers.geojson", outliers)?;
+    write_challenge(opts, "imagery-mismatches.geojson", mismatches)?;
+    Ok(())
+}
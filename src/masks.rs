@@ -0,0 +1,78 @@
+//! Single-channel label masks for `--mask-format index`: one class index
+//! per pixel in `masks/`, with `masks/classes.json` naming the indices.
+//! The RGB outlines are still written, as the rest of the pipeline reads
+//! them.
+
+use std::path::Path;
+
+use image::{GrayImage, RgbImage};
+use serde::Serialize;
+
+use crate::classes::{colors, ALL_CLASSES};
+
+pub const DIR: &str = "masks";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaskMode {
+    /// RGB outlines only, one `classes::colors` color per class.
+    #[default]
+    Rgb,
+    /// Also 8-bit grayscale masks holding the class index.
+    Index,
+}
+
+#[derive(Serialize)]
+struct ClassEntry {
+    index: usize,
+    name: String,
+    /// Color of the class in the RGB outlines.
+    color: [u8; 3],
+}
+
+/// The class index of every pixel of `outline`. Colors outside
+/// `classes::colors`, which only blending post-processing makes, get the
+/// class of the nearest color.
+pub fn index_mask(outline: &RgbImage) -> GrayImage {
+    let mut last = None;
+    GrayImage::from_fn(outline.width(), outline.height(), |x, y| {
+        let px = outline.get_pixel(x, y).0;
+        // Label images are long runs of one color.
+        let i = match last {
+            Some((color, i)) if color == px => i,
+            _ => nearest_class(px),
+        };
+        last = Some((px, i));
+        image::Luma([i])
+    })
+}
+
+fn nearest_class(px: [u8; 3]) -> u8 {
+    let distance = |c: &[u8; 3]| -> u32 {
+        c.iter()
+            .zip(px)
+            .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+            .sum()
+    };
+    (0..colors().len())
+        .min_by_key(|&i| distance(&colors()[i]))
+        .unwrap() as u8
+}
+
+/// Writes `masks/classes.json` under `out_dir`.
+pub fn write_class_map(out_dir: &Path) -> anyhow::Result<()> {
+    let entries: Vec<_> = ALL_CLASSES
+        .iter()
+        .map(|&class| ClassEntry {
+            index: class as usize,
+            name: format!("{class:?}"),
+            color: colors()[class as usize],
+        })
+        .collect();
+    let dir = out_dir.join(DIR);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        dir.join("classes.json"),
+        serde_json::to_vec_pretty(&entries)?,
+    )?;
+    Ok(())
+}
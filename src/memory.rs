@@ -0,0 +1,116 @@
+//! Memory accounting, so a run that outgrows the machine flushes its
+//! caches or stops with its outputs saved instead of being killed by the
+//! OOM killer overnight.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::ImageCache;
+
+const MIB: u64 = 1 << 20;
+/// Minimum time between two periodic reports of the same stage.
+const REPORT_EVERY: Duration = Duration::from_secs(60);
+
+static LIVE_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_BYTES: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting live bytes and allocations.
+pub struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+            PEAK_BYTES.fetch_max(live + layout.size() as u64, Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Resident set size from `/proc`, `None` where that does not exist.
+pub fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+pub fn heap_bytes() -> u64 {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Logs the memory use at the end of a stage.
+pub fn report(stage: &str) {
+    let rss = rss_bytes().map_or("?".to_string(), |b| (b / MIB).to_string());
+    info!(
+        "{stage}: rss {rss} MiB, heap {} MiB, peak {} MiB, {} allocations",
+        heap_bytes() / MIB,
+        PEAK_BYTES.load(Ordering::Relaxed) / MIB,
+        ALLOCATIONS.load(Ordering::Relaxed)
+    );
+}
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct MemoryOptions {
+    /// Heap budget in MiB. Over it, the image cache is saved and dropped
+    /// from memory; if that is not enough, the run stops with everything
+    /// drawn so far saved.
+    #[arg(long, value_name = "MIB")]
+    pub max_memory: Option<u64>,
+}
+
+/// Checks the budget at save points and reports periodically.
+pub struct Monitor {
+    max_bytes: Option<u64>,
+    last_report: Instant,
+}
+
+impl Monitor {
+    pub fn new(opts: &MemoryOptions) -> Self {
+        Self {
+            max_bytes: opts.max_memory.map(|mib| mib * MIB),
+            last_report: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self, stage: &str, cache: &ImageCache) -> anyhow::Result<()> {
+        if self.last_report.elapsed() >= REPORT_EVERY {
+            report(stage);
+            self.last_report = Instant::now();
+        }
+        let Some(max) = self.max_bytes else {
+            return Ok(());
+        };
+        if heap_bytes() <= max {
+            return Ok(());
+        }
+        warn!(
+            "{stage}: heap {} MiB is over the budget of {} MiB, flushing the image cache",
+            heap_bytes() / MIB,
+            max / MIB
+        );
+        cache.evict();
+        if heap_bytes() > max {
+            anyhow::bail!(
+                "{stage}: heap {} MiB is still over the budget of {} MiB after flushing \
+                 the image cache, stopping with the outputs saved so far",
+                heap_bytes() / MIB,
+                max / MIB
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Per-building metadata table, one JSON object per line.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    path::Path,
+};
+
+use geo::{Centroid, GeodesicArea, MultiPolygon, Polygon};
+use log::info;
+use osmpbfreader::{OsmId, Tags};
+use serde::Serialize;
+
+use crate::{
+    addresses::{self, AddressPoint},
+    attributes::{self, BuildingAttributes},
+    geometry::{
+        fill_small_holes, line_string, load_building_relations, relation_rings,
+        rings_to_multipolygon, MemberReport,
+    },
+    way_coords, OsmData,
+};
+
+#[derive(Serialize)]
+struct BuildingRecord<'a> {
+    osm_type: &'static str,
+    osm_id: i64,
+    building: &'a str,
+    area_m2: f64,
+    /// `[lon, lat]`
+    centroid: [f64; 2],
+    #[serde(flatten)]
+    attributes: BuildingAttributes,
+    #[serde(flatten)]
+    joinable: JoinableTags<'a>,
+    /// Members of a relation, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    members: Vec<Member<'a>>,
+    /// Address nodes inside the footprint, see `addresses`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    address_points: Vec<AddressPoint<'a>>,
+}
+
+/// Tags for joining with cadastral and addressing data.
+#[derive(Serialize)]
+struct JoinableTags<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(rename = "building:levels", skip_serializing_if = "Option::is_none")]
+    levels: Option<&'a str>,
+    /// `addr:*` tags without the prefix.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    address: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> JoinableTags<'a> {
+    fn from_tags(tags: &'a Tags) -> Self {
+        Self {
+            name: tags.get("name").map(|v| v.as_str()),
+            levels: tags.get("building:levels").map(|v| v.as_str()),
+            address: addresses::tags(tags),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Member<'a> {
+    #[serde(rename = "type")]
+    osm_type: &'static str,
+    id: i64,
+    role: &'a str,
+}
+
+fn osm_type(id: OsmId) -> &'static str {
+    match id {
+        OsmId::Node(_) => "node",
+        OsmId::Way(_) => "way",
+        OsmId::Relation(_) => "relation",
+    }
+}
+
+/// Writes `out` as JSON lines and the attribute vocabularies next to it as
+/// `<out>.labels.json`. With `relations`, building relations are read from
+/// `pbf` and written after the ways, with their members and roles, in
+/// place of the member ways that are tagged as buildings too. With
+/// `address_points`, address nodes are matched to the footprints. Holes
+/// under `min_hole_area` are filled in the relation footprints.
+pub fn export_metadata(
+    osm: &OsmData,
+    pbf: &std::ffi::OsStr,
+    relations: bool,
+    address_points: bool,
+    min_hole_area: Option<f64>,
+    out: &Path,
+) -> anyhow::Result<()> {
+    let loaded = match relations {
+        true => Some(load_building_relations(pbf)?),
+        false => None,
+    };
+    let mut relation_records = vec![];
+    let mut relation_footprints = vec![];
+    let mut written = HashSet::new();
+    if let Some(loaded) = &loaded {
+        let mut skipped = 0;
+        let mut filled = 0;
+        let mut report = MemberReport::default();
+        for rel in &loaded.relations {
+            let rings = relation_rings(rel, &loaded.ways, &loaded.all_relations);
+            report.add(&rings);
+            let mut area = rings_to_multipolygon(&rings, &loaded.nodes);
+            if let Some(min_m2) = min_hole_area {
+                filled += fill_small_holes(&mut area, min_m2);
+            }
+            let Some(center) = area.centroid() else {
+                skipped += 1;
+                continue;
+            };
+            let record = BuildingRecord {
+                osm_type: "relation",
+                osm_id: rel.id.0,
+                building: rel.tags.get("building").map(|v| v.as_str()).unwrap_or(""),
+                area_m2: area.geodesic_area_signed().abs(),
+                centroid: [center.x(), center.y()],
+                attributes: BuildingAttributes::from_tags(&rel.tags),
+                joinable: JoinableTags::from_tags(&rel.tags),
+                members: rel
+                    .refs
+                    .iter()
+                    .map(|r| Member {
+                        osm_type: osm_type(r.member),
+                        id: r.member.inner_id(),
+                        role: r.role.as_str(),
+                    })
+                    .collect(),
+                address_points: vec![],
+            };
+            relation_records.push(record);
+            relation_footprints.push(area);
+            written.insert(rel.id.0);
+        }
+        info!(
+            "Wrote {} building relations, skipped {skipped} without a closed outer ring",
+            loaded.relations.len() - skipped
+        );
+        report.log();
+        if let Some(min_m2) = min_hole_area {
+            info!("Filled {filled} holes under {min_m2} m^2");
+        }
+    }
+
+    let mut records = vec![];
+    let mut footprints = vec![];
+    // Member ways of the relations written are left out, so a building is
+    // one record, with the tags of the relation.
+    let mut ways = osm.standalone_buildings(&written);
+    if ways.len() < osm.ways_buildings.len() {
+        info!(
+            "Left out {} building ways that are part of a building relation",
+            osm.ways_buildings.len() - ways.len()
+        );
+    }
+    ways.sort_by_key(|w| w.id);
+    for way in ways {
+        let Some(coords) = way_coords(way, &osm.nodes_all) else {
+            continue;
+        };
+        if coords.len() < 3 {
+            continue;
+        }
+        let poly = Polygon::new(line_string(&coords), vec![]);
+        let Some(center) = poly.centroid() else {
+            continue;
+        };
+        let record = BuildingRecord {
+            osm_type: "way",
+            osm_id: way.id.0,
+            building: way.tags.get("building").map(|v| v.as_str()).unwrap_or(""),
+            area_m2: poly.geodesic_area_signed().abs(),
+            centroid: [center.x(), center.y()],
+            attributes: BuildingAttributes::from_tags(&way.tags),
+            joinable: JoinableTags::from_tags(&way.tags),
+            members: vec![],
+            address_points: vec![],
+        };
+        records.push(record);
+        footprints.push(MultiPolygon::new(vec![poly]));
+    }
+    records.extend(relation_records);
+    footprints.extend(relation_footprints);
+    if address_points {
+        let assigned = addresses::assign(&footprints, osm.nodes_all.tagged());
+        let matched: usize = assigned.iter().map(Vec::len).sum();
+        info!("Matched {matched} address points to footprints");
+        for (record, points) in records.iter_mut().zip(assigned) {
+            record.address_points = points;
+        }
+    }
+
+    let mut w = std::io::BufWriter::new(std::fs::File::create(out)?);
+    for record in &records {
+        serde_json::to_writer(&mut w, record)?;
+        writeln!(w)?;
+    }
+    w.flush()?;
+
+    let mut labels = out.as_os_str().to_owned();
+    labels.push(".labels.json");
+    std::fs::write(
+        labels,
+        serde_json::to_vec_pretty(&attributes::vocabularies())?,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Prometheus metrics of long runs, so that a job going for days can be
+//! watched from the usual dashboards. `--metrics ADDR` serves them at
+//! `http://ADDR/metrics` during `download-tiles` and `render-outlines`,
+//! and `serve` adds them, with its own, as `/metrics` of its endpoints.
+//! All counters start at zero with the process.
+
+use std::{
+    fmt::Write as _,
+    net::TcpListener,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+};
+
+use log::{info, warn};
+
+use crate::{service, timing};
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, delta: i64) {
+        self.0.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub static TILES_DOWNLOADED: Counter = Counter::new();
+pub static DOWNLOAD_FAILURES: Counter = Counter::new();
+pub static DOWNLOAD_RETRIES: Counter = Counter::new();
+/// Tiles of the area `download-tiles` has yet to go through.
+pub static DOWNLOADS_PENDING: Gauge = Gauge::new();
+pub static OBJECTS_DRAWN: Counter = Counter::new();
+pub static OBJECTS_SKIPPED: Counter = Counter::new();
+pub static OBJECTS_FAILED: Counter = Counter::new();
+pub static TILES_SAVED: Counter = Counter::new();
+/// Tiles drawn into and not saved yet.
+pub static TILES_DIRTY: Gauge = Gauge::new();
+
+/// The text exposition format, one metric after another.
+#[derive(Default)]
+pub struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        writeln!(self.0, "# HELP gendata_{name} {help}").unwrap();
+        writeln!(self.0, "# TYPE gendata_{name} {kind}").unwrap();
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        writeln!(self.0, "gendata_{name} {value}").unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        writeln!(self.0, "gendata_{name} {value}").unwrap();
+    }
+
+    /// A counter with one sample per value of `label`.
+    pub fn labeled(&mut self, name: &str, help: &str, label: &str, values: &[(&str, f64)]) {
+        self.header(name, help, "counter");
+        for (value, sample) in values {
+            writeln!(self.0, "gendata_{name}{{{label}=\"{value}\"}} {sample}").unwrap();
+        }
+    }
+
+    /// The metrics every mode has.
+    pub fn process(mut self) -> Self {
+        self.counter(
+            "tiles_downloaded_total",
+            "Imagery tiles downloaded.",
+            TILES_DOWNLOADED.get(),
+        );
+        self.counter(
+            "download_failures_total",
+            "Tile downloads that failed after all retries.",
+            DOWNLOAD_FAILURES.get(),
+        );
+        self.counter(
+            "download_retries_total",
+            "Tile requests tried again after a transient failure.",
+            DOWNLOAD_RETRIES.get(),
+        );
+        self.gauge(
+            "downloads_pending",
+            "Tiles of the area download-tiles has yet to go through.",
+            DOWNLOADS_PENDING.get() as f64,
+        );
+        self.counter(
+            "objects_drawn_total",
+            "Objects drawn into outlines.",
+            OBJECTS_DRAWN.get(),
+        );
+        self.counter(
+            "objects_skipped_total",
+            "Objects skipped for unusable geometry.",
+            OBJECTS_SKIPPED.get(),
+        );
+        self.counter(
+            "objects_failed_total",
+            "Objects that could not be drawn.",
+            OBJECTS_FAILED.get(),
+        );
+        self.counter(
+            "tiles_saved_total",
+            "Outline tiles written.",
+            TILES_SAVED.get(),
+        );
+        self.gauge(
+            "tiles_dirty",
+            "Tiles drawn into and not saved yet.",
+            TILES_DIRTY.get() as f64,
+        );
+        self.labeled(
+            "stage_seconds_total",
+            "Time spent per stage, summed over threads.",
+            "stage",
+            &timing::seconds(),
+        );
+        self
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.0.into_bytes()
+    }
+}
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct MetricsOptions {
+    /// Serve Prometheus metrics at `http://ADDR/metrics` while running,
+    /// e.g. `0.0.0.0:9184`.
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub metrics: Option<String>,
+}
+
+/// Starts serving the metrics in the background, with `--metrics`.
+pub fn start(opts: &MetricsOptions) -> anyhow::Result<()> {
+    let Some(addr) = &opts.metrics else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(addr).map_err(|why| {
+        anyhow::anyhow!(
+            "cannot serve metrics on {addr}: {why}\n\
+             hint: pick another address with --metrics"
+        )
+    })?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(why) => {
+                    warn!("error accepting a connection: {why}");
+                    continue;
+                }
+            };
+            let written = match service::read_request(&stream) {
+                Ok(target) if target.split('?').next() == Some("/metrics") => {
+                    let body = Exposition::default().process().into_body();
+                    service::respond(&mut stream, 200, CONTENT_TYPE, &body)
+                }
+                Ok(target) => {
+                    let message = format!("no endpoint {target}, metrics are at /metrics");
+                    service::respond(&mut stream, 404, "text/plain", message.as_bytes())
+                }
+                Err(why) => {
+                    info!("error reading a request: {why}");
+                    continue;
+                }
+            };
+            if let Err(why) = written {
+                info!("error answering a metrics request: {why}");
+            }
+        }
+    });
+    Ok(())
+}
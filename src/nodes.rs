@@ -0,0 +1,253 @@
+//! Node coordinates without holding every node of the extract. The PBF is
+//! read twice: the first pass keeps the objects `OsmData` needs and the
+//! tagged nodes, the second only the coordinates of the untagged nodes the
+//! kept ways reference. Those are kept as a sorted array of 16 byte
+//! records, in memory or, with `--node-store FILE`, in that file with
+//! every `INDEX_EVERY`th id in memory, for extracts too large for either.
+//!
+//! The store file is written anew on every load, and needs an extract
+//! sorted by id, as `osmium sort` leaves it.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use osmpbfreader::Node;
+
+use crate::{geometry::node_coord, GeoCoordinate};
+
+static STORE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Bytes of a record: the id, then latitude and longitude in decimicro
+/// degrees, little-endian.
+const RECORD: usize = 16;
+/// Records per block of the store file, a page.
+const INDEX_EVERY: usize = 256;
+
+/// Sets `--node-store` for the rest of the run.
+pub fn set_store(path: Option<PathBuf>) {
+    STORE.set(path).expect("node store set twice");
+}
+
+/// The file of `--node-store`, `None` to keep coordinates in memory.
+pub fn store() -> Option<&'static Path> {
+    STORE.get().and_then(Option::as_deref)
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    id: i64,
+    lat: i32,
+    lon: i32,
+}
+
+impl Record {
+    fn coord(self) -> GeoCoordinate {
+        GeoCoordinate {
+            longitude: (self.lon as f64) / 10_000_000.0,
+            latitude: (self.lat as f64) / 10_000_000.0,
+        }
+    }
+
+    fn encode(self) -> [u8; RECORD] {
+        let mut bytes = [0; RECORD];
+        bytes[..8].copy_from_slice(&self.id.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.lat.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.lon.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Record {
+            id: i64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            lat: i32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            lon: i32::from_le_bytes(bytes[12..].try_into().unwrap()),
+        }
+    }
+}
+
+enum Coords {
+    Memory(Vec<Record>),
+    Disk {
+        file: File,
+        /// First id of every block.
+        index: Vec<i64>,
+        len: usize,
+    },
+}
+
+impl Coords {
+    fn get(&self, id: i64) -> Option<Record> {
+        match self {
+            Coords::Memory(records) => records
+                .binary_search_by_key(&id, |r| r.id)
+                .ok()
+                .map(|i| records[i]),
+            Coords::Disk { file, index, len } => {
+                let block = index.partition_point(|first| *first <= id).checked_sub(1)?;
+                let start = block * INDEX_EVERY;
+                let count = INDEX_EVERY.min(len - start);
+                let mut bytes = vec![0; count * RECORD];
+                file.read_exact_at(&mut bytes, (start * RECORD) as u64)
+                    .expect("the node store cannot be read");
+                let records: Vec<_> = bytes.chunks_exact(RECORD).map(Record::decode).collect();
+                records
+                    .binary_search_by_key(&id, |r| r.id)
+                    .ok()
+                    .map(|i| records[i])
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Coords::Memory(records) => records.len(),
+            Coords::Disk { len, .. } => *len,
+        }
+    }
+}
+
+/// The nodes of an extract that ways are drawn with: tagged nodes whole,
+/// untagged ones by their coordinates.
+pub struct Nodes {
+    tagged: HashMap<i64, Node>,
+    coords: Coords,
+}
+
+impl Nodes {
+    pub fn coord(&self, id: i64) -> Option<GeoCoordinate> {
+        match self.tagged.get(&id) {
+            Some(node) => Some(node_coord(node)),
+            None => self.coords.get(id).map(Record::coord),
+        }
+    }
+
+    /// Nodes with tags, e.g. addresses and point features.
+    pub fn tagged(&self) -> &HashMap<i64, Node> {
+        &self.tagged
+    }
+
+    pub fn len(&self) -> usize {
+        self.tagged.len() + self.coords.len()
+    }
+}
+
+enum Sink {
+    Memory(Vec<Record>),
+    Disk {
+        path: PathBuf,
+        out: BufWriter<File>,
+        index: Vec<i64>,
+        len: usize,
+        last: Option<i64>,
+    },
+}
+
+/// Collects nodes into `Nodes`, writing untagged ones to the store file
+/// as they come if there is one.
+pub struct Builder {
+    tagged: HashMap<i64, Node>,
+    sink: Sink,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            tagged: HashMap::new(),
+            sink: Sink::Memory(vec![]),
+        }
+    }
+}
+
+impl Builder {
+    /// A builder writing to `store`, or keeping coordinates in memory.
+    pub fn new(store: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(path) = store else {
+            return Ok(Builder::default());
+        };
+        let file = File::create(path)
+            .map_err(|why| anyhow::anyhow!("cannot create node store {}: {why}", path.display()))?;
+        Ok(Builder {
+            tagged: HashMap::new(),
+            sink: Sink::Disk {
+                path: path.to_owned(),
+                out: BufWriter::new(file),
+                index: vec![],
+                len: 0,
+                last: None,
+            },
+        })
+    }
+
+    pub fn push(&mut self, node: Node) -> anyhow::Result<()> {
+        if !node.tags.is_empty() {
+            self.tagged.insert(node.id.0, node);
+            return Ok(());
+        }
+        let record = Record {
+            id: node.id.0,
+            lat: node.decimicro_lat,
+            lon: node.decimicro_lon,
+        };
+        match &mut self.sink {
+            Sink::Memory(records) => records.push(record),
+            Sink::Disk {
+                out,
+                index,
+                len,
+                last,
+                ..
+            } => {
+                match *last {
+                    Some(last) if last == record.id => return Ok(()),
+                    Some(last) if last > record.id => anyhow::bail!(
+                        "node {} comes after node {last}, --node-store needs an extract sorted by id\n\
+                         hint: sort it with `osmium sort`, or leave out --node-store",
+                        record.id
+                    ),
+                    _ => {}
+                }
+                if *len % INDEX_EVERY == 0 {
+                    index.push(record.id);
+                }
+                out.write_all(&record.encode())?;
+                *len += 1;
+                *last = Some(record.id);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<Nodes> {
+        let coords = match self.sink {
+            Sink::Memory(mut records) => {
+                records.sort_unstable_by_key(|r| r.id);
+                records.dedup_by_key(|r| r.id);
+                Coords::Memory(records)
+            }
+            Sink::Disk {
+                path,
+                out,
+                index,
+                len,
+                ..
+            } => {
+                out.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+                Coords::Disk {
+                    file: File::open(path)?,
+                    index,
+                    len,
+                }
+            }
+        };
+        Ok(Nodes {
+            tagged: self.tagged,
+            coords,
+        })
+    }
+}
@@ -0,0 +1,115 @@
+//! Deliberate label noise for robustness experiments. Every building gets
+//! its own random stream derived from the seed and its OSM id, so a run is
+//! reproducible and independent of drawing order.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{classes::FeatureClass, rng::Rng, GeoCoordinate};
+
+const NOISE_FILE: &str = "noise.json";
+
+/// Classes a flipped building can end up as.
+const FLIP_CLASSES: &[FeatureClass] = &[
+    FeatureClass::BuildingBelowAreaThreshold,
+    FeatureClass::Normal,
+    FeatureClass::BuildingHasExcludedTags,
+];
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+pub struct NoiseOptions {
+    /// Fraction of buildings left out of the labels entirely.
+    #[arg(long, default_value_t = 0.0)]
+    pub noise_dropout: f64,
+    /// Move every footprint vertex by up to this many meters in a random
+    /// direction.
+    #[arg(long, default_value_t = 0.0)]
+    pub noise_jitter: f64,
+    /// Fraction of buildings drawn as a different building class.
+    #[arg(long, default_value_t = 0.0)]
+    pub noise_flip: f64,
+    #[arg(long, default_value_t = 0)]
+    pub noise_seed: u64,
+}
+
+impl NoiseOptions {
+    pub fn enabled(&self) -> bool {
+        self.noise_dropout > 0.0 || self.noise_jitter > 0.0 || self.noise_flip > 0.0
+    }
+}
+
+/// What the noise did, saved along with the options.
+#[derive(Debug, Default, Serialize)]
+pub struct NoiseStats {
+    pub buildings: u64,
+    pub dropped: u64,
+    pub jittered: u64,
+    pub flipped: u64,
+}
+
+#[derive(Serialize)]
+struct NoiseRecord<'a> {
+    options: &'a NoiseOptions,
+    stats: &'a NoiseStats,
+}
+
+/// Applies the configured noise to one building. Returns `None` if the
+/// building is dropped.
+pub fn perturb(
+    opts: &NoiseOptions,
+    stats: &mut NoiseStats,
+    id: i64,
+    class: FeatureClass,
+    coords: &[GeoCoordinate],
+) -> Option<(FeatureClass, Vec<GeoCoordinate>)> {
+    stats.buildings += 1;
+    // Draw every sample regardless of the options, so that changing one
+    // rate does not reshuffle the effects of the others.
+    let mut rng = Rng::new(opts.noise_seed, id);
+    let drop = rng.next_f64() < opts.noise_dropout;
+    let flip = rng.next_f64() < opts.noise_flip;
+    let flip_to = rng.below(FLIP_CLASSES.len() - 1);
+    if drop {
+        stats.dropped += 1;
+        return None;
+    }
+
+    let mut class = class;
+    if flip {
+        let others: Vec<_> = FLIP_CLASSES.iter().filter(|c| **c != class).collect();
+        class = *others[flip_to.min(others.len() - 1)];
+        stats.flipped += 1;
+    }
+
+    let mut coords = coords.to_vec();
+    if opts.noise_jitter > 0.0 {
+        let closed = coords.len() > 1 && coords.first() == coords.last();
+        let n = if closed {
+            coords.len() - 1
+        } else {
+            coords.len()
+        };
+        for c in &mut coords[..n] {
+            let r = opts.noise_jitter * rng.next_f64().sqrt();
+            let angle = rng.next_f64() * std::f64::consts::TAU;
+            let meters_per_deg = 111_320.0;
+            c.latitude += r * angle.sin() / meters_per_deg;
+            c.longitude += r * angle.cos() / (meters_per_deg * c.latitude.to_radians().cos());
+        }
+        if closed {
+            coords[n] = coords[0];
+        }
+        stats.jittered += 1;
+    }
+    Some((class, coords))
+}
+
+pub fn save(opts: &NoiseOptions, stats: &NoiseStats, dir: &Path) -> anyhow::Result<()> {
+    let record = NoiseRecord {
+        options: opts,
+        stats,
+    };
+    std::fs::write(dir.join(NOISE_FILE), serde_json::to_vec_pretty(&record)?)?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! One tile, fast, to try rendering parameters on: `render-tile Z X Y`
+//! downloads the imagery of the tile if it is not in `tiles/` yet and
+//! draws its labels the way `render-outlines` does, into a directory of
+//! their own. The extract comes from the PBF checkpoint once it has been
+//! parsed, and only what reaches the tile is drawn, as in a work unit of
+//! the tile's size, so a run takes seconds rather than a full render.
+
+use std::{ffi::OsStr, path::PathBuf, time::Instant};
+
+use slippy_map_tiles::Tile;
+
+use crate::{
+    dedup, download_tile, history, lines, load_building_areas, load_osm, logging, paths, release,
+    render_command, repro, tilecache, worklist,
+    workspace::{OUTLINES, TILES},
+    zoom, RenderArgs,
+};
+
+#[derive(clap::Args)]
+pub struct TileOptions {
+    /// Zoom of the tile, in place of `--zoom`.
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=19))]
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+    /// Directory the tile is rendered in, with its imagery linked into
+    /// `tiles/` next to the labels. Emptied first.
+    #[arg(long, default_value = "render-tile")]
+    pub out: PathBuf,
+}
+
+/// Renders the tile of `opts` as `args` render tiles, into `opts.out`.
+pub fn render_tile(pbf: &OsStr, opts: &TileOptions, mut args: RenderArgs) -> anyhow::Result<u8> {
+    let started = Instant::now();
+    let tile = Tile::new(zoom(), opts.x, opts.y).ok_or_else(|| {
+        anyhow::anyhow!(
+            "there is no tile {}/{}/{}\n\
+             hint: x and y go up to {} at zoom {}",
+            opts.z,
+            opts.x,
+            opts.y,
+            (1u32 << opts.z) - 1,
+            opts.z
+        )
+    })?;
+    anyhow::ensure!(
+        worklist::contains(tile),
+        "tile {}/{}/{} is not in --tile-list",
+        opts.z,
+        opts.x,
+        opts.y
+    );
+    args.check()?;
+
+    let imagery = paths::tile_file(TILES, tile, ".jpg");
+    if !imagery.is_file() {
+        logging::status!("Downloading {}", imagery.display());
+        download_tile(dedup::Store::detect()?.as_ref(), tile)?;
+        tilecache::flush();
+    }
+
+    repro::clear_scratch(&opts.out, "render-tile", "--out")?;
+    // A unit the size of the tile, so that nothing else is drawn.
+    args.units.unit_zoom = Some(zoom());
+    args.units.only = Some([tile].into());
+    args.units.after_unit = None;
+    args.units.prefetch = None;
+    args.out_dir = opts.out.clone();
+    let line_features = lines::load_lines(pbf, &args.lines);
+    let building_areas = match args.relations {
+        true => load_building_areas(pbf)?,
+        false => vec![],
+    };
+    let osm = match &args.as_of {
+        Some(date) => history::load_snapshot(pbf, history::parse_date(date)?)?,
+        None => load_osm(pbf)?,
+    };
+    let code = render_command(
+        &osm,
+        &line_features,
+        &building_areas,
+        args,
+        opts.out.clone(),
+    )?;
+
+    let mask = paths::tile_file(opts.out.join(OUTLINES), tile, ".png");
+    if !mask.is_file() {
+        // Nothing reaches the tile; its labels are all background.
+        let (w, h) = image::image_dimensions(&imagery)?;
+        std::fs::create_dir_all(mask.parent().unwrap_or(&opts.out))?;
+        image::RgbImage::new(w, h).save(&mask)?;
+    }
+    let linked = paths::create_tile_file(opts.out.join(TILES), tile, ".jpg")?;
+    release::link_or_copy(&imagery, &linked)?;
+    logging::status!(
+        "Rendered {} and {} in {:.1} s",
+        linked.display(),
+        mask.display(),
+        started.elapsed().as_secs_f64()
+    );
+    Ok(code)
+}
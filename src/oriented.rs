@@ -0,0 +1,119 @@
+//! Oriented bounding boxes of the buildings in every stitched chip, in the
+//! DOTA layout: `labelTxt/<chip>.txt` next to `images/<chip>.jpg`, one
+//! line `x1 y1 x2 y2 x3 y3 x4 y4 category difficult` per building with the
+//! corners of its minimum rotated rectangle clockwise from the top left.
+
+use std::{fmt::Write as _, path::PathBuf};
+
+use geo::{Area, BoundingRect, MinimumRotatedRect, Point, Polygon};
+use log::info;
+
+use crate::{
+    chips::Grid,
+    classes::{ClassOptions, FeatureClass},
+    formats::Formats,
+    logging,
+    release::link_or_copy,
+    workspace::{STITCHED, TILES},
+    ImageCache, OsmData,
+};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct OrientedOptions {
+    /// Directory with `tiles/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    #[arg(long, default_value = "dota")]
+    pub out: PathBuf,
+    #[command(flatten)]
+    pub classes: ClassOptions,
+}
+
+/// Category of a building class in the DOTA and COCO exports, `None`
+/// for everything else.
+pub fn category(class: FeatureClass) -> Option<&'static str> {
+    Some(match class {
+        FeatureClass::Normal => "building",
+        FeatureClass::BuildingBelowAreaThreshold => "small-building",
+        FeatureClass::BuildingHasExcludedTags => "excluded-building",
+        FeatureClass::UnderConstruction => "building-under-construction",
+        FeatureClass::Greenhouse => "greenhouse",
+        FeatureClass::Barn => "barn",
+        FeatureClass::FarmAuxiliary => "farm-auxiliary",
+        _ => return None,
+    })
+}
+
+/// The corners of `rect`, clockwise on screen, i.e. with +y down, starting
+/// with the one nearest to the top left.
+fn corners(rect: &Polygon<f64>) -> Vec<Point<f64>> {
+    let mut corners: Vec<_> = rect.exterior().points().take(4).collect();
+    // Counter-clockwise with +y up is clockwise with +y down.
+    if rect.signed_area() < 0.0 {
+        corners.reverse();
+    }
+    let first = (0..corners.len())
+        .min_by(|a, b| {
+            let sum = |p: Point<f64>| p.x() + p.y();
+            sum(corners[*a]).total_cmp(&sum(corners[*b]))
+        })
+        .unwrap_or(0);
+    corners.rotate_left(first);
+    corners
+}
+
+pub fn export_oriented_boxes(osm: &OsmData, opts: &OrientedOptions) -> anyhow::Result<()> {
+    let grid = Grid::load(&opts.chips)?;
+    let mut labels = vec![String::new(); grid.chips.len()];
+    info!("Boxing buildings in {} chips", grid.chips.len());
+
+    let mut boxes = 0;
+    for (class, ring) in grid.buildings(osm, &opts.classes)? {
+        let Some(category) = category(class) else {
+            continue;
+        };
+        let Some(rect) = Polygon::new(ring, vec![]).minimum_rotated_rect() else {
+            continue;
+        };
+        let Some(bounds) = rect.bounding_rect() else {
+            continue;
+        };
+        let corners = corners(&rect);
+        for i in grid.overlapping(bounds) {
+            let [x0, y0, x1, y1] = grid.chips[i].px;
+            let inside = |p: &Point<f64>| (x0..=x1).contains(&p.x()) && (y0..=y1).contains(&p.y());
+            // Buildings cut by the chip edge count as difficult, the way
+            // DOTA marks objects that are hard to make out.
+            let difficult = !corners.iter().all(inside) as u8;
+            for p in &corners {
+                write!(labels[i], "{:.1} {:.1} ", p.x() - x0, p.y() - y0).unwrap();
+            }
+            writeln!(labels[i], "{category} {difficult}").unwrap();
+            boxes += 1;
+        }
+    }
+
+    let label_dir = opts.out.join("labelTxt");
+    let images = opts.out.join("images");
+    std::fs::create_dir_all(&label_dir)?;
+    std::fs::create_dir_all(&images)?;
+    let ext = Formats::load()?.chips.ext();
+    for (chip, labels) in grid.chips.iter().zip(&labels) {
+        let image = images.join(format!("{}{ext}", chip.key));
+        // Relinked every time, the chip may have been stitched again.
+        if image.exists() {
+            std::fs::remove_file(&image)?;
+        }
+        link_or_copy(
+            &opts.chips.join(TILES).join(format!("{}{ext}", chip.key)),
+            &image,
+        )?;
+        let gsd_m = 1.0 / ImageCache::pixels_per_meter(chip.center, (grid.tile_px, grid.tile_px));
+        std::fs::write(
+            label_dir.join(format!("{}.txt", chip.key)),
+            format!("imagesource:ArcGIS World Imagery\ngsd:{gsd_m:.4}\n{labels}"),
+        )?;
+    }
+    logging::status!("Wrote {boxes} boxes for {} chips", grid.chips.len());
+    Ok(())
+}
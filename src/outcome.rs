@@ -0,0 +1,82 @@
+//! Exit codes for automation. A run that gets to the end exits with
+//! `SUCCESS` if everything made it into the output, `PARTIAL` if some
+//! objects were skipped or failed within the thresholds and
+//! `TOO_MANY_FAILURES` otherwise. Fatal errors exit with 1, and panics with
+//! 101, as usual for Rust programs.
+
+use log::info;
+
+use crate::{logging, metrics};
+
+pub const SUCCESS: u8 = 0;
+pub const PARTIAL: u8 = 2;
+pub const TOO_MANY_FAILURES: u8 = 3;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Thresholds {
+    /// Exit with 3 if more than this many objects failed.
+    #[arg(long)]
+    pub max_failures: Option<u64>,
+    /// Exit with 3 if more than this fraction of objects failed.
+    #[arg(long)]
+    pub max_failure_rate: Option<f64>,
+}
+
+/// Counts of one run. Skipped objects had unusable geometry, failed ones
+/// could not be drawn.
+#[derive(Debug, Default)]
+pub struct RunOutcome {
+    pub total: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    /// Reason of the first failure, which usually explains the rest.
+    pub first_error: Option<String>,
+}
+
+impl RunOutcome {
+    /// Counts the result of drawing one object, `Ok(false)` meaning it was
+    /// skipped.
+    pub fn count(&mut self, result: anyhow::Result<bool>) {
+        self.total += 1;
+        match result {
+            Ok(true) => metrics::OBJECTS_DRAWN.inc(),
+            Ok(false) => {
+                self.skipped += 1;
+                metrics::OBJECTS_SKIPPED.inc();
+            }
+            Err(why) => {
+                metrics::OBJECTS_FAILED.inc();
+                info!("error fetching outline: {why}");
+                self.failed += 1;
+                self.first_error.get_or_insert_with(|| format!("{why:#}"));
+            }
+        }
+    }
+
+    /// `SUCCESS`, `PARTIAL` or `TOO_MANY_FAILURES`, printing the counts.
+    pub fn code(&self, thresholds: &Thresholds) -> u8 {
+        logging::status!(
+            "{} objects, {} skipped, {} failed",
+            self.total,
+            self.skipped,
+            self.failed
+        );
+        if let Some(why) = &self.first_error {
+            logging::status!("First failure: {why}");
+        }
+        let rate = self.failed as f64 / self.total.max(1) as f64;
+        if let Some(max) = thresholds.max_failures.filter(|max| self.failed > *max) {
+            logging::status!("More than {max} objects failed");
+            return TOO_MANY_FAILURES;
+        }
+        if let Some(max) = thresholds.max_failure_rate.filter(|max| rate > *max) {
+            logging::status!("Failure rate {rate:.4} is above {max}");
+            return TOO_MANY_FAILURES;
+        }
+        if self.skipped + self.failed > 0 {
+            PARTIAL
+        } else {
+            SUCCESS
+        }
+    }
+}
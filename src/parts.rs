@@ -0,0 +1,153 @@
+//! Buildings modeled in 3D: a `building` outline of the whole and
+//! `building:part` ways for the parts of it with a height or roof of their
+//! own, tied together by a `type=building` relation with the outline as its
+//! `outline` member and the parts as its `part` members. Parts are often
+//! tagged `building` as well, which would draw them over the outline as
+//! buildings of their own. `--building-parts` picks one of the two:
+//!
+//! - `outline`, the default: the outline is the building, its parts are
+//!   left out;
+//! - `parts`: the parts are the buildings, in place of the outline. Each
+//!   carries the tags of the outline under its own, so that a part is of
+//!   the class of the building it belongs to unless it says otherwise.
+//!
+//! Parts are only tied to their building by a relation; `building:part`
+//! ways outside one are left out either way, and so are relations without
+//! any of their parts in the extract, whose outline is kept.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use log::info;
+use osmpbfreader::{OsmId, Relation, Tags, Way};
+
+static MODE: OnceLock<Parts> = OnceLock::new();
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Parts {
+    /// Draw the outline of a building, not its parts.
+    #[default]
+    Outline,
+    /// Draw the parts of a building instead of its outline.
+    Parts,
+}
+
+/// Sets `--building-parts` for the rest of the run.
+pub fn set_mode(mode: Parts) {
+    MODE.set(mode).expect("building parts set twice");
+}
+
+pub fn mode() -> Parts {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Whether `tags` are those of a part of a building.
+pub fn is_part(tags: &Tags) -> bool {
+    tags.get("building:part").is_some_and(|v| v != "no")
+}
+
+/// Whether `tags` are those of a relation tying a building to its parts.
+pub fn is_building_relation(tags: &Tags) -> bool {
+    tags.contains("type", "building")
+}
+
+/// The `outline` and the `part` member ways of `rel`.
+fn members(rel: &Relation) -> (Vec<i64>, Vec<i64>) {
+    let (mut outlines, mut parts) = (vec![], vec![]);
+    for r in &rel.refs {
+        if let OsmId::Way(id) = r.member {
+            match r.role.as_str() {
+                "outline" => outlines.push(id.0),
+                "part" => parts.push(id.0),
+                _ => {}
+            }
+        }
+    }
+    // A building of one part may have the same way as both.
+    parts.retain(|id| !outlines.contains(id));
+    (outlines, parts)
+}
+
+/// Whether `--building-parts parts` draws the parts of `rel` rather than
+/// the relation, which then has some among `ways`.
+pub fn drawn_as_parts(rel: &Relation, ways: &HashMap<i64, Way>) -> bool {
+    mode() == Parts::Parts
+        && is_building_relation(&rel.tags)
+        && members(rel).1.iter().any(|id| ways.contains_key(id))
+}
+
+/// The tags of a part drawn as a building: those of its building, from
+/// `parent`, under its own, and `building` from `building:part` unless
+/// that just says `yes`.
+fn part_tags(part: &Tags, parent: &Tags) -> Tags {
+    let mut tags: Tags = parent
+        .iter()
+        .filter(|(k, _)| k.as_str() != "type")
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    for (k, v) in part.iter() {
+        tags.insert(k.clone(), v.clone());
+    }
+    if !part.contains_key("building") {
+        match part.get("building:part").map(|v| v.as_str()) {
+            Some(kind) if kind != "yes" => {
+                tags.insert("building".into(), kind.into());
+            }
+            _ => {
+                tags.entry("building".into())
+                    .or_insert_with(|| "yes".into());
+            }
+        }
+    }
+    tags
+}
+
+/// Keeps either the outline or the parts, in `buildings`, of every
+/// building of `relations`; `parts` are the part ways not tagged
+/// `building`, which are only drawn as parts.
+pub fn resolve(
+    buildings: &mut HashMap<i64, Way>,
+    mut parts: HashMap<i64, Way>,
+    relations: &[Relation],
+) {
+    let mode = mode();
+    let (mut dropped, mut replaced) = (0, 0);
+    for rel in relations {
+        let (outlines, part_ids) = members(rel);
+        match mode {
+            Parts::Outline => {
+                for id in part_ids {
+                    dropped += buildings.remove(&id).is_some() as usize;
+                }
+            }
+            Parts::Parts => {
+                let found: Vec<_> = part_ids
+                    .iter()
+                    .filter_map(|id| buildings.remove(id).or_else(|| parts.remove(id)))
+                    .collect();
+                if found.is_empty() {
+                    continue;
+                }
+                let parent = outlines
+                    .iter()
+                    .find_map(|id| buildings.remove(id))
+                    .map(|w| w.tags)
+                    .unwrap_or_else(|| rel.tags.clone());
+                for id in &outlines {
+                    buildings.remove(id);
+                }
+                for mut way in found {
+                    way.tags = part_tags(&way.tags, &parent);
+                    buildings.insert(way.id.0, way);
+                }
+                replaced += 1;
+            }
+        }
+    }
+    match mode {
+        Parts::Outline if dropped > 0 => {
+            info!("Left out {dropped} building parts tagged `building`, drawn by their outline")
+        }
+        Parts::Parts if replaced > 0 => info!("Drew {replaced} buildings as their parts"),
+        _ => {}
+    }
+}
@@ -0,0 +1,468 @@
+//! File names of tiles and chips. Paths are joined from components rather
+//! than formatted with `/`, and names are parsed from the `OsStr` read
+//! off the directory, so neither the separator of the platform nor a
+//! directory whose name is not UTF-8 gets in the way.
+//!
+//! Where a tile file lies under the directory of its kind follows
+//! `tile_paths` in `formats.json`, a `/`-separated template such as
+//! `{root}/{aoi}/{kind}/{z}/{x}/{y}.{ext}`, so that the output can match
+//! the layout a team already uses. `{root}` is the directory of a run,
+//! `{kind}` that of the artifact, e.g. `tiles` or `outlines`, `{aoi}` the
+//! `--aoi` name, `{name}` the tile name and `{ext}` the file extension.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use slippy_map_tiles::Tile;
+
+use crate::{logging, zoom};
+
+/// How coordinates are written in tile names, `tile_names` in
+/// `formats.json`. Names of either kind are read back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileNames {
+    /// As long as the numbers are.
+    #[default]
+    Plain,
+    /// Zero-padded to `PADDED_DIGITS`, so that names sort row by row, the
+    /// way `ls` and object store listings return them, and a prefix
+    /// selects a band of rows.
+    Padded,
+}
+
+/// Enough for every tile up to `MAX_ZOOM`.
+const PADDED_DIGITS: usize = 6;
+
+/// `{root}/{kind}/{name}.{ext}`, the flat layout of a directory per kind.
+pub const DEFAULT_TILE_PATHS: &str = "{root}/{kind}/{name}.{ext}";
+const PLACEHOLDERS: &[&str] = &["aoi", "kind", "z", "x", "y", "name", "ext"];
+
+static TILE_NAMES: OnceLock<TileNames> = OnceLock::new();
+static LAYOUT: OnceLock<Layout> = OnceLock::new();
+
+/// The resolved `tile_paths` template.
+struct Layout {
+    /// Components after `{root}`.
+    components: Vec<String>,
+    aoi: String,
+}
+
+/// Sets the naming for the rest of the run, before any name is made.
+pub fn set_tile_names(names: TileNames) {
+    TILE_NAMES.set(names).expect("tile names set twice");
+}
+
+/// Sets the `tile_paths` template and the `{aoi}` for the rest of the
+/// run, before any path is made.
+pub fn set_tile_paths(template: &str, aoi: &str) -> anyhow::Result<()> {
+    let Some(rest) = template.strip_prefix("{root}/") else {
+        bail!(
+            "tile_paths {template} does not start with {{root}}/\n\
+             hint: the default is {DEFAULT_TILE_PATHS}"
+        );
+    };
+    let mut used = vec![];
+    for component in rest.split('/') {
+        if component.is_empty() || component == "." || component == ".." {
+            bail!("tile_paths {template} has an empty, `.` or `..` component");
+        }
+        let mut chars = component;
+        while let Some(start) = chars.find('{') {
+            let Some(len) = chars[start..].find('}') else {
+                bail!("tile_paths {template} has an unclosed {{");
+            };
+            let name = &chars[start + 1..start + len];
+            if !PLACEHOLDERS.contains(&name) {
+                bail!(
+                    "tile_paths {template} has an unknown {{{name}}}\n\
+                     hint: known are {{aoi}}, {{kind}}, {{z}}, {{x}}, {{y}}, {{name}} and {{ext}}"
+                );
+            }
+            used.push(name);
+            chars = &chars[start + len + 1..];
+            if chars.starts_with('{') {
+                bail!("tile_paths {template} has two placeholders in a row, which cannot be told apart");
+            }
+        }
+    }
+    let has = |name| used.contains(&name);
+    if !has("kind") || !has("ext") || !(has("name") || (has("x") && has("y"))) {
+        bail!(
+            "tile_paths {template} does not tell kinds, extensions or tiles apart\n\
+             hint: use {{kind}}, {{ext}}, and {{name}} or both {{x}} and {{y}}"
+        );
+    }
+    let layout = Layout {
+        components: rest.split('/').map(str::to_owned).collect(),
+        aoi: aoi.to_owned(),
+    };
+    LAYOUT.set(layout).ok().expect("tile paths set twice");
+    Ok(())
+}
+
+fn layout() -> &'static Layout {
+    LAYOUT.get_or_init(|| Layout {
+        components: DEFAULT_TILE_PATHS
+            .split('/')
+            .skip(1)
+            .map(str::to_owned)
+            .collect(),
+        aoi: String::new(),
+    })
+}
+
+/// `dir` split into the `{root}` and `{kind}` of the template.
+fn root_and_kind(dir: &Path) -> (&Path, &OsStr) {
+    match (dir.parent(), dir.file_name()) {
+        (Some(root), Some(kind)) => (root, kind),
+        _ => (dir, OsStr::new("")),
+    }
+}
+
+/// A template component with everything but the tile filled in, and the
+/// extension unless it is `None`. One that is just `{kind}` is the
+/// directory name as it is, UTF-8 or not.
+fn fill_run(component: &str, kind: &OsStr, ext: Option<&str>) -> OsString {
+    if component == "{kind}" {
+        return kind.to_owned();
+    }
+    let component = component
+        .replace("{aoi}", &layout().aoi)
+        .replace("{kind}", &kind.to_string_lossy())
+        .replace("{z}", &zoom().to_string());
+    match ext {
+        Some(ext) => component.replace("{ext}", ext.strip_prefix('.').unwrap_or(ext)),
+        None => component,
+    }
+    .into()
+}
+
+/// Whether tiles of different zooms go to different files, with `{z}` in
+/// `tile_paths`. Tile names alone leave the zoom out.
+pub fn has_zoom() -> bool {
+    layout().components.iter().any(|c| c.contains("{z}"))
+}
+
+/// `{y}-{x}`, the name of a tile and of the chip it is the top left of.
+pub fn stem(tile: Tile) -> String {
+    match TILE_NAMES.get().copied().unwrap_or_default() {
+        TileNames::Plain => format!("{}-{}", tile.y(), tile.x()),
+        TileNames::Padded => format!("{:0w$}-{:0w$}", tile.y(), tile.x(), w = PADDED_DIGITS),
+    }
+}
+
+/// The file of `tile` in `dir`, the directory of one kind, following
+/// `tile_paths`: `dir/{y}-{x}{ext}` by default.
+pub fn tile_file(dir: impl AsRef<Path>, tile: Tile, ext: &str) -> PathBuf {
+    let (root, kind) = root_and_kind(dir.as_ref());
+    let name = stem(tile);
+    layout()
+        .components
+        .iter()
+        .fold(root.to_owned(), |path, component| {
+            let component = component
+                .replace("{x}", &tile.x().to_string())
+                .replace("{y}", &tile.y().to_string())
+                .replace("{name}", &name);
+            path.join(fill_run(&component, kind, Some(ext)))
+        })
+}
+
+/// `tile_file`, with the directories it is in created.
+pub fn create_tile_file(dir: impl AsRef<Path>, tile: Tile, ext: &str) -> std::io::Result<PathBuf> {
+    let path = tile_file(dir, tile, ext);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(path)
+}
+
+/// `tile_file` relative to the run directory, `/`-separated, the way
+/// manifests record it.
+pub fn relative_tile_file(kind: &str, tile: Tile, ext: &str) -> String {
+    tile_file(kind, tile, ext)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The tiles with a file in `dir`, the directory of one kind, the way
+/// `tile_file` lays them out. Other files are left out.
+pub fn list_tiles(dir: impl AsRef<Path>, ext: &str) -> Vec<Tile> {
+    tile_files(dir.as_ref(), Some(ext))
+        .into_iter()
+        .map(|f| f.tile)
+        .collect()
+}
+
+/// A file `walk` found, with the extension it has when it was looked for
+/// with any.
+struct TileFile {
+    tile: Tile,
+    path: PathBuf,
+    ext: Option<String>,
+}
+
+/// The files of tiles in `dir` with `ext`, or with any extension.
+fn tile_files(dir: &Path, ext: Option<&str>) -> Vec<TileFile> {
+    let (root, kind) = root_and_kind(dir);
+    let components: Vec<_> = layout()
+        .components
+        .iter()
+        .map(|c| fill_run(c, kind, ext))
+        .collect();
+    let mut files = vec![];
+    walk(root.to_owned(), &components, &mut vec![], &mut files);
+    files
+}
+
+/// Descends along `components`, listing the directories where one holds a
+/// part of the tile and matching their entries against it.
+fn walk(
+    path: PathBuf,
+    components: &[OsString],
+    captured: &mut Vec<(String, String)>,
+    files: &mut Vec<TileFile>,
+) {
+    let Some((component, rest)) = components.split_first() else {
+        if let Some(tile) = captured_tile(captured) {
+            let ext = captured
+                .iter()
+                .find(|(v, _)| v == "ext")
+                .map(|(_, s)| s.clone());
+            files.push(TileFile { tile, path, ext });
+        }
+        return;
+    };
+    let Some(pattern) = component.to_str().filter(|c| c.contains('{')) else {
+        return walk(path.join(component), rest, captured, files);
+    };
+    let Ok(entries) = std::fs::read_dir(&path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        // A tile name is ASCII; anything that is not UTF-8 is some other file.
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let len = captured.len();
+        if capture(pattern, name, captured) {
+            walk(path.join(name), rest, captured, files);
+        }
+        captured.truncate(len);
+    }
+}
+
+/// Matches `text` against `pattern`, a template component left with only
+/// `{x}`, `{y}`, `{name}` and maybe `{ext}`, adding what they stand for to
+/// `captured`. An extension may have dots of its own, e.g. `jpg.aux.xml`.
+fn capture(pattern: &str, text: &str, captured: &mut Vec<(String, String)>) -> bool {
+    let Some(start) = pattern.find('{') else {
+        return pattern == text;
+    };
+    let Some(text) = text.strip_prefix(&pattern[..start]) else {
+        return false;
+    };
+    let end = start + pattern[start..].find('}').unwrap();
+    let (var, rest) = (&pattern[start + 1..end], &pattern[end + 1..]);
+    let allowed = |c: char| match var {
+        "ext" => c.is_ascii_alphanumeric() || c == '.' || c == '_',
+        "name" => c.is_ascii_digit() || c == '-',
+        _ => c.is_ascii_digit(),
+    };
+    let longest = text.find(|c| !allowed(c)).unwrap_or(text.len());
+    for len in (1..=longest).rev() {
+        captured.push((var.to_owned(), text[..len].to_owned()));
+        if capture(rest, &text[len..], captured) {
+            return true;
+        }
+        captured.pop();
+    }
+    false
+}
+
+/// The tile of a path, from `{x}` and `{y}` or else from `{name}`.
+fn captured_tile(captured: &[(String, String)]) -> Option<Tile> {
+    let get = |var: &str| {
+        captured
+            .iter()
+            .find(|(v, _)| v == var)
+            .map(|(_, s)| s.as_str())
+    };
+    match (get("x"), get("y")) {
+        (Some(x), Some(y)) => Tile::new(zoom(), x.parse().ok()?, y.parse().ok()?),
+        _ => parse(OsStr::new(get("name")?), ""),
+    }
+}
+
+/// The tile a file named `{y}-{x}{ext}` holds, `None` for other files.
+pub fn parse(file_name: &OsStr, ext: &str) -> Option<Tile> {
+    // A tile name is ASCII; anything that is not UTF-8 is some other file.
+    let (y, x) = file_name.to_str()?.strip_suffix(ext)?.split_once('-')?;
+    Tile::new(zoom(), x.parse().ok()?, y.parse().ok()?)
+}
+
+/// Joins a `/`-separated relative path, the form manifests and indexes
+/// record so they read the same on every platform.
+pub fn join_relative(base: impl AsRef<Path>, relative: &str) -> PathBuf {
+    relative
+        .split('/')
+        .fold(base.as_ref().to_owned(), |path, part| path.join(part))
+}
+
+/// Renames the tile files in `dirs`, laid out as `tile_paths` says, to
+/// the configured naming, keeping their extension. Directories that do not
+/// exist are skipped.
+pub fn rename_tiles(dirs: &[PathBuf]) -> anyhow::Result<()> {
+    let mut renamed = 0;
+    for dir in dirs {
+        for file in tile_files(dir, None) {
+            let ext = file.ext.map(|e| format!(".{e}")).unwrap_or_default();
+            let to = tile_file(dir, file.tile, &ext);
+            if to == file.path {
+                continue;
+            }
+            if to.exists() {
+                bail!(
+                    "{} and {} are the same tile\n\
+                     hint: remove one of them and rerun",
+                    file.path.display(),
+                    to.display()
+                );
+            }
+            create_tile_file(dir, file.tile, &ext)?;
+            std::fs::rename(&file.path, to)?;
+            renamed += 1;
+            // Under a nested layout the directories of the old name are left
+            // empty; removing stops at the first that is not.
+            let (root, _) = root_and_kind(dir);
+            for empty in file.path.ancestors().skip(1).take_while(|p| *p != root) {
+                if std::fs::remove_dir(empty).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    logging::status!("Renamed {renamed} files");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x: u32, y: u32) -> Tile {
+        Tile::new(zoom(), x, y).unwrap()
+    }
+
+    /// An empty directory of its own under the system temp directory.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("paths-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn tile_file_is_named_after_the_tile() {
+        let path = tile_file(Path::new("run").join("tiles"), tile(200, 101), ".jpg");
+        assert_eq!(path, Path::new("run").join("tiles").join("101-200.jpg"));
+        assert_eq!(
+            relative_tile_file("outlines", tile(200, 101), ".png"),
+            "outlines/101-200.png"
+        );
+    }
+
+    #[test]
+    fn parse_reads_back_tile_file() {
+        let t = tile(79_233, 40_977);
+        let path = tile_file("tiles", t, ".jpg");
+        assert_eq!(parse(path.file_name().unwrap(), ".jpg"), Some(t));
+        assert_eq!(parse(OsStr::new("101-200.png"), ".jpg"), None);
+        assert_eq!(parse(OsStr::new("101-200"), ".jpg"), None);
+        assert_eq!(parse(OsStr::new("notes.jpg"), ".jpg"), None);
+    }
+
+    #[test]
+    fn parse_leaves_out_backslash_paths() {
+        // Only a file name is a tile, not a path with Windows separators.
+        assert_eq!(parse(OsStr::new(r"tiles\101-200.jpg"), ".jpg"), None);
+        assert_eq!(parse(OsStr::new(r"101\101-200.jpg"), ".jpg"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_leaves_out_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(parse(OsStr::from_bytes(b"101-200\xff.jpg"), ".jpg"), None);
+    }
+
+    #[test]
+    fn join_relative_splits_on_slashes() {
+        let relative = relative_tile_file("tiles", tile(200, 101), ".jpg");
+        assert_eq!(
+            join_relative("run", &relative),
+            Path::new("run").join("tiles").join("101-200.jpg")
+        );
+        assert_eq!(
+            join_relative("run", &relative),
+            tile_file(Path::new("run").join("tiles"), tile(200, 101), ".jpg")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn join_relative_keeps_backslashes_in_names() {
+        // Manifests separate with `/` only; a `\` is part of a name.
+        let path = join_relative("run", r"tiles/a\b.jpg");
+        assert_eq!(path, Path::new("run").join("tiles").join(r"a\b.jpg"));
+    }
+
+    #[test]
+    fn list_tiles_finds_tile_files_only() {
+        let dir = scratch("list").join("tiles");
+        std::fs::create_dir_all(&dir).unwrap();
+        for t in [tile(200, 101), tile(201, 101)] {
+            std::fs::write(tile_file(&dir, t, ".jpg"), b"").unwrap();
+        }
+        std::fs::write(tile_file(&dir, tile(202, 101), ".png"), b"").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"").unwrap();
+        let mut tiles = list_tiles(&dir, ".jpg");
+        tiles.sort_by_key(|t| t.x());
+        assert_eq!(tiles, vec![tile(200, 101), tile(201, 101)]);
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn list_tiles_skips_odd_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = scratch("odd").join("tiles");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(tile_file(&dir, tile(200, 101), ".jpg"), b"").unwrap();
+        std::fs::write(dir.join(OsStr::from_bytes(b"101-201\xff.jpg")), b"").unwrap();
+        std::fs::write(dir.join(r"x\101-202.jpg"), b"").unwrap();
+        assert_eq!(list_tiles(&dir, ".jpg"), vec![tile(200, 101)]);
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn list_tiles_under_non_utf8_directory() {
+        use std::os::unix::ffi::OsStrExt;
+        let root = scratch("root").join(OsStr::from_bytes(b"run\xff"));
+        let dir = root.join("tiles");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(tile_file(&dir, tile(200, 101), ".jpg"), b"").unwrap();
+        assert_eq!(list_tiles(&dir, ".jpg"), vec![tile(200, 101)]);
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
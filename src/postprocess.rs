@@ -0,0 +1,312 @@
+//! Clean-ups applied to the rasterized outlines once everything is drawn.
+
+use std::{path::Path, str::FromStr};
+
+use image::{GrayImage, Luma, RgbImage};
+use serde::Serialize;
+
+use crate::classes::colors;
+
+const POSTPROCESS_FILE: &str = "postprocess.json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MorphKind {
+    Dilate,
+    Erode,
+    Open,
+    Close,
+}
+
+/// A morphological operation on the pixels of one class, with a disk
+/// shaped kernel of `radius_m` meters.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Morph {
+    pub class: u8,
+    pub op: MorphKind,
+    pub radius_m: f64,
+}
+
+impl FromStr for Morph {
+    type Err = String;
+
+    /// Parses `CLASS:OP:METERS`, e.g. `2:close:1.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("expected CLASS:OP:METERS such as 2:close:1.5, got {s:?}");
+        let mut parts = s.split(':');
+        let (Some(class), Some(op), Some(radius), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(err());
+        };
+        let class: u8 = class.parse().map_err(|_| err())?;
+        if class as usize >= colors().len() {
+            return Err(format!("unknown class {class}"));
+        }
+        let op = match op {
+            "dilate" => MorphKind::Dilate,
+            "erode" => MorphKind::Erode,
+            "open" => MorphKind::Open,
+            "close" => MorphKind::Close,
+            _ => return Err(err()),
+        };
+        let radius_m: f64 = radius.parse().map_err(|_| err())?;
+        if radius_m.is_nan() || radius_m < 0.0 {
+            return Err(err());
+        }
+        Ok(Self {
+            class,
+            op,
+            radius_m,
+        })
+    }
+}
+
+#[derive(clap::Args, Clone, Debug, Default, Serialize)]
+pub struct PostprocessOptions {
+    /// Morphological operation on one class as `CLASS:OP:METERS`, where
+    /// `OP` is one of dilate, erode, open or close. Can be repeated, and
+    /// runs in the order given before the fragment clean-up.
+    #[arg(long = "morph", value_name = "CLASS:OP:METERS")]
+    pub morphs: Vec<Morph>,
+    /// Merge connected areas of a class smaller than this many pixels into
+    /// the class surrounding them.
+    #[arg(long, value_name = "PX")]
+    pub min_component: Option<u32>,
+    /// Fill enclosed background holes smaller than this many pixels with
+    /// the class surrounding them.
+    #[arg(long, value_name = "PX")]
+    pub max_hole: Option<u32>,
+}
+
+impl PostprocessOptions {
+    pub fn enabled(&self) -> bool {
+        !self.morphs.is_empty() || self.min_component.is_some() || self.max_hole.is_some()
+    }
+}
+
+/// Totals over all processed tiles.
+#[derive(Debug, Default, Serialize)]
+pub struct PostprocessStats {
+    pub tiles: u64,
+    /// Pixels that changed class in the morphological operations.
+    pub morphed_px: u64,
+    pub merged_components: u64,
+    pub filled_holes: u64,
+}
+
+#[derive(Serialize)]
+struct PostprocessRecord<'a> {
+    options: &'a PostprocessOptions,
+    stats: &'a PostprocessStats,
+}
+
+/// Runs every configured step on one outline. `pixels_per_meter` converts
+/// the kernel sizes for this tile.
+pub fn process(
+    img: &mut RgbImage,
+    pixels_per_meter: f64,
+    opts: &PostprocessOptions,
+    stats: &mut PostprocessStats,
+) {
+    stats.tiles += 1;
+    for morph in &opts.morphs {
+        stats.morphed_px += apply_morph(img, morph, morph.radius_m * pixels_per_meter);
+    }
+    if opts.min_component.is_some() || opts.max_hole.is_some() {
+        clean_fragments(img, opts, stats);
+    }
+}
+
+pub fn save(opts: &PostprocessOptions, stats: &PostprocessStats, dir: &Path) -> anyhow::Result<()> {
+    let record = PostprocessRecord {
+        options: opts,
+        stats,
+    };
+    std::fs::write(
+        dir.join(POSTPROCESS_FILE),
+        serde_json::to_vec_pretty(&record)?,
+    )?;
+    Ok(())
+}
+
+/// Squared distance from every pixel to the nearest pixel set in `mask`.
+fn distance_sq(mask: &GrayImage) -> image::ImageBuffer<Luma<f64>, Vec<f64>> {
+    imageproc::distance_transform::euclidean_squared_distance_transform(mask)
+}
+
+fn dilate(mask: &GrayImage, radius: f64) -> GrayImage {
+    let dist = distance_sq(mask);
+    GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([if dist.get_pixel(x, y).0[0] <= radius * radius {
+            255
+        } else {
+            0
+        }])
+    })
+}
+
+fn erode(mask: &GrayImage, radius: f64) -> GrayImage {
+    let inverted = GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([if mask.get_pixel(x, y).0[0] == 0 {
+            255
+        } else {
+            0
+        }])
+    });
+    let dist = distance_sq(&inverted);
+    GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([if dist.get_pixel(x, y).0[0] > radius * radius {
+            255
+        } else {
+            0
+        }])
+    })
+}
+
+/// Applies `morph` in place. The class only grows into background and
+/// gives up pixels to background, other classes are never overwritten.
+/// Returns the number of changed pixels.
+fn apply_morph(img: &mut RgbImage, morph: &Morph, radius: f64) -> u64 {
+    let color = colors()[morph.class as usize];
+    let mask = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        Luma([if img.get_pixel(x, y).0 == color {
+            255
+        } else {
+            0
+        }])
+    });
+    let result = match morph.op {
+        MorphKind::Dilate => dilate(&mask, radius),
+        MorphKind::Erode => erode(&mask, radius),
+        MorphKind::Open => dilate(&erode(&mask, radius), radius),
+        MorphKind::Close => erode(&dilate(&mask, radius), radius),
+    };
+    let mut changed = 0;
+    for (x, y, m) in result.enumerate_pixels() {
+        let was = mask.get_pixel(x, y).0[0] != 0;
+        let is = m.0[0] != 0;
+        let px = img.get_pixel_mut(x, y);
+        if is && !was && px.0 == BACKGROUND {
+            px.0 = color;
+            changed += 1;
+        } else if was && !is {
+            px.0 = BACKGROUND;
+            changed += 1;
+        }
+    }
+    changed
+}
+
+const BACKGROUND: [u8; 3] = [0, 0, 0];
+
+/// A 4-connected area of a single color.
+struct Component {
+    color: [u8; 3],
+    pixels: Vec<u32>,
+    touches_edge: bool,
+}
+
+fn components(img: &RgbImage) -> (Vec<Component>, Vec<u32>) {
+    let (w, h) = img.dimensions();
+    let mut labels = vec![u32::MAX; (w * h) as usize];
+    let mut found = vec![];
+    let mut stack = vec![];
+    for start in 0..w * h {
+        if labels[start as usize] != u32::MAX {
+            continue;
+        }
+        let color = img.get_pixel(start % w, start / w).0;
+        let label = found.len() as u32;
+        let mut component = Component {
+            color,
+            pixels: vec![],
+            touches_edge: false,
+        };
+        labels[start as usize] = label;
+        stack.push(start);
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % w, i / w);
+            component.pixels.push(i);
+            component.touches_edge |= x == 0 || y == 0 || x == w - 1 || y == h - 1;
+            let neighbours = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < w).then(|| i + 1),
+                (y > 0).then(|| i - w),
+                (y + 1 < h).then(|| i + w),
+            ];
+            for n in neighbours.into_iter().flatten() {
+                if labels[n as usize] == u32::MAX && img.get_pixel(n % w, n / w).0 == color {
+                    labels[n as usize] = label;
+                    stack.push(n);
+                }
+            }
+        }
+        found.push(component);
+    }
+    (found, labels)
+}
+
+/// Most common color right outside a component.
+fn surrounding_color(img: &RgbImage, labels: &[u32], label: u32, c: &Component) -> Option<[u8; 3]> {
+    let w = img.width();
+    let mut counts: Vec<([u8; 3], usize)> = vec![];
+    for &i in &c.pixels {
+        let (x, y) = (i % w, i / w);
+        let neighbours = [
+            (x > 0).then(|| i - 1),
+            (x + 1 < w).then(|| i + 1),
+            (y > 0).then(|| i - w),
+            ((i + w) < labels.len() as u32).then(|| i + w),
+        ];
+        for n in neighbours.into_iter().flatten() {
+            if labels[n as usize] == label {
+                continue;
+            }
+            let color = img.get_pixel(n % w, n / w).0;
+            match counts.iter_mut().find(|(c, _)| *c == color) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((color, 1)),
+            }
+        }
+    }
+    counts.into_iter().max_by_key(|(_, n)| *n).map(|(c, _)| c)
+}
+
+/// Merges small components and fills pinholes in place. Areas touching the
+/// tile border are left alone, they may continue in the neighbouring tile.
+fn clean_fragments(img: &mut RgbImage, opts: &PostprocessOptions, stats: &mut PostprocessStats) {
+    let (found, labels) = components(img);
+    let w = img.width();
+    let mut recolor = vec![];
+    for (label, c) in found.iter().enumerate() {
+        if c.touches_edge {
+            continue;
+        }
+        let size = c.pixels.len() as u32;
+        let is_hole = c.color == BACKGROUND;
+        let limit = if is_hole {
+            opts.max_hole
+        } else {
+            opts.min_component
+        };
+        if limit.is_none_or(|limit| size >= limit) {
+            continue;
+        }
+        if let Some(color) = surrounding_color(img, &labels, label as u32, c) {
+            recolor.push((label, color));
+            if is_hole {
+                stats.filled_holes += 1;
+            } else {
+                stats.merged_components += 1;
+            }
+        }
+    }
+    // Recolor after looking at all components, so that the result does not
+    // depend on the order they were found in.
+    for (label, color) in recolor {
+        for &i in &found[label].pixels {
+            img.put_pixel(i % w, i / w, image::Rgb(color));
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! The windows `serve` has rendered, kept encoded in memory, and the ones
+//! it renders ahead of the requests: the windows around a `/window`, and
+//! the chips after a `/chip`, as loaders read chips in order, shard by
+//! shard. A request for a window in the cache is answered without
+//! rendering or encoding anything.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use serde::Serialize;
+use slippy_map_tiles::BBox;
+
+use crate::{checks, metrics::Exposition};
+
+#[derive(clap::Args)]
+pub struct PrefetchOptions {
+    /// Memory for rendered windows, MB. The least recently used ones are
+    /// dropped first.
+    #[arg(long, default_value_t = 1024)]
+    pub cache_mb: usize,
+    /// Chips rendered ahead after a `/chip`; a `/window` prefetches the
+    /// eight windows around it. 0 turns prefetching off.
+    #[arg(long, default_value_t = 16)]
+    pub prefetch: u64,
+    /// Threads rendering prefetched windows, besides the ones answering
+    /// requests.
+    #[arg(long, default_value_t = 2)]
+    pub prefetch_threads: usize,
+}
+
+/// What a window was asked for as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// Window `N` of the sampler.
+    Chip(u64),
+    /// The corners in degrees and the ground resolution, by their bits.
+    Window { bbox: [u32; 4], gsd: u64 },
+}
+
+impl Key {
+    pub fn window(bbox: &BBox, gsd: f64) -> Self {
+        let corners = [bbox.top(), bbox.left(), bbox.bottom(), bbox.right()];
+        Self::Window {
+            bbox: corners.map(f32::to_bits),
+            gsd: gsd.to_bits(),
+        }
+    }
+
+    /// The windows a client is likely to ask for next.
+    pub fn neighbours(&self, ahead: u64) -> Vec<Key> {
+        match *self {
+            // None past the last index.
+            Key::Chip(index) => (1..=ahead)
+                .map_while(|i| index.checked_add(i).map(Key::Chip))
+                .collect(),
+            Key::Window { bbox, gsd } => {
+                let [top, left, bottom, right] = bbox.map(f32::from_bits);
+                let (height, width) = (top - bottom, right - left);
+                let mut around = vec![];
+                for dy in [-1.0, 0.0, 1.0] {
+                    for dx in [-1.0, 0.0, 1.0] {
+                        if dy == 0.0 && dx == 0.0 {
+                            continue;
+                        }
+                        // Off the map at the edges.
+                        if let Ok(bbox) = checks::bbox(
+                            top + dy * height,
+                            left + dx * width,
+                            bottom + dy * height,
+                            right + dx * width,
+                        ) {
+                            around.push(Key::window(&bbox, f64::from_bits(gsd)));
+                        }
+                    }
+                }
+                around
+            }
+        }
+    }
+}
+
+/// A rendered window, encoded as it is sent.
+pub struct Rendered {
+    pub bbox: BBox,
+    pub width: u32,
+    pub height: u32,
+    pub attempts: Option<u32>,
+    pub image_png: Vec<u8>,
+    pub mask_png: Vec<u8>,
+}
+
+impl Rendered {
+    fn bytes(&self) -> usize {
+        self.image_png.len() + self.mask_png.len()
+    }
+}
+
+struct Entry {
+    window: Arc<Rendered>,
+    /// Value of `Entries::clock` at the last use.
+    used: u64,
+    /// Prefetched and not asked for yet.
+    unused: bool,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    bytes: usize,
+    clock: u64,
+}
+
+#[derive(Default, Serialize)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetched: AtomicU64,
+    /// Hits on windows that were prefetched for them.
+    prefetch_hits: AtomicU64,
+    /// Prefetched windows dropped before anyone asked for them.
+    prefetch_wasted: AtomicU64,
+    evicted: AtomicU64,
+}
+
+/// Rendered windows up to a memory budget.
+pub struct Cache {
+    entries: Mutex<Entries>,
+    budget: usize,
+    counters: Counters,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats<'a> {
+    entries: usize,
+    bytes: usize,
+    budget: usize,
+    #[serde(flatten)]
+    counters: &'a Counters,
+}
+
+impl Cache {
+    pub fn new(opts: &PrefetchOptions) -> Self {
+        Self {
+            entries: Mutex::default(),
+            budget: opts.cache_mb << 20,
+            counters: Counters::default(),
+        }
+    }
+
+    /// The window for `key` if it is cached, counting a hit or a miss.
+    pub fn get(&self, key: &Key) -> Option<Arc<Rendered>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let Some(entry) = entries.map.get_mut(key) else {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        entry.used = clock;
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        if std::mem::take(&mut entry.unused) {
+            self.counters.prefetch_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(entry.window.clone())
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        self.entries.lock().unwrap().map.contains_key(key)
+    }
+
+    /// Keeps `window`, dropping the least recently used windows to make
+    /// room. A window larger than the whole budget is not kept.
+    pub fn insert(&self, key: Key, window: Arc<Rendered>, prefetched: bool) {
+        let size = window.bytes();
+        if size > self.budget {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let entry = Entry {
+            window,
+            used: entries.clock,
+            unused: prefetched,
+        };
+        if let Some(old) = entries.map.insert(key, entry) {
+            entries.bytes -= old.window.bytes();
+        }
+        entries.bytes += size;
+        while entries.bytes > self.budget {
+            let Some((&oldest, _)) = entries.map.iter().min_by_key(|(_, e)| e.used) else {
+                break;
+            };
+            let dropped = entries.map.remove(&oldest).unwrap();
+            entries.bytes -= dropped.window.bytes();
+            self.counters.evicted.fetch_add(1, Ordering::Relaxed);
+            if dropped.unused {
+                self.counters
+                    .prefetch_wasted
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn metrics(&self, out: &mut Exposition) {
+        let stats = self.stats();
+        let c = &self.counters;
+        out.gauge(
+            "cache_entries",
+            "Rendered windows in the cache.",
+            stats.entries as f64,
+        );
+        out.gauge(
+            "cache_bytes",
+            "Memory of the cached windows.",
+            stats.bytes as f64,
+        );
+        for (name, help, counter) in [
+            (
+                "cache_hits_total",
+                "Requests answered from the cache.",
+                &c.hits,
+            ),
+            (
+                "cache_misses_total",
+                "Requests rendered on the spot.",
+                &c.misses,
+            ),
+            ("prefetched_total", "Windows rendered ahead.", &c.prefetched),
+            (
+                "prefetch_hits_total",
+                "Requests answered with a prefetched window.",
+                &c.prefetch_hits,
+            ),
+            (
+                "prefetch_wasted_total",
+                "Prefetched windows dropped unused.",
+                &c.prefetch_wasted,
+            ),
+            (
+                "cache_evictions_total",
+                "Windows dropped for room.",
+                &c.evicted,
+            ),
+        ] {
+            out.counter(name, help, counter.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats<'_> {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            entries: entries.map.len(),
+            bytes: entries.bytes,
+            budget: self.budget,
+            counters: &self.counters,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    keys: VecDeque<Key>,
+    /// Queued or being rendered.
+    pending: HashSet<Key>,
+}
+
+/// Windows waiting to be rendered ahead, newest first.
+pub struct Prefetcher {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    /// Longest the queue gets; older guesses are given up first.
+    limit: usize,
+}
+
+impl Prefetcher {
+    pub fn new(opts: &PrefetchOptions) -> Self {
+        Self {
+            queue: Mutex::default(),
+            ready: Condvar::new(),
+            limit: (opts.prefetch as usize).max(8) * 4,
+        }
+    }
+
+    /// Queues the `keys` not cached or queued yet. The first key is
+    /// rendered first.
+    pub fn push(&self, cache: &Cache, keys: Vec<Key>) {
+        let mut queue = self.queue.lock().unwrap();
+        for key in keys.into_iter().rev() {
+            if queue.pending.contains(&key) || cache.contains(&key) {
+                continue;
+            }
+            queue.pending.insert(key);
+            queue.keys.push_front(key);
+        }
+        while queue.keys.len() > self.limit {
+            let key = queue.keys.pop_back().unwrap();
+            queue.pending.remove(&key);
+        }
+        self.ready.notify_all();
+    }
+
+    /// Windows queued and not taken by a thread yet.
+    pub fn depth(&self) -> usize {
+        self.queue.lock().unwrap().keys.len()
+    }
+
+    /// Renders queued windows into `cache` with `render`, forever.
+    pub fn run(&self, cache: &Cache, render: impl Fn(&Key) -> anyhow::Result<Rendered>) {
+        loop {
+            let key = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    match queue.keys.pop_front() {
+                        Some(key) => break key,
+                        None => queue = self.ready.wait(queue).unwrap(),
+                    }
+                }
+            };
+            match render(&key) {
+                Ok(window) => {
+                    cache.insert(key, Arc::new(window), true);
+                    cache.counters.prefetched.fetch_add(1, Ordering::Relaxed);
+                }
+                // Asked for, it fails again and is answered with why.
+                Err(why) => log::debug!("prefetching {key:?} failed: {why:#}"),
+            }
+            self.queue.lock().unwrap().pending.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chip_neighbours_follow_the_index() {
+        assert_eq!(Key::Chip(5).neighbours(2), vec![Key::Chip(6), Key::Chip(7)]);
+    }
+
+    #[test]
+    fn chip_neighbours_stop_at_the_last_index() {
+        assert_eq!(
+            Key::Chip(u64::MAX - 1).neighbours(3),
+            vec![Key::Chip(u64::MAX)]
+        );
+        assert!(Key::Chip(u64::MAX).neighbours(3).is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+//! Imagery providers. Where tiles are downloaded from is configured in
+//! `providers.toml`, one table per provider, and chosen with `--provider`:
+//!
+//! ```toml
+//! [yandex-satellite]
+//! url = "https://core-sat.maps.yandex.net/tiles?l=sat&x={x}&y={y}&z={z}"
+//!
+//! [mapbox-satellite]
+//! url = "https://api.mapbox.com/v4/mapbox.satellite/{z}/{x}/{y}.jpg?access_token={key}"
+//! api_key_env = "MAPBOX_TOKEN"
+//! headers = { User-Agent = "map-segmentation-gendata" }
+//! rate_limit = 20
+//! retries = 8
+//! timeout = 60
+//! ```
+//!
+//! `arcgis-world-imagery` is built in. Settings measured per provider,
+//! currently the registration offset from `calibrate`, are kept in
+//! `providers.json`.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        RETRY_AFTER,
+    },
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use slippy_map_tiles::Tile;
+
+use crate::{logging, metrics};
+
+const PROVIDERS_PATH: &str = "providers.json";
+const CONFIG_PATH: &str = "providers.toml";
+
+/// The imagery source `download-tiles` fetches from.
+pub const DEFAULT_PROVIDER: &str = "arcgis-world-imagery";
+const DEFAULT_URL: &str =
+    "https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{z}/{y}/{x}";
+
+/// Longest wait between two tries of a request.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+static SOURCE: OnceLock<TileSource> = OnceLock::new();
+
+/// One table of `providers.toml`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Tile URL with `{x}`, `{y}` and `{z}` in place of the tile, and
+    /// `{key}` for the API key if the provider takes one.
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Environment variable to read the API key from instead, to keep it
+    /// out of the file.
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// Sent with every request; values may use `{key}` too.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Requests per second at most, over all download threads.
+    #[serde(default)]
+    pub rate_limit: Option<f64>,
+    /// Times a request that timed out, could not connect or was answered
+    /// with 429 or a server error is tried again.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Seconds to wait before the first retry, doubled for every further
+    /// one up to `MAX_BACKOFF`. A longer `Retry-After` of the server wins.
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+    /// Seconds a request may take in all before it counts as timed out.
+    #[serde(default = "default_timeout")]
+    pub timeout: f64,
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+fn default_backoff() -> f64 {
+    1.0
+}
+
+fn default_timeout() -> f64 {
+    30.0
+}
+
+impl SourceConfig {
+    /// The default provider, also without a `providers.toml`.
+    fn builtin() -> Self {
+        Self {
+            url: DEFAULT_URL.to_string(),
+            api_key: None,
+            api_key_env: None,
+            headers: BTreeMap::new(),
+            rate_limit: None,
+            retries: default_retries(),
+            backoff: default_backoff(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+/// The provider tiles are downloaded from, resolved from its config.
+pub struct TileSource {
+    pub name: String,
+    url: String,
+    key: Option<String>,
+    client: reqwest::blocking::Client,
+    /// Earliest time the next request may be sent, and the spacing.
+    limit: Option<(Mutex<Instant>, Duration)>,
+    retries: u32,
+    backoff: Duration,
+}
+
+/// Loads `providers.toml` and picks `name` as the provider for the run.
+pub fn select(name: &str) -> anyhow::Result<()> {
+    let mut configs: BTreeMap<String, SourceConfig> = match std::fs::read_to_string(CONFIG_PATH) {
+        Ok(text) => toml::from_str(&text).map_err(|why| {
+            anyhow::anyhow!(
+                "{CONFIG_PATH} is not a valid provider list: {why}\n\
+                 hint: every provider is a table with at least a `url`"
+            )
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    configs
+        .entry(DEFAULT_PROVIDER.to_string())
+        .or_insert_with(SourceConfig::builtin);
+    let Some(config) = configs.get(name) else {
+        let known: Vec<_> = configs.keys().map(String::as_str).collect();
+        anyhow::bail!(
+            "no provider {name} in {CONFIG_PATH}\n\
+             hint: known providers are {}",
+            known.join(", ")
+        );
+    };
+    let source = TileSource::new(name, config)?;
+    SOURCE.set(source).ok().expect("provider selected twice");
+    Ok(())
+}
+
+/// The provider picked with `select`, the built-in default before that.
+pub fn source() -> &'static TileSource {
+    SOURCE.get_or_init(|| TileSource::new(DEFAULT_PROVIDER, &SourceConfig::builtin()).unwrap())
+}
+
+impl TileSource {
+    fn new(name: &str, config: &SourceConfig) -> anyhow::Result<Self> {
+        for placeholder in ["{x}", "{y}", "{z}"] {
+            anyhow::ensure!(
+                config.url.contains(placeholder),
+                "the url of provider {name} has no {placeholder}\n\
+                 hint: tile URLs look like https://host/tiles/{{z}}/{{y}}/{{x}}"
+            );
+        }
+        let key = match (&config.api_key, &config.api_key_env) {
+            (Some(key), _) => Some(key.clone()),
+            (None, Some(var)) => Some(std::env::var(var).map_err(|_| {
+                anyhow::anyhow!(
+                    "provider {name} reads its API key from ${var}, which is not set\n\
+                     hint: export {var}=<key>, or put `api_key` in {CONFIG_PATH}"
+                )
+            })?),
+            (None, None) => None,
+        };
+        let uses_key =
+            config.url.contains("{key}") || config.headers.values().any(|v| v.contains("{key}"));
+        anyhow::ensure!(
+            key.is_some() || !uses_key,
+            "provider {name} needs an API key for {{key}}\n\
+             hint: set `api_key` or `api_key_env` in {CONFIG_PATH}"
+        );
+        let mut headers = HeaderMap::new();
+        for (header, value) in &config.headers {
+            let value = value.replace("{key}", key.as_deref().unwrap_or_default());
+            headers.insert(
+                HeaderName::try_from(header.as_str())
+                    .map_err(|why| anyhow::anyhow!("provider {name}: header {header}: {why}"))?,
+                HeaderValue::try_from(value)
+                    .map_err(|why| anyhow::anyhow!("provider {name}: header {header}: {why}"))?,
+            );
+        }
+        let limit = match config.rate_limit {
+            Some(rate) => {
+                anyhow::ensure!(
+                    rate > 0.0 && rate.is_finite(),
+                    "provider {name}: rate_limit must be a positive number of requests per second"
+                );
+                Some((
+                    Mutex::new(Instant::now()),
+                    Duration::from_secs_f64(1.0 / rate),
+                ))
+            }
+            None => None,
+        };
+        for (what, seconds) in [("backoff", config.backoff), ("timeout", config.timeout)] {
+            anyhow::ensure!(
+                seconds > 0.0 && seconds.is_finite(),
+                "provider {name}: {what} must be a positive number of seconds"
+            );
+        }
+        Ok(Self {
+            name: name.to_string(),
+            url: config.url.clone(),
+            key,
+            client: reqwest::blocking::Client::builder()
+                .default_headers(headers)
+                .timeout(Duration::from_secs_f64(config.timeout))
+                .build()?,
+            limit,
+            retries: config.retries,
+            backoff: Duration::from_secs_f64(config.backoff),
+        })
+    }
+
+    /// The URL of `tile`, with the API key left out when `redacted`, for
+    /// messages.
+    pub fn url(&self, tile: Tile, redacted: bool) -> String {
+        let key = match redacted {
+            true => "<key>",
+            false => self.key.as_deref().unwrap_or_default(),
+        };
+        self.url
+            .replace("{x}", &tile.x().to_string())
+            .replace("{y}", &tile.y().to_string())
+            .replace("{z}", &tile.zoom().to_string())
+            .replace("{key}", key)
+    }
+
+    /// Requests `tile`, after waiting for the rate limit if there is one.
+    /// With `validators`, the server may answer 304 if it did not change.
+    pub fn get(
+        &self,
+        tile: Tile,
+        validators: Option<&Validators>,
+    ) -> reqwest::Result<reqwest::blocking::Response> {
+        if let Some((next, spacing)) = &self.limit {
+            let now = Instant::now();
+            let at = {
+                let mut next = next.lock().unwrap();
+                let at = (*next).max(now);
+                *next = at + *spacing;
+                at
+            };
+            std::thread::sleep(at - now);
+        }
+        let mut request = self.client.get(self.url(tile, false));
+        if let Some(v) = validators {
+            if let Some(etag) = &v.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(date) = &v.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, date);
+            }
+        }
+        request.send()
+    }
+
+    /// Downloads `tile`, trying again with exponential backoff after
+    /// failures that may pass: timeouts, connection errors, 429 and server
+    /// errors. Any other answer fails at once. With the `validators` of
+    /// the copy on disk the data is `None` if the server says it is still
+    /// current.
+    pub fn fetch(&self, tile: Tile, validators: Option<&Validators>) -> reqwest::Result<Fetched> {
+        let mut attempt = 0;
+        loop {
+            let (result, retry_after) = match self.get(tile, validators) {
+                Ok(response) => {
+                    let header = |name| {
+                        let value = response.headers().get(name)?.to_str().ok()?;
+                        Some(value.trim().to_owned())
+                    };
+                    let retry_after = header(RETRY_AFTER)
+                        .and_then(|v| v.parse().ok())
+                        .map(Duration::from_secs);
+                    let fresh = Validators {
+                        etag: header(ETAG),
+                        last_modified: header(LAST_MODIFIED),
+                    };
+                    let fetched = if response.status() == StatusCode::NOT_MODIFIED {
+                        Ok(Fetched {
+                            data: None,
+                            validators: validators.cloned().unwrap_or_default().or(fresh),
+                        })
+                    } else {
+                        let body = response.error_for_status().and_then(|r| r.bytes());
+                        body.map(|b| Fetched {
+                            data: Some(b.to_vec()),
+                            validators: fresh,
+                        })
+                    };
+                    (fetched, retry_after)
+                }
+                Err(why) => (Err(why), None),
+            };
+            match result {
+                Err(why) if attempt < self.retries && transient(&why) => {
+                    let wait = self
+                        .backoff
+                        .saturating_mul(1 << attempt.min(16))
+                        .min(MAX_BACKOFF)
+                        .max(retry_after.unwrap_or_default());
+                    attempt += 1;
+                    metrics::DOWNLOAD_RETRIES.inc();
+                    warn!(
+                        target: logging::DOWNLOAD,
+                        "{}: {why}, retry {attempt} of {} in {wait:.1?}",
+                        self.url(tile, true),
+                        self.retries
+                    );
+                    std::thread::sleep(wait);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// What a server said identifies the version of a tile, sent back to ask
+/// whether it changed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// As the server wrote it, an HTTP date.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// `newer`, falling back to these where it has none.
+    fn or(self, newer: Validators) -> Validators {
+        Validators {
+            etag: newer.etag.or(self.etag),
+            last_modified: newer.last_modified.or(self.last_modified),
+        }
+    }
+}
+
+/// The answer to a tile request.
+pub struct Fetched {
+    /// `None` if the copy on disk is still current.
+    pub data: Option<Vec<u8>>,
+    pub validators: Validators,
+}
+
+/// Whether a failed request may succeed when tried again.
+fn transient(why: &reqwest::Error) -> bool {
+    match why.status() {
+        Some(status) => status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+        None => why.is_timeout() || why.is_connect() || why.is_body(),
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Provider {
+    /// How far the imagery is shifted from OSM, meters east and north.
+    /// Outlines are drawn shifted by the same amount to line up.
+    #[serde(default)]
+    pub offset_m: [f64; 2],
+    /// How the offset was measured, for reference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<Calibration>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Calibration {
+    pub tiles: usize,
+    /// Mean imagery gradient along rasterized edges at the chosen offset,
+    /// relative to no offset.
+    pub gain: f64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Providers(pub BTreeMap<String, Provider>);
+
+impl Providers {
+    pub fn load() -> anyhow::Result<Self> {
+        match std::fs::read(PROVIDERS_PATH) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        std::fs::write(PROVIDERS_PATH, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn offset_m(&self, provider: &str) -> [f64; 2] {
+        self.0.get(provider).map(|p| p.offset_m).unwrap_or_default()
+    }
+}
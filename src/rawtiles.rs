@@ -0,0 +1,149 @@
+//! Raw tile stores: RGB planes compressed with zstd instead of JPEG.
+//! Reading a tile back is a decompression and a copy instead of a JPEG
+//! decode, for about six times the disk. The format of a store follows
+//! from its file names, see `convert-tiles`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use image::RgbImage;
+use log::info;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{list_tiles, logging, paths, workspace::TILES};
+
+pub const EXT: &str = ".rgb.zst";
+const MAGIC: &[u8; 4] = b"RGBZ";
+/// Magic, width and height.
+const HEADER: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TileFormat {
+    Jpeg,
+    Zstd,
+}
+
+impl TileFormat {
+    pub fn ext(self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => ".jpg",
+            TileFormat::Zstd => EXT,
+        }
+    }
+}
+
+/// A 12-byte header (`RGBZ`, little-endian `u32` width and height) and
+/// the zstd-compressed R, G and B planes one after another, which
+/// compress better than interleaved pixels.
+pub fn encode(img: &RgbImage, level: i32) -> anyhow::Result<Vec<u8>> {
+    let plane = (img.width() * img.height()) as usize;
+    let mut planes = vec![0; plane * 3];
+    let (r, gb) = planes.split_at_mut(plane);
+    let (g, b) = gb.split_at_mut(plane);
+    for (((px, r), g), b) in img.pixels().zip(r).zip(g).zip(b) {
+        [*r, *g, *b] = px.0;
+    }
+    let mut data = Vec::with_capacity(HEADER + plane);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&img.width().to_le_bytes());
+    data.extend_from_slice(&img.height().to_le_bytes());
+    data.extend(zstd::bulk::compress(&planes, level)?);
+    Ok(data)
+}
+
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() < HEADER || &data[..4] != MAGIC {
+        return None;
+    }
+    let w = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let h = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    Some((w, h))
+}
+
+pub fn decode(data: &[u8]) -> anyhow::Result<RgbImage> {
+    let Some((w, h)) = dimensions(data) else {
+        bail!("not a raw tile");
+    };
+    let plane = (w * h) as usize;
+    let planes = zstd::bulk::decompress(&data[HEADER..], plane * 3)?;
+    if planes.len() != plane * 3 {
+        bail!("raw tile is truncated");
+    }
+    let (r, gb) = planes.split_at(plane);
+    let (g, b) = gb.split_at(plane);
+    let mut pixels = vec![0; plane * 3];
+    for (((px, r), g), b) in pixels.chunks_exact_mut(3).zip(r).zip(g).zip(b) {
+        px.copy_from_slice(&[*r, *g, *b]);
+    }
+    Ok(RgbImage::from_raw(w, h, pixels).unwrap())
+}
+
+/// Reads a tile of either format.
+pub fn open(path: &Path) -> anyhow::Result<RgbImage> {
+    if path
+        .as_os_str()
+        .as_encoded_bytes()
+        .ends_with(EXT.as_bytes())
+    {
+        decode(&std::fs::read(path)?)
+    } else {
+        Ok(image::open(path)?.into_rgb8())
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ConvertOptions {
+    /// Store to read, in either format.
+    #[arg(long, default_value = TILES)]
+    pub from: PathBuf,
+    #[arg(long, default_value = "tiles-raw")]
+    pub out: PathBuf,
+    #[arg(long, value_enum, default_value_t = TileFormat::Zstd)]
+    pub format: TileFormat,
+    /// zstd compression level, 1 to 22.
+    #[arg(long, default_value_t = 3)]
+    pub level: i32,
+}
+
+/// Copies a tile store into another format. Tiles already converted and
+/// newer than their source are left alone.
+pub fn convert_tiles(opts: &ConvertOptions) -> anyhow::Result<()> {
+    std::fs::create_dir_all(&opts.out)?;
+    let sources: Vec<_> = [TileFormat::Jpeg, TileFormat::Zstd]
+        .into_iter()
+        .flat_map(|f| {
+            list_tiles(&opts.from, f.ext())
+                .into_iter()
+                .map(move |t| (t, f))
+        })
+        .collect();
+    let converted = sources
+        .par_iter()
+        .map(|(t, from)| {
+            let src = paths::tile_file(&opts.from, *t, from.ext());
+            let dst = paths::create_tile_file(&opts.out, *t, opts.format.ext())?;
+            let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified());
+            if let (Ok(s), Ok(d)) = (modified(&src), modified(&dst)) {
+                if d >= s {
+                    return Ok(0);
+                }
+            }
+            let img = open(&src).with_context(|| format!("reading {}", src.display()))?;
+            let tmp = dst.with_extension("part");
+            match opts.format {
+                TileFormat::Jpeg => img.save_with_format(&tmp, image::ImageFormat::Jpeg)?,
+                TileFormat::Zstd => std::fs::write(&tmp, encode(&img, opts.level)?)?,
+            }
+            std::fs::rename(&tmp, &dst)?;
+            anyhow::Ok(1)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let converted: usize = converted.into_iter().sum();
+    info!("Converted {converted} tiles");
+    logging::status!(
+        "{converted} of {} tiles converted into {}",
+        sources.len(),
+        opts.out.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! Several dataset variants from one invocation. A recipe lists variants
+//! of `render-outlines`, each with its own arguments and optionally its own
+//! zoom, and the extract is parsed once for all of them. Tiles downloaded
+//! for one variant are there for the next. A `sweep` table expands a
+//! variant into one per combination of its values, filled in for
+//! `{param}` in the name, the zoom and the arguments:
+//!
+//! ```toml
+//! out_dir = "variants"
+//!
+//! [[variant]]
+//! name = "z{zoom}-{format}"
+//! zoom = "{zoom}"
+//! args = ["--mask-format", "{format}", "--max-failure-rate", "0.01"]
+//! sweep = { zoom = [17, 18], format = ["rgb", "index"] }
+//!
+//! [[variant]]
+//! name = "farms"
+//! args = ["--agricultural-classes", "--relations"]
+//! ```
+//!
+//! Every variant renders into `out_dir/<name>/`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::{
+    lines, load_building_areas, load_osm, outcome, paths, render_command, set_zoom, zoom,
+    RenderArgs,
+};
+
+/// A value of a sweep, or the zoom, as written in the recipe.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Param {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Param::Int(v) => write!(f, "{v}"),
+            Param::Float(v) => write!(f, "{v}"),
+            Param::Text(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VariantSpec {
+    name: String,
+    /// Zoom of the tiles, `--zoom` if left out.
+    #[serde(default)]
+    zoom: Option<Param>,
+    /// Arguments of `render-outlines`, all but `--out-dir`.
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    sweep: BTreeMap<String, Vec<Param>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Recipe {
+    #[serde(default = "default_out_dir")]
+    out_dir: PathBuf,
+    #[serde(rename = "variant")]
+    variants: Vec<VariantSpec>,
+}
+
+fn default_out_dir() -> PathBuf {
+    PathBuf::from("variants")
+}
+
+/// One variant after expanding the sweeps.
+struct Variant {
+    name: String,
+    zoom: u8,
+    args: RenderArgs,
+}
+
+/// `render-outlines` arguments as a variant gives them.
+#[derive(Parser)]
+#[command(name = "render-outlines", no_binary_name = true)]
+struct VariantCli {
+    #[command(flatten)]
+    args: RenderArgs,
+}
+
+#[derive(clap::Args)]
+pub struct RecipeOptions {
+    /// The recipe, a TOML file.
+    pub file: PathBuf,
+    /// Only list the variants the sweeps expand into.
+    #[arg(long)]
+    pub list: bool,
+}
+
+/// `text` with every `{param}` of `values` filled in.
+fn fill(text: &str, values: &[(&String, &Param)]) -> String {
+    values.iter().fold(text.to_owned(), |text, (name, value)| {
+        text.replace(&format!("{{{name}}}"), &value.to_string())
+    })
+}
+
+impl VariantSpec {
+    /// Every combination of the sweep values, in the order of the
+    /// parameter names.
+    fn combinations(&self) -> Vec<Vec<(&String, &Param)>> {
+        self.sweep
+            .iter()
+            .fold(vec![vec![]], |combinations, (name, values)| {
+                combinations
+                    .iter()
+                    .flat_map(|c| {
+                        values.iter().map(move |v| {
+                            let mut c = c.clone();
+                            c.push((name, v));
+                            c
+                        })
+                    })
+                    .collect()
+            })
+    }
+
+    fn expand(&self, out_dir: &Path) -> anyhow::Result<Vec<Variant>> {
+        if let Some((name, _)) = self.sweep.iter().find(|(_, values)| values.is_empty()) {
+            anyhow::bail!("variant {}: sweep {name} has no values", self.name);
+        }
+        let mut variants = vec![];
+        for values in self.combinations() {
+            let name = fill(&self.name, &values);
+            let zoom = match &self.zoom {
+                Some(z) => fill(&z.to_string(), &values).parse().map_err(|why| {
+                    anyhow::anyhow!("variant {name}: zoom {z} is not a zoom: {why}")
+                })?,
+                None => zoom(),
+            };
+            anyhow::ensure!(
+                (1..=crate::MAX_ZOOM).contains(&zoom),
+                "variant {name}: zoom {zoom} is out of range 1..={}",
+                crate::MAX_ZOOM
+            );
+            let out = out_dir.join(&name);
+            let mut args = vec!["--out-dir".to_owned(), out.to_string_lossy().into_owned()];
+            args.extend(self.args.iter().map(|a| fill(a, &values)));
+            let cli = VariantCli::try_parse_from(&args).map_err(|why| {
+                anyhow::anyhow!(
+                    "variant {name}: {}\n\
+                     hint: `args` are those of render-outlines, without --out-dir",
+                    why.render().to_string().trim()
+                )
+            })?;
+            anyhow::ensure!(
+                cli.args.as_of.is_none(),
+                "variant {name}: --as-of reads a history extract of its own\n\
+                 hint: render snapshots with render-outlines --as-of"
+            );
+            variants.push(Variant {
+                name,
+                zoom,
+                args: cli.args,
+            });
+        }
+        Ok(variants)
+    }
+}
+
+fn load(path: &Path) -> anyhow::Result<Vec<Variant>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|why| anyhow::anyhow!("cannot read the recipe {}: {why}", path.display()))?;
+    let recipe: Recipe = toml::from_str(&text).map_err(|why| {
+        anyhow::anyhow!(
+            "{} is not a valid recipe: {why}\n\
+             hint: a recipe is a list of [[variant]] tables with a name and args",
+            path.display()
+        )
+    })?;
+    let mut variants = vec![];
+    for spec in &recipe.variants {
+        variants.extend(spec.expand(&recipe.out_dir)?);
+    }
+    anyhow::ensure!(!variants.is_empty(), "{} has no variants", path.display());
+    let mut names = HashMap::new();
+    for v in &variants {
+        if let Some(first) = names.insert(&v.name, v) {
+            anyhow::bail!(
+                "two variants are named {}, with zooms {} and {}\n\
+                 hint: put every sweep parameter into the name",
+                v.name,
+                first.zoom,
+                v.zoom
+            );
+        }
+    }
+    let zooms: Vec<_> = variants.iter().map(|v| v.zoom).collect();
+    anyhow::ensure!(
+        zooms.iter().all(|z| *z == zooms[0]) || paths::has_zoom(),
+        "the variants have different zooms, whose tiles would share file names\n\
+         hint: add {{z}} to tile_paths in formats.json, e.g. {{root}}/{{kind}}/{{z}}/{{name}}.{{ext}}"
+    );
+    Ok(variants)
+}
+
+/// Renders every variant of the recipe, returning the worst exit code of
+/// them.
+pub fn run(pbf: &std::ffi::OsStr, opts: &RecipeOptions) -> anyhow::Result<u8> {
+    let variants = load(&opts.file)?;
+    if opts.list {
+        for v in &variants {
+            println!("{} (zoom {})", v.name, v.zoom);
+        }
+        return Ok(outcome::SUCCESS);
+    }
+    for v in &variants {
+        set_zoom(v.zoom);
+        v.args
+            .check()
+            .map_err(|why| anyhow::anyhow!("variant {}: {why:#}", v.name))?;
+    }
+
+    let osm = load_osm(pbf)?;
+    let building_areas = match variants.iter().any(|v| v.args.relations) {
+        true => load_building_areas(pbf)?,
+        false => vec![],
+    };
+    // Line features depend on the line options only.
+    let mut line_features: HashMap<String, Vec<lines::LineFeature>> = HashMap::new();
+    let mut worst = outcome::SUCCESS;
+    let count = variants.len();
+    for (i, v) in variants.into_iter().enumerate() {
+        println!(
+            "Variant {} of {count}: {} at zoom {}",
+            i + 1,
+            v.name,
+            v.zoom
+        );
+        set_zoom(v.zoom);
+        let key = format!("{:?}", v.args.lines);
+        if !line_features.contains_key(&key) {
+            line_features.insert(key.clone(), lines::load_lines(pbf, &v.args.lines));
+        }
+        let areas = match v.args.relations {
+            true => &building_areas[..],
+            false => &[],
+        };
+        let out_dir = v.args.out_dir.clone();
+        let code = render_command(&osm, &line_features[&key], areas, v.args, out_dir)?;
+        worst = worst.max(code);
+    }
+    Ok(worst)
+}
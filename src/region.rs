@@ -0,0 +1,450 @@
+//! Arbitrary windows rather than fixed chips: `render_region` mosaics the
+//! cached tiles under a bounding box at a chosen ground resolution and
+//! rasterizes the objects in it straight into a mask of the same size, the
+//! way `render-outlines` draws them into tiles.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use image::{GrayImage, Luma, Rgb, RgbImage};
+use imageproc::point::Point;
+use log::{info, warn};
+use slippy_map_tiles::BBox;
+
+use crate::{
+    checks,
+    classes::{self, colors, ClassOptions, FeatureClass, Shape},
+    collect_features, fill_with_holes, footprint_class, logging, paths, provider, register,
+    ring_area, ring_coords, rings, stroke_polyline, way_coords,
+    workspace::TILES,
+    zoom, BuildingArea, Feature, GeoCoordinate, OsmData,
+};
+
+/// Largest window `region` renders, in pixels, about a 16k x 16k image.
+const MAX_PIXELS: u64 = 1 << 28;
+
+/// Decoded tiles of `tiles/` by `(x, y)`, `None` for those not there, kept
+/// across windows that lie close together.
+pub type TileImages = HashMap<(u32, u32), Option<RgbImage>>;
+
+#[derive(clap::Args)]
+pub struct RegionOptions {
+    /// Window to render as `TOP,LEFT,BOTTOM,RIGHT` in degrees.
+    #[arg(long, value_name = "TOP,LEFT,BOTTOM,RIGHT", value_parser = checks::parse_bbox)]
+    pub window: BBox,
+    /// Ground sample distance, meters per pixel.
+    #[arg(long, default_value_t = 1.0)]
+    pub gsd: f64,
+    /// Directory to write `image.png` and `mask.png` into.
+    #[arg(long, default_value = "region")]
+    pub out: PathBuf,
+    /// Also draw building relations, with their inner rings as holes, in
+    /// place of their member ways.
+    #[arg(long)]
+    pub relations: bool,
+    #[command(flatten)]
+    pub classes: ClassOptions,
+    #[command(flatten)]
+    pub rings: rings::RingOptions,
+}
+
+/// The objects masks are drawn from, resolved, classified and moved by
+/// the provider offset once, so that many windows can be drawn from them.
+pub struct Labels {
+    /// Features drawn under the buildings.
+    below: Vec<Feature>,
+    /// Closed rings of the building ways drawn on their own.
+    buildings: Vec<(FeatureClass, Vec<GeoCoordinate>)>,
+    /// Polygons of building relations, outer ring and holes.
+    areas: Vec<(FeatureClass, Vec<GeoCoordinate>, Vec<Vec<GeoCoordinate>>)>,
+    /// Features drawn on top of the buildings.
+    above: Vec<Feature>,
+}
+
+impl Labels {
+    /// The objects of `osm`, with the building relations in `areas` in
+    /// place of their member ways.
+    pub fn new(
+        osm: &OsmData,
+        areas: &[BuildingArea],
+        classes: &ClassOptions,
+        rings: &rings::RingOptions,
+    ) -> Self {
+        let offset_m = provider::Providers::load()
+            .map(|p| p.offset_m(&provider::source().name))
+            .unwrap_or_else(|why| {
+                warn!("error reading the provider offset: {why}");
+                [0.0, 0.0]
+            });
+        let tile_px = crate::chips::tile_px();
+
+        let (below, above): (Vec<_>, Vec<_>) = collect_features(osm, classes)
+            .into_iter()
+            .map(|f| Feature {
+                coords: register(&f.coords, offset_m),
+                ..f
+            })
+            .partition(|f| classes::draw_order(f.class) < classes::BUILDINGS_ORDER);
+
+        let drawn = areas
+            .iter()
+            .filter(|a| !a.area.0.is_empty())
+            .map(|a| a.relation.id.0)
+            .collect();
+        let mut ways = osm.standalone_buildings(&drawn);
+        ways.sort_by_key(|w| w.id);
+        let buildings = ways
+            .into_iter()
+            .filter_map(|way| {
+                let coords = way_coords(way, &osm.nodes_all).filter(|c| c.len() >= 3)?;
+                let coords = rings::close(rings, &mut Default::default(), way, coords)?;
+                let class = footprint_class(&way.tags, &ring_area(&coords), classes, tile_px)?;
+                Some((class, register(&coords, offset_m)))
+            })
+            .collect();
+
+        let mut polygons = vec![];
+        for area in areas.iter().filter(|a| !a.area.0.is_empty()) {
+            let tags = &area.relation.tags;
+            let Some(class) = footprint_class(tags, &area.area, classes, tile_px) else {
+                continue;
+            };
+            for poly in &area.area {
+                let outer = register(&ring_coords(poly.exterior()), offset_m);
+                let holes = poly
+                    .interiors()
+                    .iter()
+                    .map(|h| register(&ring_coords(h), offset_m))
+                    .collect();
+                polygons.push((class, outer, holes));
+            }
+        }
+
+        Self {
+            below,
+            buildings,
+            areas: polygons,
+            above,
+        }
+    }
+}
+
+/// Pixel grid over a bounding box, linear in longitude and in Web
+/// Mercator northing like the tiles themselves.
+struct Window {
+    left: f64,
+    right: f64,
+    top: f64,
+    bottom: f64,
+    width: u32,
+    height: u32,
+}
+
+fn mercator_y(latitude: f64) -> f64 {
+    latitude.to_radians().tan().asinh()
+}
+
+fn latitude(mercator_y: f64) -> f64 {
+    mercator_y.sinh().atan().to_degrees()
+}
+
+impl Window {
+    /// The window over `bbox` at `gsd`, if it has at most `max_pixels`.
+    fn new(bbox: &BBox, gsd: f64, max_pixels: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            gsd > 0.0 && gsd.is_finite(),
+            "--gsd must be a positive number of meters per pixel"
+        );
+        let (top, bottom) = (bbox.top() as f64, bbox.bottom() as f64);
+        let (left, right) = (bbox.left() as f64, bbox.right() as f64);
+        let center = (top + bottom) / 2.0;
+        let width_m = (right - left) * 111_320.0 * center.to_radians().cos();
+        let height_m = (top - bottom) * 111_320.0;
+        // In floats, as a tiny --gsd makes sides past what any integer holds.
+        let width = (width_m / gsd).round().max(1.0);
+        let height = (height_m / gsd).round().max(1.0);
+        anyhow::ensure!(
+            width <= u32::MAX as f64
+                && height <= u32::MAX as f64
+                && width * height <= max_pixels as f64,
+            "a window of {width}x{height} px is too large to render at once\n\
+             hint: raise --gsd or render a smaller window"
+        );
+        Ok(Self::sized(bbox, width as u32, height as u32))
+    }
+
+    fn sized(bbox: &BBox, width: u32, height: u32) -> Self {
+        Self {
+            left: bbox.left() as f64,
+            right: bbox.right() as f64,
+            top: mercator_y(bbox.top() as f64),
+            bottom: mercator_y(bbox.bottom() as f64),
+            width,
+            height,
+        }
+    }
+
+    fn to_px(&self, c: GeoCoordinate) -> Point<f64> {
+        Point::new(
+            (c.longitude - self.left) / (self.right - self.left) * self.width as f64,
+            (self.top - mercator_y(c.latitude)) / (self.top - self.bottom) * self.height as f64,
+        )
+    }
+
+    /// The center of pixel `x`, `y`.
+    fn to_geo(&self, x: u32, y: u32) -> GeoCoordinate {
+        let fx = (x as f64 + 0.5) / self.width as f64;
+        let fy = (y as f64 + 0.5) / self.height as f64;
+        GeoCoordinate {
+            longitude: self.left + (self.right - self.left) * fx,
+            latitude: latitude(self.top + (self.bottom - self.top) * fy),
+        }
+    }
+
+    /// Pixel corners of a ring, without the repeated first vertex.
+    fn ring(&self, coords: &[GeoCoordinate]) -> Vec<Point<i32>> {
+        let mut ring: Vec<_> = coords
+            .iter()
+            .map(|c| {
+                let p = self.to_px(*c);
+                Point::new(p.x as i32, p.y as i32)
+            })
+            .collect();
+        while ring.len() > 1 && ring.last() == ring.first() {
+            ring.pop();
+        }
+        ring
+    }
+
+    /// Whether any part of the extent of `coords`, grown by `margin_m`,
+    /// lies in the window.
+    fn overlaps(&self, coords: &[GeoCoordinate], margin_m: f64) -> bool {
+        let (mut lo, mut hi) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for c in coords {
+            let p = self.to_px(*c);
+            lo = (lo.0.min(p.x), lo.1.min(p.y));
+            hi = (hi.0.max(p.x), hi.1.max(p.y));
+        }
+        // Meters are about pixels times the ground resolution; a pixel
+        // more keeps the rounding on the safe side.
+        let margin = margin_m * self.width as f64 / self.width_m() + 1.0;
+        hi.0 >= -margin
+            && hi.1 >= -margin
+            && lo.0 <= self.width as f64 + margin
+            && lo.1 <= self.height as f64 + margin
+    }
+
+    fn width_m(&self) -> f64 {
+        let center = latitude((self.top + self.bottom) / 2.0);
+        (self.right - self.left) * 111_320.0 * center.to_radians().cos()
+    }
+
+    fn pixels_per_meter(&self) -> f64 {
+        self.width as f64 / self.width_m()
+    }
+}
+
+/// Mosaics the tiles in `tiles/` under `bbox` at `gsd` meters per pixel,
+/// and rasterizes the objects of `labels` there into a mask in the
+/// `classes::colors` of the outlines. Imagery is sampled at the nearest
+/// tile pixel; tiles not downloaded yet are left black, as the window is
+/// only read from the dataset.
+pub fn render_region(
+    labels: &Labels,
+    bbox: &BBox,
+    gsd: f64,
+    max_pixels: u64,
+) -> anyhow::Result<(RgbImage, RgbImage)> {
+    let window = Window::new(bbox, gsd, max_pixels)?;
+    info!(
+        "Rendering a window of {}x{} px at {gsd} m/px",
+        window.width, window.height
+    );
+    Ok(render(labels, &window))
+}
+
+/// Fails as `render_region` would for a window too large or a `gsd` that
+/// is no resolution, without rendering anything.
+pub fn check_window(bbox: &BBox, gsd: f64, max_pixels: u64) -> anyhow::Result<()> {
+    Window::new(bbox, gsd, max_pixels).map(|_| ())
+}
+
+/// Like `render_region`, at a given size in pixels rather than a ground
+/// resolution.
+pub fn render_window(
+    labels: &Labels,
+    bbox: &BBox,
+    (width, height): (u32, u32),
+) -> (RgbImage, RgbImage) {
+    render(labels, &Window::sized(bbox, width, height))
+}
+
+fn render(labels: &Labels, window: &Window) -> (RgbImage, RgbImage) {
+    let mut tiles = TileImages::new();
+    let (image, _) = mosaic(window, &mut tiles);
+    let missing = tiles.values().filter(|t| t.is_none()).count();
+    if missing > 0 {
+        warn!(
+            "{missing} of the {} tiles under the window are not in tiles/, left black",
+            tiles.len()
+        );
+    }
+    (image, rasterize(labels, window))
+}
+
+/// The imagery under `bbox` at `size` pixels and the footprint `coords`
+/// filled white on black at the same size, `None` if some of the imagery
+/// is not in `tiles/`.
+pub fn footprint_crop(
+    bbox: &BBox,
+    size: (u32, u32),
+    coords: &[GeoCoordinate],
+    tiles: &mut TileImages,
+) -> Option<(RgbImage, GrayImage)> {
+    let window = Window::sized(bbox, size.0, size.1);
+    let (image, complete) = mosaic(&window, tiles);
+    if !complete {
+        return None;
+    }
+    let mut mask = GrayImage::new(window.width, window.height);
+    let ring = window.ring(coords);
+    if ring.len() >= 3 {
+        imageproc::drawing::draw_polygon_mut(&mut mask, &ring, Luma([255]));
+    }
+    Some((image, mask))
+}
+
+/// The imagery under `window`, and whether all of it was in `tiles/`.
+fn mosaic(window: &Window, tiles: &mut TileImages) -> (RgbImage, bool) {
+    let mut complete = true;
+    let image = RgbImage::from_fn(window.width, window.height, |x, y| {
+        let c = window.to_geo(x, y);
+        let (fx, fy) = mercator::tile_xy(c.latitude, c.longitude, zoom());
+        let (tx, ty) = (fx.floor() as u32, fy.floor() as u32);
+        let tile = tiles.entry((tx, ty)).or_insert_with(|| {
+            let tile = slippy_map_tiles::Tile::new(zoom(), tx, ty)?;
+            image::open(paths::tile_file(TILES, tile, ".jpg"))
+                .ok()
+                .map(|img| img.into_rgb8())
+        });
+        match tile {
+            Some(img) => {
+                let px = ((fx.fract() * img.width() as f64) as u32).min(img.width() - 1);
+                let py = ((fy.fract() * img.height() as f64) as u32).min(img.height() - 1);
+                *img.get_pixel(px, py)
+            }
+            None => {
+                complete = false;
+                Rgb([0, 0, 0])
+            }
+        }
+    });
+    (image, complete)
+}
+
+fn rasterize(labels: &Labels, window: &Window) -> RgbImage {
+    let mut mask = RgbImage::new(window.width, window.height);
+    let visible = |f: &&Feature| window.overlaps(&f.coords, reach_m(f.shape));
+    for feature in labels.below.iter().filter(visible) {
+        draw_feature(&mut mask, window, feature);
+    }
+    for (class, coords) in &labels.buildings {
+        if !window.overlaps(coords, 0.0) {
+            continue;
+        }
+        let ring = window.ring(coords);
+        if ring.len() >= 3 {
+            imageproc::drawing::draw_polygon_mut(&mut mask, &ring, color(*class));
+        }
+    }
+    for (class, outer, holes) in &labels.areas {
+        if !window.overlaps(outer, 0.0) {
+            continue;
+        }
+        let outer = window.ring(outer);
+        let holes: Vec<_> = holes
+            .iter()
+            .map(|h| window.ring(h))
+            .filter(|h| h.len() >= 3)
+            .collect();
+        if outer.len() >= 3 {
+            fill_with_holes(&mut mask, &outer, &holes, color(*class));
+        }
+    }
+    for feature in labels.above.iter().filter(visible) {
+        draw_feature(&mut mask, window, feature);
+    }
+    mask
+}
+
+/// How far beyond its coordinates a feature is drawn.
+fn reach_m(shape: Shape) -> f64 {
+    match shape {
+        Shape::Area => 0.0,
+        Shape::Line { width_m } => width_m / 2.0,
+        Shape::Disk { radius_m } => radius_m,
+    }
+}
+
+fn color(class: FeatureClass) -> Rgb<u8> {
+    Rgb(colors()[class as usize])
+}
+
+/// Like `fetch_outline_feature`, into the window.
+fn draw_feature(mask: &mut RgbImage, window: &Window, feature: &Feature) {
+    let coords = &feature.coords;
+    let color = color(feature.class);
+    match feature.shape {
+        Shape::Area => {
+            let ring = window.ring(coords);
+            if ring.len() >= 3 {
+                imageproc::drawing::draw_polygon_mut(mask, &ring, color);
+            }
+        }
+        Shape::Line { width_m } => {
+            let half = (width_m / 2.0 * window.pixels_per_meter()).max(0.5);
+            let points: Vec<_> = coords.iter().map(|c| window.to_px(*c)).collect();
+            stroke_polyline(mask, &points, half, 0.0, color);
+        }
+        Shape::Disk { radius_m } => {
+            let radius = (radius_m * window.pixels_per_meter()).round() as i32;
+            let c = window.to_px(coords[0]);
+            imageproc::drawing::draw_filled_circle_mut(
+                mask,
+                (c.x as i32, c.y as i32),
+                radius.max(1),
+                color,
+            );
+        }
+    }
+}
+
+/// Writes the window of `opts` into `image.png` and `mask.png` of its
+/// `--out`.
+pub fn export_region(labels: &Labels, opts: &RegionOptions) -> anyhow::Result<()> {
+    let (image, mask) = render_region(labels, &opts.window, opts.gsd, MAX_PIXELS)?;
+    std::fs::create_dir_all(&opts.out)?;
+    image.save(opts.out.join("image.png"))?;
+    mask.save(opts.out.join("mask.png"))?;
+    logging::status!(
+        "Wrote a {}x{} px window to {}",
+        image.width(),
+        image.height(),
+        opts.out.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_past_the_pixel_limit_is_rejected() {
+        let world = crate::checks::bbox(85.0, -180.0, -85.0, 180.0).unwrap();
+        assert!(Window::new(&world, 1e-9, MAX_PIXELS).is_err());
+        assert!(Window::new(&world, 1.0, MAX_PIXELS).is_err());
+        assert!(Window::new(&world, 100_000.0, 100).is_err());
+        let window = Window::new(&world, 100_000.0, MAX_PIXELS).unwrap();
+        assert!(window.width > 1 && window.height > 1);
+    }
+}
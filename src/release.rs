@@ -0,0 +1,281 @@
+//! Versioned dataset releases. `release` freezes the chips listed in the
+//! stitch manifest into `releases/<version>/`: hard links to the chip
+//! files, which the stitcher only ever replaces by renaming, so later runs
+//! leave them alone, and a descriptor with the hash of every file and a
+//! checksum over all of them. `release-delta` lists what changed between
+//! two releases, so a consumer can upgrade by fetching only that.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    dedup::hex,
+    formats::Formats,
+    logging, paths,
+    workspace::{OUTLINES, STITCHED, TILES},
+};
+
+const RELEASES_DIR: &str = "releases";
+const DESCRIPTOR: &str = "release.json";
+/// Bumped when the descriptor changes incompatibly.
+const FORMAT: u32 = 1;
+
+/// What the manifest of `stitch_pictures` says about a block.
+#[derive(Deserialize)]
+pub struct ManifestLine {
+    pub block: String,
+    pub inputs: String,
+    pub params: String,
+    /// Tiles of the block painted `NoLabel`.
+    #[serde(default)]
+    pub no_label: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chip {
+    /// SHA-256 of the chip in `tiles/`.
+    pub image: String,
+    /// SHA-256 of `outlines/<name>.png`.
+    pub label: String,
+    /// Hashes of the block's sources and stitching parameters, from the
+    /// stitch manifest.
+    pub inputs: String,
+    pub params: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Release {
+    pub format: u32,
+    pub version: String,
+    /// Unix time the release was made.
+    pub created: u64,
+    pub chips: BTreeMap<String, Chip>,
+    /// SHA-256 over `chips`, which pins the content of the whole release.
+    pub checksum: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Delta<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub from_checksum: &'a str,
+    pub to_checksum: &'a str,
+    pub added: BTreeMap<&'a str, &'a Chip>,
+    pub changed: BTreeMap<&'a str, &'a Chip>,
+    pub removed: Vec<&'a str>,
+}
+
+fn checksum(chips: &BTreeMap<String, Chip>) -> String {
+    hex(&Sha256::digest(serde_json::to_vec(chips).unwrap()))
+}
+
+pub fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    Ok(hex(&Sha256::digest(data)))
+}
+
+fn release_dir(version: &str) -> PathBuf {
+    Path::new(RELEASES_DIR).join(version)
+}
+
+/// Reads the manifest journal, the last line per block winning and a torn
+/// line from a crash ignored.
+pub fn read_manifest(path: &Path) -> anyhow::Result<BTreeMap<String, ManifestLine>> {
+    let f = File::open(path).with_context(|| {
+        format!(
+            "reading {}\nhint: run stitch_pictures first",
+            path.display()
+        )
+    })?;
+    let mut blocks = BTreeMap::new();
+    for line in BufReader::new(f).lines() {
+        let Ok(line) = line else { break };
+        match serde_json::from_str::<ManifestLine>(&line) {
+            Ok(l) => {
+                blocks.insert(l.block.clone(), l);
+            }
+            Err(why) => warn!("ignoring manifest line {line:?}: {why}"),
+        }
+    }
+    Ok(blocks)
+}
+
+/// Links `from` to `to`, copying if they are on different filesystems.
+pub fn link_or_copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to).with_context(|| format!("copying {}", from.display()))?;
+    }
+    Ok(())
+}
+
+/// Freezes the chips in `stitched/` as release `version`.
+pub fn release(version: &str) -> anyhow::Result<()> {
+    if version.is_empty() || version.starts_with('.') || version.contains(['/', '\\']) {
+        bail!("invalid release version {version:?}\nhint: use something like 2024.1 or v3");
+    }
+    let dir = release_dir(version);
+    if dir.exists() {
+        bail!(
+            "release {version} already exists in {}\n\
+             hint: releases are immutable, pick a new version",
+            dir.display()
+        );
+    }
+    let stitched = Path::new(STITCHED);
+    let blocks = read_manifest(&stitched.join("manifest.jsonl"))?;
+
+    // Built next to the final directory and renamed into place, so an
+    // interrupted run never leaves half a release under the version.
+    let tmp = Path::new(RELEASES_DIR).join(format!(".{version}.part"));
+    if tmp.exists() {
+        std::fs::remove_dir_all(&tmp)?;
+    }
+    for sub in [TILES, OUTLINES] {
+        std::fs::create_dir_all(tmp.join(sub))?;
+    }
+    let at = |dir: &Path, file: &str| paths::join_relative(dir, file);
+    let ext = Formats::load()?.chips.ext();
+    let mut chips = BTreeMap::new();
+    for (name, block) in blocks {
+        let Some(chip) = paths::parse(OsStr::new(&name), "") else {
+            warn!("block {name} in the manifest is not a tile name, leaving it out");
+            continue;
+        };
+        // Releases keep a directory per kind, whatever `tile_paths` says.
+        let image = format!("tiles/{name}{ext}");
+        let label = format!("outlines/{name}.png");
+        let sources = [
+            paths::tile_file(stitched.join(TILES), chip, ext),
+            paths::tile_file(stitched.join(OUTLINES), chip, ".png"),
+        ];
+        if !sources.iter().all(|s| s.is_file()) {
+            warn!("block {name} is in the manifest but its files are missing, leaving it out");
+            continue;
+        }
+        for (source, file) in sources.iter().zip([&image, &label]) {
+            link_or_copy(source, &at(&tmp, file))?;
+        }
+        // Hashed from the links, which are what the release ships even
+        // if a stitcher runs meanwhile.
+        let chip = Chip {
+            image: hash_file(&at(&tmp, &image))?,
+            label: hash_file(&at(&tmp, &label))?,
+            inputs: block.inputs,
+            params: block.params,
+        };
+        chips.insert(name, chip);
+    }
+    let release = Release {
+        format: FORMAT,
+        version: version.to_owned(),
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        checksum: checksum(&chips),
+        chips,
+    };
+    std::fs::write(
+        tmp.join(DESCRIPTOR),
+        serde_json::to_string_pretty(&release)?,
+    )?;
+    std::fs::rename(&tmp, &dir)?;
+    logging::status!(
+        "Release {version}: {} chips, checksum {}",
+        release.chips.len(),
+        release.checksum
+    );
+    Ok(())
+}
+
+/// Reads the descriptor of a release and checks that it is untouched.
+pub fn load(version: &str) -> anyhow::Result<Release> {
+    let path = release_dir(version).join(DESCRIPTOR);
+    if !path.is_file() {
+        bail!(
+            "there is no {}\nhint: make the release with `release {version}`",
+            path.display()
+        );
+    }
+    load_descriptor(&path)
+}
+
+/// Reads the descriptor at `path`, checking that it is untouched.
+pub fn load_descriptor(path: &Path) -> anyhow::Result<Release> {
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let release: Release =
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))?;
+    let version = &release.version;
+    if release.format != FORMAT {
+        bail!(
+            "release {version} has descriptor format {}, this version reads {FORMAT}",
+            release.format
+        );
+    }
+    if checksum(&release.chips) != release.checksum {
+        bail!(
+            "release {version} does not match its checksum\n\
+             hint: {} was edited after the release was made",
+            path.display()
+        );
+    }
+    Ok(release)
+}
+
+pub fn diff<'a>(from: &'a Release, to: &'a Release) -> Delta<'a> {
+    let mut delta = Delta {
+        from: &from.version,
+        to: &to.version,
+        from_checksum: &from.checksum,
+        to_checksum: &to.checksum,
+        added: BTreeMap::new(),
+        changed: BTreeMap::new(),
+        removed: vec![],
+    };
+    for (name, chip) in &to.chips {
+        match from.chips.get(name) {
+            None => {
+                delta.added.insert(name, chip);
+            }
+            // Only the files matter to a consumer, a chip rebuilt to the
+            // same bytes is unchanged.
+            Some(old) if (&old.image, &old.label) != (&chip.image, &chip.label) => {
+                delta.changed.insert(name, chip);
+            }
+            Some(_) => {}
+        }
+    }
+    delta.removed = from
+        .chips
+        .keys()
+        .filter(|name| !to.chips.contains_key(*name))
+        .map(String::as_str)
+        .collect();
+    delta
+}
+
+/// Writes `releases/<to>/delta-from-<from>.json`. The added and changed
+/// chips are fetched from release `to`.
+pub fn release_delta(from: &str, to: &str) -> anyhow::Result<()> {
+    let (old, new) = (load(from)?, load(to)?);
+    let delta = diff(&old, &new);
+    let path = release_dir(to).join(format!("delta-from-{from}.json"));
+    std::fs::write(&path, serde_json::to_string_pretty(&delta)?)?;
+    logging::status!(
+        "{from} -> {to}: {} added, {} changed, {} removed, written to {}",
+        delta.added.len(),
+        delta.changed.len(),
+        delta.removed.len(),
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Checks that a release can still be regenerated. `verify-repro` draws a
+//! seeded sample of the chips of a release descriptor, renders the tiles
+//! under them with the code and configuration at hand into a scratch
+//! directory, stitches them there with `stitch_pictures --strict-pairing`
+//! and compares the chips with the hashes the release recorded. Labels
+//! may differ in up to `--tolerance` of their pixels, measured against
+//! the label files the release keeps; imagery has to be identical.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use image::GenericImageView;
+use log::warn;
+use serde::Serialize;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    chips,
+    formats::Formats,
+    lines, load_building_areas, load_osm, logging, paths, release, render_command,
+    rng::Rng,
+    stitch_at,
+    workspace::{self, OUTLINES, STITCHED, TILES},
+    zoom, RenderArgs,
+};
+
+#[derive(clap::Args)]
+pub struct VerifyOptions {
+    /// Descriptor of the release to check, `releases/<VERSION>/release.json`.
+    #[arg(long)]
+    pub manifest: PathBuf,
+    /// Chips to regenerate.
+    #[arg(long, default_value_t = 10)]
+    pub sample: usize,
+    /// Seed of the sample; the same seed draws the same chips.
+    #[arg(long, default_value_t = 0)]
+    pub sample_seed: u64,
+    /// Fraction of label pixels that may differ from the release, e.g.
+    /// 0.001. With 0 labels have to be identical files.
+    #[arg(long, default_value_t = 0.0)]
+    pub tolerance: f64,
+    /// Directory the sample is rendered and stitched in. Emptied first.
+    #[arg(long, default_value = "verify-repro")]
+    pub scratch: PathBuf,
+    /// Argument of `stitch_pictures`, as the release was stitched with,
+    /// e.g. `--stitch-arg=--anchor=aoi`. Repeated for several.
+    #[arg(long = "stitch-arg", allow_hyphen_values = true)]
+    pub stitch_args: Vec<OsString>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Verdict {
+    Identical,
+    /// The label differs in no more than `--tolerance` of its pixels.
+    WithinTolerance,
+    Differs,
+    /// Not stitched again, e.g. a block with tiles missing.
+    NotRebuilt,
+}
+
+#[derive(Serialize)]
+struct ChipReport {
+    verdict: Verdict,
+    image_identical: bool,
+    /// Share of label pixels that differ, if they were compared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_differs: Option<f64>,
+}
+
+/// The tiles a chip is stitched from, by the size of its label.
+fn chip_tiles(chip: Tile, label: &Path, tile_px: u32) -> anyhow::Result<Vec<Tile>> {
+    let (w, h) = image::image_dimensions(label)
+        .map_err(|why| anyhow::anyhow!("cannot read {}: {why}", label.display()))?;
+    let (cols, rows) = (w.div_ceil(tile_px), h.div_ceil(tile_px));
+    Ok((0..rows)
+        .flat_map(|dy| (0..cols).map(move |dx| (dx, dy)))
+        .filter_map(|(dx, dy)| Tile::new(zoom(), chip.x() + dx, chip.y() + dy))
+        .collect())
+}
+
+/// Share of pixels that differ between two labels, 1 if their sizes do.
+fn label_difference(a: &Path, b: &Path) -> anyhow::Result<f64> {
+    let (a, b) = (image::open(a)?, image::open(b)?);
+    if a.dimensions() != b.dimensions() {
+        return Ok(1.0);
+    }
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    let differ = a.pixels().zip(b.pixels()).filter(|(p, q)| p != q).count();
+    Ok(differ as f64 / (a.width() * a.height()) as f64)
+}
+
+/// Empties `scratch`, refusing to if it is a directory `command` did not
+/// make, which marks the ones it makes with a `.<command>` file. `flag`
+/// picks another directory.
+pub fn clear_scratch(scratch: &Path, command: &str, flag: &str) -> anyhow::Result<()> {
+    let marker = format!(".{command}");
+    if scratch.exists() {
+        let ours = scratch.join(&marker).is_file();
+        let empty = std::fs::read_dir(scratch)?.next().is_none();
+        anyhow::ensure!(
+            ours || empty,
+            "{} is not a scratch directory of {command}\n\
+             hint: pick an empty or new directory with {flag}",
+            scratch.display()
+        );
+        std::fs::remove_dir_all(scratch)?;
+    }
+    std::fs::create_dir_all(scratch)?;
+    std::fs::write(scratch.join(marker), "")?;
+    Ok(())
+}
+
+/// Regenerates a sample of the chips of `opts.manifest` as `args` render
+/// them and compares them with it. Fails if any chip differs.
+pub fn verify(
+    pbf: &OsStr,
+    opts: &VerifyOptions,
+    mut args: RenderArgs,
+    aoi: &str,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        args.as_of.is_none(),
+        "verify-repro checks the current extract, not a snapshot"
+    );
+    anyhow::ensure!(
+        (0.0..1.0).contains(&opts.tolerance),
+        "--tolerance is a fraction of the pixels, 0 or more and below 1"
+    );
+    let descriptor = release::load_descriptor(&opts.manifest)?;
+    let release_dir = opts.manifest.parent().unwrap_or(Path::new("."));
+    let ext = Formats::load()?.chips.ext();
+
+    let mut names: Vec<_> = descriptor.chips.keys().collect();
+    let mut rng = Rng::new(opts.sample_seed, 0);
+    for i in (1..names.len()).rev() {
+        names.swap(i, rng.below(i + 1));
+    }
+    names.truncate(opts.sample);
+    names.sort();
+
+    let tile_px = chips::tile_px();
+    let mut sample = vec![];
+    let mut tiles = HashSet::new();
+    for name in names {
+        let Some(chip) = paths::parse(OsStr::new(name), "") else {
+            warn!("chip {name} of the release is not a tile name, leaving it out");
+            continue;
+        };
+        let label = release_dir.join(OUTLINES).join(format!("{name}.png"));
+        tiles.extend(chip_tiles(chip, &label, tile_px)?);
+        sample.push((name, chip, label));
+    }
+    logging::status!(
+        "Regenerating {} chips of release {}, {} tiles",
+        sample.len(),
+        descriptor.version,
+        tiles.len()
+    );
+
+    let scratch = &opts.scratch;
+    clear_scratch(scratch, "verify-repro", "--scratch")?;
+    // One unit per tile, so that only the sample is drawn.
+    args.units.unit_zoom = Some(zoom());
+    args.units.only = Some(tiles);
+    args.units.after_unit = None;
+    args.units.prefetch = None;
+    args.out_dir = scratch.clone();
+    let line_features = lines::load_lines(pbf, &args.lines);
+    let building_areas = match args.relations {
+        true => load_building_areas(pbf)?,
+        false => vec![],
+    };
+    let osm = load_osm(pbf)?;
+    render_command(&osm, &line_features, &building_areas, args, scratch.clone())?;
+
+    if Path::new("formats.json").is_file() {
+        std::fs::copy("formats.json", scratch.join("formats.json"))?;
+    }
+    let mut stitch_args = vec![
+        OsString::from("--tiles"),
+        workspace::get().dir(TILES).into_os_string(),
+        OsString::from("--strict-pairing"),
+    ];
+    stitch_args.extend(opts.stitch_args.iter().cloned());
+    // Blocks it could not stitch show up as not rebuilt.
+    stitch_at(scratch, &stitch_args, aoi)?;
+
+    let stitched = scratch.join(STITCHED);
+    let mut report = BTreeMap::new();
+    for (name, chip, label) in sample {
+        let recorded = &descriptor.chips[name];
+        let image = paths::tile_file(stitched.join(TILES), chip, ext);
+        let outline = paths::tile_file(stitched.join(OUTLINES), chip, ".png");
+        let entry = if !image.is_file() || !outline.is_file() {
+            ChipReport {
+                verdict: Verdict::NotRebuilt,
+                image_identical: false,
+                label_differs: None,
+            }
+        } else {
+            let image_identical = release::hash_file(&image)? == recorded.image;
+            let (verdict, label_differs) = if release::hash_file(&outline)? == recorded.label {
+                (Verdict::Identical, None)
+            } else if opts.tolerance > 0.0 && label.is_file() {
+                let share = label_difference(&label, &outline)?;
+                match share <= opts.tolerance {
+                    true => (Verdict::WithinTolerance, Some(share)),
+                    false => (Verdict::Differs, Some(share)),
+                }
+            } else {
+                (Verdict::Differs, None)
+            };
+            let verdict = match image_identical {
+                true => verdict,
+                false => Verdict::Differs,
+            };
+            ChipReport {
+                verdict,
+                image_identical,
+                label_differs,
+            }
+        };
+        logging::status!(
+            "  {name}: {}{}",
+            serde_json::to_value(&entry.verdict)?
+                .as_str()
+                .unwrap_or_default(),
+            match (&entry.label_differs, entry.image_identical) {
+                (Some(share), _) => format!(", {:.4}% of the label differs", share * 100.0),
+                (None, false) if !matches!(entry.verdict, Verdict::NotRebuilt) => {
+                    ", the imagery differs".to_owned()
+                }
+                _ => String::new(),
+            }
+        );
+        report.insert(name.clone(), entry);
+    }
+    let path = scratch.join("report.json");
+    std::fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+
+    let failed = report
+        .values()
+        .filter(|r| matches!(r.verdict, Verdict::Differs | Verdict::NotRebuilt))
+        .count();
+    anyhow::ensure!(
+        failed == 0,
+        "{failed} of {} chips could not be regenerated as released, see {}\n\
+         hint: pass the render-outlines and --stitch-arg arguments the release was made with",
+        report.len(),
+        path.display()
+    );
+    logging::status!(
+        "All {} chips regenerated as released, see {}",
+        report.len(),
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Building ways whose ends do not meet. Mappers leave a few centimeters
+//! between the first and last node, or end a way on a duplicate of the
+//! first node; such ways are closed here. Ways whose ends are farther
+//! apart are not footprints and are reported instead of being filled as
+//! whatever polygon their nodes happen to make.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use geo::{HaversineDistance, Point};
+use osmpbfreader::Way;
+use serde::Serialize;
+
+use crate::{logging, GeoCoordinate};
+
+const RINGS_FILE: &str = "rings.json";
+
+#[derive(clap::Args, Clone, Debug, Serialize)]
+pub struct RingOptions {
+    /// Close building ways whose first and last node are at most this many
+    /// meters apart. Ways with a wider gap are skipped and listed in
+    /// `rings.json`.
+    #[arg(long, value_name = "M", default_value_t = 0.5)]
+    pub close_tolerance: f64,
+}
+
+/// Ways closed or skipped over a run, by id so that a way drawn in several
+/// work units is counted once.
+#[derive(Debug, Default, Serialize)]
+pub struct RingStats {
+    pub auto_closed: BTreeSet<i64>,
+    /// Gap between the ends in meters.
+    pub open: BTreeMap<i64, f64>,
+}
+
+#[derive(Serialize)]
+struct RingRecord<'a> {
+    options: &'a RingOptions,
+    stats: &'a RingStats,
+}
+
+/// The ring of a building way, closed if its ends are within the
+/// tolerance, `None` if it is open.
+pub fn close(
+    opts: &RingOptions,
+    stats: &mut RingStats,
+    way: &Way,
+    mut coords: Vec<GeoCoordinate>,
+) -> Option<Vec<GeoCoordinate>> {
+    if way.nodes.first() == way.nodes.last() {
+        return Some(coords);
+    }
+    let (first, last) = (coords[0], coords[coords.len() - 1]);
+    let gap_m = Point::new(first.longitude, first.latitude)
+        .haversine_distance(&Point::new(last.longitude, last.latitude));
+    if gap_m > opts.close_tolerance {
+        stats.open.insert(way.id.0, gap_m);
+        return None;
+    }
+    // Either the last node duplicates the first or is next to it; a
+    // duplicate is dropped rather than kept as a zero-length edge.
+    if gap_m == 0.0 {
+        coords.pop();
+    }
+    coords.push(first);
+    stats.auto_closed.insert(way.id.0);
+    Some(coords)
+}
+
+impl RingStats {
+    pub fn report(&self, opts: &RingOptions) {
+        logging::status!(
+            "Rings: {} ways closed within {} m, {} open ways skipped",
+            self.auto_closed.len(),
+            opts.close_tolerance,
+            self.open.len()
+        );
+    }
+}
+
+pub fn save(opts: &RingOptions, stats: &RingStats, dir: &Path) -> anyhow::Result<()> {
+    let record = RingRecord {
+        options: opts,
+        stats,
+    };
+    std::fs::write(dir.join(RINGS_FILE), serde_json::to_vec_pretty(&record)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmpbfreader::{NodeId, Tags, WayId};
+
+    const OPTS: RingOptions = RingOptions {
+        close_tolerance: 0.5,
+    };
+
+    fn way(nodes: &[i64]) -> Way {
+        Way {
+            id: WayId(1),
+            tags: Tags::new(),
+            nodes: nodes.iter().map(|&n| NodeId(n)).collect(),
+        }
+    }
+
+    /// Three corners of a building, and a last node `gap_m` meters east
+    /// of the first.
+    fn coords(gap_m: f64) -> Vec<GeoCoordinate> {
+        let at = |latitude, longitude| GeoCoordinate {
+            latitude,
+            longitude,
+        };
+        let dlon = gap_m / (111_320.0 * 55.0_f64.to_radians().cos());
+        vec![
+            at(55.0, 37.0),
+            at(55.0, 37.001),
+            at(55.001, 37.001),
+            at(55.0, 37.0 + dlon),
+        ]
+    }
+
+    #[test]
+    fn closed_way_is_kept_as_it_is() {
+        let mut stats = RingStats::default();
+        let ring = coords(0.0);
+        assert_eq!(
+            close(&OPTS, &mut stats, &way(&[1, 2, 3, 1]), ring.clone()),
+            Some(ring)
+        );
+        assert!(stats.auto_closed.is_empty() && stats.open.is_empty());
+    }
+
+    #[test]
+    fn duplicate_last_node_is_replaced_by_the_first() {
+        let mut stats = RingStats::default();
+        let ring = close(&OPTS, &mut stats, &way(&[1, 2, 3, 4]), coords(0.0)).unwrap();
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring[3], ring[0]);
+        assert!(stats.auto_closed.contains(&1));
+    }
+
+    #[test]
+    fn gap_within_the_tolerance_is_closed() {
+        let mut stats = RingStats::default();
+        let ring = close(&OPTS, &mut stats, &way(&[1, 2, 3, 4]), coords(0.3)).unwrap();
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[3], coords(0.3)[3]);
+        assert_eq!(ring[4], ring[0]);
+        assert!(stats.auto_closed.contains(&1));
+    }
+
+    #[test]
+    fn gap_past_the_tolerance_is_left_open() {
+        let mut stats = RingStats::default();
+        assert_eq!(
+            close(&OPTS, &mut stats, &way(&[1, 2, 3, 4]), coords(2.0)),
+            None
+        );
+        assert!(stats.auto_closed.is_empty());
+        let gap = stats.open[&1];
+        assert!((gap - 2.0).abs() < 0.01, "{gap}");
+    }
+}
@@ -0,0 +1,32 @@
+//! Small deterministic random numbers for the noise and augmentation
+//! modes.
+
+/// splitmix64, good enough for sampling and needs no dependency.
+pub struct Rng(u64);
+
+impl Rng {
+    /// A stream for one object, derived from the run seed and its id.
+    pub fn new(seed: u64, id: i64) -> Self {
+        let mut rng = Self(seed ^ (id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `0..n`, `n` must be positive.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
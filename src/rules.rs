@@ -0,0 +1,377 @@
+//! User-defined taxonomies. `--class-rules FILE` replaces the built-in
+//! mapping from tags to classes with an ordered list of rules, each a tag
+//! expression and the class an object matching it is drawn as; the first
+//! rule that matches wins and objects no rule matches are left out:
+//!
+//! ```toml
+//! [[rule]]
+//! when = "building=* AND NOT (building=roof OR building=carport)"
+//! class = "Normal"
+//!
+//! [[rule]]
+//! when = "landuse=industrial OR landuse=railway"
+//! class = 15
+//! color = [90, 90, 140]
+//! ```
+//!
+//! A class is named as in `masks/classes.json` or given by its index, and
+//! `color` changes the color it is drawn with in the outlines. Terms are
+//! `key=value`, `key!=value`, `key=*` or just `key`, combined with `AND`,
+//! `OR`, `NOT` and parentheses; keys and values cannot hold spaces.
+//! Whether an object is drawn as a building, with the small building
+//! threshold, still follows its `building` tag, and its shape its tags.
+
+use std::{path::Path, sync::OnceLock};
+
+use osmpbfreader::Tags;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::classes::{self, FeatureClass, ALL_CLASSES, COLOR_INDEX};
+
+static RULES: OnceLock<Rules> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    /// `key=value`, or any value for `None`.
+    Tag(String, Option<String>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn matches(&self, tags: &Tags) -> bool {
+        match self {
+            Expr::Tag(key, None) => tags.contains_key(key.as_str()),
+            Expr::Tag(key, Some(value)) => tags.contains(key, value),
+            Expr::Not(e) => !e.matches(tags),
+            Expr::And(a, b) => a.matches(tags) && b.matches(tags),
+            Expr::Or(a, b) => a.matches(tags) || b.matches(tags),
+        }
+    }
+}
+
+/// A recursive descent parser over the tokens of an expression, with
+/// `NOT` binding tighter than `AND`, and `AND` tighter than `OR`.
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut tokens = vec![];
+        for word in text.split_whitespace() {
+            let mut rest = word;
+            while let Some(i) = rest.find(['(', ')']) {
+                if i > 0 {
+                    tokens.push(&rest[..i]);
+                }
+                tokens.push(&rest[i..i + 1]);
+                rest = &rest[i + 1..];
+            }
+            if !rest.is_empty() {
+                tokens.push(rest);
+            }
+        }
+        Self { tokens, at: 0 }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.at += 1;
+        token
+    }
+
+    fn parse(mut self) -> Result<Expr, String> {
+        let expr = self.or()?;
+        match self.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {token:?}")),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some("OR") {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.peek() == Some("AND") {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some("NOT") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            None => Err("the expression ends early".to_owned()),
+            Some("(") => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(")") => Ok(expr),
+                    _ => Err("a ( is not closed".to_owned()),
+                }
+            }
+            Some(token @ (")" | "AND" | "OR")) => Err(format!("unexpected {token:?}")),
+            Some(term) => term_expr(term),
+        }
+    }
+}
+
+fn term_expr(term: &str) -> Result<Expr, String> {
+    let tag = |key: &str, value: &str| {
+        if key.is_empty() {
+            return Err(format!("{term:?} has no key"));
+        }
+        let value = (value != "*").then(|| value.to_owned());
+        Ok(Expr::Tag(key.to_owned(), value))
+    };
+    if let Some((key, value)) = term.split_once("!=") {
+        return Ok(Expr::Not(Box::new(tag(key, value)?)));
+    }
+    match term.split_once('=') {
+        Some((key, value)) => tag(key, value),
+        None => tag(term, "*"),
+    }
+}
+
+/// A class as a rule names it, by name or index.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClassRef {
+    Index(usize),
+    Name(String),
+}
+
+impl ClassRef {
+    fn resolve(&self) -> Result<FeatureClass, String> {
+        let found = match self {
+            ClassRef::Index(i) => ALL_CLASSES.get(*i).copied(),
+            ClassRef::Name(name) => ALL_CLASSES
+                .iter()
+                .copied()
+                .find(|c| format!("{c:?}") == *name),
+        };
+        found.ok_or_else(|| {
+            let names: Vec<_> = ALL_CLASSES.iter().map(|c| format!("{c:?}")).collect();
+            format!(
+                "no class {}, known are {} or their indices 0 to {}",
+                match self {
+                    ClassRef::Index(i) => i.to_string(),
+                    ClassRef::Name(name) => name.clone(),
+                },
+                names.join(", "),
+                ALL_CLASSES.len() - 1
+            )
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    when: String,
+    class: ClassRef,
+    #[serde(default)]
+    color: Option<[u8; 3]>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(rename = "rule")]
+    rules: Vec<RuleSpec>,
+}
+
+struct Rule {
+    when: Expr,
+    class: FeatureClass,
+}
+
+struct Rules {
+    rules: Vec<Rule>,
+    /// First 8 bytes of the SHA-256 of the file, for the PBF checkpoint.
+    fingerprint: u64,
+}
+
+/// Reads the rules of `--class-rules` for the rest of the run, setting
+/// the colors they give.
+pub fn load(path: Option<&Path>) -> anyhow::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|why| anyhow::anyhow!("cannot read {}: {why}", path.display()))?;
+    let file: RulesFile = toml::from_str(&text).map_err(|why| {
+        anyhow::anyhow!(
+            "{} is not a valid rules file: {why}\n\
+             hint: it is a list of [[rule]] tables with `when` and `class`",
+            path.display()
+        )
+    })?;
+    anyhow::ensure!(!file.rules.is_empty(), "{} has no rules", path.display());
+    let mut colors = COLOR_INDEX.to_vec();
+    let mut recolored: Vec<Option<[u8; 3]>> = vec![None; colors.len()];
+    let mut rules = vec![];
+    for (i, spec) in file.rules.iter().enumerate() {
+        let fail = |why: String| {
+            anyhow::anyhow!("{}: rule {} ({}): {why}", path.display(), i + 1, spec.when)
+        };
+        let when = Parser::new(&spec.when).parse().map_err(fail)?;
+        let class = spec.class.resolve().map_err(fail)?;
+        if class == FeatureClass::NoLabel {
+            return Err(fail(
+                "NoLabel is reserved for the tiles the stitcher has no outline of".into(),
+            ));
+        }
+        if let Some(color) = spec.color {
+            match recolored[class as usize] {
+                Some(earlier) if earlier != color => {
+                    return Err(fail(format!(
+                        "{class:?} is colored {earlier:?} by an earlier rule"
+                    )))
+                }
+                _ => {}
+            }
+            recolored[class as usize] = Some(color);
+            colors[class as usize] = color;
+        }
+        rules.push(Rule { when, class });
+    }
+    for (i, color) in colors.iter().enumerate() {
+        if let Some(j) = colors[..i].iter().position(|c| c == color) {
+            anyhow::bail!(
+                "{}: {:?} and {:?} would both be drawn {color:?}\n\
+                 hint: give every class its own color",
+                path.display(),
+                ALL_CLASSES[j],
+                ALL_CLASSES[i]
+            );
+        }
+    }
+    classes::set_colors(colors);
+    let digest = Sha256::digest(text.as_bytes());
+    let fingerprint = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    RULES
+        .set(Rules { rules, fingerprint })
+        .ok()
+        .expect("class rules loaded twice");
+    Ok(())
+}
+
+/// Whether `--class-rules` decide the classes.
+pub fn active() -> bool {
+    RULES.get().is_some()
+}
+
+/// The class of the first rule `tags` match, `None` if none does.
+pub fn class(tags: &Tags) -> Option<FeatureClass> {
+    let rules = RULES.get()?;
+    rules
+        .rules
+        .iter()
+        .find(|r| r.when.matches(tags))
+        .map(|r| r.class)
+}
+
+/// Identifies the rules, 0 without any.
+pub fn fingerprint() -> u64 {
+    RULES.get().map_or(0, |r| r.fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Expr, String> {
+        Parser::new(text).parse()
+    }
+
+    fn tag(key: &str, value: Option<&str>) -> Box<Expr> {
+        Box::new(Expr::Tag(key.to_owned(), value.map(str::to_owned)))
+    }
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        pairs.iter().map(|&(k, v)| (k.into(), v.into())).collect()
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_than_or() {
+        let expr = parse("a OR NOT b AND c").unwrap();
+        let expected = Expr::Or(
+            tag("a", None),
+            Box::new(Expr::And(
+                Box::new(Expr::Not(tag("b", None))),
+                tag("c", None),
+            )),
+        );
+        assert_eq!(expr, expected);
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        let expr = parse("(a OR b) AND NOT(c)").unwrap();
+        let expected = Expr::And(
+            Box::new(Expr::Or(tag("a", None), tag("b", None))),
+            Box::new(Expr::Not(tag("c", None))),
+        );
+        assert_eq!(expr, expected);
+    }
+
+    #[test]
+    fn terms() {
+        assert_eq!(
+            parse("building=yes").unwrap(),
+            *tag("building", Some("yes"))
+        );
+        assert_eq!(parse("building=*").unwrap(), *tag("building", None));
+        assert_eq!(parse("building").unwrap(), *tag("building", None));
+        assert_eq!(
+            parse("building!=roof").unwrap(),
+            Expr::Not(tag("building", Some("roof")))
+        );
+    }
+
+    #[test]
+    fn not_equal_matches_objects_without_the_key() {
+        let expr = parse("building!=roof").unwrap();
+        assert!(expr.matches(&tags(&[("building", "yes")])));
+        assert!(!expr.matches(&tags(&[("building", "roof")])));
+        assert!(expr.matches(&tags(&[])));
+    }
+
+    #[test]
+    fn wildcard_matches_any_value() {
+        let expr = parse("building=* AND NOT (building=roof OR building=carport)").unwrap();
+        assert!(expr.matches(&tags(&[("building", "house")])));
+        assert!(!expr.matches(&tags(&[("building", "carport")])));
+        assert!(!expr.matches(&tags(&[("landuse", "industrial")])));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in ["", "a AND", "(a OR b", "a b", "a )", "OR a", "=yes", "NOT"] {
+            assert!(parse(text).is_err(), "{text:?} parsed");
+        }
+    }
+}
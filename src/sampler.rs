@@ -0,0 +1,275 @@
+//! Random training windows drawn on the fly with `render_window`, for
+//! training straight from the tiles and the extract instead of from
+//! exported chips. Windows are centered in random downloaded tiles, and
+//! `--min-share` rejects windows with too little of a class until one
+//! qualifies, so that rare classes are seen often enough.
+
+use std::{fmt, io::Write, path::PathBuf, str::FromStr};
+
+use image::RgbImage;
+use serde::Serialize;
+use slippy_map_tiles::{BBox, Tile};
+
+use crate::{
+    checks,
+    classes::{self, colors, FeatureClass, ALL_CLASSES},
+    list_tiles, logging,
+    region::{self, Labels},
+    rings,
+    rng::Rng,
+    workspace::TILES,
+};
+
+/// `CLASS=SHARE`: at least this share of the pixels of a window are of
+/// the class, by the `FeatureClass` name, or of any building class for
+/// `buildings`.
+#[derive(Clone, Copy, Debug)]
+pub struct ClassShare {
+    class: Option<FeatureClass>,
+    share: f64,
+}
+
+impl FromStr for ClassShare {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, share)) = s.split_once('=') else {
+            return Err(format!("{s:?} is not CLASS=SHARE, e.g. buildings=0.05"));
+        };
+        let share: f64 = share
+            .parse()
+            .map_err(|why| format!("share {share:?}: {why}"))?;
+        if !(0.0..=1.0).contains(&share) {
+            return Err(format!("share {share} is not between 0 and 1"));
+        }
+        if name.eq_ignore_ascii_case("buildings") {
+            return Ok(Self { class: None, share });
+        }
+        let class = ALL_CLASSES
+            .iter()
+            .find(|c| format!("{c:?}").eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let names: Vec<_> = ALL_CLASSES.iter().map(|c| format!("{c:?}")).collect();
+                format!(
+                    "no class {name:?}, expected buildings or one of {}",
+                    names.join(", ")
+                )
+            })?;
+        Ok(Self {
+            class: Some(*class),
+            share,
+        })
+    }
+}
+
+impl fmt::Display for ClassShare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.class {
+            Some(class) => write!(f, "{class:?}={}", self.share),
+            None => write!(f, "buildings={}", self.share),
+        }
+    }
+}
+
+impl ClassShare {
+    fn met(&self, mask: &RgbImage) -> bool {
+        let matches = |px: [u8; 3]| match self.class {
+            Some(class) => px == colors()[class as usize],
+            None => classes::is_building_pixel(px),
+        };
+        let count = mask.pixels().filter(|p| matches(p.0)).count();
+        count as f64 >= self.share * (mask.width() * mask.height()) as f64
+    }
+}
+
+#[derive(clap::Args)]
+pub struct SamplerOptions {
+    /// Side of the windows, pixels.
+    #[arg(long, default_value_t = 512)]
+    pub window_px: u32,
+    /// Ground sample distance, meters per pixel.
+    #[arg(long, default_value_t = 1.0)]
+    pub gsd: f64,
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+    /// Only keep windows with at least this share of pixels of a class,
+    /// e.g. `buildings=0.05` or `Greenhouse=0.01`. May be repeated; all
+    /// have to hold.
+    #[arg(long = "min-share", value_name = "CLASS=SHARE")]
+    pub min_shares: Vec<ClassShare>,
+    /// Share of windows kept without checking `--min-share`, so that the
+    /// background is seen too.
+    #[arg(long, default_value_t = 0.0)]
+    pub background_share: f64,
+    /// Windows tried for one that meets `--min-share` before giving up.
+    #[arg(long, default_value_t = 100)]
+    pub max_attempts: u32,
+}
+
+/// A window and where it lies.
+pub struct Sample {
+    pub bbox: BBox,
+    pub image: RgbImage,
+    pub mask: RgbImage,
+    /// Windows drawn for this one, rejected ones included.
+    pub attempts: u32,
+}
+
+/// Draws seeded random windows. The same seed over the same tiles and
+/// extract gives the same windows.
+pub struct Sampler<'a> {
+    labels: &'a Labels,
+    opts: &'a SamplerOptions,
+    tiles: Vec<Tile>,
+}
+
+impl<'a> Sampler<'a> {
+    pub fn new(labels: &'a Labels, opts: &'a SamplerOptions) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            opts.gsd > 0.0 && opts.gsd.is_finite(),
+            "--gsd must be a positive number of meters per pixel"
+        );
+        anyhow::ensure!(opts.window_px > 0, "--window-px must be positive");
+        let mut tiles = list_tiles(TILES, ".jpg");
+        anyhow::ensure!(
+            !tiles.is_empty(),
+            "there are no tiles to sample windows from\n\
+             hint: download some with download-tiles first"
+        );
+        // Listing order depends on the file system.
+        tiles.sort_by_key(|t| (t.y(), t.x()));
+        Ok(Self {
+            labels,
+            opts,
+            tiles,
+        })
+    }
+
+    /// An endless stream of windows.
+    pub fn iter(&self) -> Samples<'_> {
+        Samples {
+            sampler: self,
+            rng: Rng::new(self.opts.seed, 0),
+        }
+    }
+
+    /// A window centered at a random point of a random tile.
+    fn draw(&self, rng: &mut Rng) -> anyhow::Result<BBox> {
+        let tile = self.tiles[rng.below(self.tiles.len())];
+        let (fx, fy) = (rng.next_f64(), rng.next_f64());
+        let at = mercator::within(tile, fx, fy);
+        let (lat, lon) = (at.latitude, at.longitude);
+        let half_m = self.opts.window_px as f64 * self.opts.gsd / 2.0;
+        let dlat = half_m / 111_320.0;
+        let dlon = dlat / lat.to_radians().cos();
+        checks::bbox(
+            (lat + dlat) as f32,
+            (lon - dlon) as f32,
+            (lat - dlat) as f32,
+            (lon + dlon) as f32,
+        )
+    }
+
+    /// Window number `index` of the seed, independent of the others.
+    pub fn sample_at(&self, index: u64) -> anyhow::Result<Sample> {
+        self.sample(&mut Rng::new(self.opts.seed, index as i64 + 1))
+    }
+
+    fn sample(&self, rng: &mut Rng) -> anyhow::Result<Sample> {
+        let size = (self.opts.window_px, self.opts.window_px);
+        let unchecked = rng.next_f64() < self.opts.background_share;
+        for attempts in 1..=self.opts.max_attempts.max(1) {
+            let bbox = self.draw(rng)?;
+            let (image, mask) = region::render_window(self.labels, &bbox, size);
+            if unchecked || self.opts.min_shares.iter().all(|s| s.met(&mask)) {
+                return Ok(Sample {
+                    bbox,
+                    image,
+                    mask,
+                    attempts,
+                });
+            }
+        }
+        let shares: Vec<_> = self.opts.min_shares.iter().map(|s| s.to_string()).collect();
+        anyhow::bail!(
+            "none of {} windows had {}\n\
+             hint: lower --min-share, or raise --max-attempts",
+            self.opts.max_attempts,
+            shares.join(" and ")
+        )
+    }
+}
+
+pub struct Samples<'a> {
+    sampler: &'a Sampler<'a>,
+    rng: Rng,
+}
+
+impl Iterator for Samples<'_> {
+    type Item = anyhow::Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.sampler.sample(&mut self.rng))
+    }
+}
+
+#[derive(clap::Args)]
+pub struct SampleOptions {
+    /// Number of windows to write.
+    #[arg(long, default_value_t = 100)]
+    pub count: usize,
+    /// Directory to write `images/`, `masks/` and `windows.jsonl` into.
+    #[arg(long, default_value = "windows")]
+    pub out: PathBuf,
+    /// Also draw building relations, with their inner rings as holes, in
+    /// place of their member ways.
+    #[arg(long)]
+    pub relations: bool,
+    #[command(flatten)]
+    pub sampler: SamplerOptions,
+    #[command(flatten)]
+    pub classes: classes::ClassOptions,
+    #[command(flatten)]
+    pub rings: rings::RingOptions,
+}
+
+#[derive(Serialize)]
+struct WindowRecord {
+    name: String,
+    /// `[top, left, bottom, right]` in degrees.
+    bbox: [f32; 4],
+    attempts: u32,
+}
+
+/// Writes `--count` windows of the sampler, as a sample of what training
+/// would see.
+pub fn export_windows(labels: &Labels, opts: &SampleOptions) -> anyhow::Result<()> {
+    let (images, masks) = (opts.out.join("images"), opts.out.join("masks"));
+    std::fs::create_dir_all(&images)?;
+    std::fs::create_dir_all(&masks)?;
+    let mut index = std::io::BufWriter::new(std::fs::File::create(opts.out.join("windows.jsonl"))?);
+    let sampler = Sampler::new(labels, &opts.sampler)?;
+    let mut attempts = 0;
+    for (i, sample) in sampler.iter().take(opts.count).enumerate() {
+        let sample = sample?;
+        let name = format!("{i:05}");
+        sample.image.save(images.join(format!("{name}.png")))?;
+        sample.mask.save(masks.join(format!("{name}.png")))?;
+        let b = &sample.bbox;
+        let record = WindowRecord {
+            name,
+            bbox: [b.top(), b.left(), b.bottom(), b.right()],
+            attempts: sample.attempts,
+        };
+        serde_json::to_writer(&mut index, &record)?;
+        writeln!(index)?;
+        attempts += sample.attempts;
+    }
+    index.flush()?;
+    logging::status!(
+        "Wrote {} windows to {}, drawing {attempts} in all",
+        opts.count,
+        opts.out.display()
+    );
+    Ok(())
+}
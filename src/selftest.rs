@@ -0,0 +1,119 @@
+//! A quick check of a machine and provider before a long run: downloads one
+//! known tile, checks that it decodes at the expected size, and renders
+//! known polygons the way outlines are drawn.
+
+use std::process::ExitCode;
+
+use image::RgbImage;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    classes::{colors, FeatureClass},
+    download_image, fill_with_holes, provider, zoom, GeoCoordinate, ImageCache,
+};
+
+/// Red Square, which every provider covers.
+const KNOWN_POINT: (f64, f64) = (55.7539, 37.6208);
+/// Side of the tiles every provider in use sends.
+const EXPECTED_PX: u32 = 256;
+
+/// Runs the checks, printing one line per check, and fails if any did.
+pub fn selftest() -> anyhow::Result<ExitCode> {
+    let (x, y) = mercator::tile_at(KNOWN_POINT.0, KNOWN_POINT.1, zoom());
+    let tile = Tile::new(zoom(), x, y).unwrap();
+    let source = provider::source();
+    println!("Provider {}: {}", source.name, source.url(tile, true));
+
+    let mut passed = true;
+    let mut report = |check: &str, result: anyhow::Result<String>| match result {
+        Ok(detail) => println!("PASS {check}: {detail}"),
+        Err(why) => {
+            println!("FAIL {check}: {why:#}");
+            passed = false;
+        }
+    };
+
+    let image = download_image(tile);
+    let size = image.as_ref().map(|i| (i.width(), i.height()));
+    report(
+        "download",
+        image
+            .as_ref()
+            .map(|_| "decoded".to_string())
+            .map_err(|why| anyhow::anyhow!("{why:#}")),
+    );
+    if let Ok((w, h)) = size {
+        report(
+            "size",
+            match (w, h) == (EXPECTED_PX, EXPECTED_PX) {
+                true => Ok(format!("{w}x{h}")),
+                false => Err(anyhow::anyhow!(
+                    "{w}x{h}, expected {EXPECTED_PX}x{EXPECTED_PX}\n\
+                     hint: stitching assumes square tiles of one size"
+                )),
+            },
+        );
+    }
+    report("render", render_known_polygon(tile));
+    report("spanning", render_spanning_polygon(tile));
+
+    println!(
+        "{}",
+        if passed {
+            "All checks passed"
+        } else {
+            "Some checks failed"
+        }
+    );
+    Ok(match passed {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// Draws a square over the middle half of `tile` with a hole over its
+/// middle half, and compares the pixels drawn with its area.
+fn render_known_polygon(tile: Tile) -> anyhow::Result<String> {
+    let at = |fx: f64, fy: f64| GeoCoordinate::from(mercator::within(tile, fx, fy));
+    let ring = |a: f64, b: f64| vec![at(a, a), at(b, a), at(b, b), at(a, b), at(a, a)];
+    let mut img = RgbImage::new(EXPECTED_PX, EXPECTED_PX);
+    let (outer, holes) = ImageCache::tile_relative_area(
+        tile,
+        img.dimensions(),
+        &ring(0.25, 0.75),
+        &[ring(0.375, 0.625)],
+    );
+    let color = colors()[FeatureClass::Normal as usize];
+    fill_with_holes(&mut img, &outer, &holes, image::Rgb(color));
+
+    let drawn = img.pixels().filter(|px| px.0 == color).count();
+    let side = EXPECTED_PX as f64;
+    let expected = (side / 2.0).powi(2) - (side / 4.0).powi(2);
+    let error = (drawn as f64 - expected).abs() / expected;
+    let center = img.get_pixel(EXPECTED_PX / 2, EXPECTED_PX / 2).0;
+    anyhow::ensure!(
+        error < 0.05,
+        "drew {drawn} px, expected about {expected:.0}"
+    );
+    anyhow::ensure!(center != color, "the hole was filled");
+    Ok(format!("{drawn} px drawn, expected about {expected:.0}"))
+}
+
+/// Draws a square around `tile` with no vertex in it, as of a building
+/// larger than a tile, which has to cover the whole tile.
+fn render_spanning_polygon(tile: Tile) -> anyhow::Result<String> {
+    let at = |fx: f64, fy: f64| GeoCoordinate::from(mercator::within(tile, fx, fy));
+    let ring = vec![at(-0.5, -0.5), at(1.5, -0.5), at(1.5, 1.5), at(-0.5, 1.5)];
+    let tiles = ImageCache::polygon_tiles(&ring);
+    anyhow::ensure!(
+        tiles.contains(&tile),
+        "the tile inside the polygon is not drawn into"
+    );
+    let mut img = RgbImage::new(EXPECTED_PX, EXPECTED_PX);
+    let (outer, holes) = ImageCache::tile_relative_area(tile, img.dimensions(), &ring, &[]);
+    let color = colors()[FeatureClass::Normal as usize];
+    fill_with_holes(&mut img, &outer, &holes, image::Rgb(color));
+    let missed = img.pixels().filter(|px| px.0 != color).count();
+    anyhow::ensure!(missed == 0, "{missed} px of the tile were left out");
+    Ok(format!("{} tiles drawn into", tiles.len()))
+}
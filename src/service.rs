@@ -0,0 +1,442 @@
+//! A long-running HTTP service handing windows to training jobs, so that
+//! several of them can pull data from one host that holds the tiles and
+//! the extract in memory:
+//!
+//! - `GET /window?bbox=TOP,LEFT,BOTTOM,RIGHT&gsd=1` renders a window with
+//!   `render_region`,
+//! - `GET /chip?index=N` is window `N` of the seeded sampler, the same for
+//!   every client and every run over the same data,
+//! - `GET /stats` tells what the service holds and has served,
+//! - `GET /metrics` is the same and more for Prometheus, see `metrics`.
+//!
+//! Windows come as a tar of `image.png`, `mask.png` and `window.json`, as
+//! a WebDataset loader reads them, or as one PNG with `layer=image` or
+//! `layer=mask`. Rendered windows are cached, and the ones likely asked
+//! for next are rendered ahead, see `prefetch`.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+    checks, classes, list_tiles, logging,
+    metrics::{self, Exposition},
+    prefetch::{self, Cache, Key, Prefetcher, Rendered},
+    region::{self, Labels},
+    rings,
+    sampler::{Sampler, SamplerOptions},
+    store, webdataset,
+    workspace::TILES,
+};
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request line and headers read; a `GET` needs little.
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+#[derive(clap::Args)]
+pub struct ServeOptions {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+    /// Requests handled at once.
+    #[arg(long, default_value_t = 8)]
+    pub threads: usize,
+    /// Largest window `/window` renders, in pixels. A window takes about
+    /// 6 bytes a pixel while rendered, on each of `--threads`.
+    #[arg(long, default_value_t = 1 << 22)]
+    pub max_window_px: u64,
+    /// Also draw building relations, with their inner rings as holes, in
+    /// place of their member ways.
+    #[arg(long)]
+    pub relations: bool,
+    /// How `/chip` samples windows; `/window` takes its ground resolution
+    /// as the default.
+    #[command(flatten)]
+    pub sampler: SamplerOptions,
+    #[command(flatten)]
+    pub prefetch: prefetch::PrefetchOptions,
+    #[command(flatten)]
+    pub classes: classes::ClassOptions,
+    #[command(flatten)]
+    pub rings: rings::RingOptions,
+}
+
+/// A failed request, answered with its status and message.
+struct Failure(u16, String);
+
+impl From<anyhow::Error> for Failure {
+    fn from(why: anyhow::Error) -> Self {
+        Self(500, format!("{why:#}"))
+    }
+}
+
+fn bad_request(message: impl Into<String>) -> Failure {
+    Failure(400, message.into())
+}
+
+#[derive(Default, Serialize)]
+struct Counters {
+    windows: AtomicU64,
+    chips: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Serialize)]
+struct Stats<'a> {
+    tiles: usize,
+    window_px: u32,
+    gsd: f64,
+    seed: u64,
+    uptime_s: u64,
+    served: &'a Counters,
+    cache: prefetch::CacheStats<'a>,
+}
+
+#[derive(Serialize)]
+struct WindowInfo {
+    /// `[top, left, bottom, right]` in degrees.
+    bbox: [f32; 4],
+    width: u32,
+    height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempts: Option<u32>,
+}
+
+struct Service<'a> {
+    labels: &'a Labels,
+    sampler: Sampler<'a>,
+    opts: &'a ServeOptions,
+    tiles: usize,
+    started: Instant,
+    served: Counters,
+    cache: Cache,
+    prefetcher: Prefetcher,
+}
+
+/// Serves windows of `labels` until the process is stopped.
+pub fn serve(labels: &Labels, opts: &ServeOptions) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&opts.listen).map_err(|why| {
+        anyhow::anyhow!(
+            "cannot listen on {}: {why}\n\
+             hint: pick another address with --listen",
+            opts.listen
+        )
+    })?;
+    let service = Service {
+        labels,
+        sampler: Sampler::new(labels, &opts.sampler)?,
+        opts,
+        tiles: list_tiles(TILES, ".jpg").len(),
+        started: Instant::now(),
+        served: Counters::default(),
+        cache: Cache::new(&opts.prefetch),
+        prefetcher: Prefetcher::new(&opts.prefetch),
+    };
+    logging::status!("Serving windows on http://{}", listener.local_addr()?);
+    std::thread::scope(|s| {
+        if opts.prefetch.prefetch > 0 {
+            for _ in 0..opts.prefetch.prefetch_threads {
+                s.spawn(|| {
+                    service
+                        .prefetcher
+                        .run(&service.cache, |key| service.render(key))
+                });
+            }
+        }
+        for _ in 0..opts.threads.max(1) {
+            s.spawn(|| loop {
+                match listener.accept() {
+                    Ok((stream, _)) => service.handle(stream),
+                    Err(why) => warn!("error accepting a connection: {why}"),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+impl Service<'_> {
+    fn handle(&self, mut stream: TcpStream) {
+        let target = match read_request(&stream) {
+            Ok(target) => target,
+            Err(why) => {
+                info!("error reading a request: {why}");
+                return;
+            }
+        };
+        info!("GET {target}");
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let query = parse_query(query);
+        // A panic fails the request, not the thread serving it.
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| match path {
+            "/window" => self.window(&query),
+            "/chip" => self.chip(&query),
+            "/stats" => self.stats(),
+            "/metrics" => Ok((metrics::CONTENT_TYPE, self.metrics())),
+            _ => Err(Failure(404, format!("no endpoint {path}"))),
+        }))
+        .unwrap_or_else(|_| Err(Failure(500, format!("internal error serving {path}"))));
+        let written = match result {
+            Ok((content_type, body)) => respond(&mut stream, 200, content_type, &body),
+            Err(Failure(status, message)) => {
+                self.served.failed.fetch_add(1, Ordering::Relaxed);
+                respond(&mut stream, status, "text/plain", message.as_bytes())
+            }
+        };
+        if let Err(why) = written {
+            info!("error answering {target}: {why}");
+        }
+    }
+
+    fn window(&self, query: &HashMap<String, String>) -> Result<(&'static str, Vec<u8>), Failure> {
+        let bbox = query
+            .get("bbox")
+            .ok_or_else(|| bad_request("missing bbox=TOP,LEFT,BOTTOM,RIGHT"))?;
+        let bbox = checks::parse_bbox(bbox).map_err(|why| bad_request(format!("{why:#}")))?;
+        let gsd = match query.get("gsd") {
+            Some(gsd) => gsd
+                .parse()
+                .map_err(|why| bad_request(format!("gsd {gsd:?}: {why}")))?,
+            None => self.opts.sampler.gsd,
+        };
+        region::check_window(&bbox, gsd, self.opts.max_window_px)
+            .map_err(|why| bad_request(format!("{why:#}")))?;
+        let window = self.fetch(Key::window(&bbox, gsd))?;
+        self.served.windows.fetch_add(1, Ordering::Relaxed);
+        encode(query, &window, None)
+    }
+
+    fn chip(&self, query: &HashMap<String, String>) -> Result<(&'static str, Vec<u8>), Failure> {
+        let index = query
+            .get("index")
+            .ok_or_else(|| bad_request("missing index=N"))?;
+        let index: u64 = index
+            .parse()
+            .map_err(|why| bad_request(format!("index {index:?}: {why}")))?;
+        let window = self.fetch(Key::Chip(index))?;
+        self.served.chips.fetch_add(1, Ordering::Relaxed);
+        encode(query, &window, Some(index))
+    }
+
+    /// The window for `key` from the cache, or rendered now, queueing the
+    /// windows likely asked for next either way.
+    fn fetch(&self, key: Key) -> anyhow::Result<Arc<Rendered>> {
+        let window = match self.cache.get(&key) {
+            Some(window) => window,
+            None => {
+                let window = Arc::new(self.render(&key)?);
+                self.cache.insert(key, window.clone(), false);
+                window
+            }
+        };
+        let ahead = self.opts.prefetch.prefetch;
+        if ahead > 0 {
+            self.prefetcher.push(&self.cache, key.neighbours(ahead));
+        }
+        Ok(window)
+    }
+
+    fn render(&self, key: &Key) -> anyhow::Result<Rendered> {
+        let (bbox, image, mask, attempts) = match *key {
+            Key::Chip(index) => {
+                let sample = self.sampler.sample_at(index)?;
+                (
+                    sample.bbox,
+                    sample.image,
+                    sample.mask,
+                    Some(sample.attempts),
+                )
+            }
+            Key::Window { bbox, gsd } => {
+                let [top, left, bottom, right] = bbox.map(f32::from_bits);
+                let bbox = checks::bbox(top, left, bottom, right)?;
+                let gsd = f64::from_bits(gsd);
+                let (image, mask) =
+                    region::render_region(self.labels, &bbox, gsd, self.opts.max_window_px)?;
+                (bbox, image, mask, None)
+            }
+        };
+        Ok(Rendered {
+            bbox,
+            width: image.width(),
+            height: image.height(),
+            attempts,
+            image_png: store::encode_png(&image)?,
+            mask_png: store::encode_png(&mask)?,
+        })
+    }
+
+    fn stats(&self) -> Result<(&'static str, Vec<u8>), Failure> {
+        let stats = Stats {
+            tiles: self.tiles,
+            window_px: self.opts.sampler.window_px,
+            gsd: self.opts.sampler.gsd,
+            seed: self.opts.sampler.seed,
+            uptime_s: self.started.elapsed().as_secs(),
+            served: &self.served,
+            cache: self.cache.stats(),
+        };
+        Ok((
+            "application/json",
+            serde_json::to_vec(&stats).map_err(anyhow::Error::from)?,
+        ))
+    }
+
+    fn metrics(&self) -> Vec<u8> {
+        let mut out = Exposition::default().process();
+        let served = |c: &AtomicU64| c.load(Ordering::Relaxed) as f64;
+        out.labeled(
+            "serve_requests_total",
+            "Windows served, by endpoint.",
+            "endpoint",
+            &[
+                ("window", served(&self.served.windows)),
+                ("chip", served(&self.served.chips)),
+            ],
+        );
+        out.counter(
+            "serve_failures_total",
+            "Requests answered with an error.",
+            self.served.failed.load(Ordering::Relaxed),
+        );
+        self.cache.metrics(&mut out);
+        out.gauge(
+            "prefetch_queue_depth",
+            "Windows waiting to be rendered ahead.",
+            self.prefetcher.depth() as f64,
+        );
+        out.into_body()
+    }
+}
+
+/// The window as the `layer` of the query asks for it.
+fn encode(
+    query: &HashMap<String, String>,
+    window: &Rendered,
+    index: Option<u64>,
+) -> Result<(&'static str, Vec<u8>), Failure> {
+    match query.get("layer").map(String::as_str) {
+        Some("image") => Ok(("image/png", window.image_png.clone())),
+        Some("mask") => Ok(("image/png", window.mask_png.clone())),
+        Some(layer) => Err(bad_request(format!(
+            "no layer {layer:?}, expected image or mask"
+        ))),
+        None => {
+            let b = &window.bbox;
+            let info = WindowInfo {
+                bbox: [b.top(), b.left(), b.bottom(), b.right()],
+                width: window.width,
+                height: window.height,
+                index,
+                attempts: window.attempts,
+            };
+            let mut tar = tar::Builder::new(vec![]);
+            webdataset::append(&mut tar, "image.png", &window.image_png)?;
+            webdataset::append(&mut tar, "mask.png", &window.mask_png)?;
+            let info = serde_json::to_vec(&info).map_err(anyhow::Error::from)?;
+            webdataset::append(&mut tar, "window.json", &info)?;
+            Ok((
+                "application/x-tar",
+                tar.into_inner().map_err(anyhow::Error::from)?,
+            ))
+        }
+    }
+}
+
+/// The target of a `GET` request, after reading its headers, which must
+/// fit in `MAX_REQUEST_BYTES` and come within `READ_TIMEOUT`.
+pub fn read_request(stream: &TcpStream) -> anyhow::Result<String> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_BYTES));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    anyhow::ensure!(line.ends_with('\n'), "request line too long or cut short");
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("malformed request line {line:?}");
+    };
+    anyhow::ensure!(method == "GET", "unsupported method {method}");
+    let target = target.to_owned();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        anyhow::ensure!(line.ends_with('\n'), "headers too long or cut short");
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(target)
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect()
+}
+
+/// Decodes `%2C` and the like; coordinates need nothing else.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
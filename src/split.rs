@@ -0,0 +1,190 @@
+//! Train, validation and test splits of the stitched chips. Splits are
+//! assigned by area, per tile of `--split-zoom`, so that neighbouring
+//! chips, which share buildings along their edges, never end up in
+//! different splits, and drawn from `--seed`, so that the same seed splits
+//! the same area the same way however many chips it has so far. `split`
+//! lists every chip pair under its split, and can lay them out in a folder
+//! per split; `hf-dataset` splits the same way.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use slippy_map_tiles::Tile;
+
+use crate::{
+    formats::Formats,
+    list_tiles, logging, paths,
+    release::link_or_copy,
+    rng::Rng,
+    units::unit_of,
+    workspace::{OUTLINES, STITCHED, TILES},
+    zoom, MAX_ZOOM,
+};
+
+pub const SPLITS: [&str; 3] = ["train", "validation", "test"];
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SplitRatios {
+    /// Share of the area that goes to the validation split.
+    #[arg(long, default_value_t = 0.1)]
+    pub validation: f64,
+    #[arg(long, default_value_t = 0.1)]
+    pub test: f64,
+    /// Splits are assigned per tile of this zoom rather than per chip, so
+    /// that neighbouring chips, which share buildings along their edges,
+    /// never end up in different splits.
+    #[arg(long, default_value_t = 12, value_parser = clap::value_parser!(u8).range(0..=MAX_ZOOM as i64))]
+    pub split_zoom: u8,
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+impl SplitRatios {
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.validation >= 0.0 && self.test >= 0.0 && self.validation + self.test < 1.0,
+            "--validation and --test must leave room for a training split"
+        );
+        anyhow::ensure!(
+            self.split_zoom <= zoom(),
+            "--split-zoom {} is deeper than the tiles, --zoom {}",
+            self.split_zoom,
+            zoom()
+        );
+        Ok(())
+    }
+
+    /// The split of the chip whose top-left tile is `chip`.
+    pub fn split_of(&self, chip: Tile) -> &'static str {
+        let group = unit_of(chip, self.split_zoom);
+        let mut rng = Rng::new(self.seed, ((group.y() as i64) << 32) | group.x() as i64);
+        let r = rng.next_f64();
+        if r < self.validation {
+            "validation"
+        } else if r < self.validation + self.test {
+            "test"
+        } else {
+            "train"
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Folders {
+    /// Hard link the chips, or copy them across filesystems.
+    Link,
+    /// Move the chips out of `--chips`; the stitcher renders them again
+    /// on its next run.
+    Move,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SplitOptions {
+    /// Directory with `tiles/` and `outlines/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    /// Directory of `train.txt`, `validation.txt` and `test.txt`, which
+    /// list the image and mask of a chip per line, tab-separated.
+    #[arg(long, default_value = "splits")]
+    pub out: PathBuf,
+    /// Also lay the chips out in a folder per split in `--out`, as
+    /// `train/tiles/` and `train/outlines/` and so on; the lists then
+    /// name the chips there.
+    #[arg(long, value_enum)]
+    pub folders: Option<Folders>,
+    #[command(flatten)]
+    pub ratios: SplitRatios,
+}
+
+/// `path` as the lists have it, `/`-separated.
+fn listed(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Assigns the chip pairs of `opts.chips` to splits and writes the lists,
+/// and the folders if asked for.
+pub fn split_chips(opts: &SplitOptions) -> anyhow::Result<()> {
+    opts.ratios.check()?;
+    let ext = Formats::load()?.chips.ext();
+    let mut names = list_tiles(opts.chips.join(TILES), ext);
+    names.sort_by_key(|t| (t.y(), t.x()));
+
+    let folders: Vec<_> = SPLITS.iter().map(|s| opts.out.join(s)).collect();
+    match opts.folders {
+        // Generated as a whole; a split that got smaller must not keep
+        // the chips it lost.
+        Some(Folders::Link) => {
+            for dir in folders.iter().filter(|d| d.exists()) {
+                std::fs::remove_dir_all(dir)?;
+            }
+        }
+        // What a run moved is nowhere else, so it is never removed.
+        Some(Folders::Move) => {
+            if let Some(dir) = folders.iter().find(|d| d.exists()) {
+                anyhow::bail!(
+                    "{} exists, with chips an earlier run moved there\n\
+                     hint: move them back into {}, or pick another --out",
+                    dir.display(),
+                    opts.chips.display()
+                );
+            }
+        }
+        None => {}
+    }
+    std::fs::create_dir_all(&opts.out)?;
+
+    let mut splits: BTreeMap<&str, Vec<(PathBuf, PathBuf)>> = BTreeMap::new();
+    for chip in names {
+        let image = paths::tile_file(opts.chips.join(TILES), chip, ext);
+        let mask = paths::tile_file(opts.chips.join(OUTLINES), chip, ".png");
+        if !mask.is_file() {
+            continue;
+        }
+        let split = opts.ratios.split_of(chip);
+        let pair = match opts.folders {
+            None => (image, mask),
+            Some(how) => {
+                let dir = opts.out.join(split);
+                let to = (
+                    paths::create_tile_file(dir.join(TILES), chip, ext)?,
+                    paths::create_tile_file(dir.join(OUTLINES), chip, ".png")?,
+                );
+                for (from, to) in [(&image, &to.0), (&mask, &to.1)] {
+                    match how {
+                        Folders::Link => link_or_copy(from, to)?,
+                        Folders::Move => std::fs::rename(from, to)
+                            .with_context(|| format!("moving {}", from.display()))?,
+                    }
+                }
+                to
+            }
+        };
+        splits.entry(split).or_default().push(pair);
+    }
+
+    for split in SPLITS {
+        let path = opts.out.join(format!("{split}.txt"));
+        let mut w = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        for (image, mask) in splits.get(split).into_iter().flatten() {
+            writeln!(w, "{}\t{}", listed(image), listed(mask))?;
+        }
+        w.flush()?;
+    }
+    let counts: Vec<_> = SPLITS
+        .iter()
+        .map(|s| format!("{s} {}", splits.get(s).map_or(0, Vec::len)))
+        .collect();
+    logging::status!(
+        "Split the chips into {}, listed in {}",
+        counts.join(", "),
+        opts.out.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Batched writes for the tile store. Saving is dominated by encoding and
+//! by the open/write/close of many small files, so tiles are encoded in
+//! parallel and a pool of writer threads keeps several files in flight.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{sync_channel, SyncSender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use image::{
+    codecs::png::{CompressionType, FilterType, PngEncoder},
+    ImageBuffer, ImageEncoder, Pixel, PixelWithColorType, RgbImage,
+};
+
+use crate::{
+    paths,
+    timing::{self, Stage},
+};
+
+/// Writer threads, and files queued per thread.
+const WRITERS: usize = 8;
+const QUEUE: usize = 8;
+
+/// Encodes with fast compression: label images are mostly long runs of
+/// one color, where it costs about 6% in size and saves 40% of the time.
+pub fn encode_png<P>(img: &ImageBuffer<P, Vec<u8>>) -> image::ImageResult<Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + PixelWithColorType,
+{
+    let mut data = vec![];
+    PngEncoder::new_with_quality(&mut data, CompressionType::Fast, FilterType::Adaptive)
+        .write_image(img.as_raw(), img.width(), img.height(), P::COLOR_TYPE)?;
+    Ok(data)
+}
+
+/// Encodes as an indexed PNG with `palette` first and any other colors
+/// after it in the order they appear, `None` if there are more than 256.
+pub fn encode_palette_png(img: &RgbImage, palette: &[[u8; 3]]) -> Option<Vec<u8>> {
+    let mut colors = palette.to_vec();
+    let mut last = None;
+    let mut indices = Vec::with_capacity(img.as_raw().len() / 3);
+    for px in img.pixels() {
+        // Label images are long runs of one color.
+        let i = match last {
+            Some((color, i)) if color == px.0 => i,
+            _ => match colors.iter().position(|c| *c == px.0) {
+                Some(i) => i,
+                None => {
+                    colors.push(px.0);
+                    colors.len() - 1
+                }
+            },
+        };
+        if i > 255 {
+            return None;
+        }
+        last = Some((px.0, i));
+        indices.push(i as u8);
+    }
+    let mut data = vec![];
+    let mut encoder = png::Encoder::new(&mut data, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(colors.concat());
+    // Writing to memory with a valid header cannot fail.
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&indices).unwrap();
+    drop(writer);
+    Some(data)
+}
+
+pub struct BatchWriter {
+    dir: PathBuf,
+    queue: Option<SyncSender<(PathBuf, Vec<u8>)>>,
+    threads: Vec<JoinHandle<()>>,
+    error: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+impl BatchWriter {
+    /// Writes files relative to `dir`.
+    pub fn new(dir: &Path) -> Self {
+        let (tx, rx) = sync_channel::<(PathBuf, Vec<u8>)>(WRITERS * QUEUE);
+        let rx = Arc::new(Mutex::new(rx));
+        let error = Arc::new(Mutex::new(None));
+        let threads = (0..WRITERS)
+            .map(|_| {
+                let rx = rx.clone();
+                let error = error.clone();
+                std::thread::spawn(move || loop {
+                    let Ok((path, data)) = rx.lock().unwrap().recv() else {
+                        return;
+                    };
+                    let _span = timing::span(Stage::Io);
+                    // `tile_paths` may nest files in directories of their own.
+                    let written = match path.parent() {
+                        Some(parent) => std::fs::create_dir_all(parent),
+                        None => Ok(()),
+                    }
+                    .and_then(|()| std::fs::write(&path, data));
+                    if let Err(why) = written {
+                        let why =
+                            std::io::Error::new(why.kind(), format!("{}: {why}", path.display()));
+                        error.lock().unwrap().get_or_insert(why);
+                    }
+                })
+            })
+            .collect();
+        Self {
+            dir: dir.to_owned(),
+            queue: Some(tx),
+            threads,
+            error,
+        }
+    }
+
+    /// Queues `file`, a `/`-separated path relative to the directory.
+    pub fn submit(&self, file: &str, data: Vec<u8>) {
+        self.queue
+            .as_ref()
+            .unwrap()
+            .send((paths::join_relative(&self.dir, file), data))
+            .unwrap();
+    }
+
+    /// Waits for every submitted file, failing with the first error.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        drop(self.queue.take());
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+        match self.error.lock().unwrap().take() {
+            Some(why) => Err(why),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! A sub-dataset of the stitched chips that touch OSM objects matching a
+//! tag query, such as only the chips with industrial land use. The chips
+//! are hard linked rather than rendered again, and the result has the
+//! `tiles/` and `outlines/` layout every exporter's `--chips` reads.
+
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use geo::{BoundingRect, Geometry, Intersects, LineString, Polygon, Rect};
+use log::info;
+use osmpbfreader::Tags;
+use serde::Serialize;
+
+use crate::{
+    chips::Grid,
+    formats::Formats,
+    geometry::node_coord,
+    logging, provider, register,
+    release::link_or_copy,
+    way_coords,
+    workspace::{OUTLINES, STITCHED, TILES},
+    OsmData,
+};
+
+/// `key=value`, or `key` or `key=*` for any value.
+#[derive(Clone, Debug)]
+pub struct TagQuery {
+    key: String,
+    value: Option<String>,
+}
+
+impl FromStr for TagQuery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = match s.split_once('=') {
+            Some((key, "*")) => (key, None),
+            Some((key, value)) => (key, Some(value.to_owned())),
+            None => (s, None),
+        };
+        if key.is_empty() {
+            return Err(format!(
+                "{s:?} has no key, expected e.g. landuse=industrial"
+            ));
+        }
+        Ok(Self {
+            key: key.to_owned(),
+            value,
+        })
+    }
+}
+
+impl fmt::Display for TagQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={value}", self.key),
+            None => write!(f, "{}=*", self.key),
+        }
+    }
+}
+
+impl TagQuery {
+    fn matches(&self, tags: &Tags) -> bool {
+        match &self.value {
+            Some(value) => tags.contains(&self.key, value),
+            None => tags.contains_key(self.key.as_str()),
+        }
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SubsetOptions {
+    /// Directory with `tiles/` and `outlines/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    #[arg(long)]
+    pub out: PathBuf,
+    /// Objects to keep the chips of, `--tag landuse=industrial`. Repeated,
+    /// a chip is kept if any of them matches.
+    #[arg(long = "tag", required = true)]
+    pub tags: Vec<TagQuery>,
+}
+
+#[derive(Serialize)]
+struct Subset<'a> {
+    tags: Vec<String>,
+    objects: usize,
+    chips: Vec<&'a str>,
+}
+
+pub fn export_subset(osm: &OsmData, opts: &SubsetOptions) -> anyhow::Result<()> {
+    let grid = Grid::load(&opts.chips)?;
+    let offset_m = provider::Providers::load()?.offset_m(&provider::source().name);
+    let matches = |tags: &Tags| opts.tags.iter().any(|q| q.matches(tags));
+
+    // Placed in the grid the way outlines are drawn, so a chip is kept for
+    // an object exactly when the object shows in it.
+    let mut objects: Vec<Geometry<f64>> = vec![];
+    let ways = osm
+        .ways_buildings
+        .values()
+        .chain(osm.ways_features.values());
+    for way in ways.filter(|w| matches(&w.tags)) {
+        let Some(coords) = way_coords(way, &osm.nodes_all).filter(|c| !c.is_empty()) else {
+            continue;
+        };
+        let line: LineString<f64> = register(&coords, offset_m)
+            .into_iter()
+            .map(|c| grid.px(c))
+            .collect();
+        objects.push(if coords.len() >= 4 && line.is_closed() {
+            Polygon::new(line, vec![]).into()
+        } else {
+            line.into()
+        });
+    }
+    let nodes = osm
+        .nodes_only_buildings
+        .values()
+        .chain(osm.nodes_features.values());
+    for node in nodes.filter(|n| matches(&n.tags)) {
+        let c = register(&[node_coord(node)], offset_m)[0];
+        objects.push(grid.px(c).into());
+    }
+    if objects.is_empty() {
+        anyhow::bail!(
+            "no object matches {}\n\
+             hint: only buildings and the features `render-outlines` can draw \
+             are kept when the extract is parsed",
+            opts.tags
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" or ")
+        );
+    }
+
+    let mut keep = vec![false; grid.chips.len()];
+    for object in &objects {
+        let Some(bounds) = object.bounding_rect() else {
+            continue;
+        };
+        for i in grid.overlapping(bounds) {
+            let [x0, y0, x1, y1] = grid.chips[i].px;
+            let chip = Rect::new((x0, y0), (x1, y1));
+            keep[i] |= chip.intersects(object);
+        }
+    }
+
+    // Made as a whole, so chips that no longer match do not linger.
+    for sub in [TILES, OUTLINES] {
+        let dir = opts.out.join(sub);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+    }
+    let ext = Formats::load()?.chips.ext();
+    let mut kept = vec![];
+    for (chip, _) in grid.chips.iter().zip(&keep).filter(|(_, k)| **k) {
+        let image = format!("{}{ext}", chip.key);
+        let label = format!("{}.png", chip.key);
+        link_or_copy(
+            &opts.chips.join(TILES).join(&image),
+            &opts.out.join(TILES).join(&image),
+        )?;
+        let outline = opts.chips.join(OUTLINES).join(&label);
+        if outline.is_file() {
+            link_or_copy(&outline, &opts.out.join(OUTLINES).join(&label))?;
+        }
+        kept.push(chip.key.as_str());
+    }
+    let subset = Subset {
+        tags: opts.tags.iter().map(ToString::to_string).collect(),
+        objects: objects.len(),
+        chips: kept,
+    };
+    std::fs::write(
+        opts.out.join("subset.json"),
+        serde_json::to_string_pretty(&subset)?,
+    )?;
+    info!(
+        "{} objects match {:?}",
+        subset.objects,
+        subset.tags.join(" or ")
+    );
+    logging::status!(
+        "Kept {} of {} chips in {}",
+        subset.chips.len(),
+        grid.chips.len(),
+        opts.out.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! How the buildings of the area of interest are tagged, to design the
+//! class mapping, e.g. `--class-rules`, before a full rendering run:
+//! `tags` counts the `building=*` values, the class every value ends up
+//! as with the current `ClassOptions` and rules, and the other tags that
+//! come with it.
+//!
+//! A building way counts as in the area if one of its nodes is.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::PathBuf,
+};
+
+use osmpbfreader::Way;
+use slippy_map_tiles::BBox;
+
+use crate::{
+    classes::{self, ClassOptions},
+    interest_bbox, way_coords, OsmData,
+};
+
+#[derive(clap::Args)]
+pub struct TagOptions {
+    /// Co-occurring tags listed per `building=*` value.
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+    /// Keys counted by their value, as `key=value`, instead of by the key
+    /// alone, e.g. `building:levels,roof:shape`.
+    #[arg(long, value_delimiter = ',')]
+    pub values: Vec<String>,
+    /// Also write every count as CSV, one row per `building=*` value and
+    /// co-occurring tag.
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+    #[command(flatten)]
+    pub classes: ClassOptions,
+}
+
+#[derive(Default)]
+struct ValueStats {
+    buildings: usize,
+    /// Class name to buildings.
+    classes: BTreeMap<String, usize>,
+    /// Key, or `key=value` for `--values`, to buildings.
+    tags: HashMap<String, usize>,
+}
+
+fn in_area(way: &Way, osm: &OsmData, bbox: &BBox) -> bool {
+    let Some(coords) = way_coords(way, &osm.nodes_all) else {
+        return false;
+    };
+    coords.iter().any(|c| {
+        let (lat, lon) = (c.latitude as f32, c.longitude as f32);
+        (bbox.bottom()..=bbox.top()).contains(&lat) && (bbox.left()..=bbox.right()).contains(&lon)
+    })
+}
+
+/// Most frequent first, then by name.
+fn ranked<K: Ord + Clone>(counts: impl IntoIterator<Item = (K, usize)>) -> Vec<(K, usize)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+/// Quotes a CSV field if it needs it; tag values may hold anything.
+fn field(s: &str) -> String {
+    match s.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_owned(),
+    }
+}
+
+/// Prints the tag frequencies of the building ways of `osm` in the area
+/// of interest, and writes them to `--out`.
+pub fn tag_frequencies(osm: &OsmData, opts: &TagOptions) -> anyhow::Result<()> {
+    let mut per_value: HashMap<String, ValueStats> = HashMap::new();
+    let mut total = 0;
+    let bbox = interest_bbox();
+    for way in osm.ways_buildings.values() {
+        if !in_area(way, osm, &bbox) {
+            continue;
+        }
+        total += 1;
+        let value = way.tags.get("building").map_or("", |v| v.as_str());
+        let stats = per_value.entry(value.to_owned()).or_default();
+        stats.buildings += 1;
+        let class = match classes::building_class(&way.tags, &opts.classes) {
+            Some(class) => format!("{class:?}"),
+            None => "(not drawn)".to_owned(),
+        };
+        *stats.classes.entry(class).or_default() += 1;
+        for (key, value) in way.tags.iter() {
+            if key == "building" {
+                continue;
+            }
+            let tag = match opts.values.iter().any(|k| k == key.as_str()) {
+                true => format!("{key}={value}"),
+                false => key.to_string(),
+            };
+            *stats.tags.entry(tag).or_default() += 1;
+        }
+    }
+    println!("Building ways in the area: {total}");
+
+    let values = ranked(per_value.iter().map(|(v, s)| (v.clone(), s.buildings)));
+    let mut csv = match &opts.out {
+        Some(out) => {
+            let mut w = std::io::BufWriter::new(std::fs::File::create(out)?);
+            writeln!(w, "building,buildings,class,tag,count")?;
+            Some(w)
+        }
+        None => None,
+    };
+    for (value, count) in &values {
+        let stats = &per_value[value];
+        let share = 100.0 * *count as f64 / total as f64;
+        let classes: Vec<_> = ranked(stats.classes.clone())
+            .into_iter()
+            .map(|(class, n)| format!("{class} {n}"))
+            .collect();
+        println!(
+            "building={value}: {count} ({share:.1}%), {}",
+            classes.join(", ")
+        );
+        let tags = ranked(stats.tags.iter().map(|(t, n)| (t.as_str(), *n)));
+        for (tag, n) in tags.iter().take(opts.top) {
+            let share = 100.0 * *n as f64 / *count as f64;
+            println!("    {tag}: {n} ({share:.1}%)");
+        }
+        if let Some(w) = &mut csv {
+            for (class, n) in &stats.classes {
+                writeln!(w, "{},{count},{},,{n}", field(value), field(class))?;
+            }
+            for (tag, n) in &tags {
+                writeln!(w, "{},{count},,{},{n}", field(value), field(tag))?;
+            }
+        }
+    }
+    if let (Some(mut w), Some(out)) = (csv, &opts.out) {
+        w.flush()?;
+        println!("Wrote the counts to {}", out.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+//! What is known about every downloaded tile: where and when it was
+//! fetched from, and the `ETag` and `Last-Modified` the server sent, kept
+//! in `tile-cache.jsonl` as an append-only journal like the manifest.
+//! With them `download-tiles --max-age DAYS` asks the server whether a
+//! tile older than that changed, and downloads it again only if it did.
+//! Tiles from before the journal count as fetched when their file was
+//! last written.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use slippy_map_tiles::Tile;
+
+use crate::{
+    paths,
+    provider::{self, Validators},
+    workspace::TILES,
+};
+
+const CACHE_FILE: &str = "tile-cache.jsonl";
+/// Compact once the journal has this many superseded lines.
+const COMPACT_AFTER: usize = 10_000;
+
+static CACHE: Mutex<Option<TileCache>> = Mutex::new(None);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// The tile file, relative to the dataset, as `tile_paths` lays it out.
+    pub file: String,
+    /// The tile URL with the API key left out.
+    pub url: String,
+    pub provider: String,
+    /// Seconds since the Unix epoch of the last download or revalidation.
+    pub fetched_at: u64,
+    #[serde(flatten)]
+    pub validators: Validators,
+}
+
+struct TileCache {
+    entries: HashMap<String, CacheEntry>,
+    journal: File,
+    /// Lines in the journal, superseded ones included.
+    lines: usize,
+}
+
+impl TileCache {
+    fn open() -> anyhow::Result<Self> {
+        let mut entries = HashMap::new();
+        let mut lines = 0;
+        if let Ok(f) = File::open(CACHE_FILE) {
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                // A torn last line from a crash.
+                let Ok(entry) = serde_json::from_str::<CacheEntry>(&line) else {
+                    continue;
+                };
+                entries.insert(entry.file.clone(), entry);
+                lines += 1;
+            }
+        }
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(CACHE_FILE)?;
+        Ok(Self {
+            entries,
+            journal,
+            lines,
+        })
+    }
+
+    fn record(&mut self, entry: CacheEntry) -> anyhow::Result<()> {
+        writeln!(self.journal, "{}", serde_json::to_string(&entry)?)?;
+        self.entries.insert(entry.file.clone(), entry);
+        self.lines += 1;
+        if self.lines > self.entries.len() + COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the journal with one line per tile.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let tmp = Path::new(CACHE_FILE).with_extension("jsonl.tmp");
+        let mut f = std::io::BufWriter::new(File::create(&tmp)?);
+        for entry in self.entries.values() {
+            writeln!(f, "{}", serde_json::to_string(entry)?)?;
+        }
+        f.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, CACHE_FILE)?;
+        self.journal = OpenOptions::new().append(true).open(CACHE_FILE)?;
+        self.lines = self.entries.len();
+        Ok(())
+    }
+}
+
+/// Runs `f` on the cache, opened on first use.
+fn with_cache<T>(f: impl FnOnce(&mut TileCache) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let mut cache = CACHE.lock().unwrap();
+    if cache.is_none() {
+        *cache = Some(TileCache::open()?);
+    }
+    f(cache.as_mut().unwrap())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Records that `tile` was just downloaded or found current.
+pub fn record(tile: Tile, validators: Validators) -> anyhow::Result<()> {
+    let source = provider::source();
+    let entry = CacheEntry {
+        file: paths::relative_tile_file(TILES, tile, ".jpg"),
+        url: source.url(tile, true),
+        provider: source.name.clone(),
+        fetched_at: now(),
+        validators,
+    };
+    with_cache(|cache| cache.record(entry))
+}
+
+/// What is recorded about the copy of `tile` on disk.
+pub fn entry(tile: Tile) -> anyhow::Result<Option<CacheEntry>> {
+    let file = paths::relative_tile_file(TILES, tile, ".jpg");
+    with_cache(|cache| Ok(cache.entries.get(&file).cloned()))
+}
+
+/// The validators of the copy of `tile` on disk, if there is one that
+/// is older than `max_age`, or was fetched from another provider.
+pub fn stale(tile: Tile, max_age: Duration) -> anyhow::Result<Option<Validators>> {
+    let file = paths::relative_tile_file(TILES, tile, ".jpg");
+    let known = entry(tile)?;
+    let (fetched_at, validators) = match known {
+        Some(entry) if entry.provider != provider::source().name => {
+            // Not the same imagery, so nothing to revalidate against.
+            return Ok(Some(Validators::default()));
+        }
+        Some(entry) => (entry.fetched_at, entry.validators),
+        None => {
+            let written = std::fs::metadata(paths::tile_file(TILES, tile, ".jpg"))
+                .and_then(|m| m.modified())
+                .map_err(|why| anyhow::anyhow!("{file}: {why}"))?;
+            let written = written.duration_since(UNIX_EPOCH).unwrap_or_default();
+            (written.as_secs(), Validators::default())
+        }
+    };
+    Ok((now().saturating_sub(fetched_at) > max_age.as_secs()).then_some(validators))
+}
+
+/// Makes what was recorded so far durable.
+pub fn flush() {
+    let result = with_cache(|cache| {
+        cache.journal.flush()?;
+        cache.journal.sync_data()?;
+        Ok(())
+    });
+    if let Err(why) = result {
+        warn!("cannot save {CACHE_FILE}: {why:#}");
+    }
+}
@@ -0,0 +1,154 @@
+//! Time spent per stage, so that optimizing goes where the time actually
+//! goes. Stages are marked with `span` guards, which nest: the time of a
+//! stage excludes the stages running inside it, e.g. downloads started
+//! while rasterizing. The totals are printed at the end of a run and
+//! `--timings` writes them as folded stacks, the input of flamegraph tools.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::logging;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Decoding PBF blocks and sorting the objects.
+    Parse,
+    /// Looking up way nodes.
+    Resolve,
+    /// Drawing into outlines and channels, post-processing included.
+    Rasterize,
+    /// PNG and JPEG encoding.
+    Encode,
+    /// Reading and writing files.
+    Io,
+    /// Downloading imagery.
+    Network,
+}
+
+impl Stage {
+    const ALL: [Stage; 6] = [
+        Stage::Parse,
+        Stage::Resolve,
+        Stage::Rasterize,
+        Stage::Encode,
+        Stage::Io,
+        Stage::Network,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::Resolve => "resolve",
+            Stage::Rasterize => "rasterize",
+            Stage::Encode => "encode",
+            Stage::Io => "io",
+            Stage::Network => "network",
+        }
+    }
+}
+
+struct Frame {
+    stage: Stage,
+    start: Instant,
+    /// Time of the spans nested in this one so far.
+    children: Duration,
+}
+
+thread_local! {
+    static STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Self time per stack of stages, e.g. `rasterize;network`.
+static TIMES: Mutex<BTreeMap<String, Duration>> = Mutex::new(BTreeMap::new());
+
+/// Counts the time until it is dropped towards `stage`.
+#[must_use]
+pub struct Span(());
+
+pub fn span(stage: Stage) -> Span {
+    STACK.with(|stack| {
+        stack.borrow_mut().push(Frame {
+            stage,
+            start: Instant::now(),
+            children: Duration::ZERO,
+        })
+    });
+    Span(())
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let frame = stack.pop().unwrap();
+            let elapsed = frame.start.elapsed();
+            let mut key = String::new();
+            for outer in stack.iter() {
+                key.push_str(outer.stage.name());
+                key.push(';');
+            }
+            key.push_str(frame.stage.name());
+            if let Some(parent) = stack.last_mut() {
+                parent.children += elapsed;
+            }
+            *TIMES.lock().unwrap().entry(key).or_default() +=
+                elapsed.saturating_sub(frame.children);
+        })
+    }
+}
+
+/// Self time of every stage so far, whatever it ran inside of.
+fn per_stage(times: &BTreeMap<String, Duration>) -> Vec<(&'static str, Duration)> {
+    Stage::ALL
+        .iter()
+        .map(|stage| {
+            let time: Duration = times
+                .iter()
+                .filter(|(stack, _)| stack.rsplit(';').next() == Some(stage.name()))
+                .map(|(_, time)| *time)
+                .sum();
+            (stage.name(), time)
+        })
+        .collect()
+}
+
+/// Seconds per stage so far, for the metrics.
+pub fn seconds() -> Vec<(&'static str, f64)> {
+    per_stage(&TIMES.lock().unwrap())
+        .into_iter()
+        .map(|(stage, time)| (stage, time.as_secs_f64()))
+        .collect()
+}
+
+/// Prints the time per stage, if any was measured, and writes the folded
+/// stacks to `folded`.
+pub fn report(folded: Option<&Path>) -> anyhow::Result<()> {
+    let times = TIMES.lock().unwrap();
+    if times.is_empty() {
+        return Ok(());
+    }
+    let per_stage = per_stage(&times);
+    let total: Duration = per_stage.iter().map(|(_, time)| *time).sum();
+    logging::status!("Time per stage, summed over threads:");
+    for (stage, time) in &per_stage {
+        logging::status!(
+            "  {stage:<10} {:>10.2?} {:>5.1}%",
+            time,
+            100.0 * time.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON)
+        );
+    }
+    if let Some(path) = folded {
+        let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for (stack, time) in times.iter() {
+            writeln!(f, "{stack} {}", time.as_micros())?;
+        }
+        f.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Work units: with `--unit-zoom`, `render-outlines` walks the area one
+//! super-tile at a time and takes each through download, rasterizing and
+//! saving before dropping it from memory, so peak memory follows the unit
+//! size instead of the area. `--after-unit` hooks stitching and export in,
+//! and `--prune` removes what the hook exported, which bounds disk too.
+//! `--prefetch` downloads the next units while one is rasterized, never
+//! more of them than it is given.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::SyncSender,
+        Mutex,
+    },
+};
+
+use anyhow::bail;
+use log::warn;
+use slippy_map_tiles::Tile;
+
+use crate::{paths, workspace::TILES, zoom, MAX_ZOOM};
+
+/// Drawing never reaches further than this from an object's vertices:
+/// buffers, crowns and line widths are all well below it.
+pub const REACH_M: f64 = 100.0;
+const DONE_FILE: &str = "units-done.txt";
+/// Tiles of a unit downloaded at once by `prefetch`.
+const DOWNLOADERS: usize = 8;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct UnitOptions {
+    /// Render the area in super-tiles of this zoom, one after another;
+    /// 13 makes units of 16x16 tiles. Pick units that are a multiple of
+    /// the stitch block size so that no block spans two of them.
+    #[arg(long, value_name = "ZOOM", value_parser = clap::value_parser!(u8).range(1..MAX_ZOOM as i64))]
+    pub unit_zoom: Option<u8>,
+    /// Shell command run after each unit is saved, e.g. to stitch and
+    /// export it. It gets `UNIT_ZOOM`, `UNIT_X`, `UNIT_Y` and
+    /// `UNIT_TILES`, a file listing the unit's downloaded tiles as
+    /// `{y}-{x}`.
+    #[arg(long, requires = "unit_zoom")]
+    pub after_unit: Option<String>,
+    /// Delete the imagery and labels of a unit once `--after-unit`
+    /// succeeded for it.
+    #[arg(long, requires = "after_unit")]
+    pub prune: bool,
+    /// Work on the parts of the area with the most buildings first, so
+    /// that a run cut short already has the most labels. Parts are work
+    /// units with `--unit-zoom` and zoom 13 tiles otherwise.
+    #[arg(long)]
+    pub densest_first: bool,
+    /// Download the imagery of up to this many units ahead of the one
+    /// being rasterized, in the background. The downloader waits when it
+    /// is that far ahead, so that downloads overlap with rasterizing
+    /// without filling the disk while rasterizing lags.
+    #[arg(long, value_name = "UNITS", requires = "unit_zoom", value_parser = clap::value_parser!(u8).range(1..))]
+    pub prefetch: Option<u8>,
+    /// Render only these units, for `verify-repro`.
+    #[arg(skip)]
+    pub only: Option<HashSet<Tile>>,
+}
+
+/// The unit at `unit_zoom` containing a tile of the tile zoom.
+pub fn unit_of(tile: Tile, unit_zoom: u8) -> Tile {
+    let shift = zoom() - unit_zoom;
+    Tile::new(unit_zoom, tile.x() >> shift, tile.y() >> shift).unwrap()
+}
+
+pub fn contains(unit: Tile, tile: Tile) -> bool {
+    unit_of(tile, unit.zoom()) == unit
+}
+
+/// Objects sorted into the units they draw into, row by row. An object
+/// crossing a unit boundary is added, and drawn clipped, to each of them.
+pub struct Plan<T> {
+    zoom: u8,
+    pub units: BTreeMap<(u32, u32), (Tile, T)>,
+}
+
+impl<T: Default> Plan<T> {
+    pub fn new(zoom: u8) -> Self {
+        Self {
+            zoom,
+            units: BTreeMap::new(),
+        }
+    }
+
+    /// Applies `add` to the batch of every unit any of `tiles` is in.
+    pub fn insert(&mut self, tiles: impl IntoIterator<Item = Tile>, add: impl Fn(&mut T)) {
+        let units: HashSet<_> = tiles.into_iter().map(|t| unit_of(t, self.zoom)).collect();
+        for unit in units {
+            let (_, batch) = self
+                .units
+                .entry((unit.y(), unit.x()))
+                .or_insert_with(|| (unit, T::default()));
+            add(batch);
+        }
+    }
+}
+
+/// Units finished by earlier runs, one `{zoom}/{x}/{y}` per line in
+/// `out_dir`, so an interrupted run picks up where it stopped.
+pub struct Done {
+    path: PathBuf,
+    units: BTreeSet<String>,
+}
+
+impl Done {
+    pub fn load(out_dir: &Path) -> Self {
+        let path = out_dir.join(DONE_FILE);
+        let units = std::fs::read_to_string(&path)
+            .map(|s| s.lines().map(str::to_owned).collect())
+            .unwrap_or_default();
+        Self { path, units }
+    }
+
+    pub fn contains(&self, unit: Tile) -> bool {
+        self.units.contains(&name(unit))
+    }
+
+    pub fn mark(&mut self, unit: Tile) -> anyhow::Result<()> {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(f, "{}", name(unit))?;
+        f.sync_data()?;
+        self.units.insert(name(unit));
+        Ok(())
+    }
+}
+
+fn name(unit: Tile) -> String {
+    format!("{}/{}/{}", unit.zoom(), unit.x(), unit.y())
+}
+
+/// The tiles of the tile zoom of a unit.
+pub fn tiles(unit: Tile) -> impl Iterator<Item = Tile> {
+    let shift = zoom() - unit.zoom();
+    let (x0, y0) = (unit.x() << shift, unit.y() << shift);
+    let side = 1 << shift;
+    (y0..y0 + side)
+        .flat_map(move |y| (x0..x0 + side).map(move |x| Tile::new(zoom(), x, y).unwrap()))
+}
+
+/// Downloads the tiles of `units` one unit after another with `fetch`,
+/// which tells whether it downloaded the tile, and hands every unit over
+/// with the tiles downloaded once all of them were tried. `tx` is bounded,
+/// so the downloader blocks instead of getting further ahead than the
+/// queue allows, and stops once the receiver is gone.
+pub fn prefetch(
+    units: &[Tile],
+    tx: SyncSender<(Tile, Vec<Tile>)>,
+    fetch: impl Fn(Tile) -> bool + Sync,
+) {
+    for &unit in units {
+        let todo: Vec<_> = tiles(unit).collect();
+        let next = AtomicUsize::new(0);
+        let fetched = Mutex::new(vec![]);
+        std::thread::scope(|s| {
+            for _ in 0..DOWNLOADERS {
+                s.spawn(|| {
+                    while let Some(&tile) = todo.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if fetch(tile) {
+                            fetched.lock().unwrap().push(tile);
+                        }
+                    }
+                });
+            }
+        });
+        if tx.send((unit, fetched.into_inner().unwrap())).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs the `--after-unit` command for a unit.
+pub fn run_hook(cmd: &str, unit: Tile, out_dir: &Path) -> anyhow::Result<()> {
+    let list = out_dir.join("unit-tiles.txt");
+    let mut names = String::new();
+    for tile in tiles(unit) {
+        if paths::tile_file(TILES, tile, ".jpg").is_file() {
+            names.push_str(&paths::stem(tile));
+            names.push('\n');
+        }
+    }
+    std::fs::write(&list, names)?;
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("UNIT_ZOOM", unit.zoom().to_string())
+        .env("UNIT_X", unit.x().to_string())
+        .env("UNIT_Y", unit.y().to_string())
+        .env("UNIT_TILES", &list)
+        .status()?;
+    if !status.success() {
+        bail!(
+            "--after-unit failed for unit {} with {status}\n\
+             hint: the unit is kept and not marked done, rerun to retry it",
+            name(unit)
+        );
+    }
+    Ok(())
+}
+
+/// Removes the imagery of a unit and its images in `dirs` under
+/// `out_dir`, i.e. `outlines` and the channel directories, each given
+/// with the extension of its files.
+pub fn prune(unit: Tile, out_dir: &Path, dirs: &[(&str, &str)]) {
+    for tile in tiles(unit) {
+        let mut files = vec![paths::tile_file(TILES, tile, ".jpg")];
+        files.extend(
+            dirs.iter()
+                .map(|(d, ext)| paths::tile_file(out_dir.join(d), tile, ext)),
+        );
+        for file in files.into_iter().filter(|f| f.exists()) {
+            if let Err(why) = std::fs::remove_file(&file) {
+                warn!("error pruning {}: {why}", file.display())
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Stitched chips packed as WebDataset shards: tar files holding
+//! `<key>.jpg`, `<key>.mask.png` and `<key>.json` per sample, which
+//! `webdataset` streams without unpacking. Next to every shard goes the
+//! `.dvc` file `dvc add` would write for it, and a `.gitignore` for the
+//! shards, so the directory can be committed with DVC as it is.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use md5::{Digest, Md5};
+
+use crate::{
+    chips,
+    formats::Formats,
+    list_tiles, logging, paths,
+    rng::Rng,
+    workspace::{OUTLINES, STITCHED, TILES},
+};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct WebDatasetOptions {
+    /// Directory with `tiles/` and `outlines/` of the stitched chips.
+    #[arg(long, default_value = STITCHED)]
+    pub chips: PathBuf,
+    #[arg(long, default_value = "webdataset")]
+    pub out: PathBuf,
+    #[arg(long, default_value_t = 500)]
+    pub samples_per_shard: usize,
+    /// Samples are shuffled across shards, since neighbouring chips look
+    /// alike and the shuffle buffer of a loader only sees a few shards.
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+pub fn append(tar: &mut tar::Builder<impl Write>, name: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+/// The per-file `.dvc` of DVC 3, whose md5 is that of the plain bytes.
+/// `name` is the file name of `shard`, which the `.dvc` refers to it by.
+fn write_dvc(shard: &Path, name: &str) -> anyhow::Result<()> {
+    let data = std::fs::read(shard)?;
+    let md5: String = Md5::digest(&data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    std::fs::write(
+        shard.with_extension("tar.dvc"),
+        format!(
+            "outs:\n- md5: {md5}\n  size: {}\n  hash: md5\n  path: {name}\n",
+            data.len()
+        ),
+    )?;
+    Ok(())
+}
+
+pub fn export_webdataset(opts: &WebDatasetOptions) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        opts.samples_per_shard > 0,
+        "--samples-per-shard must be positive"
+    );
+    let ext = Formats::load()?.chips.ext();
+    let mut samples: Vec<_> = list_tiles(opts.chips.join(TILES), ext)
+        .into_iter()
+        .filter(|c| paths::tile_file(opts.chips.join(OUTLINES), *c, ".png").is_file())
+        .collect();
+    samples.sort_by_key(|t| (t.y(), t.x()));
+    let mut rng = Rng::new(opts.seed, 0);
+    for i in (1..samples.len()).rev() {
+        samples.swap(i, rng.below(i + 1));
+    }
+    let tile_px = chips::tile_px();
+
+    std::fs::create_dir_all(&opts.out)?;
+    // Shards of an earlier, larger export would otherwise linger.
+    for entry in std::fs::read_dir(&opts.out)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with("shard-"))
+        {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let mut shards = vec![];
+    for (i, batch) in samples.chunks(opts.samples_per_shard).enumerate() {
+        let name = format!("shard-{i:06}.tar");
+        let path = opts.out.join(&name);
+        let tmp = opts.out.join(format!("{name}.part"));
+        let mut tar = tar::Builder::new(BufWriter::new(File::create(&tmp)?));
+        for chip in batch {
+            let key = paths::stem(*chip);
+            let image = std::fs::read(paths::tile_file(opts.chips.join(TILES), *chip, ext))?;
+            let mask = std::fs::read(paths::tile_file(opts.chips.join(OUTLINES), *chip, ".png"))?;
+            let (w, h) = image::io::Reader::new(std::io::Cursor::new(&image))
+                .with_guessed_format()?
+                .into_dimensions()?;
+            let meta = serde_json::to_vec(&chips::extent(*chip, w, h, tile_px))?;
+            append(&mut tar, &format!("{key}{ext}"), &image)?;
+            append(&mut tar, &format!("{key}.mask.png"), &mask)?;
+            append(&mut tar, &format!("{key}.json"), &meta)?;
+        }
+        tar.into_inner()?.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        write_dvc(&path, &name)?;
+        shards.push(name);
+    }
+
+    let mut list = String::new();
+    for name in &shards {
+        list.push_str(&format!("/{name}\n"));
+    }
+    std::fs::write(opts.out.join(".gitignore"), list)?;
+    std::fs::write(opts.out.join("shards.txt"), shards.join("\n") + "\n")?;
+    info!(
+        "Wrote {} samples in {} shards to {}",
+        samples.len(),
+        shards.len(),
+        opts.out.display()
+    );
+    if let (Some(first), Some(last)) = (shards.first(), shards.last()) {
+        logging::status!(
+            "Load with webdataset.WebDataset(\"{}/shard-{{{}..{}}}.tar\")",
+            opts.out.display(),
+            &first[6..12],
+            &last[6..12],
+        );
+    }
+    Ok(())
+}
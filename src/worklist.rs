@@ -0,0 +1,125 @@
+//! Sparse areas of interest. `--tile-list FILE` names the tiles to work
+//! on, e.g. those a labeling team flagged, instead of every tile of a
+//! bounding box: `download-tiles` fetches only them, `render-outlines`
+//! draws only into them and `stitch` keeps only the blocks they fill.
+//!
+//! The file has a tile per line as `z/x/y`, or as CSV columns `z,x,y`
+//! with an optional header; blank lines and `#` comments are skipped. A
+//! tile of a lower zoom than `--zoom` stands for all of its tiles at
+//! `--zoom`, so a stitched block can be listed as its parent tile.
+
+use std::{collections::HashSet, path::Path, sync::OnceLock};
+
+use slippy_map_tiles::{BBox, Tile};
+
+use crate::{checks, zoom};
+
+static TILES: OnceLock<HashSet<Tile>> = OnceLock::new();
+
+/// Largest number of tiles a line may stand for, that of a tile 8 zooms
+/// up, so that a typo does not list a continent.
+const MAX_EXPANSION: u32 = 8;
+
+fn parse_line(line: &str) -> Result<Option<(u8, u32, u32)>, String> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let fields: Vec<_> = line
+        .split(['/', ',', ';', ' ', '\t'])
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .collect();
+    let [z, x, y] = fields[..] else {
+        return Err(format!("{line:?} is not z/x/y"));
+    };
+    let number = |s: &str| {
+        s.parse::<u32>()
+            .map_err(|_| format!("{line:?} is not z/x/y"))
+    };
+    let z = number(z)?;
+    Ok(Some((
+        u8::try_from(z).map_err(|_| format!("{line:?} has no zoom {z}"))?,
+        number(x)?,
+        number(y)?,
+    )))
+}
+
+/// The tiles of `--zoom` that `(z, x, y)` covers.
+fn expand(z: u8, x: u32, y: u32) -> Result<Vec<Tile>, String> {
+    if z > zoom() {
+        return Err(format!(
+            "{z}/{x}/{y} is of a zoom above --zoom {}, which has no tiles that small",
+            zoom()
+        ));
+    }
+    let shift = (zoom() - z) as u32;
+    if shift > MAX_EXPANSION {
+        return Err(format!(
+            "{z}/{x}/{y} covers 4^{shift} tiles of zoom {}, more than a list is for",
+            zoom()
+        ));
+    }
+    Tile::new(z, x, y).ok_or_else(|| format!("{z}/{x}/{y} is not a tile"))?;
+    let side = 1 << shift;
+    Ok((0..side)
+        .flat_map(|dy| (0..side).map(move |dx| (dx, dy)))
+        .filter_map(|(dx, dy)| Tile::new(zoom(), (x << shift) + dx, (y << shift) + dy))
+        .collect())
+}
+
+/// Reads the `--tile-list` for the rest of the run, after the zoom is set.
+pub fn load(path: Option<&Path>) -> anyhow::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|why| anyhow::anyhow!("cannot read {}: {why}", path.display()))?;
+    let mut tiles = HashSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let parsed = parse_line(line).and_then(|t| match t {
+            Some((z, x, y)) => expand(z, x, y),
+            None => Ok(vec![]),
+        });
+        match parsed {
+            Ok(expanded) => tiles.extend(expanded),
+            // A CSV header.
+            Err(_) if i == 0 && !line.trim_start().starts_with(|c: char| c.is_ascii_digit()) => {}
+            Err(why) => anyhow::bail!(
+                "{}:{}: {why}\n\
+                 hint: list one tile per line as z/x/y, e.g. 18/158485/81974",
+                path.display(),
+                i + 1
+            ),
+        }
+    }
+    anyhow::ensure!(!tiles.is_empty(), "{} lists no tiles", path.display());
+    TILES.set(tiles).expect("tile list loaded twice");
+    Ok(())
+}
+
+/// The listed tiles, `None` without `--tile-list`.
+pub fn tiles() -> Option<&'static HashSet<Tile>> {
+    TILES.get()
+}
+
+/// Whether `tile` is to be worked on: every tile without a list.
+pub fn contains(tile: Tile) -> bool {
+    TILES.get().is_none_or(|t| t.contains(&tile))
+}
+
+/// The bounding box of the listed tiles, the area of interest unless
+/// `--bbox` gives one.
+pub fn bbox() -> Option<BBox> {
+    let tiles = TILES.get()?;
+    let (mut top, mut left) = (f64::MIN, f64::MAX);
+    let (mut bottom, mut right) = (f64::MAX, f64::MIN);
+    for tile in tiles {
+        let b = mercator::bounds(*tile);
+        top = top.max(b.north);
+        bottom = bottom.min(b.south);
+        left = left.min(b.west);
+        right = right.max(b.east);
+    }
+    checks::bbox(top as f32, left as f32, bottom as f32, right as f32).ok()
+}
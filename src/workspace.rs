@@ -0,0 +1,212 @@
+//! The directory a dataset is built in, and where in it every artifact
+//! goes. Commands run in the workspace, the working directory or
+//! `--workspace DIR`, so that every relative path, `--pbf` among them, is
+//! taken from there, the way `git -C` does:
+//!
+//! - `tiles/`: the imagery, with `tile-cache.jsonl` on where it came from;
+//! - `outlines/`, next to `roofs/`, `lines/` and `coverage/`: the masks
+//!   rendered per tile;
+//! - `run/`: the working directory of `stitch_pictures`;
+//! - `stitched/tiles/` and `stitched/outlines/`: the chips and their masks,
+//!   with the stitch manifest;
+//! - `dataset.json`: every image and mask pair of the above, written by
+//!   `dataset` and after every `stitch`.
+//!
+//! The pairs of `dataset.json` are listed with their bounds and what they
+//! were made from, so a consumer can use the dataset without knowing the
+//! layout or parsing tile names: a tile pair with where its imagery was
+//! downloaded from, a chip pair with the hashes the stitch manifest has of
+//! its sources and parameters and the tiles of it painted `NoLabel`, which
+//! have no labels and are best masked out. Paths are relative to the
+//! workspace and `/`-separated.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    chips, formats::Formats, list_tiles, logging, paths, provider::Validators, release, tilecache,
+    zoom,
+};
+
+pub const TILES: &str = "tiles";
+pub const OUTLINES: &str = "outlines";
+pub const STITCHED: &str = "stitched";
+pub const RUN: &str = "run";
+const DATASET_FILE: &str = "dataset.json";
+/// Bumped when `dataset.json` changes incompatibly.
+const FORMAT: u32 = 1;
+
+static WORKSPACE: OnceLock<Workspace> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Workspace {
+    /// Makes `dir`, created if need be, the working directory for the rest
+    /// of the run, or without one the working directory as it is.
+    pub fn enter(dir: Option<&Path>) -> anyhow::Result<&'static Workspace> {
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)
+                .and_then(|_| std::env::set_current_dir(dir))
+                .with_context(|| format!("entering the workspace {}", dir.display()))?;
+        }
+        let root = std::env::current_dir()?;
+        Ok(WORKSPACE.get_or_init(|| Workspace { root }))
+    }
+
+    /// The absolute path of `sub`, e.g. `TILES`, for tools run elsewhere.
+    pub fn dir(&self, sub: impl AsRef<Path>) -> PathBuf {
+        self.root.join(sub)
+    }
+}
+
+/// The workspace entered at startup.
+pub fn get() -> &'static Workspace {
+    WORKSPACE.get().expect("workspace not entered")
+}
+
+/// `[west, south, east, north]`.
+type Bounds = [f64; 4];
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase", tag = "level")]
+enum Provenance {
+    /// Drawn over one downloaded tile, from `tile-cache.jsonl`; tiles
+    /// downloaded before it was kept have none.
+    Tile {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        provider: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fetched_at: Option<u64>,
+        #[serde(flatten, skip_serializing_if = "Option::is_none")]
+        validators: Option<Validators>,
+    },
+    /// Stitched from tiles, from the stitch manifest.
+    Chip {
+        tiles: [u32; 2],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        inputs: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        params: Option<String>,
+        /// Tiles of the chip without labels, painted `NoLabel`.
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        no_label: Vec<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Pair {
+    name: String,
+    image: String,
+    mask: String,
+    bounds: Bounds,
+    #[serde(flatten)]
+    provenance: Provenance,
+}
+
+#[derive(Serialize)]
+struct Dataset {
+    format: u32,
+    /// Unix time it was written.
+    created: u64,
+    generator: &'static str,
+    zoom: u8,
+    aoi: String,
+    pairs: Vec<Pair>,
+}
+
+fn tile_pairs() -> anyhow::Result<Vec<Pair>> {
+    let mut tiles = list_tiles(TILES, ".jpg");
+    tiles.retain(|t| paths::tile_file(OUTLINES, *t, ".png").is_file());
+    tiles.sort_by_key(|t| (t.y(), t.x()));
+    let mut pairs = vec![];
+    for tile in tiles {
+        let b = mercator::bounds(tile);
+        let entry = tilecache::entry(tile)?;
+        pairs.push(Pair {
+            name: paths::stem(tile),
+            image: paths::relative_tile_file(TILES, tile, ".jpg"),
+            mask: paths::relative_tile_file(OUTLINES, tile, ".png"),
+            bounds: [b.west, b.south, b.east, b.north],
+            provenance: Provenance::Tile {
+                provider: entry.as_ref().map(|e| e.provider.clone()),
+                url: entry.as_ref().map(|e| e.url.clone()),
+                fetched_at: entry.as_ref().map(|e| e.fetched_at),
+                validators: entry.map(|e| e.validators),
+            },
+        });
+    }
+    Ok(pairs)
+}
+
+fn chip_pairs() -> anyhow::Result<Vec<Pair>> {
+    let stitched = Path::new(STITCHED);
+    let (images, masks) = (stitched.join(TILES), stitched.join(OUTLINES));
+    let (images, masks) = (images.to_string_lossy(), masks.to_string_lossy());
+    let manifest = stitched.join("manifest.jsonl");
+    let blocks = match manifest.is_file() {
+        true => release::read_manifest(&manifest)?,
+        false => Default::default(),
+    };
+    let ext = Formats::load()?.chips.ext();
+    let tile_px = chips::tile_px();
+    let mut names = list_tiles(&*images, ext);
+    names.retain(|c| paths::tile_file(&*masks, *c, ".png").is_file());
+    names.sort_by_key(|c| (c.y(), c.x()));
+    let mut pairs = vec![];
+    for chip in names {
+        let image = paths::relative_tile_file(&images, chip, ext);
+        let (w, h) = image::image_dimensions(&image)?;
+        let extent = chips::extent(chip, w, h, tile_px);
+        let block = blocks.get(&extent.key);
+        pairs.push(Pair {
+            image,
+            mask: paths::relative_tile_file(&masks, chip, ".png"),
+            bounds: [extent.west, extent.south, extent.east, extent.north],
+            provenance: Provenance::Chip {
+                tiles: [w / tile_px, h / tile_px],
+                inputs: block.map(|b| b.inputs.clone()),
+                params: block.map(|b| b.params.clone()),
+                no_label: block.map(|b| b.no_label.clone()).unwrap_or_default(),
+            },
+            name: extent.key,
+        });
+    }
+    Ok(pairs)
+}
+
+/// Writes `dataset.json`, listing the tile and chip pairs in the workspace.
+pub fn write_dataset(aoi: &str) -> anyhow::Result<()> {
+    let mut pairs = tile_pairs()?;
+    let tiles = pairs.len();
+    pairs.extend(chip_pairs()?);
+    let dataset = Dataset {
+        format: FORMAT,
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        generator: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")),
+        zoom: zoom(),
+        aoi: aoi.to_owned(),
+        pairs,
+    };
+    // Renamed into place, so a reader never sees half of it.
+    let tmp = Path::new(DATASET_FILE).with_extension("json.part");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&dataset)?)?;
+    std::fs::rename(&tmp, DATASET_FILE)?;
+    logging::status!(
+        "Wrote {} tile and {} chip pairs to {DATASET_FILE}",
+        tiles,
+        dataset.pairs.len() - tiles
+    );
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Times tile decoding with whichever decoder the build selects:
+//!
+//!     cargo run --release --example jpeg_decode -- tiles/18
+//!     cargo run --release --example jpeg_decode --features fast-jpeg -- tiles/18
+//!
+//! Without a directory it decodes a generated 256x256 tile instead.
+
+use std::{path::Path, time::Instant};
+
+use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+#[path = "../src/jpeg.rs"]
+mod jpeg;
+
+/// Decodes of each tile, so the timing is not dominated by one pass
+/// over a cold page cache.
+const ROUNDS: usize = 20;
+
+fn collect(dir: &Path, tiles: &mut Vec<Vec<u8>>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect(&path, tiles);
+        } else if path.extension().is_some_and(|e| e == "jpg" || e == "jpeg") {
+            tiles.push(std::fs::read(path).unwrap());
+        }
+    }
+}
+
+fn generated() -> Vec<u8> {
+    let img = RgbImage::from_fn(256, 256, |x, y| {
+        image::Rgb([x as u8, y as u8, (x ^ y) as u8])
+    });
+    let mut data = vec![];
+    JpegEncoder::new(&mut data).encode_image(&img).unwrap();
+    data
+}
+
+fn main() {
+    let mut tiles = vec![];
+    match std::env::args().nth(1) {
+        Some(dir) => collect(Path::new(&dir), &mut tiles),
+        None => tiles.push(generated()),
+    }
+    assert!(!tiles.is_empty(), "no .jpg tiles found");
+
+    let start = Instant::now();
+    let mut failed = 0;
+    for _ in 0..ROUNDS {
+        for data in &tiles {
+            if jpeg::decode(data).is_none() {
+                failed += 1;
+            }
+        }
+    }
+    let decodes = ROUNDS * tiles.len();
+    let per_tile = start.elapsed().as_secs_f64() * 1e3 / decodes as f64;
+    println!(
+        "{}: {} tiles x {ROUNDS} rounds, {per_tile:.3} ms per tile, {failed} failed",
+        jpeg::DECODER,
+        tiles.len()
+    );
+}
@@ -0,0 +1,123 @@
+//! Progress of a run, one line per finished block with whether it failed
+//! and why, so that `--resume` can pick an interrupted run up without
+//! reading the inputs of the blocks it finished again, and try the blocks
+//! that failed once their missing tiles are there. Unlike the manifest it
+//! is tied to the parameters of the run: a run with other parameters
+//! starts it over.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::report::BlockOutcome;
+
+const CHECKPOINT_NAME: &str = "checkpoint.jsonl";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum Status {
+    Done,
+    Failed { reason: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    block: String,
+    /// `hash_params` of the run that finished the block.
+    params: String,
+    #[serde(flatten)]
+    status: Status,
+}
+
+/// What an earlier run with the same parameters got to.
+#[derive(Default)]
+pub struct Resumed {
+    pub done: HashSet<String>,
+    /// Block name to why it failed.
+    pub failed: BTreeMap<String, String>,
+}
+
+pub struct Checkpoint {
+    journal: File,
+    params: String,
+}
+
+impl Checkpoint {
+    /// Starts the checkpoint of a run with `params` in `dir`, that of the
+    /// blocks. With `resume` it keeps what an earlier run with the same
+    /// parameters recorded, and returns it; otherwise it is emptied.
+    pub fn open(dir: &Path, params: &str, resume: bool) -> (Self, Resumed) {
+        let path = dir.join(CHECKPOINT_NAME);
+        let mut latest = BTreeMap::new();
+        let mut other_params = 0;
+        if resume {
+            if let Ok(f) = File::open(&path) {
+                for line in BufReader::new(f).lines() {
+                    let Ok(line) = line else { break };
+                    // A torn last line from a crash.
+                    let Ok(line) = serde_json::from_str::<Line>(&line) else {
+                        continue;
+                    };
+                    if line.params != params {
+                        other_params += 1;
+                        continue;
+                    }
+                    latest.insert(line.block.clone(), line);
+                }
+            }
+        }
+        if other_params > 0 {
+            status!("Ignoring {other_params} checkpointed blocks stitched with other parameters");
+        }
+
+        // Rewritten with one line per block, like the manifest.
+        let tmp = dir.join(format!("{CHECKPOINT_NAME}.tmp"));
+        std::fs::create_dir_all(dir).unwrap();
+        let mut f = std::io::BufWriter::new(File::create(&tmp).unwrap());
+        let mut resumed = Resumed::default();
+        for (block, line) in latest {
+            writeln!(f, "{}", serde_json::to_string(&line).unwrap()).unwrap();
+            match line.status {
+                Status::Done => {
+                    resumed.done.insert(block);
+                }
+                Status::Failed { reason } => {
+                    resumed.failed.insert(block, reason);
+                }
+            }
+        }
+        f.into_inner().unwrap().sync_all().unwrap();
+        std::fs::rename(tmp, &path).unwrap();
+        let journal = OpenOptions::new().append(true).open(path).unwrap();
+        let checkpoint = Self {
+            journal,
+            params: params.to_owned(),
+        };
+        (checkpoint, resumed)
+    }
+
+    /// Records how a block ended and flushes it right away.
+    pub fn record(&mut self, block: &str, outcome: &BlockOutcome) {
+        let status = match outcome {
+            BlockOutcome::Rendered | BlockOutcome::Skipped => Status::Done,
+            BlockOutcome::Failed(reason) => Status::Failed {
+                reason: reason.clone(),
+            },
+            BlockOutcome::Unpaired(tiles) => Status::Failed {
+                reason: format!("{} unpaired tiles", tiles.len()),
+            },
+        };
+        let line = Line {
+            block: block.to_owned(),
+            params: self.params.clone(),
+            status,
+        };
+        writeln!(self.journal, "{}", serde_json::to_string(&line).unwrap()).unwrap();
+        self.journal.flush().unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Formats of the stitched chips and masks, from the `formats.json` of
+//! `map-segmentation-gendata`. Only the keys that concern stitching are
+//! read here, tile naming and paths among them.
+
+use std::path::Path;
+
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+
+use crate::layout;
+
+const FORMATS_FILE: &str = "formats.json";
+
+/// Quality of lossy WebP chips, the default of the JPEG encoder.
+#[cfg(feature = "webp")]
+const WEBP_QUALITY: u8 = 75;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChipFormat {
+    #[default]
+    Jpeg,
+    Webp,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaskFormat {
+    #[default]
+    Png,
+    PalettePng,
+}
+
+/// How coordinates are written in tile names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileNames {
+    #[default]
+    Plain,
+    /// Zero-padded to six digits, enough for every tile up to zoom 19.
+    Padded,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Formats {
+    pub chips: ChipFormat,
+    pub masks: MaskFormat,
+    pub tile_names: TileNames,
+    pub tile_paths: Option<String>,
+}
+
+impl Formats {
+    /// Reads the `formats.json` of the `workspace`, the defaults without one.
+    pub fn load(workspace: &Path) -> Result<Self, String> {
+        let path = workspace.join(FORMATS_FILE);
+        match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|why| format!("cannot parse {}: {why}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(why) => Err(format!("cannot read {}: {why}", path.display())),
+        }
+    }
+
+    pub fn tile_paths(&self) -> &str {
+        self.tile_paths
+            .as_deref()
+            .unwrap_or(layout::DEFAULT_TILE_PATHS)
+    }
+
+    /// Checks that this build can write the chosen formats.
+    pub fn check(&self) -> Result<(), String> {
+        if self.chips == ChipFormat::Webp && !cfg!(feature = "webp") {
+            return Err(format!(
+                "{FORMATS_FILE} asks for WebP chips\n\
+                 hint: rebuild with `--features webp`"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl TileNames {
+    /// `{y}-{x}`, the name of a tile and of the block it is the top left
+    /// of. Names of either kind parse the same.
+    pub fn name(self, x: u32, y: u32) -> String {
+        match self {
+            Self::Plain => format!("{y}-{x}"),
+            Self::Padded => format!("{y:06}-{x:06}"),
+        }
+    }
+}
+
+impl ChipFormat {
+    pub fn ext(self) -> &'static str {
+        match self {
+            Self::Jpeg => ".jpg",
+            Self::Webp => ".webp",
+        }
+    }
+}
+
+/// Encodes a chip as WebP; `Formats::check` keeps this from being called
+/// in builds without the encoder.
+pub fn encode_webp(img: &RgbImage) -> Vec<u8> {
+    #[cfg(feature = "webp")]
+    {
+        use image::codecs::webp::{WebPEncoder, WebPQuality};
+        let mut data = vec![];
+        WebPEncoder::new_with_quality(&mut data, WebPQuality::lossy(WEBP_QUALITY))
+            .encode(img, img.width(), img.height(), image::ColorType::Rgb8)
+            .unwrap();
+        data
+    }
+    #[cfg(not(feature = "webp"))]
+    {
+        let _ = img;
+        unreachable!("WebP chips need the webp feature")
+    }
+}
+
+/// The palette of an indexed PNG, `None` for any other image.
+pub fn palette_of(png_data: &[u8]) -> Option<Vec<[u8; 3]>> {
+    let reader = png::Decoder::new(png_data).read_info().ok()?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+        return None;
+    }
+    let palette = info.palette.as_ref()?;
+    Some(
+        palette
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect(),
+    )
+}
+
+/// Encodes as an indexed PNG with `palette` first and any other colors
+/// after it in the order they appear, `None` if there are more than 256.
+/// `transparent` is made fully transparent for viewers, keeping its index.
+pub fn encode_palette_png(
+    img: &RgbImage,
+    palette: &[[u8; 3]],
+    transparent: [u8; 3],
+) -> Option<Vec<u8>> {
+    let mut colors = palette.to_vec();
+    let mut last = None;
+    let mut indices = Vec::with_capacity(img.as_raw().len() / 3);
+    for px in img.pixels() {
+        // Label images are long runs of one color.
+        let i = match last {
+            Some((color, i)) if color == px.0 => i,
+            _ => match colors.iter().position(|c| *c == px.0) {
+                Some(i) => i,
+                None => {
+                    colors.push(px.0);
+                    colors.len() - 1
+                }
+            },
+        };
+        if i > 255 {
+            return None;
+        }
+        last = Some((px.0, i));
+        indices.push(i as u8);
+    }
+    let mut data = vec![];
+    let mut encoder = png::Encoder::new(&mut data, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(colors.concat());
+    if let Some(i) = colors.iter().position(|c| *c == transparent) {
+        let mut alpha = vec![255; i + 1];
+        alpha[i] = 0;
+        encoder.set_trns(alpha);
+    }
+    // Writing to memory with a valid header cannot fail.
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&indices).unwrap();
+    drop(writer);
+    Some(data)
+}
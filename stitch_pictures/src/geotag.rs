@@ -0,0 +1,203 @@
+//! Location of a chip written into the JPEG itself: the center as EXIF
+//! GPS tags, which photo viewers and curation tools show on a map, and
+//! the bounds as XMP. Both go into APP1 segments right after the SOI
+//! marker of the encoded image.
+//!
+//! With `--world-files` also next to the chip and its mask, in any
+//! format: a world file with the pixel size and position in EPSG:3857,
+//! the projection of the tiles, and a `.prj` naming it, which GIS tools
+//! and geo-aware loaders read.
+
+/// Radius of the sphere of Web Mercator, meters.
+const EARTH_RADIUS_M: f64 = 6_378_137.0;
+
+/// EPSG:3857 in the WKT of `.prj` files.
+pub const WEB_MERCATOR_PRJ: &str = concat!(
+    r#"PROJCS["WGS_1984_Web_Mercator_Auxiliary_Sphere","#,
+    r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984",SPHEROID["WGS_1984",6378137.0,298.257223563]],"#,
+    r#"PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]],"#,
+    r#"PROJECTION["Mercator_Auxiliary_Sphere"],PARAMETER["False_Easting",0.0],"#,
+    r#"PARAMETER["False_Northing",0.0],PARAMETER["Central_Meridian",0.0],"#,
+    r#"PARAMETER["Standard_Parallel_1",0.0],PARAMETER["Auxiliary_Sphere_Type",0.0],"#,
+    r#"UNIT["Meter",1.0]]"#
+);
+
+/// Chip extent in degrees.
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub north: f64,
+    pub south: f64,
+    pub east: f64,
+    pub west: f64,
+}
+
+impl Bounds {
+    /// Extent of the tiles `x0..x1` by `y0..y1` at `zoom`.
+    pub fn of_tiles(x: std::ops::Range<u32>, y: std::ops::Range<u32>, zoom: u8) -> Self {
+        let nw = mercator::coordinate(x.start as f64, y.start as f64, zoom);
+        let se = mercator::coordinate(x.end as f64, y.end as f64, zoom);
+        Self {
+            north: nw.latitude,
+            south: se.latitude,
+            west: nw.longitude,
+            east: se.longitude,
+        }
+    }
+
+    /// `[west, north, east, south]` in EPSG:3857 meters.
+    fn web_mercator(&self) -> [f64; 4] {
+        let x = |lon: f64| EARTH_RADIUS_M * lon.to_radians();
+        let y = |lat: f64| EARTH_RADIUS_M * lat.to_radians().tan().asinh();
+        [x(self.west), y(self.north), x(self.east), y(self.south)]
+    }
+
+    pub fn center(&self) -> (f64, f64) {
+        (
+            (self.north + self.south) / 2.0,
+            (self.east + self.west) / 2.0,
+        )
+    }
+}
+
+/// The world file of an image of `width` by `height` pixels over `bounds`:
+/// the size of a pixel, negative down, and the center of the top left one.
+pub fn world_file(bounds: &Bounds, width: u32, height: u32) -> String {
+    let [west, north, east, south] = bounds.web_mercator();
+    let (px_w, px_h) = (
+        (east - west) / width as f64,
+        (north - south) / height as f64,
+    );
+    format!(
+        "{px_w:.10}\n0.0\n0.0\n{:.10}\n{:.10}\n{:.10}\n",
+        -px_h,
+        west + px_w / 2.0,
+        north - px_h / 2.0
+    )
+}
+
+/// Extension of the world file of an image with `ext`: its first and last
+/// letter and a `w`, e.g. `jgw` for `.jpg`.
+pub fn world_file_ext(ext: &str) -> String {
+    let ext = ext.trim_start_matches('.');
+    let (first, last) = (&ext[..1], &ext[ext.len() - 1..]);
+    format!("{first}{last}w")
+}
+
+/// Degrees as the three rationals EXIF wants, seconds to 1/10000.
+fn dms(deg: f64) -> [(u32, u32); 3] {
+    let deg = deg.abs();
+    let d = deg.floor();
+    let m = ((deg - d) * 60.0).floor();
+    let s = ((deg - d) * 60.0 - m) * 60.0;
+    [
+        (d as u32, 1),
+        (m as u32, 1),
+        ((s * 10_000.0).round() as u32, 10_000),
+    ]
+}
+
+/// A little-endian TIFF structure with IFD0 pointing to a GPS IFD.
+fn exif(lat: f64, lon: f64) -> Vec<u8> {
+    const ASCII: u16 = 2;
+    const LONG: u16 = 4;
+    const BYTE: u16 = 1;
+    const RATIONAL: u16 = 5;
+    // Header, IFD0 with one entry, then the GPS IFD with five entries and
+    // the two rational arrays after it.
+    let ifd0 = 8;
+    let gps = ifd0 + 2 + 12 + 4;
+    let lat_at = gps + 2 + 5 * 12 + 4;
+    let lon_at = lat_at + 24;
+
+    let mut out = b"II*\0".to_vec();
+    out.extend_from_slice(&(ifd0 as u32).to_le_bytes());
+    let entry = |out: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&value);
+    };
+    out.extend_from_slice(&1u16.to_le_bytes());
+    entry(&mut out, 0x8825, LONG, 1, (gps as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+
+    let lat_ref = if lat >= 0.0 { *b"N\0\0\0" } else { *b"S\0\0\0" };
+    let lon_ref = if lon >= 0.0 { *b"E\0\0\0" } else { *b"W\0\0\0" };
+    out.extend_from_slice(&5u16.to_le_bytes());
+    entry(&mut out, 0x0000, BYTE, 4, [2, 3, 0, 0]);
+    entry(&mut out, 0x0001, ASCII, 2, lat_ref);
+    entry(&mut out, 0x0002, RATIONAL, 3, (lat_at as u32).to_le_bytes());
+    entry(&mut out, 0x0003, ASCII, 2, lon_ref);
+    entry(&mut out, 0x0004, RATIONAL, 3, (lon_at as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    for (num, den) in dms(lat).into_iter().chain(dms(lon)) {
+        out.extend_from_slice(&num.to_le_bytes());
+        out.extend_from_slice(&den.to_le_bytes());
+    }
+    out
+}
+
+/// XMP signs coordinates by suffix, `DDD,MM.mmmmmmK`.
+fn xmp_coord(deg: f64, pos: char, neg: char) -> String {
+    let abs = deg.abs();
+    let minutes = (abs - abs.floor()) * 60.0;
+    let suffix = if deg >= 0.0 { pos } else { neg };
+    format!("{},{minutes:.6}{suffix}", abs.floor())
+}
+
+fn xmp(b: &Bounds) -> String {
+    let (lat, lon) = b.center();
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+    xmlns:chip="urn:map-segmentation-gendata:chip:1"
+    exif:GPSLatitude="{}"
+    exif:GPSLongitude="{}"
+    chip:North="{:.8}"
+    chip:South="{:.8}"
+    chip:East="{:.8}"
+    chip:West="{:.8}"/>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="r"?>"#,
+        xmp_coord(lat, 'N', 'S'),
+        xmp_coord(lon, 'E', 'W'),
+        b.north,
+        b.south,
+        b.east,
+        b.west,
+    )
+}
+
+fn app1(payload: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(payload);
+    segment
+}
+
+/// Inserts EXIF and XMP for `bounds` into an encoded JPEG.
+pub fn tag(jpeg: &[u8], bounds: &Bounds) -> Vec<u8> {
+    let (lat, lon) = bounds.center();
+    let mut exif_payload = b"Exif\0\0".to_vec();
+    exif_payload.extend(exif(lat, lon));
+    let mut xmp_payload = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+    xmp_payload.extend_from_slice(xmp(bounds).as_bytes());
+
+    let mut out = Vec::with_capacity(jpeg.len() + exif_payload.len() + xmp_payload.len() + 8);
+    out.extend_from_slice(&jpeg[..2]);
+    // EXIF has to come before any other APP segment but JFIF's APP0.
+    let mut rest = &jpeg[2..];
+    if rest.starts_with(&[0xFF, 0xE0]) {
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize + 2;
+        out.extend_from_slice(&rest[..len]);
+        rest = &rest[len..];
+    }
+    out.extend(app1(&exif_payload));
+    out.extend(app1(&xmp_payload));
+    out.extend_from_slice(rest);
+    out
+}
@@ -0,0 +1,59 @@
+//! JPEG decoding of source tiles, which dominates stitching. With the
+//! `fast-jpeg` feature tiles go through zune-jpeg, falling back to the
+//! image crate for anything it rejects.
+//!
+//! Encoding stays with the image crate: zune-jpeg only decodes, and a
+//! block is encoded once for the 64 tiles (at the default block size)
+//! decoded into it, so encoding is a small share of a run.
+//! `cargo run --release --example jpeg_decode [--features fast-jpeg]`
+//! measures the decoder on a directory of tiles.
+
+use image::DynamicImage;
+
+/// Name of the decoder in use, part of the stitch parameters because
+/// the decoders round differently.
+#[cfg(feature = "fast-jpeg")]
+pub const DECODER: &str = "zune-jpeg";
+#[cfg(not(feature = "fast-jpeg"))]
+pub const DECODER: &str = "image";
+
+#[cfg(feature = "fast-jpeg")]
+pub fn decode(data: &[u8]) -> Option<DynamicImage> {
+    use zune_core::{bytestream::ZCursor, colorspace::ColorSpace, options::DecoderOptions};
+
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGB);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(ZCursor::new(data), options);
+    let fast = decoder.decode().ok().and_then(|pixels| {
+        let info = decoder.info()?;
+        image::RgbImage::from_raw(info.width as u32, info.height as u32, pixels)
+    });
+    match fast {
+        Some(img) => Some(DynamicImage::ImageRgb8(img)),
+        None => image::load_from_memory(data).ok(),
+    }
+}
+
+#[cfg(not(feature = "fast-jpeg"))]
+pub fn decode(data: &[u8]) -> Option<DynamicImage> {
+    image::load_from_memory(data).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+    #[test]
+    fn decodes_to_the_encoded_size() {
+        let img = RgbImage::from_fn(64, 32, |x, y| image::Rgb([x as u8 * 4, y as u8 * 8, 128]));
+        let mut data = vec![];
+        JpegEncoder::new(&mut data).encode_image(&img).unwrap();
+        let decoded = decode(&data).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (64, 32));
+    }
+
+    #[test]
+    fn garbage_is_not_an_image() {
+        assert!(decode(b"not a jpeg").is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Where tile and chip files lie, the `tile_paths` template of
+//! `formats.json`, e.g. `{root}/{aoi}/{kind}/{z}/{x}/{y}.{ext}`. The
+//! generator checks the template; this only resolves it. A directory such
+//! as `../tiles` is the `{root}` `..` and the `{kind}` `tiles`.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use crate::formats::TileNames;
+
+pub const DEFAULT_TILE_PATHS: &str = "{root}/{kind}/{name}.{ext}";
+
+pub struct Layout {
+    /// Components after `{root}`.
+    components: Vec<String>,
+    aoi: String,
+    zoom: u8,
+    names: TileNames,
+}
+
+impl Layout {
+    pub fn new(template: &str, aoi: &str, zoom: u8, names: TileNames) -> Result<Self, String> {
+        let Some(rest) = template.strip_prefix("{root}/") else {
+            return Err(format!(
+                "tile_paths {template} does not start with {{root}}/"
+            ));
+        };
+        Ok(Self {
+            components: rest.split('/').map(str::to_owned).collect(),
+            aoi: aoi.to_owned(),
+            zoom,
+            names,
+        })
+    }
+
+    /// The file of the tile at `x`, `y` in `dir`, the directory of one kind.
+    pub fn path(&self, dir: &Path, x: u32, y: u32, ext: &str) -> PathBuf {
+        let (root, kind) = root_and_kind(dir);
+        let name = self.names.name(x, y);
+        self.components
+            .iter()
+            .fold(root.to_owned(), |path, component| {
+                let component = component
+                    .replace("{x}", &x.to_string())
+                    .replace("{y}", &y.to_string())
+                    .replace("{name}", &name);
+                path.join(self.fill_run(&component, kind, ext))
+            })
+    }
+
+    /// `x`, `y` of the tiles with a file in `dir`. Other files are left out.
+    pub fn list(&self, dir: &Path, ext: &str) -> Vec<(u32, u32)> {
+        let (root, kind) = root_and_kind(dir);
+        let components: Vec<_> = self
+            .components
+            .iter()
+            .map(|c| self.fill_run(c, kind, ext))
+            .collect();
+        let mut tiles = vec![];
+        walk(root.to_owned(), &components, &mut vec![], &mut tiles);
+        tiles
+    }
+
+    fn fill_run(&self, component: &str, kind: &OsStr, ext: &str) -> OsString {
+        if component == "{kind}" {
+            return kind.to_owned();
+        }
+        component
+            .replace("{aoi}", &self.aoi)
+            .replace("{kind}", &kind.to_string_lossy())
+            .replace("{z}", &self.zoom.to_string())
+            .replace("{ext}", ext.strip_prefix('.').unwrap_or(ext))
+            .into()
+    }
+}
+
+fn root_and_kind(dir: &Path) -> (&Path, &OsStr) {
+    match (dir.parent(), dir.file_name()) {
+        (Some(root), Some(kind)) => (root, kind),
+        _ => (dir, OsStr::new("")),
+    }
+}
+
+fn walk(
+    path: PathBuf,
+    components: &[OsString],
+    captured: &mut Vec<(String, String)>,
+    tiles: &mut Vec<(u32, u32)>,
+) {
+    let Some((component, rest)) = components.split_first() else {
+        tiles.extend(captured_tile(captured));
+        return;
+    };
+    let Some(pattern) = component.to_str().filter(|c| c.contains('{')) else {
+        return walk(path.join(component), rest, captured, tiles);
+    };
+    let Ok(entries) = std::fs::read_dir(&path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        // Tile names are ASCII, a name that is not UTF-8 is some other file.
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let len = captured.len();
+        if capture(pattern, name, captured) {
+            walk(path.join(name), rest, captured, tiles);
+        }
+        captured.truncate(len);
+    }
+}
+
+fn capture(pattern: &str, text: &str, captured: &mut Vec<(String, String)>) -> bool {
+    let Some(start) = pattern.find('{') else {
+        return pattern == text;
+    };
+    let Some(text) = text.strip_prefix(&pattern[..start]) else {
+        return false;
+    };
+    let end = start + pattern[start..].find('}').unwrap();
+    let (var, rest) = (&pattern[start + 1..end], &pattern[end + 1..]);
+    let allowed = |c: char| c.is_ascii_digit() || (var == "name" && c == '-');
+    let longest = text.find(|c| !allowed(c)).unwrap_or(text.len());
+    for len in (1..=longest).rev() {
+        captured.push((var.to_owned(), text[..len].to_owned()));
+        if capture(rest, &text[len..], captured) {
+            return true;
+        }
+        captured.pop();
+    }
+    false
+}
+
+fn captured_tile(captured: &[(String, String)]) -> Option<(u32, u32)> {
+    let get = |var: &str| {
+        captured
+            .iter()
+            .find(|(v, _)| v == var)
+            .map(|(_, s)| s.as_str())
+    };
+    let (x, y) = match (get("x"), get("y")) {
+        (Some(x), Some(y)) => (x, y),
+        _ => {
+            let (y, x) = get("name")?.split_once('-')?;
+            (x, y)
+        }
+    };
+    Some((x.parse().ok()?, y.parse().ok()?))
+}
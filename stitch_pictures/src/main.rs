@@ -1,173 +1,787 @@
-use std::{collections::HashSet, sync::mpsc, thread::spawn};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use image::{DynamicImage, RgbImage};
-use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
+use checkpoint::Checkpoint;
+use clap::{Parser, ValueEnum};
+use formats::{ChipFormat, Formats, MaskFormat};
+use image::{DynamicImage, ImageFormat, RgbImage};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use layout::Layout;
+use manifest::{BlockRecord, InputHasher, Manifest};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use report::{BlockOutcome, Report};
+use serde::Serialize;
 use slippy_map_tiles::Tile;
 
-const ZOOM: u8 = 17; // zoom where 1px=1m;
-
-fn get_tile(t: Tile) -> Option<DynamicImage> {
-    image::io::Reader::open(format!("../tiles/{}-{}.jpg", t.y(), t.x()))
-        .ok()?
-        .decode()
-        .ok()
-}
-
-fn get_outline(t: Tile) -> Option<DynamicImage> {
-    image::io::Reader::open(format!("../outlines/{}-{}.png", t.y(), t.x()))
-        .ok()?
-        .decode()
-        .ok()
-}
-
-fn build_tile_img(tile: &Tile) -> bool {
-    if std::fs::OpenOptions::new()
-        .open(format!("../stitched/tiles/{}-{}.jpg", tile.y(), tile.x()))
-        .is_ok()
-    {
-        return true; // already exists
-    }
-
-    let mut target_tile = RgbImage::new(256 * 8, 256 * 8);
-    let mut target_outline = RgbImage::new(256 * 8, 256 * 8);
-    for x in 0..8 {
-        for y in 0..8 {
-            let t = Tile::new(ZOOM, tile.x() + x, tile.y() + y).unwrap();
-            match get_tile(t) {
-                Some(img) => {
-                    image::imageops::overlay(
-                        &mut target_tile,
-                        &img.clone().into_rgb8(),
-                        img.width() as i64 * x as i64,
-                        img.height() as i64 * y as i64,
-                    );
-                }
-                None => {
-                    println!("{tile:?} cannot render: {t:?}: no tile");
-                    return false;
-                }
-            };
-
-            match get_outline(t) {
-                Some(img) => {
-                    image::imageops::overlay(
-                        &mut target_outline,
-                        &img.clone().into_rgb8(),
-                        img.width() as i64 * x as i64,
-                        img.height() as i64 * y as i64,
-                    );
-                }
-                None => {
-                    let mut error = RgbImage::new(256, 256);
-                    error.chunks_exact_mut(3).for_each(|v| {
-                        v[0] = 0;
-                        v[1] = 0;
-                        v[2] = 255;
-                    });
-                    image::imageops::overlay(
-                        &mut target_outline,
-                        &error,
-                        error.width() as i64 * x as i64,
-                        error.height() as i64 * y as i64,
-                    );
-
-                    // println!("{tile:?} cannot render: {t:?}: no outline");
-                    // return false;
-                }
-            };
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// `println!` unless `--quiet`; errors that stop the run print anyway.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
         }
+    };
+}
+
+mod checkpoint;
+mod formats;
+mod geotag;
+mod jpeg;
+mod layout;
+mod manifest;
+mod raw;
+mod report;
+
+const BLOCK: u32 = 8; // tiles per side of a stitched block, by default
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+enum Anchor {
+    /// Blocks start at tile coordinates divisible by the block size.
+    Global,
+    /// Blocks start at the top-left tile of the downloaded area.
+    Aoi,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+enum Edge {
+    /// Blocks that stick out of the area are not rendered.
+    Skip,
+    /// Parts of a block outside the area are filled with empty imagery
+    /// and `NoLabel` outlines.
+    Pad,
+    /// Blocks are cut down to the part that lies inside the area.
+    Crop,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// How the block grid is aligned.
+    #[arg(long, value_enum, default_value_t = Anchor::Global)]
+    anchor: Anchor,
+    /// What to do with blocks that cross the edge of the area.
+    #[arg(long, value_enum, default_value_t = Edge::Skip)]
+    edge: Edge,
+    /// Tiles per block, e.g. `2x2` for 512 px chips of 256 px tiles.
+    /// Blocks of another size than 8x8 go to a directory of their own,
+    /// `stitched-NxN/`, still named after their top-left tile.
+    #[arg(long, value_name = "NxN", default_value = "8x8", value_parser = parse_grid)]
+    grid: u32,
+    /// Tiles each block shares with the next one on either axis, for
+    /// overlapping crops. Adds `-overlapP` to the directory.
+    #[arg(long, value_name = "P", default_value_t = 0)]
+    overlap: u32,
+    /// Only emit blocks where every tile has both imagery and an outline,
+    /// instead of painting missing outlines as `NoLabel` squares. Padding
+    /// counts as missing, so this turns `--edge pad` into `--edge skip`.
+    #[arg(long)]
+    strict_pairing: bool,
+    /// Directory the generator builds the dataset in, with its
+    /// `formats.json`, `tiles/` and `outlines/`; the blocks are written
+    /// into it too. By default the parent, as it is run from `run/`.
+    #[arg(long, value_name = "DIR", default_value = "..")]
+    workspace: PathBuf,
+    /// Imagery to stitch, either JPEG tiles or a raw store made with
+    /// `convert-tiles`, which stitches without decoding JPEG. By default
+    /// `tiles/` of the workspace.
+    #[arg(long)]
+    tiles: Option<PathBuf>,
+    /// Zoom of the tiles, as `--zoom` of the generator; 17 is where one
+    /// pixel is about a meter.
+    #[arg(long, default_value_t = 17, value_parser = clap::value_parser!(u8).range(1..=19))]
+    zoom: u8,
+    /// Name of the area, for `{aoi}` in the `tile_paths` of `formats.json`,
+    /// as `--aoi` of the generator.
+    #[arg(long, default_value = "moscow")]
+    aoi: String,
+    /// Exit with 3 if more than this many blocks fail.
+    #[arg(long)]
+    max_failures: Option<usize>,
+    /// Exit with 3 if more than this fraction of blocks fail.
+    #[arg(long)]
+    max_failure_rate: Option<f64>,
+    /// Leave the location out of the stitched JPEGs. By default their
+    /// EXIF has the GPS position of the center and their XMP the bounds.
+    /// WebP chips are never tagged.
+    #[arg(long)]
+    no_geotags: bool,
+    /// Also write a world file and a `.prj` next to every chip and mask,
+    /// e.g. `.jgw` and `.pgw`, placing them in EPSG:3857 for GIS tools.
+    #[arg(long)]
+    world_files: bool,
+    /// Pick up an interrupted run from `stitched/checkpoint.jsonl`: blocks
+    /// it finished are kept without reading their inputs again, blocks
+    /// that failed are tried again, e.g. once their missing tiles have
+    /// been downloaded. Only a run with the same parameters is resumed.
+    #[arg(long)]
+    resume: bool,
+    /// Print nothing but the errors that stop the run, and draw no
+    /// progress bar; the outcome is in the exit code and `report.json`.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// Everything that changes the pixels, or tags, of a stitched block. Its
+/// hash is stored in the manifest, so changing any of these forces a rebuild.
+#[derive(Debug, Serialize)]
+struct StitchParams {
+    zoom: u8,
+    block: u32,
+    /// Left out without overlap like `jpeg_decoder`.
+    #[serde(skip_serializing_if = "is_default")]
+    overlap: u32,
+    tile_size: (u32, u32),
+    anchor: Anchor,
+    edge: Edge,
+    strict_pairing: bool,
+    error_color: [u8; 3],
+    /// Left out for the default decoder so existing manifests stay valid.
+    #[serde(skip_serializing_if = "is_default_decoder")]
+    jpeg_decoder: &'static str,
+    /// Left out when false, which is what blocks made before geotagging
+    /// have, so those are rebuilt with tags.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    geotags: bool,
+    /// Left out when false like `geotags`, so that turning it on rebuilds
+    /// the blocks with the files.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    world_files: bool,
+    /// From `formats.json`, left out for the defaults like `jpeg_decoder`.
+    #[serde(skip_serializing_if = "is_default")]
+    chips: ChipFormat,
+    #[serde(skip_serializing_if = "is_default")]
+    masks: MaskFormat,
+}
+
+fn is_default<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+
+fn is_default_decoder(name: &&str) -> bool {
+    *name == "image"
+}
+
+fn parse_grid(s: &str) -> Result<u32, String> {
+    let (w, h) = s.split_once('x').unwrap_or((s, s));
+    let (Ok(w), Ok(h)) = (w.parse::<u32>(), h.parse::<u32>()) else {
+        return Err(format!("{s} is not NxN, e.g. 4x4"));
+    };
+    if w != h {
+        return Err("blocks are square, NxN".to_owned());
     }
+    if !(1..=64).contains(&w) {
+        return Err(format!("{w} tiles per side is not in 1..=64"));
+    }
+    Ok(w)
+}
 
-    target_tile
-        .save(format!("../stitched/tiles/{}-{}.jpg", tile.y(), tile.x()))
-        .unwrap();
-    target_outline
-        .save(format!(
-            "../stitched/outlines/{}-{}.png",
-            tile.y(),
-            tile.x()
-        ))
-        .unwrap();
+/// How the area is cut into blocks.
+#[derive(Clone, Copy, Debug)]
+struct Blocks {
+    /// Tiles per side.
+    size: u32,
+    /// Tiles shared with the neighbor, less than `size`.
+    overlap: u32,
+}
+
+impl Blocks {
+    /// Tiles from the start of one block to that of the next.
+    fn stride(&self) -> u32 {
+        self.size - self.overlap
+    }
+
+    /// Starts of the blocks, counted from `origin`, containing `v`.
+    fn starts(&self, origin: u32, v: u32) -> impl Iterator<Item = u32> {
+        let (d, stride) = (v - origin, self.stride());
+        let first = (d + 1).saturating_sub(self.size).div_ceil(stride);
+        (first..=d / stride).map(move |k| origin + k * stride)
+    }
+
+    /// `stitched/` of the workspace, or for other blocks a directory named
+    /// after them.
+    fn dir(&self, workspace: &Path) -> PathBuf {
+        let mut name = "stitched".to_owned();
+        if self.size != BLOCK || self.overlap > 0 {
+            name += &format!("-{0}x{0}", self.size);
+        }
+        if self.overlap > 0 {
+            name += &format!("-overlap{}", self.overlap);
+        }
+        workspace.join(name)
+    }
+}
 
-    true
+/// Extent of the downloaded tiles, inclusive on both ends.
+#[derive(Clone, Copy, Debug)]
+struct Aoi {
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
 }
 
-fn main() {
-    let mut tiles_touched = HashSet::new();
+impl Aoi {
+    fn from_tiles(tiles: &[Tile]) -> Option<Self> {
+        let first = tiles.first()?;
+        let mut aoi = Self {
+            min_x: first.x(),
+            min_y: first.y(),
+            max_x: first.x(),
+            max_y: first.y(),
+        };
+        for t in tiles {
+            aoi.min_x = aoi.min_x.min(t.x());
+            aoi.min_y = aoi.min_y.min(t.y());
+            aoi.max_x = aoi.max_x.max(t.x());
+            aoi.max_y = aoi.max_y.max(t.y());
+        }
+        Some(aoi)
+    }
 
-    let mut files = std::fs::read_dir("../tiles")
-        .unwrap()
-        .map(|v| v.unwrap().file_name().to_string_lossy().to_string())
-        .collect::<Vec<_>>();
-    files.sort();
+    fn contains(&self, x: u32, y: u32) -> bool {
+        (self.min_x..=self.max_x).contains(&x) && (self.min_y..=self.max_y).contains(&y)
+    }
 
-    let mut all_tiles = vec![];
+    /// Whether all of the tiles `x` by `y` lie inside.
+    fn covers(&self, x: &Range<u32>, y: &Range<u32>) -> bool {
+        x.start >= self.min_x
+            && x.end <= self.max_x + 1
+            && y.start >= self.min_y
+            && y.end <= self.max_y + 1
+    }
 
-    for name in files.into_iter() {
-        // let img = image::io::Reader::open(format!("tiles/{name}"))
-        //     .unwrap()
-        //     .decode()
-        //     .unwrap();
-        let mut parts = name.strip_suffix(".jpg").unwrap().split("-");
-        let y = parts.next().unwrap().parse().unwrap();
-        let x = parts.next().unwrap().parse().unwrap();
-        let tile = Tile::new(ZOOM, x, y).unwrap();
-        all_tiles.push(tile);
+    /// Top-left tiles of the blocks containing `(x, y)`, more than one
+    /// if they overlap.
+    fn anchors_of(&self, anchor: Anchor, blocks: Blocks, x: u32, y: u32) -> Vec<(u32, u32)> {
+        let (ox, oy) = match anchor {
+            Anchor::Global => (0, 0),
+            Anchor::Aoi => (self.min_x, self.min_y),
+        };
+        let xs: Vec<_> = blocks.starts(ox, x).collect();
+        blocks
+            .starts(oy, y)
+            .flat_map(|y0| xs.iter().map(move |x0| (*x0, y0)))
+            .collect()
     }
+}
 
-    println!("{}", all_tiles.len());
+/// Color of the `NoLabel` class of the generator, reserved for tiles
+/// without an outline; transparent in palette masks.
+const NO_LABEL_COLOR: [u8; 3] = [0, 0, 255];
 
-    let mut ok = 0;
-    let mut fail = 0;
+/// Where the imagery comes from; its format follows from the file names.
+struct TileStore {
+    dir: PathBuf,
+    ext: &'static str,
+    layout: Arc<Layout>,
+}
 
-    let style = ProgressStyle::with_template(
-        "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
-    )
-    .unwrap();
+impl TileStore {
+    /// Lists the tiles of `dir`, which must all be in the same format.
+    fn open(dir: &Path, layout: Arc<Layout>, zoom: u8) -> Result<(Self, Vec<Tile>), String> {
+        let raw = layout.list(dir, raw::EXT);
+        let jpeg = layout.list(dir, ".jpg");
+        // Under a nested `tile_paths` the directory itself need not exist.
+        if raw.is_empty() && jpeg.is_empty() && !dir.is_dir() {
+            return Err(format!("cannot read {}: not a directory", dir.display()));
+        }
+        if !raw.is_empty() && !jpeg.is_empty() {
+            return Err(format!("{} mixes JPEG and raw tiles", dir.display()));
+        }
+        let (ext, found) = match raw.is_empty() {
+            false => (raw::EXT, raw),
+            true => (".jpg", jpeg),
+        };
+        let mut tiles: Vec<_> = found
+            .into_iter()
+            .filter_map(|(x, y)| Tile::new(zoom, x, y))
+            .collect();
+        tiles.sort_by_key(|t| (t.y(), t.x()));
+        let store = Self {
+            dir: dir.to_owned(),
+            ext,
+            layout,
+        };
+        Ok((store, tiles))
+    }
 
-    let (tx, rx) = std::sync::mpsc::sync_channel(100);
+    fn path(&self, t: Tile) -> PathBuf {
+        self.layout.path(&self.dir, t.x(), t.y(), self.ext)
+    }
 
-    enum Request {
-        Contains(Tile, mpsc::SyncSender<bool>),
-        Add(Tile),
+    fn decode(&self, data: Option<&Vec<u8>>) -> Option<DynamicImage> {
+        if self.ext == raw::EXT {
+            raw::decode(data?).map(DynamicImage::ImageRgb8)
+        } else {
+            jpeg::decode(data?)
+        }
     }
 
-    spawn(move || loop {
-        let req: Request = rx.recv().unwrap();
-        match req {
-            Request::Contains(tile, tx) => tx.send(tiles_touched.contains(&tile)).unwrap(),
-            Request::Add(tile) => {
-                tiles_touched.insert(tile);
-            }
+    /// Reads the pixel size of a tile without decoding it. Every other
+    /// tile is expected to match, which `build_tile_img` checks.
+    fn dimensions(&self, t: Tile) -> Option<(u32, u32)> {
+        if self.ext == raw::EXT {
+            let mut header = [0; 12];
+            let mut f = std::fs::File::open(self.path(t)).ok()?;
+            std::io::Read::read_exact(&mut f, &mut header).ok()?;
+            raw::dimensions(&header)
+        } else {
+            image::image_dimensions(self.path(t)).ok()
         }
+    }
+}
+
+struct Job {
+    tiles: TileStore,
+    layout: Arc<Layout>,
+    /// `outlines/` of the workspace.
+    outlines: PathBuf,
+    /// Where the blocks go, `Blocks::dir`.
+    stitched: PathBuf,
+    zoom: u8,
+    tile_size: (u32, u32),
+    aoi: Aoi,
+    blocks: Blocks,
+    edge: Edge,
+    strict_pairing: bool,
+    geotags: bool,
+    world_files: bool,
+    formats: Formats,
+    params_hash: String,
+    manifest: Mutex<Manifest>,
+    /// Blocks the resumed run finished.
+    done: HashSet<String>,
+}
+
+fn outline_path(t: Tile, job: &Job) -> PathBuf {
+    job.layout.path(&job.outlines, t.x(), t.y(), ".png")
+}
+
+fn decode(data: Option<&Vec<u8>>) -> Option<DynamicImage> {
+    image::load_from_memory(data?).ok()
+}
+
+fn no_label_outline(tile_size: (u32, u32)) -> RgbImage {
+    let mut outline = RgbImage::new(tile_size.0, tile_size.1);
+    outline.chunks_exact_mut(3).for_each(|v| {
+        v.copy_from_slice(&NO_LABEL_COLOR);
     });
+    outline
+}
 
-    let pb = ProgressBar::new(all_tiles.len() as u64).with_style(style);
-    all_tiles.sort_by_key(|v| (v.x(), v.y()));
-    all_tiles.par_iter().for_each(|tile| {
-        pb.inc(1);
-        if tile.x() % 8 != 0 {
-            return;
+/// `path` with `.part` appended, keeping its extension intact.
+fn part_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".part");
+    tmp.into()
+}
+
+/// Makes the directory `path` is in, which `tile_paths` may nest.
+fn create_parent(path: &Path) {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+}
+
+/// Saves through a temporary file so an interrupted run never leaves a
+/// truncated image under the final name.
+fn save_atomic(img: &RgbImage, path: &Path, format: ImageFormat) {
+    create_parent(path);
+    let tmp = part_path(path);
+    img.save_with_format(&tmp, format).unwrap();
+    std::fs::rename(tmp, path).unwrap();
+}
+
+/// Like `save_atomic`, for an image encoded already.
+fn write_atomic(data: &[u8], path: &Path) {
+    create_parent(path);
+    let tmp = part_path(path);
+    std::fs::write(&tmp, data).unwrap();
+    std::fs::rename(tmp, path).unwrap();
+}
+
+/// Writes the world file and `.prj` of the image at `path`.
+fn save_world_files(path: &Path, bounds: &geotag::Bounds, size: (u32, u32)) {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    let world = geotag::world_file(bounds, size.0, size.1);
+    write_atomic(
+        world.as_bytes(),
+        &path.with_extension(geotag::world_file_ext(&ext)),
+    );
+    write_atomic(
+        geotag::WEB_MERCATOR_PRJ.as_bytes(),
+        &path.with_extension("prj"),
+    );
+}
+
+/// Like `save_atomic`, with the location of the block in the file.
+fn save_jpeg_tagged(img: &RgbImage, path: &Path, bounds: &geotag::Bounds) {
+    let mut jpeg = vec![];
+    image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+        .encode_image(img)
+        .unwrap();
+    write_atomic(&geotag::tag(&jpeg, bounds), path);
+}
+
+/// Range of tiles of the block at `anchor` that end up in the output, in
+/// tile coordinates.
+fn block_ranges(anchor: (u32, u32), job: &Job) -> (Range<u32>, Range<u32>) {
+    let (aoi, size) = (job.aoi, job.blocks.size);
+    match job.edge {
+        Edge::Skip | Edge::Pad => (anchor.0..anchor.0 + size, anchor.1..anchor.1 + size),
+        Edge::Crop => (
+            anchor.0.max(aoi.min_x)..(anchor.0 + size).min(aoi.max_x + 1),
+            anchor.1.max(aoi.min_y)..(anchor.1 + size).min(aoi.max_y + 1),
+        ),
+    }
+}
+
+fn build_tile_img(anchor: (u32, u32), job: &Job) -> (String, BlockOutcome) {
+    let Job {
+        tile_size,
+        aoi,
+        edge,
+        ..
+    } = job;
+    let (tile_size, edge) = (*tile_size, *edge);
+    let (x_range, y_range) = block_ranges(anchor, job);
+    let (x0, y0) = (x_range.start, y_range.start);
+    let name = job.formats.tile_names.name(x0, y0);
+    let stitched = &job.stitched;
+    let tile_out = job
+        .layout
+        .path(&stitched.join("tiles"), x0, y0, job.formats.chips.ext());
+    let outline_out = job.layout.path(&stitched.join("outlines"), x0, y0, ".png");
+    if job.done.contains(&name) && tile_out.exists() && outline_out.exists() {
+        return (name, BlockOutcome::Skipped);
+    }
+
+    let mut sources = vec![];
+    let mut hasher = InputHasher::default();
+    for tx in x_range.clone() {
+        for ty in y_range.clone() {
+            let t = Tile::new(job.zoom, tx, ty).unwrap();
+            if edge == Edge::Pad && !aoi.contains(tx, ty) {
+                sources.push((t, None, None));
+                continue;
+            }
+            let tile_data = std::fs::read(job.tiles.path(t)).ok();
+            let outline = outline_path(t, job);
+            let outline_data = std::fs::read(&outline).ok();
+            hasher.add("tiles", t, tile_data.as_deref());
+            hasher.add("outlines", t, outline_data.as_deref());
+            sources.push((t, tile_data, outline_data));
         }
-        if tile.y() % 8 != 0 {
-            return;
+    }
+    if job.strict_pairing {
+        let unpaired: Vec<_> = sources
+            .iter()
+            .filter_map(|(t, tile_data, outline_data)| {
+                let t = format!("{}-{}", t.y(), t.x());
+                match (tile_data, outline_data) {
+                    (Some(_), Some(_)) => None,
+                    (Some(_), None) => Some(format!("{t}: no outline")),
+                    (None, Some(_)) => Some(format!("{t}: no tile")),
+                    (None, None) => Some(format!("{t}: no tile and no outline")),
+                }
+            })
+            .collect();
+        if !unpaired.is_empty() {
+            return (name, BlockOutcome::Unpaired(unpaired));
+        }
+    }
+
+    let mut record = BlockRecord {
+        inputs: hasher.finish(),
+        params: job.params_hash.clone(),
+        no_label: vec![],
+    };
+
+    let up_to_date = job
+        .manifest
+        .lock()
+        .unwrap()
+        .blocks
+        .get(&name)
+        .is_some_and(|r| r.inputs == record.inputs && r.params == record.params);
+    if up_to_date && tile_out.exists() && outline_out.exists() {
+        return (name, BlockOutcome::Skipped);
+    }
+
+    let (tile_w, tile_h) = tile_size;
+    let width = tile_w * x_range.len() as u32;
+    let height = tile_h * y_range.len() as u32;
+    let mut target_tile = RgbImage::new(width, height);
+    let mut target_outline = RgbImage::new(width, height);
+    for (t, tile_data, outline_data) in &sources {
+        let t = *t;
+        let (x, y) = (t.x() - x0, t.y() - y0);
+        if edge == Edge::Pad && !aoi.contains(t.x(), t.y()) {
+            record
+                .no_label
+                .push(job.formats.tile_names.name(t.x(), t.y()));
+            image::imageops::overlay(
+                &mut target_outline,
+                &no_label_outline(tile_size),
+                tile_w as i64 * x as i64,
+                tile_h as i64 * y as i64,
+            );
+            continue;
         }
-        let (my_tx, my_rx) = mpsc::sync_channel(1);
-        tx.send(Request::Contains(*tile, my_tx)).unwrap();
+        match job.tiles.decode(tile_data.as_ref()) {
+            Some(img) if img.width() != tile_w || img.height() != tile_h => {
+                let reason = format!(
+                    "{t:?}: tile is {}x{}, expected {tile_w}x{tile_h}",
+                    img.width(),
+                    img.height()
+                );
+                return (name, BlockOutcome::Failed(reason));
+            }
+            Some(img) => {
+                image::imageops::overlay(
+                    &mut target_tile,
+                    &img.into_rgb8(),
+                    tile_w as i64 * x as i64,
+                    tile_h as i64 * y as i64,
+                );
+            }
+            None => {
+                return (name, BlockOutcome::Failed(format!("{t:?}: no tile")));
+            }
+        };
 
-        if !my_rx.recv().unwrap() {
-            if build_tile_img(&tile) {
-            } else {
+        match decode(outline_data.as_ref()) {
+            Some(img) if img.width() != tile_w || img.height() != tile_h => {
+                let reason = format!(
+                    "{t:?}: outline is {}x{}, expected {tile_w}x{tile_h}",
+                    img.width(),
+                    img.height()
+                );
+                return (name, BlockOutcome::Failed(reason));
             }
-            for dx in 0..8 {
-                for dy in 0..8 {
-                    tx.send(Request::Add(
-                        Tile::new(ZOOM, tile.x() + dx, tile.y() + dy).unwrap(),
-                    ))
-                    .unwrap();
-                }
+            Some(img) => {
+                image::imageops::overlay(
+                    &mut target_outline,
+                    &img.into_rgb8(),
+                    tile_w as i64 * x as i64,
+                    tile_h as i64 * y as i64,
+                );
+            }
+            None if job.strict_pairing => {
+                let reason = format!("{t:?}: outline does not decode");
+                return (name, BlockOutcome::Failed(reason));
             }
+            None => {
+                record
+                    .no_label
+                    .push(job.formats.tile_names.name(t.x(), t.y()));
+                image::imageops::overlay(
+                    &mut target_outline,
+                    &no_label_outline(tile_size),
+                    tile_w as i64 * x as i64,
+                    tile_h as i64 * y as i64,
+                );
+            }
+        };
+    }
+
+    let bounds = geotag::Bounds::of_tiles(x_range, y_range, job.zoom);
+    match job.formats.chips {
+        ChipFormat::Jpeg if job.geotags => save_jpeg_tagged(&target_tile, &tile_out, &bounds),
+        ChipFormat::Jpeg => save_atomic(&target_tile, &tile_out, ImageFormat::Jpeg),
+        ChipFormat::Webp => write_atomic(&formats::encode_webp(&target_tile), &tile_out),
+    }
+    // Indexed like the outlines it is made of, so that indices keep
+    // meaning the same classes.
+    let palette = (job.formats.masks == MaskFormat::PalettePng).then(|| {
+        sources
+            .iter()
+            .find_map(|(_, _, outline)| formats::palette_of(outline.as_ref()?))
+            .unwrap_or_default()
+    });
+    match palette.and_then(|p| formats::encode_palette_png(&target_outline, &p, NO_LABEL_COLOR)) {
+        Some(data) => write_atomic(&data, &outline_out),
+        None => save_atomic(&target_outline, &outline_out, ImageFormat::Png),
+    }
+    if job.world_files {
+        for path in [&tile_out, &outline_out] {
+            save_world_files(path, &bounds, (width, height));
         }
+    }
+    record.no_label.sort();
+    job.manifest.lock().unwrap().record(name.clone(), record);
+
+    (name, BlockOutcome::Rendered)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    QUIET.store(args.quiet, Ordering::Relaxed);
+
+    let formats = match Formats::load(&args.workspace).and_then(|f| f.check().map(|_| f)) {
+        Ok(formats) => formats,
+        Err(why) => {
+            println!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let layout = match Layout::new(
+        formats.tile_paths(),
+        &args.aoi,
+        args.zoom,
+        formats.tile_names,
+    ) {
+        Ok(layout) => Arc::new(layout),
+        Err(why) => {
+            println!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let tiles_dir = args
+        .tiles
+        .clone()
+        .unwrap_or_else(|| args.workspace.join("tiles"));
+    let (tiles, all_tiles) = match TileStore::open(&tiles_dir, layout.clone(), args.zoom) {
+        Ok(opened) => opened,
+        Err(why) => {
+            println!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    status!("{}", all_tiles.len());
+
+    // Any readable tile will do, the first may be a broken download.
+    let Some(tile_size) = all_tiles.iter().find_map(|t| tiles.dimensions(*t)) else {
+        println!("No readable tiles found, nothing to stitch");
+        return ExitCode::FAILURE;
+    };
+    status!("Tile size: {}x{}", tile_size.0, tile_size.1);
+    let geotags = !args.no_geotags && formats.chips == ChipFormat::Jpeg;
+
+    let aoi = Aoi::from_tiles(&all_tiles).unwrap();
+    status!("Area: {aoi:?}");
+
+    if args.overlap >= args.grid {
+        println!(
+            "--overlap {} leaves no stride between blocks of {} tiles",
+            args.overlap, args.grid
+        );
+        return ExitCode::FAILURE;
+    }
+    let blocks = Blocks {
+        size: args.grid,
+        overlap: args.overlap,
+    };
+    let anchors: BTreeSet<_> = all_tiles
+        .iter()
+        .flat_map(|t| aoi.anchors_of(args.anchor, blocks, t.x(), t.y()))
+        .collect();
+
+    let style = ProgressStyle::with_template(
+        "[{elapsed_precise}->{eta_precise}] {bar:100} [{human_pos}/{human_len} {percent}% {per_sec}]",
+    )
+    .unwrap();
+
+    let params = StitchParams {
+        zoom: args.zoom,
+        block: blocks.size,
+        overlap: blocks.overlap,
+        tile_size,
+        anchor: args.anchor,
+        edge: args.edge,
+        strict_pairing: args.strict_pairing,
+        error_color: NO_LABEL_COLOR,
+        jpeg_decoder: jpeg::DECODER,
+        geotags,
+        world_files: args.world_files,
+        chips: formats.chips,
+        masks: formats.masks,
+    };
+    let params_hash = manifest::hash_params(&params);
+    let stitched = blocks.dir(&args.workspace);
+    let (checkpoint, resumed) = Checkpoint::open(&stitched, &params_hash, args.resume);
+    if args.resume {
+        status!(
+            "Resuming: {} blocks done, {} failed and tried again",
+            resumed.done.len(),
+            resumed.failed.len()
+        );
+    }
+    let checkpoint = Mutex::new(checkpoint);
+    let job = Job {
+        tiles,
+        layout,
+        outlines: args.workspace.join("outlines"),
+        stitched: stitched.clone(),
+        zoom: args.zoom,
+        tile_size,
+        aoi,
+        blocks,
+        edge: args.edge,
+        strict_pairing: args.strict_pairing,
+        geotags,
+        world_files: args.world_files,
+        formats,
+        params_hash,
+        manifest: Mutex::new(Manifest::load(&stitched)),
+        done: resumed.done,
+    };
+    // Cropped at the edge, overlapping blocks may come down to the same
+    // top-left tile; the one reaching furthest stands for the others.
+    let mut by_origin = BTreeMap::new();
+    let mut outside = 0;
+    for anchor in anchors {
+        let (x_range, y_range) = block_ranges(anchor, &job);
+        // Left out before reading anything, not failed for want of the
+        // tiles outside the area.
+        if job.edge == Edge::Skip && !job.aoi.covers(&x_range, &y_range) {
+            outside += 1;
+            continue;
+        }
+        by_origin.insert((x_range.start, y_range.start), anchor);
+    }
+    let anchors: Vec<_> = by_origin.into_values().collect();
+    if outside > 0 {
+        status!("Left out {outside} blocks sticking out of the area, see --edge");
+    }
+
+    let report = Mutex::new(Report::default());
+    let target = match args.quiet {
+        true => ProgressDrawTarget::hidden(),
+        false => ProgressDrawTarget::stderr(),
+    };
+    let pb = ProgressBar::with_draw_target(Some(anchors.len() as u64), target).with_style(style);
+    anchors.par_iter().for_each(|anchor| {
+        pb.inc(1);
+        let (name, outcome) = build_tile_img(*anchor, &job);
+        checkpoint.lock().unwrap().record(&name, &outcome);
+        report.lock().unwrap().record(name, outcome);
     });
+    pb.finish();
+
+    job.manifest.into_inner().unwrap().compact();
+
+    let mut report = report.into_inner().unwrap();
+    report.judge(args.max_failures, args.max_failure_rate);
+    report.save(&stitched);
+    for (name, reason) in &report.failures {
+        status!("{name} cannot render: {reason}");
+    }
+    status!(
+        "Rendered: {}, skipped: {}, failed: {}",
+        report.rendered,
+        report.skipped,
+        report.failed
+    );
+    ExitCode::from(report.exit_code)
 }
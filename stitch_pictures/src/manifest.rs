@@ -0,0 +1,147 @@
+//! Record of what every stitched block was built from, so reruns can tell
+//! an up-to-date block from a stale or half-written one. Kept as an
+//! append-only journal, one line per finished block, so that a crash loses
+//! at most the block being written.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use slippy_map_tiles::Tile;
+
+const MANIFEST_NAME: &str = "manifest.jsonl";
+/// Compact once the journal has this many superseded lines.
+const COMPACT_AFTER: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockRecord {
+    /// Hash over the bytes of every source tile and outline in the block.
+    pub inputs: String,
+    /// Hash over the stitching parameters that affect the output pixels.
+    pub params: String,
+    /// Tiles of the block painted `NoLabel`, for lack of an outline or
+    /// outside the area, so loaders can leave them out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_label: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalLine {
+    block: String,
+    #[serde(flatten)]
+    record: BlockRecord,
+}
+
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub blocks: BTreeMap<String, BlockRecord>,
+    /// The directory of the stitched blocks it is about.
+    dir: PathBuf,
+    journal: Option<File>,
+    /// Lines in the journal, superseded ones included.
+    lines: usize,
+}
+
+impl Manifest {
+    /// Reads the journal in `dir`, ignoring a torn last line from a
+    /// crash, and compacts it if it has grown.
+    pub fn load(dir: &Path) -> Self {
+        let mut manifest = Self {
+            dir: dir.to_owned(),
+            ..Self::default()
+        };
+        if let Ok(f) = File::open(dir.join(MANIFEST_NAME)) {
+            for line in BufReader::new(f).lines() {
+                let Ok(line) = line else { break };
+                match serde_json::from_str::<JournalLine>(&line) {
+                    Ok(l) => {
+                        manifest.blocks.insert(l.block, l.record);
+                        manifest.lines += 1;
+                    }
+                    Err(e) => status!("Ignoring manifest line {:?}: {e}", line),
+                }
+            }
+        }
+        manifest.compact();
+        manifest
+    }
+
+    /// Records a finished block and flushes it to the journal right away.
+    pub fn record(&mut self, block: String, record: BlockRecord) {
+        let line = serde_json::to_string(&JournalLine {
+            block: block.clone(),
+            record: record.clone(),
+        })
+        .unwrap();
+        self.blocks.insert(block, record);
+        let journal = self.journal.as_mut().unwrap();
+        writeln!(journal, "{line}").unwrap();
+        journal.flush().unwrap();
+        self.lines += 1;
+        if self.lines > self.blocks.len() + COMPACT_AFTER {
+            self.compact();
+        }
+    }
+
+    /// Rewrites the journal with one line per block.
+    pub fn compact(&mut self) {
+        let path = self.dir.join(MANIFEST_NAME);
+        let tmp = self.dir.join(format!("{MANIFEST_NAME}.tmp"));
+        std::fs::create_dir_all(&self.dir).unwrap();
+        let mut f = std::io::BufWriter::new(File::create(&tmp).unwrap());
+        for (block, record) in &self.blocks {
+            let line = JournalLine {
+                block: block.clone(),
+                record: record.clone(),
+            };
+            writeln!(f, "{}", serde_json::to_string(&line).unwrap()).unwrap();
+        }
+        f.into_inner().unwrap().sync_all().unwrap();
+        std::fs::rename(tmp, &path).unwrap();
+        self.lines = self.blocks.len();
+        self.journal = Some(OpenOptions::new().append(true).open(path).unwrap());
+    }
+}
+
+/// Incrementally hashes block inputs. Missing files are hashed as a marker
+/// so that a tile appearing later changes the hash. Inputs are told apart
+/// by what they are, not where they are, so that moving the workspace or
+/// pointing `--tiles` at a copy of the store keeps blocks up to date.
+#[derive(Default)]
+pub struct InputHasher(Sha256);
+
+impl InputHasher {
+    /// Hashes `data` as the `kind` of input of `tile`, e.g. `tiles` or
+    /// `outlines`.
+    pub fn add(&mut self, kind: &str, tile: Tile, data: Option<&[u8]>) {
+        self.0.update((kind.len() as u64).to_le_bytes());
+        self.0.update(kind.as_bytes());
+        self.0.update([tile.zoom()]);
+        self.0.update(tile.x().to_le_bytes());
+        self.0.update(tile.y().to_le_bytes());
+        match data {
+            Some(data) => {
+                self.0.update((data.len() as u64).to_le_bytes());
+                self.0.update(data);
+            }
+            None => self.0.update(u64::MAX.to_le_bytes()),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        to_hex(&self.0.finalize())
+    }
+}
+
+pub fn hash_params(params: &impl Serialize) -> String {
+    to_hex(&Sha256::digest(serde_json::to_vec(params).unwrap()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
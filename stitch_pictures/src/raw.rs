@@ -0,0 +1,34 @@
+//! Reader for raw tile stores written by `convert-tiles`: a 12-byte
+//! header (`RGBZ`, little-endian `u32` width and height) followed by the
+//! zstd-compressed R, G and B planes.
+
+use image::RgbImage;
+
+pub const EXT: &str = ".rgb.zst";
+const MAGIC: &[u8; 4] = b"RGBZ";
+const HEADER: usize = 12;
+
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if data.len() < HEADER || &data[..4] != MAGIC {
+        return None;
+    }
+    let w = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let h = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    Some((w, h))
+}
+
+pub fn decode(data: &[u8]) -> Option<RgbImage> {
+    let (w, h) = dimensions(data)?;
+    let plane = (w * h) as usize;
+    let planes = zstd::bulk::decompress(&data[HEADER..], plane * 3).ok()?;
+    if planes.len() != plane * 3 {
+        return None;
+    }
+    let (r, gb) = planes.split_at(plane);
+    let (g, b) = gb.split_at(plane);
+    let mut pixels = vec![0; plane * 3];
+    for (((px, r), g), b) in pixels.chunks_exact_mut(3).zip(r).zip(g).zip(b) {
+        px.copy_from_slice(&[*r, *g, *b]);
+    }
+    RgbImage::from_raw(w, h, pixels)
+}
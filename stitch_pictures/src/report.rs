@@ -0,0 +1,81 @@
+//! End-of-run summary, written next to the stitched blocks so pipelines can
+//! tell a complete run from a partial one without parsing logs. The same
+//! verdict is the exit code: 0 if every block is in place, 2 if some
+//! failed within the thresholds, 3 if too many failed. Runs that cannot
+//! start exit with 1 and panics with 101.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+const REPORT_NAME: &str = "report.json";
+
+pub const SUCCESS: u8 = 0;
+pub const PARTIAL: u8 = 2;
+pub const TOO_MANY_FAILURES: u8 = 3;
+
+pub enum BlockOutcome {
+    Rendered,
+    /// Output exists and was built from the same inputs and parameters.
+    Skipped,
+    Failed(String),
+    /// Strict pairing rejected the block; lists the tiles lacking one of
+    /// the two modalities.
+    Unpaired(Vec<String>),
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub rendered: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Block name to the reason it could not be rendered.
+    pub failures: BTreeMap<String, String>,
+    /// Block name to the tiles that had imagery without an outline or the
+    /// other way round. Only filled in with `--strict-pairing`.
+    pub unpaired: BTreeMap<String, Vec<String>>,
+    pub exit_code: u8,
+}
+
+impl Report {
+    pub fn record(&mut self, name: String, outcome: BlockOutcome) {
+        match outcome {
+            BlockOutcome::Rendered => self.rendered += 1,
+            BlockOutcome::Skipped => self.skipped += 1,
+            BlockOutcome::Failed(reason) => {
+                self.failed += 1;
+                self.failures.insert(name, reason);
+            }
+            BlockOutcome::Unpaired(tiles) => {
+                self.failed += 1;
+                self.failures
+                    .insert(name.clone(), format!("{} unpaired tiles", tiles.len()));
+                self.unpaired.insert(name, tiles);
+            }
+        }
+    }
+
+    /// Sets `exit_code` from the failure counts, printing why it is not
+    /// `SUCCESS`.
+    pub fn judge(&mut self, max_failures: Option<usize>, max_failure_rate: Option<f64>) {
+        let total = self.rendered + self.skipped + self.failed;
+        let rate = self.failed as f64 / total.max(1) as f64;
+        self.exit_code = if let Some(max) = max_failures.filter(|max| self.failed > *max) {
+            status!("More than {max} blocks failed");
+            TOO_MANY_FAILURES
+        } else if let Some(max) = max_failure_rate.filter(|max| rate > *max) {
+            status!("Failure rate {rate:.4} is above {max}");
+            TOO_MANY_FAILURES
+        } else if self.failed > 0 {
+            PARTIAL
+        } else {
+            SUCCESS
+        };
+    }
+
+    /// Writes the report into `dir`, the directory of the blocks.
+    pub fn save(&self, dir: &Path) {
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        std::fs::write(dir.join(REPORT_NAME), data).unwrap();
+    }
+}